@@ -0,0 +1,155 @@
+// Benchmarks of the parser
+//
+// Run them with `make bench`.
+
+extern crate test;
+extern crate toml = "github.com/mneumann/rust-toml#toml:0.1";
+
+use std::io::{BufReader,File};
+use std::io::fs;
+use std::os;
+use test::BenchHarness;
+
+// A generated document of about 5MB, with a bit of everything
+fn large_document() -> ~str {
+    let mut doc = ~"";
+    for i in range(0, 40000) {
+        doc.push_str(format!("[[record]]\nid = {}\nname = \"record {}\" \\# its name\n\
+                              ts = 1979-05-27T07:32:00Z\ntags = [\"a\", \"b\", \"c\"]\n\
+                              ratio = {}.5\n\n", i, i, i));
+    }
+    return doc;
+}
+
+#[bench]
+fn parse_large_document(b: &mut BenchHarness) {
+    let doc = large_document();
+    b.bytes = doc.len() as u64;
+    b.iter(|| { toml::parse_from_str(doc).unwrap(); });
+}
+
+// Reads through a BufferedReader, refilling the parser's buffer
+// once per chunk
+#[bench]
+fn parse_large_document_from_reader(b: &mut BenchHarness) {
+    let doc = large_document();
+    b.bytes = doc.len() as u64;
+    b.iter(|| { toml::parse_from_reader(&mut BufReader::new(doc.as_bytes())).unwrap(); });
+}
+
+// A generated document of mostly strings without escapes, which
+// parse_borrowed doesn't copy
+fn string_document() -> ~str {
+    let mut doc = ~"";
+    for i in range(0, 40000) {
+        doc.push_str(format!("[[entry]]\nkey = \"entry-{}\"\ntitle = \"The title of entry {}\"\n\
+                              path = '/var/lib/entries/{}'\nlabels = [\"red\", \"green\", \"blue\"]\n\n", i, i, i));
+    }
+    return doc;
+}
+
+#[bench]
+fn parse_string_document(b: &mut BenchHarness) {
+    let doc = string_document();
+    b.bytes = doc.len() as u64;
+    b.iter(|| { toml::parse_from_str(doc).unwrap(); });
+}
+
+#[bench]
+fn parse_string_document_borrowed(b: &mut BenchHarness) {
+    let doc = string_document();
+    b.bytes = doc.len() as u64;
+    b.iter(|| { toml::parse_borrowed(doc).unwrap(); });
+}
+
+// Many tables of many small pairs
+fn small_keys_document() -> ~str {
+    let mut doc = ~"";
+    for i in range(0, 2000) {
+        doc.push_str(format!("[table{}]\n", i));
+        for j in range(0, 50) {
+            doc.push_str(format!("k{} = {}\n", j, j));
+        }
+    }
+    return doc;
+}
+
+// A few arrays of many elements
+fn array_document() -> ~str {
+    let mut doc = ~"";
+    for name in ["ints", "floats", "strings"].iter() {
+        doc.push_str(format!("{} = [", *name));
+        for i in range(0, 100000) {
+            if i > 0 { doc.push_str(", ") }
+            match *name {
+                "ints" => doc.push_str(i.to_str()),
+                "floats" => doc.push_str(format!("{}.25", i)),
+                _ => doc.push_str(format!("\"s{}\"", i))
+            }
+        }
+        doc.push_str("]\n");
+    }
+    return doc;
+}
+
+// Long strings of every kind, with comments and indentation between them
+fn long_string_document() -> ~str {
+    let text = "Lorem ipsum dolor sit amet, consectetur adipiscing elit. ".repeat(200);
+    let mut doc = ~"";
+    for i in range(0, 200) {
+        doc.push_str(format!("    \\# string {}\nbasic{} = \"{}\"\nliteral{} = '{}'\n\
+                              multi{} = \"\"\"\n{}\n{}\"\"\"\n", i, i, text, i, text, i, text, text));
+    }
+    return doc;
+}
+
+// Deeply nested sections and inline values
+fn nested_document() -> ~str {
+    let mut doc = ~"";
+    let mut name = ~"root";
+    for i in range(0, 500) {
+        name.push_str(format!(".level{}", i));
+        doc.push_str(format!("[{}]\nvalue = \\{ a = [[1, 2], [3, 4]], b = \\{ c = \\{ d = {} \\} \\} \\}\n", name, i));
+    }
+    return doc;
+}
+
+#[bench]
+fn parse_small_keys(b: &mut BenchHarness) {
+    let doc = small_keys_document();
+    b.bytes = doc.len() as u64;
+    b.iter(|| { toml::parse_from_str(doc).unwrap(); });
+}
+
+#[bench]
+fn parse_arrays(b: &mut BenchHarness) {
+    let doc = array_document();
+    b.bytes = doc.len() as u64;
+    b.iter(|| { toml::parse_from_str(doc).unwrap(); });
+}
+
+#[bench]
+fn parse_long_strings(b: &mut BenchHarness) {
+    let doc = long_string_document();
+    b.bytes = doc.len() as u64;
+    b.iter(|| { toml::parse_from_str(doc).unwrap(); });
+}
+
+#[bench]
+fn parse_nested(b: &mut BenchHarness) {
+    let doc = nested_document();
+    b.bytes = doc.len() as u64;
+    b.iter(|| { toml::parse_from_str(doc).unwrap(); });
+}
+
+// The same document read from a file, for comparison with parsing it in
+// place from memory
+#[bench]
+fn parse_large_document_from_file(b: &mut BenchHarness) {
+    let doc = large_document();
+    let path = os::tmpdir().join("toml-bench-large.toml");
+    File::create(&path).write_str(doc).unwrap();
+    b.bytes = doc.len() as u64;
+    b.iter(|| { toml::parse_from_path(&path).unwrap(); });
+    fs::unlink(&path).unwrap();
+}
@@ -0,0 +1,331 @@
+// Command line front-end of the library: parses a document and prints
+// its value
+//
+// Usage: toml [OPTIONS] [FILE]
+//        toml --check [FILE...]
+//        toml --merge [OPTIONS] FILE...
+//        toml --toml-test
+//
+// The document is read from standard input if FILE is missing or "-".
+// With --get PATH only the value at PATH is printed, as `Value::lookup`
+// finds it: tables as TOML documents, other values as they would be
+// written after `key = `, and strings without quotes if --raw is given.
+// With --flat every leaf is printed as a "path = value" line, in
+// document order, limited to the leaves below the path given with
+// --prefix. With --check the files are only validated, and nothing is
+// printed for valid ones. With --from-json the input is a JSON object,
+// which is printed as a TOML document; as TOML has no null, a null in
+// it is an error unless --null says to leave it out ("skip") or to make
+// it an empty string ("empty-string").
+//
+// --merge merges the files from left to right with `Value::merge_with`,
+// so that later files override earlier ones, and prints the result as a
+// TOML document, or as JSON with --json. Arrays in later files replace
+// earlier ones, unless --array=append says to append their elements.
+//
+// --toml-test makes the tool a parser for the toml-test suite: the
+// document is read from standard input, parsed as TOML 0.4 like the suite
+// expects, and printed as tagged JSON, and an invalid document only makes
+// the exit status 1, without any output.
+//
+// The exit status is 0 if the documents parse, 1 if one is invalid, 2
+// for I/O errors and wrong arguments, and 3 if the --get or --prefix
+// PATH does not exist. Errors are written to standard error as
+// "FILE:LINE:COLUMN: MESSAGE", followed by the line and a caret under
+// the column, and nothing to standard output.
+
+extern crate getopts;
+extern crate toml = "github.com/mneumann/rust-toml#toml:0.1";
+
+use std::cmp;
+use std::io;
+use std::io::{BufReader,File};
+use std::os;
+use std::str;
+use std::task;
+
+fn main() {
+    let opts = ~[
+        getopts::optflag("", "check", "only validate the files"),
+        getopts::optflag("", "merge", "merge the files, later ones overriding earlier ones"),
+        getopts::optopt("", "array", "how --merge combines arrays: replace or append", "STRATEGY"),
+        getopts::optflag("", "toml-test", "act as a parser for the toml-test suite"),
+        getopts::optflag("", "from-json", "convert a JSON object into TOML"),
+        getopts::optopt("", "null", "what --from-json does with null: skip or empty-string", "POLICY"),
+        getopts::optflag("", "json", "print the value as JSON"),
+        getopts::optflag("", "pretty", "indent the JSON output"),
+        getopts::optflag("", "debug", "print the value in its debug form (the default)"),
+        getopts::optopt("", "get", "print only the value at PATH", "PATH"),
+        getopts::optflag("", "raw", "print strings found by --get without quotes"),
+        getopts::optflag("", "flat", "print every leaf as a `path = value` line"),
+        getopts::optopt("", "prefix", "print only the leaves of --flat below PATH", "PATH"),
+        getopts::optflag("h", "help", "print this help")
+    ];
+    let matches = match getopts::getopts(os::args().tail(), opts) {
+        Ok(matches) => matches,
+        Err(f) => {
+            error(f.to_err_msg());
+            os::set_exit_status(2);
+            return;
+        }
+    };
+    if matches.opt_present("help") {
+        println!("{}", getopts::usage("Usage: toml [OPTIONS] [FILE]\n       toml --check [FILE...]\n       \
+                                       toml --merge [OPTIONS] FILE...\n       toml --toml-test", opts));
+        return;
+    }
+
+    if matches.opt_present("toml-test") {
+        os::set_exit_status(toml_test());
+        return;
+    }
+
+    let names = if matches.free.is_empty() { ~[~"-"] } else { matches.free.clone() };
+    if matches.opt_present("check") {
+        let mut status = 0;
+        for name in names.iter() {
+            match parse(name.as_slice()) {
+                Ok(_) => {}
+                Err(s) => { status = cmp::max(status, s) }
+            }
+        }
+        os::set_exit_status(status);
+        return;
+    }
+
+    let json = matches.opt_present("json");
+    let pretty = matches.opt_present("pretty");
+
+    if matches.opt_present("merge") {
+        let arrays = match matches.opt_str("array") {
+            None => toml::Replace,
+            Some(ref strategy) if strategy.as_slice() == "replace" => toml::Replace,
+            Some(ref strategy) if strategy.as_slice() == "append" => toml::Append,
+            Some(strategy) => {
+                error(format!("invalid --array strategy `{}`, expected replace or append", strategy));
+                os::set_exit_status(2);
+                return;
+            }
+        };
+        let opts = toml::MergeOptions { arrays: arrays, .. toml::MergeOptions::new() };
+        os::set_exit_status(merge(names, opts, json, pretty));
+        return;
+    }
+
+    if matches.free.len() > 1 {
+        error("only one FILE can be given without --check or --merge");
+        os::set_exit_status(2);
+        return;
+    }
+    let name = names[0].as_slice();
+
+    if matches.opt_present("from-json") {
+        let nulls = match matches.opt_str("null") {
+            None => toml::RejectNull,
+            Some(ref policy) if policy.as_slice() == "skip" => toml::SkipNull,
+            Some(ref policy) if policy.as_slice() == "empty-string" => toml::NullAsEmptyString,
+            Some(policy) => {
+                error(format!("invalid --null policy `{}`, expected skip or empty-string", policy));
+                os::set_exit_status(2);
+                return;
+            }
+        };
+        os::set_exit_status(from_json(name, &toml::JsonOptions { nulls: nulls, .. toml::JsonOptions::new() }));
+        return;
+    }
+
+    let value = match parse(name) {
+        Ok(value) => value,
+        Err(status) => {
+            os::set_exit_status(status);
+            return;
+        }
+    };
+
+    if matches.opt_present("flat") {
+        let prefix = matches.opt_str("prefix").unwrap_or(~"");
+        let root = match lookup(&value, prefix.as_slice()) {
+            Some(root) => root,
+            None => { return }
+        };
+        for (path, leaf) in root.leaves().move_iter() {
+            let path = if path.is_empty() { prefix.clone() }
+                       else if prefix.is_empty() { path }
+                       else { format!("{}.{}", prefix, path) };
+            println!("{} = {}", path, leaf.to_inline_toml_str());
+        }
+        return;
+    }
+
+    match matches.opt_str("get") {
+        Some(path) => {
+            let found = match lookup(&value, path.as_slice()) {
+                Some(found) => found,
+                None => { return }
+            };
+            match *found {
+                _ if json => print_json(found, pretty),
+                toml::String(ref s) if matches.opt_present("raw") => println!("{}", *s),
+                toml::Table(..) => print_toml(found, path.as_slice()),
+                _ => println!("{}", found.to_inline_toml_str())
+            }
+        }
+        None if json => print_json(&value, pretty),
+        None => println!("{}", value)
+    }
+}
+
+// Prints the table at `path` as a document, reporting it with exit status
+// 1 if TOML cannot hold it
+fn print_toml(value: &toml::Value, path: &str) {
+    match value.to_toml_str() {
+        Ok(toml) => print!("{}", toml),
+        Err(e) => {
+            if path.is_empty() { error(format!("cannot write the document: {}", e)) }
+            else { error(format!("{}: cannot write the table: {}", path, e)) }
+            os::set_exit_status(1);
+        }
+    }
+}
+
+// Looks up `path`, the whole document if empty, reporting it if missing
+fn lookup<'a>(value: &'a toml::Value, path: &str) -> Option<&'a toml::Value> {
+    if path.is_empty() { return Some(value) }
+    match value.lookup_detailed(path) {
+        Ok(found) => Some(found),
+        Err(e) => {
+            error(format!("{}: {}", path, e));
+            os::set_exit_status(3);
+            None
+        }
+    }
+}
+
+fn print_json(value: &toml::Value, pretty: bool) {
+    println!("{}", if pretty { value.to_pretty_json_str() } else { value.to_json_str() });
+}
+
+fn error(message: &str) {
+    let _ = io::stderr().write_line(message);
+}
+
+// Merges the files `names` from left to right and prints the result,
+// returning the exit status
+fn merge(names: &[~str], opts: toml::MergeOptions, json: bool, pretty: bool) -> int {
+    let mut merged = toml::Table(false, ~toml::TableMap::new());
+    for name in names.iter() {
+        let value = match parse(name.as_slice()) {
+            Ok(value) => value,
+            Err(status) => { return status }
+        };
+        match merged.merge_with(&value, opts.clone()) {
+            Ok(()) => {}
+            Err(e) => {
+                error(format!("{}: cannot merge: {}", *name, e));
+                return 1;
+            }
+        }
+    }
+
+    if json {
+        print_json(&merged, pretty);
+        return 0;
+    }
+    match merged.to_toml_str() {
+        Ok(toml) => {
+            print!("{}", toml);
+            0
+        }
+        Err(e) => {
+            error(format!("cannot write the merged document: {}", e));
+            1
+        }
+    }
+}
+
+// Runs the toml-test protocol, returning the exit status. The document is
+// parsed in a task of its own, so that even a failure of the parser ends
+// with status 1.
+fn toml_test() -> int {
+    let bytes = match io::stdin().read_to_end() {
+        Ok(bytes) => bytes,
+        Err(_) => { return 2 }
+    };
+    match task::try(proc() {
+        let opts = toml::ParserOptions { version: toml::V0_4, .. toml::ParserOptions::new() };
+        toml::parse_with_options(&mut BufReader::new(bytes), &opts).ok().map(|doc| doc.value.to_tagged_json())
+    }) {
+        Ok(Some(json)) => {
+            println!("{}", json);
+            0
+        }
+        Ok(None) | Err(_) => 1
+    }
+}
+
+// Reads the file `name`, or standard input for "-". Errors are reported,
+// and give the exit status to use.
+fn read(name: &str) -> Result<~[u8], int> {
+    let read = if name == "-" {
+        io::stdin().read_to_end()
+    } else {
+        match File::open(&Path::new(name)) {
+            Ok(mut file) => file.read_to_end(),
+            Err(e) => Err(e)
+        }
+    };
+    match read {
+        Ok(bytes) => Ok(bytes),
+        Err(e) => {
+            error(format!("{}: {}", name, e));
+            Err(2)
+        }
+    }
+}
+
+// Prints the JSON object in the file `name` as TOML, returning the exit
+// status
+fn from_json(name: &str, opts: &toml::JsonOptions) -> int {
+    let bytes = match read(name) {
+        Ok(bytes) => bytes,
+        Err(status) => { return status }
+    };
+    let name = if name == "-" { "<stdin>" } else { name };
+    let result = match str::from_utf8(bytes) {
+        Some(json) => toml::json_to_toml_str_with(json, opts),
+        None => Err(~"invalid UTF-8")
+    };
+    match result {
+        Ok(toml) => {
+            print!("{}", toml);
+            0
+        }
+        Err(e) => {
+            error(format!("{}: {}", name, e));
+            1
+        }
+    }
+}
+
+// Parses the file `name`, or standard input for "-". Errors are reported,
+// and give the exit status to use. The input is read up front, to show
+// the lines errors are found on.
+fn parse(name: &str) -> Result<toml::Value, int> {
+    let bytes = match read(name) {
+        Ok(bytes) => bytes,
+        Err(status) => { return Err(status) }
+    };
+
+    match toml::parse_located(&mut BufReader::new(bytes)) {
+        Ok(value) => Ok(value),
+        Err(diagnostic) => {
+            let name = if name == "-" { "<stdin>" } else { name };
+            let report = match str::from_utf8(bytes) {
+                Some(source) => diagnostic.render(source),
+                None => diagnostic.to_str()
+            };
+            error(format!("{}:{}", name, report));
+            Err(1)
+        }
+    }
+}
@@ -36,6 +36,11 @@ fn main() {
             os::set_exit_status(1);
             return;
         }
+        Err(toml::Rejected { line: line, reason: reason }) => {
+            println!("line {}: {}", line, reason);
+            os::set_exit_status(1);
+            return;
+        }
         Err(toml::IOError(e)) => {
             println!("I/O error: {}", e);
             os::set_exit_status(1);
@@ -0,0 +1,32 @@
+// Fuzz harness: runs `toml::fuzz_one` on every FILE, or on standard input
+// if none is given, as fuzzers like afl expect. A crash or a failed task
+// is a bug of the parser. The exit status is 2 if an input cannot be
+// read, and 0 otherwise.
+//
+// Usage: fuzz [FILE...]
+
+extern crate toml = "github.com/mneumann/rust-toml#toml:0.1";
+
+use std::io;
+use std::io::File;
+use std::os;
+
+fn main() {
+    let args = os::args();
+    if args.len() == 1 {
+        run("<stdin>", io::stdin().read_to_end());
+    }
+    for name in args.tail().iter() {
+        run(name.as_slice(), File::open(&Path::new(name.as_slice())).read_to_end());
+    }
+}
+
+fn run(name: &str, read: io::IoResult<~[u8]>) {
+    match read {
+        Ok(data) => toml::fuzz_one(data),
+        Err(e) => {
+            let _ = io::stderr().write_line(format!("{}: {}", name, e));
+            os::set_exit_status(2);
+        }
+    }
+}
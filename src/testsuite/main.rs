@@ -5,18 +5,26 @@
 // If given a path, it will perform the same tests like toml-test [1],
 // so no need to install "go" :).
 
+#[feature(phase)];
+
 extern crate serialize;
 extern crate collections = "collections#0.10-pre";
-extern crate toml = "github.com/mneumann/rust-toml#toml:0.1";
+#[phase(syntax, link)] extern crate toml = "github.com/mneumann/rust-toml#toml:0.1";
 
 use serialize::json;
+use toml::lexer;
 use serialize::json::{Json,String,List,Object};
 
 use collections::treemap::TreeMap;
+use collections::hashmap::{HashMap,HashSet};
 use std::os;
 use std::path::Path;
+use std::io;
+use std::io::fs;
 use std::io::fs::walk_dir;
-use std::io::File;
+use std::io::{BufReader,BufferedReader,File,IoResult};
+use std::rand::{Rng,SeedableRng,XorShiftRng};
+use std::task;
 
 fn to_json_type(typ: ~str, val: Json) -> Json {
     let mut tree = ~TreeMap::new();
@@ -66,10 +74,2247 @@ fn to_json(v: &toml::Value) -> Json {
     }
 }
 
+// A writer accepting only `remaining` more bytes
+struct FailingWriter {
+    remaining: uint
+}
+
+impl Writer for FailingWriter {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        if buf.len() > self.remaining {
+            self.remaining = 0;
+            return Err(io::standard_error(io::OtherIoError));
+        }
+        self.remaining -= buf.len();
+        return Ok(());
+    }
+}
+
+// A reader returning at most one byte per read
+struct TrickleReader {
+    data: ~[u8],
+    pos: uint
+}
+
+impl Reader for TrickleReader {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        if self.pos >= self.data.len() {
+            return Err(io::standard_error(io::EndOfFile));
+        }
+        if buf.len() == 0 { return Ok(0) }
+        buf[0] = self.data[self.pos];
+        self.pos += 1;
+        return Ok(1);
+    }
+}
+
+// A buffer handing out its data in chunks of `chunk` bytes
+struct ChunkedBuffer {
+    data: ~[u8],
+    pos: uint,
+    chunk: uint
+}
+
+impl Reader for ChunkedBuffer {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        let len = {
+            let chunk = try!(self.fill_buf());
+            let len = std::cmp::min(chunk.len(), buf.len());
+            std::vec::bytes::copy_memory(buf, chunk.slice_to(len));
+            len
+        };
+        self.consume(len);
+        return Ok(len);
+    }
+}
+
+impl Buffer for ChunkedBuffer {
+    fn fill_buf<'a>(&'a mut self) -> IoResult<&'a [u8]> {
+        if self.pos == self.data.len() { return Err(io::standard_error(io::EndOfFile)) }
+        let end = std::cmp::min(self.pos + self.chunk, self.data.len());
+        return Ok(self.data.slice(self.pos, end));
+    }
+
+    fn consume(&mut self, amt: uint) {
+        self.pos += amt;
+    }
+}
+
+// Counts the keys of each section without building any tables
+struct KeyCounter {
+    counts: ~[(~str, uint)]
+}
+
+impl toml::Visitor for KeyCounter {
+    fn section(&mut self, path: ~[~str], _is_array: bool, _span: toml::Span) -> Result<(), ~str> {
+        self.counts.push((path.connect("."), 0));
+        Ok(())
+    }
+
+    fn pair(&mut self, _key: ~str, _val: toml::Value, _span: toml::Span) -> Result<(), ~str> {
+        if self.counts.is_empty() { self.counts.push((~"", 0)) }
+        *self.counts.mut_last().unwrap().mut1() += 1;
+        Ok(())
+    }
+}
+
+// Reports keys longer than `max_len` with their positions
+struct LongKeyLinter {
+    max_len: uint,
+    found: ~[(~str, uint, uint)]
+}
+
+impl toml::Visitor for LongKeyLinter {
+    fn section(&mut self, _path: ~[~str], _is_array: bool, _span: toml::Span) -> Result<(), ~str> {
+        Ok(())
+    }
+
+    fn pair(&mut self, key: ~str, _val: toml::Value, span: toml::Span) -> Result<(), ~str> {
+        if key.char_len() > self.max_len {
+            self.found.push((key, span.line, span.col));
+        }
+        Ok(())
+    }
+}
+
+// Rejects keys starting with an underscore
+struct NoPrivateKeys;
+
+impl toml::Visitor for NoPrivateKeys {
+    fn section(&mut self, _path: ~[~str], _is_array: bool, _span: toml::Span) -> Result<(), ~str> {
+        Ok(())
+    }
+
+    fn pair(&mut self, key: ~str, _val: toml::Value, _span: toml::Span) -> Result<(), ~str> {
+        if key.starts_with("_") { return Err(format!("private key `{}`", key)) }
+        Ok(())
+    }
+}
+
+// Tests of the parse entry points
+fn parser_tests(path: &Path) -> ~[(&'static str, bool)] {
+    let mut results = ~[];
+
+    let text = "title = \"example\"\n[owner]\nname = \"Tom\"\n";
+    let from_bytes = toml::parse_from_bytes(text.as_bytes()).ok();
+    results.push(("parse_from_str", from_bytes.is_some() && toml::parse_from_str(text).ok() == from_bytes));
+    results.push(("FromStr for Value", from_bytes.is_some() && from_str::<toml::Value>(text) == from_bytes));
+    results.push(("FromStr rejects invalid documents", from_str::<toml::Value>("a = ").is_none()));
+
+    let mut counter = KeyCounter { counts: ~[] };
+    let text = "a = 1\nb = 2\n[x]\nc = 3\n[[y]]\n[[y]]\nd = 4\ne = 5\n";
+    let ok = toml::parse_with_visitor(&mut BufReader::new(text.as_bytes()), &mut counter).is_ok();
+    results.push(("parse_with_visitor", ok && counter.counts == ~[(~"", 2), (~"x", 1), (~"y", 0), (~"y", 2)]));
+
+    let text = "a = 1\n[t]\n_secret = 2\n";
+    results.push(("visitor errors are propagated with their line",
+                   toml::parse_with_visitor(&mut BufReader::new(text.as_bytes()), &mut NoPrivateKeys) ==
+                   Err(toml::Rejected { line: 3, reason: ~"private key `_secret`" })));
+    results.push(("duplicate keys are rejected with their line",
+                   toml::parse_from_str("[t]\na = 1\na = 2\n") ==
+                   Err(toml::Rejected { line: 3, reason: ~"duplicate key `t.a`" })));
+    // whether each document is valid
+    let sequences = [
+        ("[a.b.c]\n[a]\n", true),
+        ("[a]\n[a.b.c]\n", true),
+        ("[a.b.c]\n[a.b]\n[a]\n", true),
+        ("[[a]]\n[a.b]\n[[a]]\n[a.b]\n", true),
+        ("[a.b.c]\n[a]\n[a]\n", false),
+        ("[a.b.c]\n[a.b]\n[a.b]\n", false),
+        ("[a]\nb = 1\n[a.b]\n", false),
+        ("[a.b.c]\n[a]\nb = 1\n", false),
+        ("[a.b]\n[[a]]\n", false),
+        ("[[a]]\n[a.b]\n[a.b]\n", false),
+        ("[a]\nb = { c = 1 }\n[a.b]\n", false),
+        ("[a]\n[[a.b]]\n[[a.b]]\n[a]\n", false)
+    ];
+    results.push(("tables created for sub-tables are defined once",
+                   sequences.iter().all(|&(text, valid)| {
+                       toml::parse_from_str(text).is_ok() == valid && toml::parse_borrowed(text).is_ok() == valid
+                   })));
+    results.push(("conflicting headers are rejected with both lines",
+                   toml::parse_from_str("[a.b.c]\n[a]\nx = 1\n[a]\n") ==
+                   Err(toml::Rejected { line: 4, reason: ~"table `a` defined twice, on lines 2 and 4" }) &&
+                   toml::parse_from_str("[a.b]\n\n[[a]]\n") ==
+                   Err(toml::Rejected { line: 3, reason: ~"`[[a]]` on line 3 conflicts with table `a` from line 1" }) &&
+                   toml::parse_from_str("[[a]]\n[a]\n") ==
+                   Err(toml::Rejected { line: 2, reason: ~"`[a]` on line 2 conflicts with array of tables `a` from line 1" }) &&
+                   toml::parse_from_str("[[a]]\n[[a]]\n[a.b]\n[a.b]\n") ==
+                   Err(toml::Rejected { line: 4, reason: ~"table `a.b` defined twice, on lines 3 and 4" })));
+    // whether each fixture is accepted as TOML 0.4 and as TOML 1.0
+    let features = [("mixed-array", false, true), ("nested-mixed-array", true, true),
+                    ("inf", false, true), ("nan", false, true), ("inline-table", true, true)];
+    let parse_as = |name: &str, version: toml::TomlVersion| {
+        let opts = toml::ParserOptions { version: version, .. toml::ParserOptions::new() };
+        let file = path.join("versions").join(format!("{}.toml", name));
+        toml::parse_with_options(&mut BufferedReader::new(File::open(&file)), &opts).is_ok()
+    };
+    results.push(("features by TOML version", features.iter().all(|&(name, v0_4, v1_0)| {
+        parse_as(name, toml::V0_4) == v0_4 && parse_as(name, toml::V1_0) == v1_0
+    })));
+    let opts = toml::ParserOptions { version: toml::V0_4, .. toml::ParserOptions::new() };
+    let parse = |text: &str| toml::parse_with_options(&mut BufReader::new(text.as_bytes()), &opts).map(|d| d.value);
+    results.push(("features of later versions are rejected by name",
+                   parse("a = [1, \"b\"]\n") ==
+                   Err(toml::Rejected { line: 1, reason: ~"arrays of mixed types are not allowed in TOML 0.4" }) &&
+                   parse("x = 1\ny = [\n  -inf,\n]\n") ==
+                   Err(toml::Rejected { line: 3, reason: ~"inf and nan are not allowed in TOML 0.4" })));
+    results.push(("TOML 1.0 is the default",
+                   toml::parse_from_str("a = [1, \"b\", 2.5]\nb = nan\n").is_ok() &&
+                   toml::parse_borrowed("a = [1, \"b\", 2.5]\n").is_ok()));
+    let borrowed = |text: &str, opts: &toml::ParserOptions| {
+        toml::parse_borrowed_with_options(text, opts).map(|v| v.to_owned_value())
+    };
+    results.push(("parse_borrowed_with_options checks the version",
+                   borrowed("a = [1, \"b\"]\n", &opts) ==
+                   Err(toml::Rejected { line: 1, reason: ~"arrays of mixed types are not allowed in TOML 0.4" }) &&
+                   borrowed("x = 1\ny = [\n  -inf,\n]\n", &opts) ==
+                   Err(toml::Rejected { line: 3, reason: ~"inf and nan are not allowed in TOML 0.4" }) &&
+                   borrowed("a = [[1], [\"b\"]]\n", &opts).is_ok() &&
+                   borrowed("a = [1, \"b\"]\nb = nan\n", &toml::ParserOptions::new()).is_ok()));
+    let shallow = toml::ParserOptions { max_table_depth: 3, .. toml::ParserOptions::new() };
+    results.push(("parse_borrowed_with_options checks the table depth",
+                   borrowed("[a.b.c]\n", &shallow).is_ok() &&
+                   borrowed("x = 1\n[a.b.c.d]\n", &shallow) ==
+                   Err(toml::Rejected { line: 2, reason: ~"section `a.b.c.d` is nested deeper than 3 tables" })));
+    let text = "a = [{ b = 1 }, 2]\nc = [{ d = 1 }, { d = 2 }]\n";
+    let is_plain = |v: &toml::Value| {
+        v.lookup("a").map(|a| a.type_str()) == Some("array") &&
+        v.lookup("c").map(|c| c.type_str()) == Some("array of tables")
+    };
+    results.push(("only arrays of tables alone are arrays of tables",
+                   toml::parse_from_str(text).ok().map_or(false, |v| is_plain(&v)) &&
+                   borrowed(text, &toml::ParserOptions::new()).ok().map_or(false, |v| is_plain(&v))));
+    results.push(("a key cannot become a table",
+                   toml::parse_from_str("apple = \"fruit\"\n\n[apple]\n") ==
+                   Err(toml::Rejected { line: 3, reason: ~"key `apple` defined twice: string on line 1, table on line 3" })));
+    results.push(("a key cannot become an array of tables",
+                   toml::parse_from_str("apple = 1\n[[apple]]\n") ==
+                   Err(toml::Rejected { line: 2,
+                                        reason: ~"key `apple` defined twice: integer on line 1, array of tables on line 2" })));
+    results.push(("a table cannot become a key",
+                   toml::parse_from_str("[fruit.apple]\n[fruit]\napple = 1\n") ==
+                   Err(toml::Rejected { line: 3,
+                                        reason: ~"key `fruit.apple` defined twice: table on line 1, integer on line 3" })));
+    results.push(("an array of tables cannot become a key",
+                   toml::parse_from_str("[[fruit.apple]]\n[fruit]\napple = \"x\"\n") ==
+                   Err(toml::Rejected { line: 3,
+                                        reason: ~"key `fruit.apple` defined twice: array of tables on line 1, string on line 3" })));
+
+    let text = "true = 1\nfalse = 0\n123 = \"x\"\n2024-01-01 = \"release\"\ninf = 2\n\
+                [t]\ntrue = 1\n123 = \"y\"\n2024-01-01 = { 1 = 2 }\n[[456]]\n";
+    let keys_ok = |v: &toml::Value| {
+        v.lookup_int("true") == Some(1) && v.lookup_int("false") == Some(0) && v.lookup_str("123") == Some("x") &&
+        v.lookup_str("2024-01-01") == Some("release") && v.lookup_int("inf") == Some(2) &&
+        v.lookup_int("t.true") == Some(1) && v.lookup_str("t.123") == Some("y") &&
+        v.lookup_int("t.2024-01-01.1") == Some(2) && v.lookup("456[0]").is_some() && v.lookup("456.0").is_some()
+    };
+    let parsed = toml::parse_from_str(text).ok();
+    results.push(("keys that look like values", parsed.as_ref().map_or(false, |v| keys_ok(v)) &&
+                   parsed.as_ref().and_then(|v| v.get_table()).map_or(false, |t| t.contains_key(&~"2024-01-01")) &&
+                   toml::parse_borrowed(text).ok().map_or(false, |v| v.to_owned_value() == *parsed.get_ref()) &&
+                   parsed.as_ref().and_then(|v| v.to_toml_str().ok())
+                       .and_then(|s| toml::parse_from_str(s.as_slice()).ok()) == parsed));
+
+    let message = |text: &str| toml::parse_located(&mut BufReader::new(text.as_bytes())).err().map(|d| d.message);
+    results.push(("parse_located messages",
+                   message("[a\tb]\n") == Some(~"expected ']' after section name") &&
+                   message("[[a]\n") == Some(~"expected ']]' to close array-of-tables header") &&
+                   message("a 1\n") == Some(~"expected '=' after key") &&
+                   message("a = [1, 2 3]\n") == Some(~"expected ',' or ']' after array element") &&
+                   message("a = { x = 1, x = 2 }\n") == Some(~"duplicate key `x` in inline table") &&
+                   message("[t]\na = 1\na = 2\n") == Some(~"duplicate key `t.a`") &&
+                   message("a = 1\n") == None));
+
+    let source = "a = 1\n\tk\u00e9y = [1, 2 3]\n";
+    let diagnostic = toml::parse_located(&mut BufReader::new(source.as_bytes())).err();
+    results.push(("Diagnostic::render",
+                   diagnostic.as_ref().map(|d| d.render(source)) ==
+                   Some(~"2:14: expected ',' or ']' after array element\n\
+                          \tk\u00e9y = [1, 2 3]\n\
+                          \t            ^")));
+    results.push(("Diagnostic::render without the line",
+                   diagnostic.as_ref().map(|d| d.render("")) ==
+                   Some(~"2:14: expected ',' or ']' after array element")));
+
+    let mut linter = LongKeyLinter { max_len: 8, found: ~[] };
+    let text = "# settings\nshort = 1\n\n  indented_long_key = 2\n\n# a section\n[section] # trailing\n\
+                long_key_here = \"x\" # comment\n\n\n   [[items]]\n\"quoted long key\" = 3\n";
+    let ok = toml::parse_with_visitor(&mut BufReader::new(text.as_bytes()), &mut linter).is_ok();
+    results.push(("visitor spans", ok && linter.found == ~[(~"indented_long_key", 4, 3),
+                                                            (~"long_key_here", 8, 1),
+                                                            (~"quoted long key", 12, 1)]));
+
+    let text = "# config\n[server]\nhost = \"a\" # main\nports = [1, 2]\n";
+    let sp = |line: uint, col: uint, start: uint, end: uint| {
+        toml::Span { line: line, col: col, byte_start: start, byte_end: end }
+    };
+    let expected = ~[
+        (lexer::Comment(~" config"), sp(1, 1, 0, 8)), (lexer::Newline, sp(1, 9, 8, 9)),
+        (lexer::LBracket, sp(2, 1, 9, 10)), (lexer::Key(~"server"), sp(2, 2, 10, 16)),
+        (lexer::RBracket, sp(2, 8, 16, 17)), (lexer::Newline, sp(2, 9, 17, 18)),
+        (lexer::Key(~"host"), sp(3, 1, 18, 22)), (lexer::Equals, sp(3, 6, 23, 24)),
+        (lexer::StringLit(~"a"), sp(3, 8, 25, 28)), (lexer::Comment(~" main"), sp(3, 12, 29, 35)),
+        (lexer::Newline, sp(3, 18, 35, 36)),
+        (lexer::Key(~"ports"), sp(4, 1, 36, 41)), (lexer::Equals, sp(4, 7, 42, 43)),
+        (lexer::LBracket, sp(4, 9, 44, 45)), (lexer::IntLit(false, 1), sp(4, 10, 45, 46)),
+        (lexer::Comma, sp(4, 11, 46, 47)), (lexer::IntLit(false, 2), sp(4, 13, 48, 49)),
+        (lexer::RBracket, sp(4, 14, 49, 50)), (lexer::Newline, sp(4, 15, 50, 51)),
+        (lexer::Eof, sp(5, 1, 51, 51))
+    ];
+    let mut rd = BufReader::new(text.as_bytes());
+    let tokens: ~[Result<(toml::Token, toml::Span), toml::Error>] = toml::Lexer::new(&mut rd).collect();
+    results.push(("lexer tokens and spans", tokens == expected.move_iter().map(|t| Ok(t)).collect()));
+
+    let text = "a   =   'some literal é text'   # note € here\n\t  b = \"x\\ty\tz \U0001F600 end\"\n\
+                c = \"\"\"\nfirst line\nsecond\"\"\"  \r\n";
+    let mut rd = BufReader::new(text.as_bytes());
+    let expected: ~[Result<(toml::Token, toml::Span), toml::Error>] = toml::Lexer::new(&mut rd).collect();
+    let mut same = expected.len() == 14;
+    for chunk in range(1u, 9) {
+        let mut rd = ChunkedBuffer { data: text.as_bytes().to_owned(), pos: 0, chunk: chunk };
+        let tokens: ~[Result<(toml::Token, toml::Span), toml::Error>] = toml::Lexer::new(&mut rd).collect();
+        same = same && tokens == expected;
+    }
+    results.push(("lexer spans with runs split across chunks", same &&
+                   expected[3] == Ok((lexer::Comment(~" note € here"), sp(1, 33, 33, 48))) &&
+                   expected[5] == Ok((lexer::Key(~"b"), sp(2, 4, 52, 53)))));
+    results.push(("parser on the lexer", toml::parse_from_str(text).ok().map_or(false, |v| {
+        v.lookup_str("server.host") == Some("a") && v.lookup("server.ports[1]") == Some(&toml::PosInt(2))
+    })));
+
+    let text = "title = \"x\"\n[server]\nports = [8001, 8002]\n[[backends]]\nhost = \"a\"\n\
+                [[backends]]\nhost = \"b\"\n";
+    let opts = toml::ParserOptions { record_spans: true, .. toml::ParserOptions::new() };
+    let doc = toml::parse_with_options(&mut BufReader::new(text.as_bytes()), &opts).unwrap();
+    results.push(("span of a scalar", doc.span("title") == Some(sp(1, 9, 8, 11))));
+    results.push(("span of an array element",
+                   doc.span("server.ports[1]").map(|s| (s.line, s.col)) == Some((3, 16))));
+    results.push(("span of a key in a table array",
+                   doc.span("backends[1].host").map(|s| (s.line, s.col)) == Some((7, 8)) &&
+                   doc.span("backends.1.host") == doc.span("backends[1].host")));
+    results.push(("span of a table array element",
+                   doc.span("backends[1]").map(|s| (s.line, s.col)) == Some((6, 1))));
+    let doc = toml::parse_with_options(&mut BufReader::new(text.as_bytes()), &toml::ParserOptions::new());
+    results.push(("spans are not recorded by default",
+                   doc.ok().map_or(false, |d| d.span("title").is_none() && d.value.lookup_str("title") == Some("x"))));
+
+    let text = "name = \"gen\"\n[package]\nversion = 1\n[metadata]\nowner = \"me\"\n\
+                [metadata.extra]\nbuild = 42\n[dependencies]\nlist = [1, 2, 3]\n";
+    let filtered = toml::parse_filtered(&mut BufReader::new(text.as_bytes()), |name| name == "metadata");
+    results.push(("parse_filtered keeps the accepted section",
+                   filtered.ok() == toml::parse_from_str("[metadata]\nowner = \"me\"\n").ok()));
+    let filtered = toml::parse_filtered(&mut BufReader::new(text.as_bytes()), |name| {
+        name.is_empty() || name.starts_with("metadata")
+    });
+    results.push(("parse_filtered with root pairs and sub-tables",
+                   filtered.ok() == toml::parse_from_str("name = \"gen\"\n[metadata]\nowner = \"me\"\n\
+                                                          [metadata.extra]\nbuild = 42\n").ok()));
+    results.push(("parse_filtered checks skipped sections",
+                   toml::parse_filtered(&mut BufReader::new(bytes!("[a]\nx = 1\n[b]\ny = ")), |name| name == "a").is_err()));
+
+    let text = "[first]\ntext = \"\"\"\n[second]\nfake = 1\n\"\"\"\n[second]\nx = 1\n[second.sub]\ny = 2\n\
+                [[third]]\nz = 1\n[[third]]\nz = 2\n[third.sub]\nw = 3\n";
+    let section = |name: &str| toml::parse_section(&mut BufReader::new(text.as_bytes()), name);
+    results.push(("parse_section of a middle table",
+                   section("second").ok() == Some(toml::parse_from_str("x = 1\n[sub]\ny = 2\n").ok())));
+    results.push(("parse_section of an array of tables",
+                   section("third").ok().and_then(|v| v).map_or(false, |v| {
+                       v.lookup("[0].z") == Some(&toml::PosInt(1)) && v.lookup("[1].sub.w") == Some(&toml::PosInt(3))
+                   })));
+    results.push(("parse_section of a missing section", section("fourth") == Ok(None)));
+    results.push(("parse_section keeps sub-tables following other sections",
+                   toml::parse_section(&mut BufReader::new(bytes!("[a]\nx = 1\n[b]\nz = 3\n[a.c]\ny = 2\n")), "a").ok() ==
+                   Some(toml::parse_from_str("x = 1\n[c]\ny = 2\n").ok())));
+    results.push(("parse_section reads the whole document",
+                   toml::parse_section(&mut BufReader::new(bytes!("[a]\nx = 1\n[b]\ny = ")), "a").is_err()));
+    let text = "[\"a.b\"]\nx = 1\n[a.b]\ny = 2\n[a.\"b.c\"]\nz = 3\n";
+    let section = |name: &str| toml::parse_section(&mut BufReader::new(text.as_bytes()), name);
+    results.push(("parse_section tells quoted keys with dots apart",
+                   section("\"a.b\"") == Ok(toml::parse_from_str("x = 1\n").ok()) &&
+                   section("a.b") == Ok(toml::parse_from_str("y = 2\n").ok()) &&
+                   section("a.\"b.c\"") == Ok(toml::parse_from_str("z = 3\n").ok()) &&
+                   section("a[0]") == Ok(None)));
+
+    let text = "[t]\na = 1\nb = 2\na = 3\n";
+    let parse_dup = |policy: toml::DuplicatePolicy| {
+        let opts = toml::ParserOptions { duplicates: policy, .. toml::ParserOptions::new() };
+        toml::parse_with_options(&mut BufReader::new(text.as_bytes()), &opts)
+    };
+    results.push(("duplicate keys are rejected by default",
+                   parse_dup(toml::Reject).err() == Some(toml::Rejected { line: 4, reason: ~"duplicate key `t.a`" })));
+    results.push(("duplicate keys with KeepFirst", parse_dup(toml::KeepFirst).ok().map_or(false, |d| {
+        d.value.lookup("t.a") == Some(&toml::PosInt(1)) &&
+        d.warnings == ~[(4, ~"duplicate key `t.a` is ignored")]
+    })));
+    results.push(("duplicate keys with KeepLast", parse_dup(toml::KeepLast).ok().map_or(false, |d| {
+        d.value.lookup("t.a") == Some(&toml::PosInt(3)) && d.value.lookup("t.b") == Some(&toml::PosInt(2)) &&
+        d.warnings == ~[(4, ~"duplicate key `t.a` replaces the earlier definition")]
+    })));
+    let opts = toml::ParserOptions { duplicates: toml::KeepLast, .. toml::ParserOptions::new() };
+    results.push(("duplicate sections are always rejected",
+                   toml::parse_with_options(&mut BufReader::new(bytes!("[t]\n[t]\n")), &opts).is_err()));
+
+    let opts = toml::ParserOptions { max_table_depth: 3, .. toml::ParserOptions::new() };
+    let parse = |text: &str| toml::parse_with_options(&mut BufReader::new(text.as_bytes()), &opts).map(|d| d.value);
+    results.push(("max_table_depth allows headers at the limit",
+                   parse("[a.b.c]\nx = 1\n[[a.b.d]]\n").is_ok() && parse("[a.\"b.c\".d]\n").is_ok() &&
+                   parse("[a.'b.c'.d]\n").is_ok() && parse("[a.\"b\\\".c\".d]\n").is_ok()));
+    results.push(("max_table_depth rejects deeper headers",
+                   parse("x = 1\n[a.b.c.d]\ny = 2\n") ==
+                   Err(toml::Rejected { line: 2, reason: ~"section `a.b.c.d` is nested deeper than 3 tables" }) &&
+                   parse("[[a.b.c.\"d\"]]\n").is_err()));
+    let opts = toml::ParserOptions { max_string_len: Some(8), max_array_len: Some(3), max_total_keys: Some(4),
+                                     .. toml::ParserOptions::new() };
+    let parse = |text: &str| toml::parse_with_options(&mut BufReader::new(text.as_bytes()), &opts).map(|d| d.value);
+    results.push(("size limits let smaller documents through",
+                   parse("a = \"12345678\"\nb = [1, 2, 3]\n[t]\nc = { d = 'xé' }\n").is_ok()));
+    results.push(("max_string_len",
+                   parse("a = 1\nb = \"123456789\"\n") ==
+                   Err(toml::Rejected { line: 2, reason: ~"string longer than max_string_len (8 bytes)" }) &&
+                   parse("a = '''\n12345\n6789'''\n").is_err() && parse("\"123456789\" = 1\n").is_err() &&
+                   parse("a = \"\\u00e9\\u00e9\\u00e9\\u00e9\\u00e9\"\n").is_err()));
+    // strings spanning more than one chunk of the lexer's buffer
+    let long = |quote: &str, len: uint| format!("a = {}{}{}\n", quote, "x".repeat(len), quote);
+    let with_max_len = |text: &str, max: uint| {
+        let opts = toml::ParserOptions { max_string_len: Some(max), .. toml::ParserOptions::new() };
+        toml::parse_with_options(&mut BufReader::new(text.as_bytes()), &opts).map(|d| d.value)
+    };
+    results.push(("max_string_len with strings longer than a chunk",
+                   with_max_len(long("\"", 100000).as_slice(), 8) ==
+                   Err(toml::Rejected { line: 1, reason: ~"string longer than max_string_len (8 bytes)" }) &&
+                   with_max_len(long("'", 100000).as_slice(), 8).is_err() &&
+                   with_max_len(long("\"", 70000).as_slice(), 70000).is_ok() &&
+                   with_max_len(long("'", 70001).as_slice(), 70000).is_err()));
+    results.push(("max_array_len",
+                   parse("a = [[1, 2], [3]]\nb = [1, 2, 3, 4]\n") ==
+                   Err(toml::Rejected { line: 2, reason: ~"array longer than max_array_len (3 elements)" })));
+    results.push(("max_total_keys",
+                   parse("a = 1\n[t]\nb = { c = 1, d = 2 }\ne = 3\n") ==
+                   Err(toml::Rejected { line: 4, reason: ~"more than max_total_keys (4) keys" })));
+
+    let with_max = |text: &str, max: Option<uint>| {
+        let opts = toml::ParserOptions { max_input_bytes: max, .. toml::ParserOptions::new() };
+        toml::parse_with_options(&mut BufReader::new(text.as_bytes()), &opts)
+    };
+    results.push(("max_input_bytes stops reading mid-document",
+                   with_max("a = 1\nb = \"two\"\n[t]\nc = [1, 2, 3]\n", Some(24)).err() ==
+                   Some(toml::Rejected { line: 4, reason: ~"input longer than max_input_bytes (24 bytes)" })));
+    let example = File::open(&path.join("valid").join("example.toml")).read_to_string().unwrap();
+    let len = example.len();
+    results.push(("max_input_bytes with a fixture",
+                   with_max(example.as_slice(), Some(len)).is_ok() && match with_max(example.as_slice(), Some(len - 1)) {
+                       Err(toml::Rejected { reason: reason, .. }) => reason == format!("input longer than max_input_bytes ({} bytes)", len - 1),
+                       _ => false
+                   }));
+    let text = "name = \"Gr\u00FC\u00DFe \u20AC\"\n[t]\n";
+    results.push(("bytes_read is the length of the input",
+                   with_max(example.as_slice(), None).ok().map(|d| d.bytes_read()) == Some(len) &&
+                   with_max(text, None).ok().map(|d| d.bytes_read()) == Some(text.len())));
+
+    results.push(("overlong UTF-8 is rejected",
+                   toml::parse_from_bytes(bytes!("a = 1\nb = \"x", 0xc0, 0xaf, "\"\n")) ==
+                   Err(toml::Rejected { line: 2, reason: ~"invalid UTF-8 at byte 12" })));
+    results.push(("a lone UTF-8 continuation byte is rejected",
+                   toml::parse_located(&mut BufReader::new(bytes!("key = 1 # caf", 0x80, "\n"))).err()
+                   .map_or(false, |d| d.to_str() == ~"1:14: invalid UTF-8 at byte 13")));
+    results.push(("UTF-8 cut off at the end is rejected",
+                   toml::parse_from_bytes(bytes!("a = 1\n# ", 0xe2, 0x82)) ==
+                   Err(toml::Rejected { line: 2, reason: ~"invalid UTF-8 at byte 8" })));
+    let opts = toml::ParserOptions { lossy_utf8: true, .. toml::ParserOptions::new() };
+    let parsed = toml::parse_with_options(&mut BufReader::new(bytes!("a = \"caf", 0xe9, "\"\n# ", 0xe2, 0x82)), &opts);
+    results.push(("lossy_utf8 reads invalid UTF-8 as U+FFFD",
+                   parsed.ok().map_or(false, |d| d.value.lookup_str("a") == Some("caf\uFFFD"))));
+
+    // the fixtures read the same with "\r\n" line breaks
+    let mut same = true;
+    for filename in walk_dir(&path.join("valid")).unwrap() {
+        if !filename.is_file() || filename.extension_str() != Some("toml") { continue }
+        let text = std::str::from_utf8_owned(File::open(&filename).read_to_end().unwrap()).unwrap();
+        let crlf = text.replace("\n", "\r\n");
+        let expected = toml::parse_from_str(text.as_slice()).ok();
+        same = same && expected.is_some() && toml::parse_from_str(crlf.as_slice()) == expected &&
+               toml::parse_borrowed(crlf.as_slice()).ok().map(|v| v.to_owned_value()) == expected;
+    }
+    results.push(("CRLF line breaks", same));
+    let located = |text: &str| toml::parse_located(&mut BufReader::new(text.as_bytes())).err().map(|d| d.to_str());
+    results.push(("errors after CRLF line breaks are located",
+                   located("# comment\r\n\r\na = '''\r\nx'''\r\nb = [1, 2 3]\r\n") ==
+                   Some(~"5:11: expected ',' or ']' after array element")));
+    let mut rd = BufReader::new(bytes!("\na = 1"));
+    let tokens: ~[Result<(toml::Token, toml::Span), toml::Error>] = toml::Lexer::new(&mut rd).collect();
+    results.push(("lexer lines after a leading line break",
+                   tokens.head() == Some(&Ok((lexer::Newline, sp(1, 1, 0, 1)))) &&
+                   tokens.len() > 1 && tokens[1] == Ok((lexer::Key(~"a"), sp(2, 1, 1, 2)))));
+    results.push(("error lines after leading blank lines",
+                   located("\n\na = [1 2]\n") == Some(~"3:8: expected ',' or ']' after array element") &&
+                   located("\n[t]\n[t]\n").map_or(false, |e| e.starts_with("3:1: "))));
+    results.push(("error lines after a comment on line 1",
+                   located("# comment\na = [1 2]\n") == Some(~"2:8: expected ',' or ']' after array element")));
+    results.push(("error on an unterminated last line",
+                   located("a = 1\nb = \"abc") == Some(~"2:9: unexpected end of input while parsing string started at line 2")));
+
+    let text = "[server]\nhost = \"example.org\"\nports = [8001, 8002]\nopts = { tls = true }\n\
+                started = 1979-05-27T07:32:00Z\nname = '''\nx\n'''\n";
+    let truncated = |end: &str| {
+        let at = text.find_str(end).map_or(0, |i| i + end.len());
+        located(text.slice_to(at)).unwrap_or(~"")
+    };
+    results.push(("the end of the input names what it cut off",
+                   truncated("[serv") == ~"1:6: unterminated table header" &&
+                   truncated("\"exam").ends_with(": unexpected end of input while parsing string started at line 2") &&
+                   truncated("[8001,").ends_with(" array started at line 3") &&
+                   truncated("{ tls =").ends_with(" inline table started at line 4") &&
+                   truncated("tls = tr").ends_with(" boolean started at line 4") &&
+                   truncated("1979-05-2").ends_with(" datetime started at line 5") &&
+                   truncated("name =").ends_with(" pair `name` started at line 6") &&
+                   truncated("'''\nx\n") == ~"8:1: unexpected end of input while parsing string started at line 6"));
+    let text = "port = 8080 # the usual\nhost = 'a'# no space\nports = [1,\n  2] # after ']'\n[t] # header\nx = {}#\n";
+    results.push(("empty table names in headers",
+                   located("a = 1\n[]\n") == Some(~"2:1: empty table name in header") &&
+                   located("[ ]\n") == Some(~"1:1: empty table name in header") &&
+                   located("\n[[]]\n") == Some(~"2:1: empty table name in header") &&
+                   toml::parse_borrowed("[ ]\n").is_err()));
+    results.push(("unterminated table headers",
+                   located("x = 1\n[a\nb = 1\n") == Some(~"2:3: unterminated table header") &&
+                   located("x = 1\n[[a.b\r\n") == Some(~"2:6: unterminated table header") &&
+                   located("x = 1\n[a") == Some(~"2:3: unterminated table header") &&
+                   located("[") == Some(~"1:2: unterminated table header") &&
+                   located("[\n") == Some(~"1:2: unterminated table header")));
+    results.push(("array-of-tables headers need ']]'",
+                   located("x = 1\n[[a]\n") == Some(~"2:5: expected ']]' to close array-of-tables header") &&
+                   located("[[a] ]\n") == Some(~"1:5: expected ']]' to close array-of-tables header")));
+    results.push(("comments after values and headers",
+                   toml::parse_from_str(text).ok().map_or(false, |v| {
+                       v.lookup_int("port") == Some(8080) && v.lookup_str("host") == Some("a") &&
+                       v.lookup("ports[1]") == Some(&toml::PosInt(2)) && v.lookup("t.x").is_some()
+                   }) && toml::parse_borrowed(text).is_ok()));
+    results.push(("only a comment may follow a value on its line",
+                   located("a = 1 b = 2\n") == Some(~"1:7: expected a line break after value") &&
+                   located("a = [1, 2] 3\n") == Some(~"1:12: expected a line break after value") &&
+                   located("a = { b = 1 } c\n") == Some(~"1:15: expected a line break after value") &&
+                   toml::parse_borrowed("a = 1 b = 2\n").is_err()));
+    results.push(("only a comment may follow a section header on its line",
+                   located("[t] x = 1\n") == Some(~"1:5: unexpected character after table header") &&
+                   located("[[t]] ]\n") == Some(~"1:7: unexpected character after table header") &&
+                   toml::parse_borrowed("[t] x = 1\n").is_err()));
+    let text = "[dog.\"tater.man\"]\ntype = \"pug\"\n[\"quoted only\"]\nx = 1\n[a.'literal.seg']\ny = 2\n\
+                [ b . \"c\" ]\nz = 3\n[\"e\\u0041\\\"\"]\nw = 4\n[\"h#i\"] # comment\nv = 5\n";
+    results.push(("quoted keys in headers",
+                   toml::parse_from_str(text).ok().map_or(false, |v| {
+                       v.lookup_str("dog.\"tater.man\".type") == Some("pug") &&
+                       v.lookup_int("\"quoted only\".x") == Some(1) &&
+                       v.lookup_int("a.\"literal.seg\".y") == Some(2) && v.lookup_int("b.c.z") == Some(3) &&
+                       v.lookup_int("\"eA\\\"\".w") == Some(4) && v.lookup_int("\"h#i\".v") == Some(5) &&
+                       v.lookup("dog.tater").is_none()
+                   }) &&
+                   toml::parse_borrowed(text).ok().map_or(false, |v| v.lookup("dog.\"tater.man\".type").is_some()) &&
+                   toml::parse_from_str("[a.\"\"]\n").is_err()));
+    results.push(("malformed keys in headers",
+                   located("[a.]\n") == Some(~"1:4: empty key in table header") &&
+                   located("[a..b]\n") == Some(~"1:4: empty key in table header") &&
+                   located("[web\"server]\n") == Some(~"1:5: expected ']' after section name") &&
+                   located("[\"a\" b]\n") == Some(~"1:6: expected ']' after section name") &&
+                   located("[\"a\nb\"]\n").is_some() &&
+                   toml::parse_borrowed("[a..b]\n").is_err() && toml::parse_borrowed("[web\"server]\n").is_err()));
+    results.push(("trailing characters after a header's closing bracket",
+                   located("x = 1\n[a]b]\n") == Some(~"2:4: unexpected character after table header") &&
+                   located("[a] = 1\n") == Some(~"1:5: unexpected character after table header") &&
+                   located("[a][b]\n") == Some(~"1:4: unexpected character after table header") &&
+                   located("[[a]] [b] # c\n") == Some(~"1:7: unexpected character after table header") &&
+                   located("[a]  # fine\nb = 1\n").is_none() &&
+                   toml::parse_borrowed("[a]b]\n").is_err() && toml::parse_borrowed("[a][b]\n").is_err()));
+    results.push(("control characters are rejected outside strings",
+                   located("a\x00b = 1\n") == Some(~"1:2: unexpected control character 0x00") &&
+                   located("a = 1\nb =\x00 2\n") == Some(~"2:4: unexpected control character 0x00") &&
+                   located("\x01a = 1\n") == Some(~"1:1: unexpected control character 0x01") &&
+                   located("[a\x7f]\n") == Some(~"1:3: unexpected control character 0x7F")));
+    results.push(("NUL is rejected in comments",
+                   located("a = 1 # note\x00\n") == Some(~"1:13: unexpected control character 0x00") &&
+                   toml::parse_from_str("a = 1 # note\x01\n").is_ok()));
+    let with_strict = |text: &str, strict: bool| {
+        let opts = toml::ParserOptions { strict: strict, .. toml::ParserOptions::new() };
+        toml::parse_with_options(&mut BufReader::new(text.as_bytes()), &opts).map(|d| d.value)
+    };
+    results.push(("comments may hold any other characters",
+                   with_strict("a = 1 # caf\u00e9 \U0001F600\tdone\n# \u20AC\n", true).is_ok() &&
+                   with_strict("a = 1 # caf\u00e9 \U0001F600\tdone\n# \u20AC\n", false).is_ok()));
+    results.push(("strict rejects control characters in comments",
+                   with_strict("a = 1 # bell \x07\n", true) == Err(toml::ParseError) &&
+                   with_strict("# \x1b[31m\nb = 2\n", true).is_err() && with_strict("# a\rb\n", true).is_err() &&
+                   with_strict("a = 1 # bell \x07\n", false).is_ok() && with_strict("# a\r\nb = 2\r\n", true).is_ok()));
+    results.push(("strict wants a value on the line of its '='",
+                   with_strict("key = \n 1\n", true) == Err(toml::ParseError) &&
+                   with_strict("key = # soon\n1\n", true).is_err() && with_strict("t = { a =\n1 }\n", true).is_err() &&
+                   with_strict("key = \n 1\n", false).ok().map_or(false, |v| v.lookup_int("key") == Some(1))));
+    results.push(("no spaces are needed around '='",
+                   with_strict("key=1\nt={a=[1,\n2]}\n", true).ok().map_or(false, |v| v.lookup_int("t.a[1]") == Some(2)) &&
+                   with_strict("key=1\n", false).is_ok()));
+    results.push(("a lone CR is no line break",
+                   located("a = 1\rb = 2\r\n") == Some(~"1:6: expected a key or a section header") &&
+                   toml::parse_from_str("a = \"\"\"x\ry\"\"\"\n").is_err() &&
+                   toml::parse_from_str("a = '''\r'''\n").is_err()));
+
+    let deep = |depth: uint| format!("[{}]\n", std::vec::from_elem(depth, "k").connect("."));
+    results.push(("tables may nest 64 deep by default",
+                   toml::parse_from_str(deep(64)).is_ok() && toml::parse_from_str(deep(65)).is_err() &&
+                   toml::parse_borrowed(deep(64)).is_ok() && toml::parse_borrowed(deep(65)).is_err()));
+
+    let buffer = bytes!("a = 1\n").to_owned();
+    let parsed = toml::parse_from_bytes(buffer.as_slice());
+    results.push(("parse_from_bytes borrows its input",
+                   parsed.ok().and_then(|v| v.lookup_int("a")) == Some(1) && buffer.len() == 6));
+
+    let text = "# caf\u00E9 \u20AC\nname = \"Gr\u00FC\u00DFe \u20AC \U0001F600\" # \U0001F600\n\
+                when = 1979-05-27T07:32:00Z\nlines = \"\"\"\n\u00E9t\u00E9\"\"\"\n";
+    let expected = toml::parse_from_str(text.as_slice()).ok();
+    let mut same = expected.is_some();
+    for chunk in range(1u, 9) {
+        let mut rd = ChunkedBuffer { data: text.as_bytes().to_owned(), pos: 0, chunk: chunk };
+        same = same && toml::parse_from_buffer(&mut rd).ok() == expected;
+    }
+    results.push(("parse_from_buffer with chunks splitting characters", same));
+
+    let text = "[[server_group.members]]\nhost_name_of_member = \"a\"\n\"quoted key\" = 1.25e-3\n\
+                [[server_group.members]]\nhost_name_of_member = \"b\"\n\"quoted key\" = -1979\n";
+    let expected = toml::parse_from_str(text.as_slice()).ok();
+    let mut same = expected.as_ref().map_or(false, |v| {
+        v.lookup_str("server_group.members[1].host_name_of_member") == Some("b")
+    });
+    for chunk in range(1u, 9) {
+        let mut rd = ChunkedBuffer { data: text.as_bytes().to_owned(), pos: 0, chunk: chunk };
+        same = same && toml::parse_from_buffer(&mut rd).ok() == expected;
+    }
+    results.push(("parse_from_buffer with chunks splitting keys", same));
+
+    let text = "title = \"plain\"\nescaped = \"tab\\there\"\n\"esc\\u0041\" = 'C:\\dir'\n\
+                [[t.a]]\nml = \"\"\"\nfirst\"\"\"\nraw = '''x'''\n[[t.a]]\nlist = [\"1\", \"\\u0032\"]\n";
+    let doc = toml::parse_borrowed(text);
+    results.push(("parse_borrowed agrees with parse_from_str",
+                   doc.as_ref().ok().map(|v| v.to_owned_value()) == toml::parse_from_str(text).ok()));
+    let doc = doc.unwrap();
+    let is_slice = |path: &str, expected: &str| {
+        match doc.lookup(path) {
+            Some(&borrowed::String(std::str::Slice(s))) => s == expected,
+            _ => false
+        }
+    };
+    results.push(("parse_borrowed borrows plain strings",
+                   is_slice("title", "plain") && is_slice("escA", "C:\\dir") &&
+                   is_slice("t.a[0].raw", "x") && is_slice("t.a[1].list[0]", "1")));
+    let is_owned = |path: &str, expected: &str| {
+        match doc.lookup(path) {
+            Some(&borrowed::String(std::str::Owned(ref s))) => s.as_slice() == expected,
+            _ => false
+        }
+    };
+    results.push(("parse_borrowed owns strings with escapes",
+                   is_owned("escaped", "tab\there") && is_owned("t.a[0].ml", "first") &&
+                   is_owned("t.a[1].list[1]", "2")));
+    results.push(("parse_borrowed checks the document",
+                   toml::parse_borrowed("a = 1\na = 2\n").is_err() && toml::parse_borrowed("[a]\n[a]\n").is_err() &&
+                   toml::parse_borrowed("a = [1,, 2]\n").is_err()));
+    let limited = toml::ParserOptions { max_string_len: Some(3), max_array_len: Some(2), max_total_keys: Some(3),
+                                        strict: true, .. toml::ParserOptions::new() };
+    let same_error = |text: &str| {
+        let expected = toml::parse_with_options(&mut BufReader::new(text.as_bytes()), &limited).map(|d| d.value);
+        expected.is_err() && toml::parse_borrowed_with_options(text, &limited).map(|v| v.to_owned_value()) == expected
+    };
+    results.push(("parse_borrowed_with_options fails like parse_with_options",
+                   same_error("a = \"long\"\n") && same_error("a = [1, 2, 3]\n") &&
+                   same_error("a = 1\nb = 2\nc = 3\nd = 4\n") && same_error("a =\n1\n") &&
+                   same_error("a = { b = 1, b = 2 }\n") &&
+                   toml::parse_borrowed_with_options("a = \"abc\"\nb = [1, 2]\n", &limited).is_ok()));
+    let keep_last = toml::ParserOptions { duplicates: toml::KeepLast, .. toml::ParserOptions::new() };
+    let keep_first = toml::ParserOptions { duplicates: toml::KeepFirst, .. toml::ParserOptions::new() };
+    results.push(("parse_borrowed_with_options follows the duplicate policy",
+                   toml::parse_borrowed_with_options("a = 1\na = 2\n", &keep_last).ok()
+                       .and_then(|v| v.lookup("a").map(|a| a.to_owned_value())) == Some(toml::PosInt(2)) &&
+                   toml::parse_borrowed_with_options("a = 1\na = 2\n", &keep_first).ok()
+                       .and_then(|v| v.lookup("a").map(|a| a.to_owned_value())) == Some(toml::PosInt(1)) &&
+                   toml::parse_borrowed_with_options("[t.u]\n[t]\nu = 1\n", &keep_last).is_err() &&
+                   toml::parse_borrowed_with_options("[t]\nx = 1\n[t]\n", &keep_last).is_err()));
+    let mut text = ~"";
+    for i in range(0, 100) { text.push_str(format!("k{} = {}\n", i, i)) }
+    let found = toml::parse_borrowed(text.as_slice()).ok().map_or(false, |v| {
+        v.lookup("k0").is_some() && v.lookup("k99").is_some() && v.lookup("k100").is_none()
+    });
+    text.push_str("k50 = 0\n");
+    results.push(("parse_borrowed finds keys of large tables",
+                   found && toml::parse_borrowed(text.as_slice()) ==
+                   Err(toml::Rejected { line: 101, reason: ~"duplicate key `k50`" })));
+
+    let mut text = ~"";
+    for i in range(0, 50000) {
+        text.push_str(format!("[[record]]\nid = {}\nname = \"record {}\"\nts = 1979-05-27T07:32:00Z\n", i, i));
+    }
+    results.push(("table array of 50000 tables", toml::parse_from_str(text).ok().map_or(false, |v| {
+        v.lookup("record").and_then(|r| r.get_table_array()).map_or(0, |a| a.len()) == 50000 &&
+        v.lookup_int("record[49999].id") == Some(49999) && v.lookup_str("record[123].name") == Some("record 123")
+    })));
+    let key_ptr = |v: &toml::Value, path: &str| {
+        v.lookup(path).and_then(|t| t.get_table()).and_then(|t| t.keys().next().map(|k| k.as_ptr()))
+    };
+    results.push(("tables of a table array share their keys",
+                   toml::parse_from_str("[[r]]\nid = 1\n[[r]]\nid = 2\n").ok().map_or(false, |v| {
+        key_ptr(&v, "r[0]").is_some() && key_ptr(&v, "r[0]") == key_ptr(&v, "r[1]")
+    })));
+
+    let mut map = toml::TableMap::new();
+    for i in range(0u, 20) {
+        map.insert(format!("k{}", i), toml::PosInt(i as u64));
+    }
+    results.push(("TableMap with an index",
+                   map.pop(&~"k3") == Some(toml::PosInt(3)) && map.len() == 19 &&
+                   map.find_equiv(&"k19") == Some(&toml::PosInt(19)) && map.keys().nth(3) == Some(&~"k4") &&
+                   map.swap(~"k5", toml::PosInt(50)) == Some(toml::PosInt(5)) &&
+                   map.find(&~"k5") == Some(&toml::PosInt(50)) && map.find(&~"k3").is_none()));
+
+    // tables created with a large capacity are indexed from the start,
+    // others once they outgrow a linear search
+    let mut same = true;
+    for n in range(0u, 20) {
+        let mut small = toml::TableMap::new();
+        let mut indexed = toml::TableMap::with_capacity(100);
+        for i in range(0, n) {
+            small.insert(format!("k{}", i), toml::PosInt(i as u64));
+            indexed.insert(format!("k{}", i), toml::PosInt(i as u64));
+        }
+        let key = format!("k{}", n / 2);
+        same = same && small.pop(&key) == indexed.pop(&key) && small == indexed &&
+               small.keys().collect::<~[&~str]>() == indexed.keys().collect::<~[&~str]>() &&
+               range(0, n).all(|i| small.find_equiv(&format!("k{}", i)) == indexed.find_equiv(&format!("k{}", i)));
+        *small.find_or_insert_with(~"new", |_| toml::PosInt(0)) = toml::PosInt(1);
+        *indexed.find_or_insert_with(~"new", |_| toml::PosInt(0)) = toml::PosInt(1);
+        same = same && small == indexed && small.keys().last() == Some(&~"new") &&
+               small.swap(~"k0", toml::Boolean(true)) == indexed.swap(~"k0", toml::Boolean(true));
+        small.clear();
+        same = same && small.is_empty() && small.find(&~"k0").is_none();
+    }
+    results.push(("TableMap behaves the same with and without an index", same));
+
+    let record = "[[record]]\nname = \"a record with some text\"\n".as_bytes();
+    let mut region = "x = 1\n".as_bytes().to_owned();
+    let mut count = 0u;
+    while region.len() < 4 * 1024 * 1024 {
+        region.push_all(record);
+        count += 1;
+    }
+    results.push(("parse_from_bytes on a multi-megabyte region", toml::parse_from_bytes(region).ok().map_or(false, |v| {
+        v.lookup("record").and_then(|r| r.get_table_array()).map_or(0, |a| a.len()) == count
+    })));
+    region[4] = 0xC0;
+    results.push(("invalid UTF-8 at the start of a region", match toml::parse_from_bytes(region) {
+        Err(toml::IOError(ref e)) => e.kind == io::InvalidInput,
+        _ => false
+    }));
+
+    let dir = os::tmpdir().join("rust-toml-parse-files-test");
+    let _ = fs::rmdir_recursive(&dir);
+    fs::mkdir(&dir, io::UserRWX).unwrap();
+    let mut paths = ~[];
+    for i in range(0, 20) {
+        let path = dir.join(format!("manifest{}.toml", i));
+        let text = if i == 7 { ~"name = \n" } else { format!("[package]\nname = \"crate{}\"\n", i) };
+        File::create(&path).write_str(text).unwrap();
+        paths.push(path);
+    }
+    paths.push(dir.join("missing.toml"));
+    let parsed = toml::parse_files(paths, 4);
+    let _ = fs::rmdir_recursive(&dir);
+    results.push(("parse_files keeps the order of the paths",
+                   parsed.len() == 21 && parsed.iter().zip(paths.iter()).all(|(&(ref p, _), q)| p == q)));
+    results.push(("parse_files reports each file", parsed.iter().enumerate().all(|(i, &(_, ref result))| {
+        match *result {
+            Ok(ref v) => i != 7 && i != 20 && v.lookup_str("package.name").map(|s| s.to_owned()) == Some(format!("crate{}", i)),
+            Err(toml::ParseError) => i == 7,
+            Err(toml::IOError(..)) => i == 20,
+            Err(_) => false
+        }
+    })));
+
+    let frozen = toml::parse_from_str(text).unwrap().freeze();
+    let clones: ~[toml::FrozenValue] = range(0, 1000).map(|_| frozen.clone()).collect();
+    results.push(("FrozenValue clones share the value",
+                   clones.iter().all(|c| c.ptr_eq(&frozen)) &&
+                   clones[999].lookup_str("record[123].name") == Some("record 123")));
+    let (tx, rx) = channel();
+    for i in range(0, 4) {
+        let (frozen, tx) = (frozen.clone(), tx.clone());
+        spawn(proc() {
+            tx.send(frozen.lookup_int(format!("record[{}].id", i * 1000)) == Some(i as i64 * 1000));
+        });
+    }
+    results.push(("FrozenValue shared with tasks", range(0, 4).all(|_| rx.recv())));
+    let mut copy = frozen.to_value();
+    let changed = copy.set("record[0].id", toml::PosInt(7)).is_ok();
+    results.push(("FrozenValue::to_value makes a separate copy",
+                   changed && copy.lookup_int("record[0].id") == Some(7) &&
+                   frozen.lookup_int("record[0].id") == Some(0) && clones[0].lookup_int("record[0].id") == Some(0)));
+
+    let text = "name = \"Gr\u00FC\u00DFe \u20AC \U0001F600\"\n[t]\nx = 1\n";
+    let mut trickle = TrickleReader { data: text.as_bytes().to_owned(), pos: 0 };
+    results.push(("parse_from_reader with 1-byte reads",
+                   toml::parse_from_reader(&mut trickle).ok() == toml::parse_from_str(text).ok() &&
+                   toml::parse_from_str(text).ok().map_or(false, |v| {
+                       v.lookup_str("name") == Some("Gr\u00FC\u00DFe \u20AC \U0001F600")
+                   })));
+
+    let text = "ratio = 1\nscale = 0.5\nlimit = 9007199254740992\nodd = 9007199254740993\n\
+                even = 9007199254740994\ndebt = -3\nfloor = -9007199254740993\nhuge = 18446744073709551615\n\
+                name = \"x\"\n";
+    results.push(("get_as_float converts integers exactly", toml::parse_from_str(text).ok().map_or(false, |v| {
+        v.lookup_float_lenient("ratio") == Some(1.0) && v.lookup_float_lenient("scale") == Some(0.5) &&
+        v.lookup_float("ratio").is_none() &&
+        v.lookup_float_lenient("limit") == Some(9007199254740992.0) &&
+        v.lookup_float_lenient("odd").is_none() &&
+        v.lookup_float_lenient("even") == Some(9007199254740994.0) &&
+        v.lookup_float_lenient("debt") == Some(-3.0) && v.lookup_float_lenient("floor").is_none() &&
+        v.lookup_float_lenient("huge").is_none() && v.lookup_float_lenient("name").is_none() &&
+        v.lookup_float_lenient("missing").is_none()
+    })));
+    results.push(("get_as_float_lossy rounds large integers", toml::parse_from_str(text).ok().map_or(false, |v| {
+        v.lookup("odd").and_then(|n| n.get_as_float_lossy()) == Some(9007199254740992.0) &&
+        v.lookup("floor").and_then(|n| n.get_as_float_lossy()) == Some(-9007199254740992.0) &&
+        v.lookup("huge").and_then(|n| n.get_as_float_lossy()) == Some(18446744073709551616.0) &&
+        v.lookup("debt").and_then(|n| n.get_as_float_lossy()) == Some(-3.0) &&
+        v.lookup("name").and_then(|n| n.get_as_float_lossy()).is_none()
+    })));
+
+    let text = "plugins = []\nports = [80, 443]\nname = \"x\"\nempty = \"\"\nzero = 0\n\
+                [server]\nhost = \"a\"\n[none]\n[[record]]\n[[record]]\n";
+    results.push(("len and is_empty of containers", toml::parse_from_str(text).ok().map_or(false, |v| {
+        v.len() == Some(5) && !v.is_empty() &&
+        v.lookup("plugins").map(|p| p.is_empty()) == Some(true) &&
+        v.lookup("plugins").and_then(|p| p.len()) == Some(0) &&
+        v.lookup("ports").and_then(|p| p.len()) == Some(2) &&
+        v.lookup("server").and_then(|s| s.len()) == Some(1) &&
+        v.lookup("none").map(|t| t.is_empty()) == Some(true) &&
+        v.lookup("record").and_then(|r| r.len()) == Some(2) &&
+        v.lookup("record.0").map(|r| r.is_empty()) == Some(true) &&
+        toml::TableArray(~[]).is_empty() && toml::Value::table().is_empty()
+    })));
+    results.push(("len and is_empty of scalars", toml::parse_from_str(text).ok().map_or(false, |v| {
+        ["name", "empty", "zero"].iter().all(|k| {
+            v.lookup(*k).map_or(false, |s| s.len().is_none() && !s.is_empty())
+        }) &&
+        toml::NoValue.len().is_none() && !toml::NoValue.is_empty() &&
+        toml::Boolean(false).len().is_none() && toml::Float(0.0).len().is_none()
+    })));
+    results.push(("contains_key only finds keys of tables", toml::parse_from_str(text).ok().map_or(false, |v| {
+        v.contains_key("server") && !v.contains_key("host") &&
+        v.lookup("server").map(|s| s.contains_key("host")) == Some(true) &&
+        v.lookup("none").map(|t| t.contains_key("host")) == Some(false) &&
+        v.lookup("ports").map(|p| p.contains_key("0")) == Some(false) &&
+        v.lookup("record").map(|r| r.contains_key("0")) == Some(false) &&
+        v.lookup("name").map(|s| s.contains_key("x")) == Some(false) &&
+        !toml::NoValue.contains_key("")
+    })));
+
+    let text = "name = \"h\u00E9llo\"\nports = [80, 443, 8080]\nratio = 0.5\non = true\n\
+                when = 1979-05-27T07:32:00Z\n[server.tls]\nciphers = [\"a\", \"bc\"]\n\
+                [[plugins]]\nid = 1\n[[plugins]]\nopts = { deep = [ [1] ] }\n";
+    let expected = toml::Stats { keys: 12, tables: 5, table_arrays: 1, arrays: 4, strings: 3, integers: 5,
+                                 floats: 1, booleans: 1, datetimes: 1, max_depth: 6, max_array_len: 3,
+                                 total_string_bytes: 9 };
+    let mut visitor = toml::StatsVisitor::new();
+    let streamed = toml::parse_with_visitor(&mut BufReader::new(text.as_bytes()), &mut visitor).is_ok();
+    results.push(("Value::stats counts values by type",
+                   toml::parse_from_str(text).ok().map(|v| v.stats()) == Some(expected.clone()) &&
+                   toml::parse_from_str("").ok().map(|v| v.stats()) == Some(toml::Stats::new()) &&
+                   toml::PosInt(1).stats() == toml::Stats { integers: 1, .. toml::Stats::new() }));
+    results.push(("StatsVisitor counts without building the tree", streamed && visitor.stats() == expected));
+
+    let mut same = true;
+    for filename in walk_dir(&path.join("valid")).unwrap() {
+        if !filename.is_file() || filename.extension_str() != Some("toml") { continue }
+        let bytes = File::open(&filename).read_to_end().unwrap();
+        let mut visitor = toml::StatsVisitor::new();
+        let streamed = toml::parse_with_visitor(&mut BufReader::new(bytes.as_slice()), &mut visitor).is_ok();
+        same = same && streamed && toml::parse_from_bytes(bytes.as_slice()).ok().map(|v| v.stats()) ==
+               Some(visitor.stats());
+    }
+    results.push(("StatsVisitor matches Value::stats on the fixtures", same));
+
+    return results;
+}
+
+// Tests of the accessors, lookups and editing methods of Value
+fn value_tests(path: &Path) -> ~[(&'static str, bool)] {
+    let mut results = ~[];
+
+    let doc = toml::parse_from_str("zero = 0\nmax = 18446744073709551615\nneg = -1\n\
+                                    min = -9223372036854775808\nbelow = -9223372036854775809\n").unwrap();
+    results.push(("get_uint over the full u64 range",
+                   doc.lookup_uint("zero") == Some(0) && doc.lookup_uint("max") == Some(std::u64::MAX) &&
+                   doc.lookup_uint("neg").is_none() && doc.lookup_uint("missing").is_none() &&
+                   toml::Float(1.0).get_uint().is_none()));
+    results.push(("get_int at the ends of the i64 range",
+                   doc.lookup_int("zero") == Some(0) && doc.lookup_int("neg") == Some(-1) &&
+                   doc.lookup_int("min") == Some(std::i64::MIN) && doc.lookup_int("below").is_none() &&
+                   doc.lookup_int("max").is_none()));
+
+
+    let mut doc = toml::parse_from_str("[feature]\nenabled = false\nlevels = [1, 2]\n").unwrap();
+    let flipped = match doc.lookup_mut("feature.enabled") {
+        Some(enabled) => { *enabled = toml::Boolean(true); true }
+        None => false
+    };
+    results.push(("lookup_mut changes a value in place",
+                   flipped && doc.lookup_bool("feature.enabled") == Some(true) &&
+                   doc.lookup_mut("feature.missing").is_none() && doc.lookup_mut("feature.enabled.x").is_none()));
+    match doc.lookup_mut("feature.levels[1]") {
+        Some(level) => { *level = toml::PosInt(5) }
+        None => {}
+    }
+    match doc.lookup_mut("feature").and_then(|t| t.get_table_mut()) {
+        Some(table) => { table.insert(~"name", toml::String(~"x")); }
+        None => {}
+    }
+    let mut scalar = toml::PosInt(1);
+    results.push(("get_table_mut and lookup_mut with indices",
+                   doc.lookup_int("feature.levels[1]") == Some(5) && doc.lookup_str("feature.name") == Some("x") &&
+                   scalar.get_table_mut().is_none() && scalar.get_vec_mut().is_none()));
+
+
+    let big = toml::String("x".repeat(100000));
+    let ptr = match big { toml::String(ref s) => s.as_bytes().as_ptr(), _ => std::ptr::null() };
+    let moved = match big.into_str() {
+        Ok(s) => s.as_bytes().as_ptr() == ptr && s.len() == 100000,
+        Err(_) => false
+    };
+    results.push(("into_str moves the string without copying", moved));
+    let table = toml::parse_from_str("[t]\na = 1\n").unwrap().lookup("t").unwrap().clone();
+    let ptr = match table { toml::Table(_, ref map) => &**map as *toml::TableMap, _ => std::ptr::null() };
+    results.push(("into_table moves the table", match table.into_table() {
+        Ok(map) => &*map as *toml::TableMap == ptr && map.len() == 1,
+        Err(_) => false
+    }));
+    results.push(("into_* hand the value back on a mismatch",
+                   toml::PosInt(1).into_vec() == Err(toml::PosInt(1)) &&
+                   toml::Array(~[]).into_str() == Err(toml::Array(~[])) &&
+                   toml::TableArray(~[]).into_table_array() == Ok(~[]) &&
+                   toml::Array(~[toml::PosInt(1)]).into_table_array() == Err(toml::Array(~[toml::PosInt(1)]))));
+
+
+    let first = toml::parse_from_str("a = 1\n[t]\nx = [1, 2]\ny = \"s\"\n[t.u]\nz = true\n").unwrap();
+    let second = toml::parse_from_str("a = 1\n[t.u]\nz = true\n[t]\ny = \"s\"\nx = [1, 2]\n").unwrap();
+    let fewer = toml::parse_from_str("a = 1\n[t]\nx = [1, 2]\n[t.u]\nz = true\n").unwrap();
+    let more = toml::parse_from_str("a = 1\nb = 2\n[t]\nx = [1, 2]\ny = \"s\"\n[t.u]\nz = true\n").unwrap();
+    let changed = toml::parse_from_str("a = 1\n[t]\nx = [2, 1]\ny = \"s\"\n[t.u]\nz = true\n").unwrap();
+    results.push(("equality of nested tables ignores key order",
+                   first == second && first != fewer && first != more && more != first && first != changed &&
+                   toml::Table(true, ~toml::TableMap::new()) == toml::Table(false, ~toml::TableMap::new())));
+    let nan: f64 = std::f64::NAN;
+    results.push(("equality of numbers",
+                   toml::PosInt(0) == toml::NegInt(0) && toml::NegInt(0) == toml::PosInt(0) &&
+                   toml::PosInt(5) != toml::NegInt(5) && toml::PosInt(5) != toml::Float(5.0) &&
+                   toml::Float(0.0) == toml::Float(-0.0) && toml::Float(nan) != toml::Float(nan) &&
+                   toml::Array(~[toml::PosInt(1)]) != toml::TableArray(~[toml::PosInt(1)])));
+
+
+    let parsed = toml::parse_from_str("[t]\na = 1\nb = [\"x\", \"y\"]\n").unwrap().lookup("t").unwrap().clone();
+    let mut map = ~toml::TableMap::new();
+    map.insert(~"b", toml::Array(~[toml::String(~"x"), toml::String(~"y")]));
+    map.insert(~"a", toml::PosInt(1));
+    let mut set = HashSet::new();
+    set.insert(parsed.clone());
+    set.insert(toml::Table(true, map));
+    set.insert(parsed);
+    results.push(("equal tables dedupe in a hash set", set.len() == 1));
+    let mut set = HashSet::new();
+    for v in [toml::PosInt(0), toml::NegInt(0), toml::Float(0.0), toml::Float(-0.0), toml::PosInt(1), toml::NegInt(1)].iter() {
+        set.insert(v.clone());
+    }
+    results.push(("equal numbers hash alike", set.len() == 4));
+
+
+    let doc = toml::parse_from_str("[server]\nport = \"8080\"\nhosts = [\"a\", 1.5]\nretries = 3\n").unwrap();
+    results.push(("try_* accessors describe the mismatch",
+                   toml::String(~"x").try_int().err().map(|e| e.to_str()) == Some(~"expected integer, found string") &&
+                   toml::PosInt(1).try_table().err().map(|e| e.to_str()) == Some(~"expected table, found integer") &&
+                   toml::Boolean(true).try_bool() == Ok(true)));
+    results.push(("typed lookups name the path of a mismatch",
+                   doc.try_lookup_int("server.port").err().map(|e| e.to_str()) ==
+                   Some(~"`server.port`: expected integer, found string") &&
+                   doc.try_lookup_str("server.hosts[1]") ==
+                   Err(toml::WrongType { at: ~"server.hosts[1]", expected: "string", found: "float" }) &&
+                   doc.try_lookup_int("server.retries") == Ok(3) && doc.try_lookup_str("server.hosts.0") == Ok("a") &&
+                   doc.try_lookup_bool("server.missing") == Err(toml::KeyNotFound { at: ~"server.missing" })));
+
+
+    let doc = toml::parse_from_str("[server]\nport = 8080\nbig = 70000\n[groups]\nadmins = [1, 2]\nusers = [3]\n\
+                                    none = []\n[bad]\nx = [1, \"a\"]\n").unwrap();
+    let groups: Option<HashMap<~str, ~[i64]>> = doc.get("groups");
+    results.push(("get extracts a map of arrays in one call", groups.map_or(false, |g| {
+        g.len() == 3 && g.find(&~"admins") == Some(&~[1i64, 2]) && g.find(&~"users") == Some(&~[3i64]) &&
+        g.find(&~"none") == Some(&~[])
+    })));
+    let port: Option<u16> = doc.get("server.port");
+    let big: Option<u16> = doc.get("server.big");
+    let wide: Option<u32> = doc.get("server.big");
+    let mixed: Option<~[i64]> = doc.get("bad.x");
+    let bad: Option<HashMap<~str, ~[i64]>> = doc.get("bad");
+    let missing: Option<bool> = doc.get("server.missing");
+    results.push(("get narrows integers and fails on any bad element",
+                   port == Some(8080) && big.is_none() && wide == Some(70000) && mixed.is_none() &&
+                   bad.is_none() && missing.is_none()));
+
+
+    let doc = toml::parse_from_str("[hosts.\"127.0.0.1\"]\nport = 80\n[paths]\n\"C:\\\\dir\" = 1\n'a.b' = 2\n\
+                                    'say \"hi\"' = 3\n").unwrap();
+    results.push(("lookup with quoted segments",
+                   doc.lookup_int("hosts.\"127.0.0.1\".port") == Some(80) &&
+                   doc.lookup_int("hosts.127.0.0.1.port").is_none() &&
+                   doc.lookup_int("paths.\"C:\\\\dir\"") == Some(1) && doc.lookup_int("paths.\"C:\\dir\"").is_none() &&
+                   doc.lookup_int("paths.\"a.b\"") == Some(2) &&
+                   doc.lookup_int("paths.\"say \\\"hi\\\"\"") == Some(3) &&
+                   doc.lookup("hosts.\"127").is_none() && doc.lookup("paths.\"a.b\"x").is_none()));
+    results.push(("lookup_path takes keys verbatim",
+                   doc.lookup_path(["hosts", "127.0.0.1", "port"]).and_then(|v| v.get_int()) == Some(80) &&
+                   doc.lookup_path(["paths", "C:\\dir"]).and_then(|v| v.get_int()) == Some(1) &&
+                   doc.lookup_path(["paths", "\"a.b\""]).is_none() &&
+                   doc.lookup_path([]) == Some(&doc)));
+
+
+    let doc = toml::parse_from_str("matrix = [[1, 2], [3, 4, 5]]\nports = [80, 443]\n\"servers[0]\" = \"literal\"\n\
+                                    [[servers]]\nip = \"a\"\n[[servers]]\nip = \"b\"\n").unwrap();
+    results.push(("lookup with bracketed indices",
+                   doc.lookup_str("servers[1].ip") == Some("b") && doc.lookup_str("servers.1.ip") == Some("b") &&
+                   doc.lookup_int("ports[1]") == Some(443) && doc.lookup_int("matrix[1][2]") == Some(5) &&
+                   doc.lookup_int("matrix.1.2") == Some(5) && doc.lookup("matrix[1]").and_then(|v| v.len()) == Some(3) &&
+                   doc.lookup("ports[2]").is_none() && doc.lookup("servers[0][0]").is_none()));
+    results.push(("malformed brackets fail the lookup",
+                   doc.lookup("servers[").is_none() && doc.lookup("servers[x]").is_none() &&
+                   doc.lookup("servers[0").is_none() && doc.lookup("servers[0]ip").is_none() &&
+                   doc.lookup_detailed("servers[x].ip") == Err(toml::InvalidPath)));
+    results.push(("a key with brackets is reached by quoting it",
+                   doc.lookup_str("\"servers[0]\"") == Some("literal") &&
+                   doc.lookup_str("servers[0].ip") == Some("a")));
+
+
+    let doc = toml::parse_from_str("[errors]\n404 = \"not found\"\n0 = \"zero\"\n[[items]]\nname = \"first\"\n").unwrap();
+    results.push(("numeric segments are keys on tables and indices on arrays",
+                   doc.lookup_str("errors.404") == Some("not found") && doc.lookup_str("errors.0") == Some("zero") &&
+                   doc.lookup_str("errors.\"0\"") == Some("zero") && doc.lookup_str("items.0.name") == Some("first") &&
+                   doc.lookup_str("items[0].name") == Some("first") && doc.lookup("errors[0]").is_none() &&
+                   doc.lookup("items.\"0\"").is_none() && doc.lookup("items.1").is_none()));
+
+
+    let doc = toml::parse_from_str("[server]\nhost = \"a\"\nports = [80, 443]\n[[db]]\nname = \"x\"\n").unwrap();
+    results.push(("lookup_detailed tells why a path does not resolve",
+                   doc.lookup_detailed("server.ports[1]") == Ok(&toml::PosInt(443)) &&
+                   doc.lookup_detailed("server.missing") == Err(toml::KeyNotFound { at: ~"server.missing" }) &&
+                   doc.lookup_detailed("server.host.x") == Err(toml::NotATable { at: ~"server.host", found: "string" }) &&
+                   doc.lookup_detailed("db.0.name.x") == Err(toml::NotATable { at: ~"db[0].name", found: "string" }) &&
+                   doc.lookup_detailed("server[0]") == Err(toml::NotAnArray { at: ~"server", found: "table" }) &&
+                   doc.lookup_detailed("server.ports[5]") == Err(toml::IndexOutOfBounds { at: ~"server.ports", len: 2 }) &&
+                   doc.lookup_detailed("db.1") == Err(toml::IndexOutOfBounds { at: ~"db", len: 1 }) &&
+                   doc.lookup_detailed("server.ports[") == Err(toml::InvalidPath)));
+    results.push(("LookupError messages",
+                   doc.lookup_detailed("server.host.x").err().map(|e| e.to_str()) ==
+                   Some(~"`server.host` is string, not a table") &&
+                   doc.lookup_detailed("server.ports[5]").err().map(|e| e.to_str()) ==
+                   Some(~"index out of bounds: `server.ports` has 2 elements")));
+
+
+    let doc = toml::parse_from_str("[server]\nhost = \"example.com\"\nport = 8080\ndebug = true\nratio = 0.25\n\
+                                    aliases = [\"a\", \"b\"]\nports = [1, 2]\nnone = []\n").unwrap();
+    results.push(("typed lookups",
+                   doc.lookup_str("server.host") == Some("example.com") && doc.lookup_int("server.port") == Some(8080) &&
+                   doc.lookup_bool("server.debug") == Some(true) && doc.lookup_float("server.ratio") == Some(0.25) &&
+                   doc.lookup_strs("server.aliases") == Some(~["a", "b"]) && doc.lookup_strs("server.none") == Some(~[])));
+    results.push(("typed lookups give None on mismatches and missing paths",
+                   doc.lookup_str("server.port").is_none() && doc.lookup_int("server.host").is_none() &&
+                   doc.lookup_bool("server.port").is_none() && doc.lookup_float("server.port").is_none() &&
+                   doc.lookup_strs("server.ports").is_none() && doc.lookup_strs("server.host").is_none() &&
+                   doc.lookup_str("server.missing").is_none() && doc.lookup_int("missing.port").is_none()));
+
+
+    let doc = toml::parse_from_str("[net]\ntimeout = 5\nhost = \"h\"\nverbose = false\nscale = 1.5\n").unwrap();
+    results.push(("lookups with defaults use present values",
+                   doc.lookup_int_or("net.timeout", 30) == 5 && doc.lookup_str_or("net.host", "x") == "h" &&
+                   doc.lookup_bool_or("net.verbose", true) == false && doc.lookup_float_or("net.scale", 2.0) == 1.5 &&
+                   doc.lookup_or("net.timeout", 30u16) == 5u16));
+    results.push(("lookups with defaults fall back on missing paths",
+                   doc.lookup_int_or("net.retries", 30) == 30 && doc.lookup_str_or("db.host", "x") == "x" &&
+                   doc.lookup_bool_or("net.quiet", true) == true && doc.lookup_float_or("net.ratio", 2.0) == 2.0 &&
+                   doc.lookup_or("net.retries", 3u16) == 3u16 && doc.checked_lookup_or("net.retries", 3i64) == Some(3)));
+    results.push(("lookups with defaults on wrong types",
+                   doc.lookup_int_or("net.host", 30) == 30 && doc.lookup_str_or("net.timeout", "x") == "x" &&
+                   doc.lookup_or("net.host", 3u16) == 3u16 &&
+                   doc.checked_lookup_or("net.host", 3i64).is_none() &&
+                   doc.checked_lookup_or("net.timeout", 3i64) == Some(5)));
+
+
+    let doc = toml::parse_from_str("[[servers]]\nip = \"10.0.0.1\"\n[[servers]]\nip = \"10.0.0.2\"\n[[servers]]\n\
+                                    ip = \"10.0.0.3\"\n[dependencies.a]\nversion = \"1.0\"\n[dependencies.b]\n\
+                                    path = \"../b\"\n[dependencies.c]\nversion = \"2.1\"\n").unwrap();
+    let strs = |values: ~[&toml::Value]| -> ~[~str] { values.iter().filter_map(|v| v.get_str()).map(|s| s.clone()).collect() };
+    results.push(("lookup_all over an array of tables",
+                   strs(doc.lookup_all("servers.*.ip")) == ~[~"10.0.0.1", ~"10.0.0.2", ~"10.0.0.3"] &&
+                   doc.lookup_all("servers.*").len() == 3 && doc.lookup_all("servers.*.port").is_empty()));
+    results.push(("lookup_all over a table skips entries lacking the key",
+                   strs(doc.lookup_all("dependencies.*.version")) == ~[~"1.0", ~"2.1"] &&
+                   doc.lookup_all("*.*").len() == 6 && doc.lookup_all("servers[1].ip").len() == 1 &&
+                   doc.lookup_all("missing.*").is_empty() && doc.lookup_all("servers[").is_empty()));
+
+
+    let doc = toml::parse_from_str("name = \"x\"\n[server]\nports = [80, 443]\n\"a.b\" = 1\nempty = []\n\
+                                    [server.tls]\n[[db]]\nid = 1\n").unwrap();
+    let paths: ~[~str] = doc.leaves().move_iter().map(|(path, _)| path).collect();
+    results.push(("flatten paths", paths == ~[~"name", ~"server.ports.0", ~"server.ports.1", ~"server.\"a.b\"",
+                                               ~"server.empty", ~"server.tls", ~"db.0.id"]));
+    let flat = doc.flatten();
+    results.push(("flatten values can be looked up by their paths",
+                   flat.len() == 7 && flat.find(&~"server.\"a.b\"") == Some(&toml::PosInt(1)) &&
+                   flat.find(&~"server.tls") == Some(&toml::Value::table()) &&
+                   flat.find(&~"server.empty") == Some(&toml::Value::array()) &&
+                   flat.iter().all(|(path, v)| doc.lookup(path.as_slice()) == Some(v))));
+
+
+    let mut same = true;
+    for filename in walk_dir(&path.join("valid")).unwrap() {
+        if !filename.is_file() || filename.extension_str() != Some("toml") { continue }
+        let doc = toml::parse_from_path(&filename).unwrap();
+        same = same && toml::Value::unflatten(&doc.flatten()).ok() == Some(doc);
+    }
+    results.push(("unflatten inverts flatten on the fixtures", same));
+    let doc = toml::parse_from_str("[hosts.\"127.0.0.1\"]\nport = 80\n[errors]\n404 = \"x\"\n").unwrap();
+    results.push(("unflatten inverts flatten with quoted keys",
+                   toml::Value::unflatten(&doc.flatten()).ok() == Some(doc.clone())));
+    let mut map = HashMap::new();
+    map.insert(~"a.b", toml::PosInt(1));
+    map.insert(~"a", toml::PosInt(2));
+    let conflict = toml::Value::unflatten(&map);
+    let mut map = HashMap::new();
+    map.insert(~"a.0", toml::PosInt(1));
+    map.insert(~"a.2", toml::PosInt(2));
+    let gap = toml::Value::unflatten(&map);
+    results.push(("unflatten rejects conflicting paths and gaps",
+                   conflict.is_err() && gap == Err(~"missing index 1 in `a`")));
+
+
+    let doc = toml::parse_from_str("title = \"t\"\n[owner]\nname = \"n\"\n[db]\nports = [1, 2]\n[db.extra]\n\
+                                    [[db.replicas]]\nhost = \"r\"\n").unwrap();
+    let mut walked = ~[];
+    doc.walk(|path, _| walked.push(path.connect("/")));
+    let mut sorted = walked.clone();
+    sorted.sort();
+    results.push(("walk visits every leaf depth-first",
+                   walked == ~[~"title", ~"owner/name", ~"db/ports/0", ~"db/ports/1", ~"db/extra", ~"db/replicas/0/host"] &&
+                   sorted == ~[~"db/extra", ~"db/ports/0", ~"db/ports/1", ~"db/replicas/0/host", ~"owner/name", ~"title"]));
+    let entries: ~[(&str, &toml::Value)] = doc.lookup("owner").unwrap().iter().collect();
+    let elements: ~[(uint, &toml::Value)] = doc.lookup("db.ports").unwrap().iter_vec().collect();
+    results.push(("iter, iter_vec and keys",
+                   entries == ~[("name", &toml::String(~"n"))] &&
+                   elements == ~[(0u, &toml::PosInt(1)), (1u, &toml::PosInt(2))] &&
+                   doc.keys() == ~["title", "owner", "db"] &&
+                   doc.lookup("db.replicas").unwrap().iter_vec().count() == 1));
+    let (scalar, table, array) = (toml::PosInt(1), toml::Value::table(), toml::Value::array());
+    results.push(("iterators over other values are empty",
+                   scalar.iter().next().is_none() && scalar.iter_vec().next().is_none() && scalar.keys().is_empty() &&
+                   table.iter_vec().next().is_none() && array.iter().next().is_none()));
+
+
+    let mut doc = toml::Value::table();
+    let created = doc.set("server.port", toml::PosInt(9090));
+    results.push(("set creates missing tables", created == Ok(None) && doc.lookup_int("server.port") == Some(9090) &&
+                   doc.lookup("server").map_or(false, |s| s.get_table().is_some())));
+    let mut doc = toml::parse_from_str("ports = [80, 443]\none = [1]\n[server]\nport = 80\n[[db]]\nname = \"a\"\n").unwrap();
+    results.push(("set replaces a scalar and returns it",
+                   doc.set("server.port", toml::PosInt(9090)) == Ok(Some(toml::PosInt(80))) &&
+                   doc.set("ports[0]", toml::PosInt(8080)) == Ok(Some(toml::PosInt(80))) &&
+                   doc.set("one[0]", toml::String(~"x")) == Ok(Some(toml::PosInt(1))) &&
+                   doc.lookup_int("server.port") == Some(9090) && doc.lookup_int("ports[0]") == Some(8080)));
+    let mut table = ~toml::TableMap::new();
+    table.insert(~"name", toml::String(~"b"));
+    results.push(("set appends to arrays of tables",
+                   doc.set("db[1]", toml::Table(false, table)) == Ok(None) &&
+                   doc.set("db[2].name", toml::String(~"c")) == Ok(None) &&
+                   doc.set("ports[2]", toml::PosInt(8443)) == Ok(None) &&
+                   doc.lookup_str("db[1].name") == Some("b") && doc.lookup_str("db[2].name") == Some("c") &&
+                   doc.lookup("ports").and_then(|p| p.len()) == Some(3) && doc.to_toml_str().is_ok()));
+    results.push(("set keeps arrays well-typed for the version",
+                   doc.set("db[0]", toml::PosInt(1)) ==
+                   Err(toml::WrongType { at: ~"db[0]", expected: "table", found: "integer" }) &&
+                   doc.set("db[3]", toml::String(~"x")) ==
+                   Err(toml::WrongType { at: ~"db[3]", expected: "table", found: "string" }) &&
+                   doc.set_with("ports[3]", toml::String(~"x"), toml::V0_4) ==
+                   Err(toml::WrongType { at: ~"ports[3]", expected: "integer", found: "string" }) &&
+                   doc.set_with("ports[1]", toml::Float(1.0), toml::V0_4) ==
+                   Err(toml::WrongType { at: ~"ports[1]", expected: "integer", found: "float" }) &&
+                   doc.set("ports[9]", toml::PosInt(1)) == Err(toml::IndexOutOfBounds { at: ~"ports", len: 3 }) &&
+                   doc.set("ports[3]", toml::String(~"x")) == Ok(None) &&
+                   doc.set_with("ports[1]", toml::Float(1.0), toml::V1_0) == Ok(Some(toml::PosInt(443))) &&
+                   doc.lookup("db").and_then(|p| p.len()) == Some(3) && doc.to_toml_str().is_ok()));
+    results.push(("set refuses to replace scalars on the way unless forced",
+                   doc.set("server.port.x", toml::PosInt(1)) ==
+                   Err(toml::NotATable { at: ~"server.port", found: "integer" }) &&
+                   doc.lookup_int("server.port") == Some(9090) &&
+                   doc.set_force("server.port.x", toml::PosInt(1)) == Ok(None) &&
+                   doc.lookup_int("server.port.x") == Some(1)));
+
+
+    let mut doc = toml::parse_from_str("[a.b]\nc = 1\nd = 2\n[[e]]\nf = 1\n[[g]]\nh = 1\n[[g]]\nh = 2\n").unwrap();
+    results.push(("remove takes nested keys and keeps their siblings",
+                   doc.remove("a.b.c") == Some(toml::PosInt(1)) && doc.lookup_int("a.b.d") == Some(2) &&
+                   doc.remove("a.b.x") == None && doc.remove("a.b.d.x") == None));
+    results.push(("remove keeps emptied tables unless pruning",
+                   doc.remove("a.b.d") == Some(toml::PosInt(2)) && doc.lookup("a.b").is_some()));
+    results.push(("remove shifts array elements down",
+                   doc.remove("g[0]").map_or(false, |t| t.lookup_int("h") == Some(1)) &&
+                   doc.lookup_int("g[0].h") == Some(2) && doc.remove("g[1]") == None));
+    results.push(("remove drops arrays of tables left empty",
+                   doc.remove("e[0]").is_some() && doc.lookup("e").is_none() &&
+                   doc.remove("g[0].h") == Some(toml::PosInt(2)) && doc.lookup("g[0]").is_some() &&
+                   doc.to_toml_str().is_ok()));
+    let mut doc = toml::parse_from_str("[a.b]\nc = 1\n[x]\ny = 1\n").unwrap();
+    results.push(("remove_pruned drops emptied tables only",
+                   doc.remove_pruned("a.b.c") == Some(toml::PosInt(1)) && doc.lookup("a").is_none() &&
+                   doc.lookup_int("x.y") == Some(1)));
+
+
+    let built = toml::TableBuilder::new()
+        .insert("best-day-ever", toml::Datetime(1987, 7, 5, 17, 45, 0))
+        .table("numtheory", |t| t.insert("boring", false).insert("perfection", ~[6u, 28, 496]))
+        .build();
+    let parsed = toml::parse_from_path(&path.join("valid").join("example.toml"));
+    results.push(("TableBuilder builds the example fixture", built.is_ok() && built == parsed.map_err(|_| ~"")));
+    let built = toml::TableBuilder::new()
+        .table_array("albums", |t| t.insert("name", "Born to Run")
+                     .table_array("songs", |s| s.insert("name", "Jungleland"))
+                     .table_array("songs", |s| s.insert("name", "Meeting Across the River")))
+        .table_array("albums", |t| t.insert("name", "Born in the USA")
+                     .table_array("songs", |s| s.insert("name", "Glory Days"))
+                     .table_array("songs", |s| s.insert("name", "Dancing in the Dark")))
+        .build();
+    let parsed = toml::parse_from_path(&path.join("valid").join("table-array-nest.toml"));
+    results.push(("TableBuilder builds nested arrays of tables", built.is_ok() && built == parsed.map_err(|_| ~"")));
+    let twice = toml::TableBuilder::new().insert("a", 1u).table("a", |t| t);
+    results.push(("TableBuilder reports duplicate keys", twice.build() == Err(~"duplicate key `a`")));
+
+
+    let mut doc = toml::parse_from_str("ports = [80]\nnone = []\nname = \"x\"\n[[db]]\nid = 1\n").unwrap();
+    let mut ports = toml::Array(~[toml::PosInt(80)]);
+    results.push(("push appends values of the elements' type",
+                   ports.push(toml::PosInt(443)) == Ok(()) &&
+                   ports == toml::Array(~[toml::PosInt(80), toml::PosInt(443)])));
+    results.push(("push rejects values of another type in TOML 0.4",
+                   ports.push_with(toml::String(~"x"), toml::V0_4) ==
+                   Err(toml::TypeMismatch { expected: "integer", found: "string" }) && ports.len() == Some(2)));
+    results.push(("push accepts values of any type in TOML 1.0",
+                   ports.push(toml::String(~"x")) == Ok(()) && ports.push_with(toml::Float(1.5), toml::V1_0) == Ok(()) &&
+                   ports.len() == Some(4)));
+    let mut empty = toml::Array(~[]);
+    let mut scalar = toml::Boolean(true);
+    results.push(("push into empty arrays and non-arrays",
+                   empty.push(toml::Float(1.5)) == Ok(()) && empty.len() == Some(1) &&
+                   scalar.push(toml::PosInt(1)) == Err(toml::TypeMismatch { expected: "array", found: "boolean" })));
+    let mut table = ~toml::TableMap::new();
+    table.insert(~"id", toml::PosInt(2));
+    results.push(("push_at appends to arrays of tables",
+                   doc.push_at("db", toml::Table(false, table.clone())) == Ok(()) &&
+                   doc.lookup_int("db[1].id") == Some(2)));
+    results.push(("push_at rejects non-tables in arrays of tables",
+                   doc.push_at("db", toml::PosInt(3)) ==
+                   Err(toml::WrongType { at: ~"db", expected: "table", found: "integer" }) &&
+                   doc.push_at_with("ports", toml::String(~"x"), toml::V0_4) ==
+                   Err(toml::WrongType { at: ~"ports", expected: "integer", found: "string" }) &&
+                   doc.push_at("ports", toml::String(~"x")) == Ok(()) && doc.lookup_str("ports[1]") == Some("x") &&
+                   doc.push_at("name", toml::PosInt(1)) ==
+                   Err(toml::WrongType { at: ~"name", expected: "array", found: "string" })));
+    results.push(("push_at creates missing arrays",
+                   doc.push_at("none", toml::PosInt(1)) == Ok(()) &&
+                   doc.push_at("new.list", toml::PosInt(1)) == Ok(()) &&
+                   doc.push_at("new.tables", toml::Table(false, table)) == Ok(()) &&
+                   doc.lookup_int("none[0]") == Some(1) && doc.lookup_int("new.list[0]") == Some(1) &&
+                   doc.lookup_int("new.tables[0].id") == Some(2) && doc.to_toml_str().is_ok()));
+
+
+    let base = toml::parse_from_str("name = \"app\"\nports = [80]\n[server]\nhost = \"a\"\nport = 80\n").unwrap();
+    let overlay = toml::parse_from_str("ports = [443]\n[server]\nport = 8080\n[log]\nlevel = 1\n").unwrap();
+    let mut merged = base.clone();
+    results.push(("merge layers tables recursively",
+                   merged.merge(&overlay) == Ok(()) && merged.lookup_str("name") == Some("app") &&
+                   merged.lookup_str("server.host") == Some("a") && merged.lookup_int("server.port") == Some(8080) &&
+                   merged.lookup_int("log.level") == Some(1) && merged.lookup_int("ports[0]") == Some(443) &&
+                   merged.lookup("ports").and_then(|p| p.len()) == Some(1)));
+    let mut merged = base.clone();
+    let mut scalar = toml::PosInt(1);
+    results.push(("merge only takes tables",
+                   merged.merge(&toml::PosInt(1)) == Err(toml::TypeMismatch { expected: "table", found: "integer" }) &&
+                   scalar.merge(&overlay) ==
+                   Err(toml::TypeMismatch { expected: "table", found: "integer" }) && merged == base));
+    let append = toml::MergeOptions { arrays: toml::Append, ..toml::MergeOptions::new() };
+    let mut merged = base.clone();
+    results.push(("merge_with appends arrays",
+                   merged.merge_with(&overlay, append) == Ok(()) &&
+                   merged.lookup("ports") == Some(&toml::Array(~[toml::PosInt(80), toml::PosInt(443)]))));
+    let db = toml::parse_from_str("[[db]]\nhost = \"a\"\nport = 1\n[[db]]\nhost = \"b\"\n").unwrap();
+    let db_overlay = toml::parse_from_str("[[db]]\nport = 2\n[[db]]\nport = 3\n[[db]]\nhost = \"c\"\n").unwrap();
+    let by_index = toml::MergeOptions { arrays: toml::MergeByIndex, ..toml::MergeOptions::new() };
+    let mut merged = db.clone();
+    results.push(("merge_with merges arrays by index",
+                   merged.merge_with(&db_overlay, by_index) == Ok(()) &&
+                   merged.lookup_str("db[0].host") == Some("a") && merged.lookup_int("db[0].port") == Some(2) &&
+                   merged.lookup_str("db[1].host") == Some("b") && merged.lookup_int("db[1].port") == Some(3) &&
+                   merged.lookup_str("db[2].host") == Some("c")));
+    let mut deletion = ~toml::TableMap::new();
+    deletion.insert(~"name", toml::NoValue);
+    let deletion = toml::Table(false, deletion);
+    let deleting = toml::MergeOptions { delete_on_novalue: true, ..toml::MergeOptions::new() };
+    let mut kept = base.clone();
+    let mut deleted = base.clone();
+    results.push(("merge_with deletes keys set to NoValue on request",
+                   kept.merge_with(&deletion, toml::MergeOptions::new()) == Ok(()) && kept == base &&
+                   deleted.merge_with(&deletion, deleting) == Ok(()) && deleted.lookup("name").is_none()));
+    let clash = toml::parse_from_str("server = \"b\"\n").unwrap();
+    let strict = toml::MergeOptions { overlay_wins: false, ..toml::MergeOptions::new() };
+    let mut replaced = base.clone();
+    let mut refused = base.clone();
+    results.push(("merge_with replaces values of another type unless told not to",
+                   replaced.merge_with(&clash, toml::MergeOptions::new()) == Ok(()) &&
+                   replaced.lookup_str("server") == Some("b") &&
+                   refused.merge_with(&clash, strict) ==
+                   Err(toml::WrongType { at: ~"server", expected: "table", found: "string" })));
+
+
+    results.push(("ToValue picks the integer variant by sign",
+                   toml::to_value(&5i) == toml::PosInt(5) && toml::to_value(&-5i8) == toml::NegInt(5) &&
+                   toml::to_value(&std::i64::MIN) == toml::NegInt(1u64 << 63) &&
+                   toml::to_value(&std::u64::MAX) == toml::PosInt(std::u64::MAX) &&
+                   toml::to_value(&0i32) == toml::PosInt(0)));
+    results.push(("ToValue converts scalars",
+                   toml::to_value(&true) == toml::Boolean(true) && toml::to_value(&1.5f64) == toml::Float(1.5) &&
+                   toml::to_value(&0.5f32) == toml::Float(0.5) && toml::to_value(&"x") == toml::String(~"x") &&
+                   toml::to_value(&~"y") == toml::String(~"y")));
+    results.push(("Value::table and Value::array are empty",
+                   toml::Value::table().is_empty() && toml::Value::table().get_table().is_some() &&
+                   toml::Value::array() == toml::Array(~[]) && toml::to_value(&~[1u]) != toml::Value::array()));
+    let mut server = HashMap::new();
+    server.insert(~"host", toml::to_value(&"example.org"));
+    server.insert(~"ports", toml::to_value(&~[80i, 443]));
+    server.insert(~"offset", toml::to_value(&-3i));
+    let mut backend = HashMap::new();
+    backend.insert(~"weight", 0.5f64);
+    let mut root = HashMap::new();
+    root.insert(~"server", toml::to_value(&server));
+    root.insert(~"backends", toml::to_value(&~[backend.clone(), backend]));
+    root.insert(~"enabled", toml::to_value(&true));
+    let parsed = toml::parse_from_str("enabled = true\n[server]\nhost = \"example.org\"\nports = [80, 443]\n\
+                                       offset = -3\n[[backends]]\nweight = 0.5\n[[backends]]\nweight = 0.5\n");
+    results.push(("ToValue builds the same document as the parser", parsed == Ok(toml::to_value(&root))));
+
+
+    let pairs = ~[(~"a", toml::PosInt(1)), (~"b", toml::PosInt(2)), (~"a", toml::PosInt(3))];
+    let builder: toml::TableBuilder = pairs.clone().move_iter().collect();
+    let lenient: toml::TableBuilder = pairs.clone().move_iter().collect();
+    let lenient = lenient.build_lenient();
+    results.push(("TableBuilder collects pairs, refusing duplicates in build",
+                   builder.build() == Err(~"duplicate key `a`") &&
+                   lenient.lookup_int("a") == Some(3) && lenient.lookup_int("b") == Some(2)));
+    let map: toml::TableMap = pairs.clone().move_iter().collect();
+    results.push(("TableMap collects pairs, the last one winning",
+                   map.len() == 2 && map.find_equiv(&"a") == Some(&toml::PosInt(3))));
+    let mut builder = toml::TableBuilder::new().insert("a", 1u);
+    builder.extend(&mut ~[(~"b", toml::PosInt(2))].move_iter());
+    let extended = builder.build().ok();
+    let mut clashing = toml::TableBuilder::new().insert("a", 1u);
+    clashing.extend(&mut ~[(~"a", toml::PosInt(2))].move_iter());
+    results.push(("TableBuilder extends with pairs, checking for duplicates",
+                   extended.as_ref().and_then(|v| v.lookup_int("b")) == Some(2) &&
+                   extended.as_ref().and_then(|v| v.lookup_int("a")) == Some(1) &&
+                   clashing.build() == Err(~"duplicate key `a`")));
+    let mut map = map;
+    map.extend(&mut ~[(~"b", toml::PosInt(4)), (~"c", toml::PosInt(5))].move_iter());
+    results.push(("TableMap extends with pairs, the last one winning",
+                   map.len() == 3 && map.find_equiv(&"b") == Some(&toml::PosInt(4)) &&
+                   map.find_equiv(&"a") == Some(&toml::PosInt(3))));
+    let array: toml::Value = range(1u64, 4).map(|i| toml::PosInt(i)).collect();
+    results.push(("Values collect into an array",
+                   array == toml::Array(~[toml::PosInt(1), toml::PosInt(2), toml::PosInt(3)])));
+
+
+    let built = value!({
+        title: "example",
+        enabled: true,
+        server: { host: "example.org", ports: [80, 443], offset: (-3), ratio: 0.5 },
+        empty: [],
+        backends: [{ weight: 1 }, { weight: 2 }]
+    });
+    let parsed = toml::parse_from_str("title = \"example\"\nenabled = true\nempty = []\n\
+                                       [server]\nhost = \"example.org\"\nports = [80, 443]\noffset = -3\nratio = 0.5\n\
+                                       [[backends]]\nweight = 1\n[[backends]]\nweight = 2\n");
+    results.push(("value! builds the same document as the parser", parsed == Ok(built)));
+    let quoted = value!({ "max-connections": 10, "127.0.0.1": { port: 80 }, name: "x" });
+    results.push(("value! takes string literals as keys",
+                   quoted.lookup_int("max-connections") == Some(10) &&
+                   quoted.lookup_int("\"127.0.0.1\".port") == Some(80) && quoted.lookup_str("name") == Some("x")));
+    results.push(("value! fails on duplicate keys", task::try(proc() { value!({ a: 1, "a": 2 }); }).is_err()));
+
+
+    let old = toml::parse_from_str("name = \"a\"\nports = [80]\n[server]\nhost = \"x\"\n[log]\nlevel = 1\n").unwrap();
+    results.push(("diff of identical documents is empty", old.diff(&old.clone()).is_empty()));
+    let mut added = old.clone();
+    added.set("server.port", toml::PosInt(80)).unwrap();
+    results.push(("diff reports an added key",
+                   old.diff(&added) == ~[toml::DiffEntry { path: ~"server.port", kind: toml::Added(toml::PosInt(80)) }]));
+    let mut removed = old.clone();
+    let log = removed.remove("log").unwrap();
+    results.push(("diff reports a removed section as one entry",
+                   old.diff(&removed) == ~[toml::DiffEntry { path: ~"log", kind: toml::Removed(log) }]));
+    let mut changed = old.clone();
+    changed.set("name", toml::String(~"b")).unwrap();
+    changed.set("ports[0]", toml::PosInt(81)).unwrap();
+    results.push(("diff reports changed scalars and whole arrays",
+                   old.diff(&changed) ==
+                   ~[toml::DiffEntry { path: ~"name",
+                                       kind: toml::Changed { old: toml::String(~"a"), new: toml::String(~"b") } },
+                     toml::DiffEntry { path: ~"ports",
+                                       kind: toml::Changed { old: toml::Array(~[toml::PosInt(80)]),
+                                                             new: toml::Array(~[toml::PosInt(81)]) } }]));
+
+    return results;
+}
+
+// Tests of the emitter beyond round-tripping the fixtures
+fn emitter_tests(path: &Path) -> ~[(&'static str, bool)] {
+    let mut results = ~[];
+    let doc = toml::parse_from_path(&path.join("valid").join("example.toml")).unwrap();
+
+    let mut wr = FailingWriter { remaining: 10 };
+    results.push(("write error is returned", doc.write_toml(&mut wr).is_err()));
+
+    let mut wr = FailingWriter { remaining: 0 };
+    let bad = toml::String(~"not a table");
+    results.push(("non-table root is rejected", bad.write_toml(&mut wr).is_err()));
+
+    let tmp = os::tmpdir().join("rust-toml-emitter-test.toml");
+    let written = {
+        let mut file = File::create(&tmp);
+        doc.write_toml(&mut file)
+    };
+    let reread = toml::parse_from_path(&tmp);
+    let _ = fs::unlink(&tmp);
+    results.push(("write to file", written.is_ok() && reread == Ok(doc.clone())));
+
+    let layout = toml::parse_from_bytes(bytes!(
+        "title = \"layout\"\n",
+        "ports = [8001, 8002, 8003, 8004]\n",
+        "[server]\n",
+        "host = \"localhost\"\n",
+        "[[backends]]\n",
+        "name = \"a\"\n",
+        "[[backends]]\n",
+        "name = \"b\"\n")).unwrap();
+
+    let headers = toml::EmitOptions::new();
+    let mut inline = toml::EmitOptions::new();
+    inline.inline_tables = 2;
+    inline.inline_table_arrays = true;
+    let mut narrow = toml::EmitOptions::new();
+    narrow.max_width = 20;
+
+    let expected = [
+        (&headers, "[server]\nhost = \"localhost\"\n"),
+        (&headers, "[[backends]]\nname = \"a\"\n"),
+        (&inline, "server = { host = \"localhost\" }\n"),
+        (&inline, "backends = [{ name = \"a\" }, { name = \"b\" }]\n"),
+        (&narrow, "ports = [\n    8001,\n    8002,\n    8003,\n    8004,\n]\n")
+    ];
+    for &(opts, text) in expected.iter() {
+        let emitted = layout.to_toml_str_with(opts).unwrap();
+        let reparsed = toml::parse_from_bytes(emitted.as_bytes());
+        results.push(("layout options", emitted.contains(text) && reparsed == Ok(layout.clone())));
+    }
+
+    let mut ascii = toml::EmitOptions::new();
+    ascii.ascii_only = true;
+
+    let strings = [
+        ("C:\\Users\\toml", &headers, "s = 'C:\\Users\\toml'\n"),
+        ("it's C:\\", &headers, "s = \"it's C:\\\\\"\n"),
+        ("smile \U0001F600", &headers, "s = \"smile \U0001F600\"\n"),
+        ("smile \U0001F600", &ascii, "s = \"smile \\U0001F600\"\n"),
+        ("two\nlines", &headers, "s = \"\"\"\ntwo\nlines\"\"\"\n")
+    ];
+    for &(string, opts, text) in strings.iter() {
+        let doc = toml::TableBuilder::new().insert("s", string).build().unwrap();
+        let emitted = doc.to_toml_str_with(opts).unwrap();
+        let reparsed = toml::parse_from_bytes(emitted.as_bytes());
+        results.push(("string quoting", emitted.as_slice() == text && reparsed == Ok(doc.clone())));
+    }
+    let doc = toml::parse_from_str("[café]\nx = 1\n[[café.crème]]\ny = 2\n").unwrap();
+    let emitted = doc.to_toml_str_with(&ascii).unwrap();
+    let names = "[\"a.b\"]\nx = 1\n[a.'x y'.\"q\\\"uote\"]\ny = 2\n[\" lead\".\"tab\\t\".\"'\"]\n\
+                   [[\"c]d\"]]\nz = 3\n[\"e\\u0001\".\"#f=g\"]\n";
+    results.push(("quoted and dotted table names survive being written",
+                   toml::roundtrip_check(names) == Ok(()) &&
+                   toml::parse_from_str("[\"a.b\"]\nx = 1\n").ok().and_then(|v| v.to_toml_str().ok()) ==
+                   Some(~"[\"a.b\"]\nx = 1\n")));
+    results.push(("table names are escaped with ascii_only",
+                   emitted.as_slice() == "[\"caf\\u00E9\"]\nx = 1\n\n[[\"caf\\u00E9\".\"cr\\u00E8me\"]]\ny = 2\n" &&
+                   toml::parse_from_str(emitted.as_slice()) == Ok(doc.clone())));
+
+    let ordered = "zebra = 1\napple = 2\n\n[zoo]\nmango = 3\n\n[[tigers]]\nname = \"b\"\n\n[alpha]\nkiwi = 4\n";
+    let mut doc = toml::parse_from_bytes(ordered.as_bytes()).unwrap();
+    results.push(("key order is kept", doc.to_toml_str().unwrap().as_slice() == ordered));
+
+    doc.set("banana", toml::PosInt(5)).unwrap();
+    doc.set("zoo.aardvark", toml::PosInt(6)).unwrap();
+    let expected = "zebra = 1\napple = 2\nbanana = 5\n\n[zoo]\nmango = 3\naardvark = 6\n";
+    results.push(("inserted keys come last", doc.to_toml_str().unwrap().starts_with(expected)));
+
+    let first = toml::parse_from_bytes(bytes!(
+        "b = [1.5, -0.0]\nc = \"C:\\\\x\"\n[t]\nz = 1\ny = 0\n[s]\nx = 1\n")).unwrap();
+    let second = toml::parse_from_bytes(bytes!(
+        "c = 'C:\\x'\nb = [1.5, 0.0]\n[s]\nx = 1\n[t]\ny = -0\nz = 1\n")).unwrap();
+    let canonical = first.to_canonical_toml_str().unwrap();
+    results.push(("canonical form of equal values", canonical == second.to_canonical_toml_str().unwrap()));
+    results.push(("canonical form sorts keys", canonical.as_slice() ==
+        "b = [1.5, 0.0]\nc = \"C:\\\\x\"\n\n[s]\nx = 1\n\n[t]\ny = 0\nz = 1\n"));
+
+    let mut changed = second.clone();
+    changed.set("t.z", toml::PosInt(2)).unwrap();
+    results.push(("canonical form of changed value", canonical != changed.to_canonical_toml_str().unwrap()));
+
+    let mixed = toml::parse_from_str("b = 1\nab = 2\nB = 3\na = 4\n\"a b\" = 5\n\
+                                      [t]\nz = [{y = 1, Y = 2}]\nZ = 6\n").unwrap();
+    let keys: ~[&str] = mixed.sorted_entries().iter().map(|&(k, _)| k).collect();
+    results.push(("sorted_entries sorts by case and prefix", keys == ~["B", "a", "a b", "ab", "b", "t"] &&
+                   mixed.lookup("t").map_or(false, |t| {
+                       t.sorted_entries().iter().map(|&(k, _)| k).collect::<~[&str]>() == ~["Z", "z"]
+                   }) &&
+                   mixed.lookup("b").map_or(false, |b| b.sorted_entries().is_empty())));
+
+    let mut sorted = ~[];
+    mixed.sorted_walk(|path, v| sorted.push(format!("{}={}", path.connect("."), v.to_inline_toml_str())));
+    let mut walked = ~[];
+    mixed.walk(|path, _| walked.push(path.connect(".")));
+    results.push(("sorted_walk visits tables in key order",
+                   sorted == ~[~"B=3", ~"a=4", ~"a b=5", ~"ab=2", ~"b=1", ~"t.Z=6", ~"t.z.0.Y=2", ~"t.z.0.y=1"] &&
+                   walked == ~[~"b", ~"ab", ~"B", ~"a", ~"a b", ~"t.z.0.y", ~"t.z.0.Y", ~"t.Z"]));
+    let mut table = ~toml::TableMap::new();
+    table.insert(~"a", toml::NoValue);
+    table.insert(~"b", toml::Array(~[toml::PosInt(1), toml::NoValue]));
+    results.push(("to_inline_toml_str leaves out missing values",
+                   toml::Table(false, table).to_inline_toml_str() == ~"{ b = [1] }" &&
+                   toml::NoValue.to_inline_toml_str() == ~""));
+
+    let dir = os::tmpdir().join("rust-toml-write-test");
+    let _ = fs::rmdir_recursive(&dir);
+    fs::mkdir(&dir, io::UserRWX).unwrap();
+    let file = dir.join("config.toml");
+    let written = doc.write_to_file(&file);
+    let reread = toml::parse_from_path(&file);
+    let entries = fs::readdir(&dir).unwrap();
+    let _ = fs::rmdir_recursive(&dir);
+    results.push(("write_to_file", written == Ok(toml::AtomicRename) && reread == Ok(doc.clone())));
+    results.push(("write_to_file leaves no temporary file", entries == ~[file.clone()]));
+
+    let floats = [0.1, 1e300, 5e-324, -0.0, 1.0, -1.5, 0.30000000000000004, 1.7976931348623157e308,
+                  2.2250738585072014e-308, 123456789012345680.0, 1e-5, 9.999999999999999e15,
+                  std::f64::INFINITY, std::f64::NEG_INFINITY];
+    for &f in floats.iter() {
+        let doc = toml::TableBuilder::new().insert("f", f).build().unwrap();
+        let emitted = doc.to_toml_str().unwrap();
+        let ok = match toml::parse_from_bytes(emitted.as_bytes()).ok().and_then(|d| d.lookup("f").map(|v| v.clone())) {
+            Some(toml::Float(g)) => {
+                let (a, b): (u64, u64) = unsafe { (std::mem::transmute(f), std::mem::transmute(g)) };
+                a == b
+            }
+            _ => false
+        };
+        results.push(("float keeps type and bits", ok));
+    }
+
+    let mixed = "a = [1, \"x\", [2], { b = 1 }]\nc = [[{ d = 1 }], 2.5]\n";
+    let reread = toml::parse_from_str(mixed).ok().and_then(|v| v.to_toml_str().ok())
+        .and_then(|s| toml::parse_from_str(s.as_slice()).ok());
+    results.push(("arrays of mixed types are written", toml::roundtrip_check(mixed) == Ok(()) &&
+                   reread.is_some() && reread == toml::parse_from_str(mixed).ok()));
+    let keys = "a = [1, { \"x,y\" = 2, \"b}\" = 3, \"{c\" = 4, \"d\\u0001\" = 5 }]\n\"x,y\" = 6\n\
+                [t]\n\"e\\u007F\" = 7\n\"f,g}\" = 8\n";
+    let inline = toml::EmitOptions { inline_tables: 4, .. toml::EmitOptions::new() };
+    let parsed = toml::parse_from_str(keys).ok();
+    let reread = parsed.as_ref().and_then(|v| v.to_toml_str_with(&inline).ok())
+        .and_then(|s| toml::parse_from_str(s.as_slice()).ok());
+    results.push(("keys of inline tables are quoted where bare keys end", toml::roundtrip_check(keys) == Ok(()) &&
+                   parsed.is_some() && reread == parsed));
+    let mut tables = toml::Array(~[]);
+    tables.push(toml::Value::table()).unwrap();
+    let only_tables = toml::TableBuilder::new().insert("a", tables).build().unwrap();
+    results.push(("plain arrays of only tables are rejected",
+                   only_tables.to_toml_str() ==
+                   Err(toml::Unrepresentable { at: ~"a", reason: "plain array holding only tables" })));
+
+    let nan = toml::TableBuilder::new().insert("f", std::f64::NAN).build().unwrap();
+    results.push(("nan is written as nan", nan.to_toml_str().unwrap().as_slice() == "f = nan\n"));
+
+    results.push(("display_str of scalars",
+                   toml::PosInt(8080).display_str() == Some(~"8080") &&
+                   toml::NegInt(42).display_str() == Some(~"-42") && toml::NegInt(0).display_str() == Some(~"0") &&
+                   toml::Boolean(true).display_str() == Some(~"true") &&
+                   toml::Float(3.14).display_str() == Some(~"3.14") && toml::Float(2.0).display_str() == Some(~"2.0") &&
+                   toml::Float(std::f64::NEG_INFINITY).display_str() == Some(~"-inf") &&
+                   toml::Datetime(1979, 5, 27, 7, 32, 0).display_str() == Some(~"1979-05-27T07:32:00Z") &&
+                   toml::String(~"say \"hi\"\n").display_str() == Some(~"say \"hi\"\n")));
+    results.push(("display_str of other values",
+                   toml::Array(~[toml::PosInt(1)]).display_str().is_none() &&
+                   toml::TableArray(~[]).display_str().is_none() &&
+                   toml::TableBuilder::new().insert("a", 1i64).build().unwrap().display_str().is_none() &&
+                   toml::NoValue.display_str().is_none()));
+
+    return results;
+}
+
+// Tests of the conversions to and from JSON
+fn json_tests() -> ~[(&'static str, bool)] {
+    let mut results = ~[];
+
+    let doc = toml::parse_from_bytes(bytes!(
+        "s = \"quote \\\" control \\u0001 \u00FC\"\n",
+        "i = 42\n",
+        "n = -7\n",
+        "big = 18446744073709551615\n",
+        "f = 1.5\n",
+        "b = true\n",
+        "d = 1979-05-27T07:32:00Z\n",
+        "a = [[1, 2], []]\n",
+        "[t]\n",
+        "x = \"y\"\n",
+        "[[ta]]\n",
+        "z = 1\n")).unwrap();
+    let expected = json::from_str("{\"s\": \"quote \\\" control \\u0001 \u00FC\", \
+        \"i\": 42, \"n\": -7, \"big\": 18446744073709551615, \"f\": 1.5, \"b\": true, \
+        \"d\": \"1979-05-27T07:32:00Z\", \"a\": [[1, 2], []], \
+        \"t\": {\"x\": \"y\"}, \"ta\": [{\"z\": 1}]}").unwrap();
+
+    let compact = doc.to_json_str();
+    let pretty = doc.to_pretty_json_str();
+    results.push(("to_json_str", json::from_str(compact.as_slice()).ok() == Some(expected.clone())));
+    results.push(("to_pretty_json_str", json::from_str(pretty.as_slice()).ok() == Some(expected)));
+    results.push(("to_json_str keeps big integers", compact.contains("\"big\":18446744073709551615")));
+
+    // golden outputs of toml-test fixtures
+    let golden = [
+        ("answer = 42\nneganswer = -42\n",
+         "{\"answer\":{\"type\":\"integer\",\"value\":\"42\"},\
+          \"neganswer\":{\"type\":\"integer\",\"value\":\"-42\"}}"),
+        ("pi = 3.14\nnegpi = -3.14\n",
+         "{\"pi\":{\"type\":\"float\",\"value\":\"3.14\"},\
+          \"negpi\":{\"type\":\"float\",\"value\":\"-3.14\"}}"),
+        ("ints = [1, 2, 3]\nstrings = [\"a\", \"b\"]\n",
+         "{\"ints\":{\"type\":\"array\",\"value\":[{\"type\":\"integer\",\"value\":\"1\"},\
+          {\"type\":\"integer\",\"value\":\"2\"},{\"type\":\"integer\",\"value\":\"3\"}]},\
+          \"strings\":{\"type\":\"array\",\"value\":[{\"type\":\"string\",\"value\":\"a\"},\
+          {\"type\":\"string\",\"value\":\"b\"}]}}"),
+        ("[[people]]\nfirst_name = \"Bruce\"\n\n[[people]]\nfirst_name = \"Eric\"\n",
+         "{\"people\":[{\"first_name\":{\"type\":\"string\",\"value\":\"Bruce\"}},\
+          {\"first_name\":{\"type\":\"string\",\"value\":\"Eric\"}}]}"),
+        ("bestdayever = 1987-07-05T17:45:00Z\n",
+         "{\"bestdayever\":{\"type\":\"datetime\",\"value\":\"1987-07-05T17:45:00Z\"}}")
+    ];
+    let mut all_match = true;
+    for &(input, expected) in golden.iter() {
+        match toml::parse_from_bytes(input.as_bytes()) {
+            Ok(doc) => { all_match = all_match && doc.to_tagged_json().as_slice() == expected }
+            Err(_) => { all_match = false }
+        }
+    }
+    results.push(("to_tagged_json golden output", all_match));
+
+    let input = "{\"title\": \"x\\u00e9\\ud83d\\ude00\", \"i\": -12, \"f\": 1.0, \"e\": 2e3, \
+        \"big\": 18446744073709551615, \"when\": \"1979-05-27T07:32:00Z\", \"ok\": false, \
+        \"a\": [1, 2, 3], \"t\": {\"u\": {\"v\": \"w\"}}, \"ta\": [{\"k\": 1}, {\"k\": 2}]}";
+    let from_json = toml::from_json_str(input).ok();
+    let reparsed = from_json.as_ref()
+        .and_then(|v| v.to_toml_str().ok())
+        .and_then(|s| toml::parse_from_bytes(s.as_bytes()).ok());
+    results.push(("from_json_str survives TOML round trip",
+                   from_json.is_some() && reparsed == from_json));
+    results.push(("from_json_str number forms", from_json.as_ref().map_or(false, |v| {
+        v.lookup("i") == Some(&toml::NegInt(12)) && v.lookup("f") == Some(&toml::Float(1.0)) &&
+        v.lookup("e") == Some(&toml::Float(2000.0)) && v.lookup("big") == Some(&toml::PosInt(18446744073709551615))
+    })));
+    results.push(("from_json_str keeps datetime strings by default", from_json.as_ref().map_or(false, |v| {
+        v.lookup("when") == Some(&toml::String(~"1979-05-27T07:32:00Z"))
+    })));
+    let opts = toml::JsonOptions { datetimes: true, .. toml::JsonOptions::new() };
+    results.push(("from_json_str_with datetimes", toml::from_json_str_with(input, &opts).ok().map_or(false, |v| {
+        v.lookup("when") == Some(&toml::Datetime(1979, 5, 27, 7, 32, 0))
+    })));
+
+    let nulls = "{\"a\": null, \"b\": 1}";
+    results.push(("from_json_str skips null members", toml::from_json_str(nulls).ok().map_or(false, |v| {
+        v.lookup("a").is_none() && v.lookup("b") == Some(&toml::PosInt(1))
+    })));
+    results.push(("from_json_str rejects null in arrays", toml::from_json_str("{\"a\": [null]}").is_err()));
+    let opts = toml::JsonOptions { nulls: toml::KeepNull, .. toml::JsonOptions::new() };
+    results.push(("from_json_str_with keeps nulls", toml::from_json_str_with(nulls, &opts).ok().map_or(false, |v| {
+        v.lookup("a") == Some(&toml::NoValue)
+    })));
+    results.push(("from_json_str rejects non-object root", toml::from_json_str("[1]").is_err()));
+    results.push(("from_json_str rejects unpaired surrogates",
+                   toml::from_json_str("{\"a\": \"\\ud83d\\u0041\"}") == Err(~"unpaired surrogate in line 1") &&
+                   toml::from_json_str("{\"a\": \"\\ud83d\\ud83d\"}") == Err(~"unpaired surrogate in line 1") &&
+                   toml::from_json_str("{\"a\": \"\\ude00\"}") == Err(~"unpaired surrogate in line 1")));
+    results.push(("from_json_str rejects leading zeros",
+                   toml::from_json_str("{\"a\": 012}") == Err(~"leading zeros are not allowed in line 1") &&
+                   toml::from_json_str("{\"a\": -00.5}").is_err() &&
+                   toml::from_json_str("{\"a\": 0, \"b\": -0.5, \"c\": 1e05}").is_ok()));
+    let opts = toml::JsonOptions { nulls: toml::RejectNull, .. toml::JsonOptions::new() };
+    results.push(("from_json_str_with rejects nulls",
+                   toml::from_json_str_with(nulls, &opts) == Err(~"null has no TOML representation in line 1")));
+    let opts = toml::JsonOptions { nulls: toml::NullAsEmptyString, .. toml::JsonOptions::new() };
+    results.push(("from_json_str_with makes nulls empty strings",
+                   toml::from_json_str_with(nulls, &opts).ok().map_or(false, |v| {
+        v.lookup("a") == Some(&toml::String(~""))
+    })));
+
+    let config = "{\n  \"name\": \"web\",\n  \"debug\": false,\n  \"tags\": [\"x\", \"y\"],\n  \
+                  \"server\": {\"host\": \"0.0.0.0\", \"port\": 8080, \"tls\": {\"cert\": \"/etc/cert.pem\"}},\n  \
+                  \"upstreams\": [{\"name\": \"a\", \"weight\": 1}, {\"name\": \"b\", \"weight\": 2.5}]\n}\n";
+    let expected = "name = \"web\"\ndebug = false\ntags = [\"x\", \"y\"]\n\n\
+                    [server]\nhost = \"0.0.0.0\"\nport = 8080\n\n[server.tls]\ncert = \"/etc/cert.pem\"\n\n\
+                    [[upstreams]]\nname = \"a\"\nweight = 1\n\n[[upstreams]]\nname = \"b\"\nweight = 2.5\n";
+    let converted = toml::json_to_toml_str(config).ok().and_then(|s| toml::parse_from_str(s.as_slice()).ok());
+    results.push(("json_to_toml_str", converted.is_some() && converted == toml::parse_from_str(expected).ok()));
+    results.push(("json_to_toml_str rejects a non-object root",
+                   toml::json_to_toml_str("[1, 2]") == Err(~"JSON root must be an object")));
+    results.push(("json_to_toml_str rejects nulls",
+                   toml::json_to_toml_str("{\"a\": 1,\n\"b\": null}") ==
+                   Err(~"null has no TOML representation in line 2")));
+    let mixed = toml::json_to_toml_str("{\"a\": [{\"b\": 1}, 2]}").ok();
+    results.push(("json_to_toml_str converts mixed arrays",
+                   mixed.and_then(|s| toml::parse_from_str(s.as_slice()).ok())
+                        .map_or(false, |v| v.lookup_int("a[0].b") == Some(1) && v.lookup_int("a[1]") == Some(2))));
+
+    return results;
+}
+
+#[deriving(Clone,Encodable,Decodable,Eq,Show)]
+struct Limits {
+    max_connections: u32,
+    timeout: f64
+}
+
+#[deriving(Clone,Encodable,Decodable,Eq,Show)]
+struct Backend {
+    host: ~str,
+    weight: i64
+}
+
+#[deriving(Clone,Encodable,Decodable,Eq,Show)]
+struct ServerConfig {
+    name: ~str,
+    port: u16,
+    debug: bool,
+    tags: ~[~str],
+    owner: Option<~str>,
+    limits: Limits,
+    backends: ~[Backend]
+}
+
+fn encoder_tests() -> ~[(&'static str, bool)] {
+    let mut results = ~[];
+
+    let config = ServerConfig {
+        name: ~"frontend",
+        port: 8080,
+        debug: false,
+        tags: ~[~"web", ~"eu-west"],
+        owner: None,
+        limits: Limits { max_connections: 512, timeout: 2.5 },
+        backends: ~[Backend { host: ~"10.0.0.1", weight: -1 }, Backend { host: ~"10.0.0.2", weight: 3 }]
+    };
+    let decoded = toml::encode_str(&config).ok()
+        .and_then(|s| toml::parse_from_bytes(s.as_bytes()).ok())
+        .map(|v| { let c: ServerConfig = toml::from_toml(v); c });
+    results.push(("encode round trip", decoded == Some(config.clone())));
+
+    let value = toml::encode(&config).ok();
+    results.push(("encode omits None fields", value.as_ref().map_or(false, |v| v.lookup("owner").is_none())));
+    results.push(("encode struct vectors as table arrays", value.as_ref().map_or(false, |v| {
+        match v.lookup("backends") { Some(&toml::TableArray(ref a)) => a.len() == 2, _ => false }
+    })));
+
+    let mut bad_keys = HashMap::new();
+    bad_keys.insert(1u, ~"one");
+    results.push(("encode rejects non-string map keys", toml::encode(&bad_keys).is_err()));
+    results.push(("encode rejects tuple root", toml::encode(&(1, 2)).is_err()));
+
+    let empty = toml::parse_from_bytes(bytes!("")).unwrap();
+    results.push(("decode_with_defaults of an empty document",
+                   toml::decode_with_defaults(empty, &config) == Ok(config.clone())));
+    let partial = toml::parse_from_bytes(bytes!("[limits]\ntimeout = 10.0\n")).unwrap();
+    let expected = ServerConfig { limits: Limits { timeout: 10.0, .. config.limits.clone() }, .. config.clone() };
+    results.push(("decode_with_defaults overrides one nested field",
+                   toml::decode_with_defaults(partial, &config) == Ok(expected)));
+
+    return results;
+}
+
+#[deriving(Decodable,Eq,Show)]
+struct Owner {
+    name: ~str,
+    age: u8,
+    nickname: Option<~str>
+}
+
+#[deriving(Decodable,Eq,Show)]
+struct Database {
+    server: ~str,
+    ports: ~[u16],
+    enabled: bool
+}
+
+#[deriving(Decodable,Eq,Show)]
+struct Server {
+    name: ~str,
+    weight: f64
+}
+
+#[deriving(Decodable,Eq,Show)]
+struct Document {
+    title: ~str,
+    owner: Owner,
+    database: Database,
+    servers: ~[Server]
+}
+
+static DECODE_FIXTURE: &'static str = "title = \"example\"
+
+[owner]
+name = \"Tom\"
+age = 42
+
+[database]
+server = \"192.168.1.1\"
+ports = [8001, 8002]
+enabled = true
+
+[[servers]]
+name = \"alpha\"
+weight = 1.5
+
+[[servers]]
+name = \"beta\"
+weight = 2.0
+";
+
+fn decode_str(s: &str) -> Result<Document, ~str> {
+    match toml::parse_from_bytes(s.as_bytes()) {
+        Ok(value) => toml::decode(value).map_err(|e| format!("{}", e)),
+        Err(e) => Err(format!("parse error: {}", e))
+    }
+}
+
+fn decoder_tests() -> ~[(&'static str, bool)] {
+    let mut results = ~[];
+
+    let expected = Document {
+        title: ~"example",
+        owner: Owner { name: ~"Tom", age: 42, nickname: None },
+        database: Database { server: ~"192.168.1.1", ports: ~[8001, 8002], enabled: true },
+        servers: ~[Server { name: ~"alpha", weight: 1.5 }, Server { name: ~"beta", weight: 2.0 }]
+    };
+    results.push(("decode nested structs", decode_str(DECODE_FIXTURE) == Ok(expected)));
+
+    let inline = DECODE_FIXTURE.replace("[[servers]]\nname = \"alpha\"\nweight = 1.5\n\n\
+                                         [[servers]]\nname = \"beta\"\nweight = 2.0\n",
+                                        "")
+        .replace("title = \"example\"\n",
+                 "title = \"example\"\nservers = [{name = \"a\", weight = 1.0}]\n");
+    results.push(("decode array of inline tables", decode_str(inline.as_slice()).map(|d| d.servers.len()) == Ok(1)));
+
+    let corrupted = [
+        ("title = \"example\"\n", "", "missing field `title` at the top level"),
+        ("age = 42\n", "", "missing field `age` in table `owner`"),
+        ("age = 42", "age = \"42\"", "`owner.age`: expected integer, found string"),
+        ("age = 42", "age = 300", "`owner.age`: integer out of range for u8"),
+        ("age = 42", "age = -1", "`owner.age`: integer out of range for u8"),
+        ("8002]", "80000]", "`database.ports[1]`: integer out of range for u16"),
+        ("enabled = true", "enabled = 1", "`database.enabled`: expected boolean, found integer"),
+        ("weight = 1.5", "weight = \"heavy\"", "`servers[0].weight`: expected float, found string"),
+        ("[owner]", "owner = 1\n[other]", "`owner`: expected table, found integer")
+    ];
+    let mut all_match = true;
+    for &(from, to, message) in corrupted.iter() {
+        let input = DECODE_FIXTURE.replace(from, to);
+        match decode_str(input.as_slice()) {
+            Err(e) => {
+                if e.as_slice() != message {
+                    println!("   decode error: expected `{}`, got `{}`", message, e);
+                    all_match = false;
+                }
+            }
+            Ok(_) => { all_match = false }
+        }
+    }
+    results.push(("decode error messages", all_match));
+
+    let typo = DECODE_FIXTURE.replace("age = 42\n", "age = 42\nnicknmae = \"T\"\n");
+    let strict = toml::DecodeOptions { deny_unknown_keys: true };
+    let value = toml::parse_from_bytes(typo.as_bytes()).unwrap();
+    results.push(("decode ignores unknown keys by default",
+                   toml::decode::<Document>(value.clone()).is_ok()));
+    results.push(("decode_with rejects unknown keys",
+                   toml::decode_with::<Document>(value, &strict).map_err(|e| format!("{}", e)) ==
+                   Err(~"unknown keys in table `owner`: `nicknmae` (did you mean `nickname`?)")));
+    results.push(("decode_with accepts known keys",
+                   toml::decode_with::<Document>(toml::parse_from_bytes(DECODE_FIXTURE.as_bytes()).unwrap(),
+                                                 &strict).is_ok()));
+
+    let limits = Limits { max_connections: 10, timeout: 1.5 };
+    let decode_limits = |input: &str, opts: &toml::DecodeOptions| {
+        toml::parse_from_bytes(input.as_bytes()).ok().and_then(|v| toml::decode_with::<Limits>(v, opts).ok())
+    };
+    let kebab = toml::DecodeOptions { kebab_case: true, .. toml::DecodeOptions::new() };
+    results.push(("decode_with kebab-case keys",
+                   decode_limits("max-connections = 10\ntimeout = 1.5\n", &kebab) == Some(limits.clone())));
+    let ignore_case = toml::DecodeOptions { ignore_case: true, .. toml::DecodeOptions::new() };
+    results.push(("decode_with mixed-case keys",
+                   decode_limits("Max_Connections = 10\nTIMEOUT = 1.5\n", &ignore_case) == Some(limits.clone())));
+    let renames = toml::DecodeOptions { renames: ~[(~"conns", ~"max_connections")], .. toml::DecodeOptions::new() };
+    results.push(("decode_with renamed keys",
+                   decode_limits("conns = 10\ntimeout = 1.5\n", &renames) == Some(limits.clone())));
+    let ambiguous = toml::parse_from_bytes(bytes!("max-connections = 1\nmax_connections = 2\ntimeout = 1.5\n"));
+    results.push(("decode_with rejects ambiguous keys",
+                   toml::decode_with::<Limits>(ambiguous.unwrap(), &kebab).map_err(|e| format!("{}", e)) ==
+                   Err(~"keys `max-connections`, `max_connections` at the top level all match field `max_connections`")));
+
+    return results;
+}
+
+static COMMENTED_FIXTURE: &'static str = "# Configuration of the example service
+# (edit with care)
+
+title = \"example\" # shown in the UI
+debug = false
+
+# where to listen
+[server]
+host = \"localhost\"
+# the port may be changed
+port = 8080 # default
+
+  # first backend
+[[backends]] # primary
+host = \"a\"
+
+[[backends]]
+# second backend
+host = \"b\" # standby
+
+# end of file
+";
+
+fn document_tests(path: &Path) -> ~[(&'static str, bool)] {
+    let mut results = ~[];
+
+    let mut identical = true;
+    for file in fs::readdir(&path.join("valid")).unwrap().iter() {
+        if file.extension_str() != Some("toml") { continue }
+        let text = File::open(file).read_to_str().unwrap();
+        match toml::parse_document(text) {
+            Ok(doc) => { identical = identical && doc.to_str() == text }
+            Err(_) => {} // reported by the fixture tests
+        }
+    }
+    results.push(("Document writes unmodified fixtures byte for byte", identical));
+
+    let doc = toml::parse_document(COMMENTED_FIXTURE);
+    results.push(("parse_document", doc.as_ref().ok().map_or(false, |d| {
+        Some(d.value()) == toml::parse_from_str(COMMENTED_FIXTURE).ok().as_ref()
+    })));
+    let mut doc = doc.unwrap();
+    results.push(("leading comments of a pair",
+                   doc.leading_comments("title") == ~[" Configuration of the example service", " (edit with care)"] &&
+                   doc.leading_comments("server.port") == ~[" the port may be changed"]));
+    results.push(("trailing comment of a pair",
+                   doc.trailing_comment("title") == Some(" shown in the UI") &&
+                   doc.trailing_comment("debug").is_none()));
+    results.push(("comments of a table array element",
+                   doc.leading_comments("backends[0]") == ~[" first backend"] &&
+                   doc.trailing_comment("backends[0]") == Some(" primary") &&
+                   doc.trailing_comment("backends.1.host") == Some(" standby")));
+
+    results.push(("Document::set replaces a value",
+                   doc.set("server.port", 9090i64) == Ok(Some(toml::PosInt(8080))) &&
+                   doc.lookup("server.port") == Some(&toml::PosInt(9090))));
+    let expected = COMMENTED_FIXTURE.replace("port = 8080", "port = 9090");
+    results.push(("Document keeps comments in place", doc.to_str() == expected));
+    let changed: ~[(&str, &str)] = COMMENTED_FIXTURE.lines().zip(doc.to_str().lines())
+        .filter(|&(a, b)| a != b).collect();
+    results.push(("Document::set changes a single line", changed == ~[("port = 8080 # default", "port = 9090 # default")]));
+
+    results.push(("Document::set adds a key to its section",
+                   doc.set("backends[0].weight", 2i64) == Ok(None) &&
+                   doc.to_str().contains("host = \"a\"\nweight = 2\n\n[[backends]]")));
+    let mut doc = toml::parse_document("a = [1, 2] # list\nb = 1").unwrap();
+    results.push(("Document::set rewrites only the value",
+                   doc.set("a[1]", 3i64).is_ok() && doc.set("c", true).is_ok() &&
+                   doc.to_str() == ~"a = [1, 3] # list\nb = 1\nc = true\n"));
+    results.push(("Document::set rejects missing values",
+                   doc.set("d", toml::NoValue) == Err(toml::WrongType { at: ~"d", expected: "value", found: "none" }) &&
+                   doc.set("a", toml::Array(~[toml::NoValue])).is_err() &&
+                   doc.to_str() == ~"a = [1, 3] # list\nb = 1\nc = true\n"));
+    results.push(("Document::set rejects sections",
+                   doc.set("server", 1i64).is_err() && doc.lookup_str("server.host") == Some("localhost")));
+
+    return results;
+}
+
+// Runs `inputs` through `toml::fuzz_one` in a task of its own, returning
+// whether none of them failed the task
+fn survives(inputs: ~[~[u8]]) -> bool {
+    task::try(proc() {
+        for data in inputs.iter() { toml::fuzz_one(data.as_slice()) }
+    }).is_ok()
+}
+
+// Tests that no input makes the parsers fail: inputs which once did, and
+// random mutations of the fixtures
+fn fuzz_tests(path: &Path) -> ~[(&'static str, bool)] {
+    let mut results = ~[];
+
+    let deep = |open: &str, n: uint| format!("a = {}", open.repeat(n));
+    let mut corpus: ~[~[u8]] = ~[
+        deep("[", 100000), deep("{ b = ", 100000), deep("[{ b = ", 50000),
+        ~"[]", ~"[[]]", ~"[a.]", ~"[.a]", ~"[a..b]", ~"[[a.]]", ~"[\"\"]",
+        ~"a = [{ b = 1 }]\n[a.c]\n", ~"a = [{ b = 1 }]\n[[a]]\n", ~"a = []\n[[a]]\n", ~"a = []\n[a.b]\n",
+        ~"a = 1\n[a.b]\n", ~"[a]\n[[a]]\n", ~"[[a]]\n[a]\n", ~"[[a]]\n[a.b]\n[[a]]\n[a.b]\n",
+        ~"a = \"\\u12", ~"a = \"\\U0011FFFF\"", ~"a = \"abc", ~"a = '''", ~"a = \"\"\"x", ~"a = \"\\",
+        ~"a = 1979-", ~"a = 1979-05-27T07:32:0", ~"a = 9999-99-99T99:99:99Z", ~"a = 0000-00-00T00:00:00Z",
+        ~"a = 18446744073709551616", ~"a = -18446744073709551616", ~"a = 1e99999", ~"a = -", ~"a = +inf",
+        ~"\r", ~"\r\n", ~"a\r= 1", ~"\x00", ~"a = \"\x00\"", ~"=", ~"= 1", ~"a = [1,", ~"a = {", ~"a = { b"
+    ].move_iter().map(|s| s.into_bytes()).collect();
+    corpus.push_all_move(~[~[0xff], ~[0xc3], ~[0x61, 0x20, 0x3d, 0x20, 0x22, 0xe2, 0x82, 0x22],
+                           ~[0x5b, 0xf0, 0x9f, 0x98, 0x5d], ~[0xed, 0xa0, 0x80]]);
+    results.push(("inputs which once failed the parser", survives(corpus)));
+
+    let nested = |n: uint| format!("a = {}{}", "[".repeat(n), "]".repeat(n));
+    results.push(("arrays may nest 128 deep", toml::parse_from_str(nested(128).as_slice()).is_ok() &&
+                   toml::parse_borrowed(nested(128).as_slice()).is_ok()));
+    let too_deep = nested(129);
+    results.push(("arrays may not nest 129 deep", toml::parse_from_str(too_deep.as_slice()).is_err() &&
+                   toml::parse_borrowed(too_deep.as_slice()).is_err() &&
+                   toml::parse_located(&mut BufReader::new(too_deep.as_bytes())).err().map_or(false, |d| {
+                       d.to_str() == ~"1:133: arrays and inline tables nested deeper than 128"
+                   })));
+
+    // every fixture with a few random changes, the same ones on every run
+    let mut rng: XorShiftRng = SeedableRng::from_seed([0x746f6d6cu32, 1, 2, 3]);
+    let special = "[]{}=\"'\\.,#\n- ".as_bytes();
+    let mut mutated = ~[];
+    for dir in ["valid", "invalid"].iter() {
+        for filename in walk_dir(&path.join(*dir)).unwrap() {
+            if !filename.is_file() || filename.extension_str() != Some("toml") { continue }
+            let original = File::open(&filename).read_to_end().unwrap();
+            for _ in range(0, 50) {
+                let mut data = original.clone();
+                for _ in range(0, rng.gen_range(1u, 5)) {
+                    let at = rng.gen_range(0u, data.len() + 1);
+                    match rng.gen_range(0u, 4) {
+                        0 if at < data.len() => { data[at] = rng.gen::<u8>() }
+                        1 if at < data.len() => { data.remove(at); }
+                        2 => { data.insert(at, special[rng.gen_range(0u, special.len())]) }
+                        _ => {
+                            let end = rng.gen_range(at, data.len() + 1);
+                            let copy = data.slice(at, end).to_owned();
+                            data.push_all(copy);
+                        }
+                    }
+                }
+                mutated.push(data);
+            }
+        }
+    }
+    results.push(("random mutations of the fixtures", survives(mutated)));
+
+    return results;
+}
+
+// Runs `command` through the shell, returning its exit status, output
+// and error output
+fn run_cli(command: &str) -> (int, ~str, ~str) {
+    let out = io::Process::output("sh", [~"-c", command.to_owned()]).unwrap();
+    let status = match out.status {
+        io::process::ExitStatus(status) => status,
+        io::process::ExitSignal(signal) => -signal
+    };
+    (status, std::str::from_utf8_owned(out.output).unwrap(), std::str::from_utf8_owned(out.error).unwrap())
+}
+
+// Tests of the command line tool, bin/toml
+fn cli_tests(path: &Path) -> ~[(&'static str, bool)] {
+    let mut results = ~[];
+
+    let (status, out, err) = run_cli("printf 'a = 1\\nb = [1, 2 3]\\n' | ./bin/toml");
+    results.push(("cli reports invalid input on stderr",
+                   status == 1 && out.is_empty() &&
+                   err == ~"<stdin>:2:11: expected ',' or ']' after array element\nb = [1, 2 3]\n          ^\n"));
+    let (status, out, err) = run_cli("printf '[t]\\na = 1\\na = 2\\n' | ./bin/toml -");
+    results.push(("cli reports rejected input",
+                   status == 1 && out.is_empty() && err == ~"<stdin>:3:1: duplicate key `t.a`\na = 2\n^\n"));
+    let (status, out, err) = run_cli("printf 'a = 1\\n' | ./bin/toml");
+    results.push(("cli reads stdin", status == 0 && out.contains("PosInt(1)") && err.is_empty()));
+    let example = path.join("valid").join("example.toml");
+    let (status, _, err) = run_cli(format!("./bin/toml {}", example.display()));
+    results.push(("cli reads a file", status == 0 && err.is_empty()));
+    let (status, out, _) = run_cli(format!("./bin/toml --json {}", example.display()));
+    let expected = toml::parse_from_path(&example).ok().and_then(|v| json::from_str(v.to_json_str().as_slice()).ok());
+    results.push(("cli --json", status == 0 && expected.is_some() && json::from_str(out.as_slice()).ok() == expected));
+    let (status, out, _) = run_cli(format!("./bin/toml --json --pretty {}", example.display()));
+    results.push(("cli --json --pretty", status == 0 && out.lines().count() > 3 && json::from_str(out.as_slice()).ok() == expected));
+    let (status, out, _) = run_cli("printf 'when = 1979-05-27T07:32:00Z\\n' | ./bin/toml --json");
+    results.push(("cli --json writes datetimes as RFC 3339",
+                   status == 0 && out == ~"{\"when\":\"1979-05-27T07:32:00Z\"}\n"));
+    let hosts = "printf '[server]\\nhosts = [\"alpha\", \"beta gamma\"]\\nport = 80\\n'";
+    let (status, out, _) = run_cli(format!("{} | ./bin/toml --get server.port", hosts));
+    results.push(("cli --get scalar", status == 0 && out == ~"80\n"));
+    let (status, out, _) = run_cli(format!("{} | ./bin/toml --get server.hosts.1", hosts));
+    results.push(("cli --get quotes strings", status == 0 && out == ~"\"beta gamma\"\n"));
+    let (status, out, _) = run_cli(format!("{} | ./bin/toml --get 'server.hosts[1]' --raw", hosts));
+    results.push(("cli --get --raw", status == 0 && out == ~"beta gamma\n"));
+    let (status, out, _) = run_cli(format!("{} | ./bin/toml --get server.hosts", hosts));
+    results.push(("cli --get array", status == 0 && out == ~"[\"alpha\", \"beta gamma\"]\n"));
+    let (status, out, _) = run_cli(format!("{} | ./bin/toml --get server --json", hosts));
+    results.push(("cli --get --json",
+                   status == 0 && out == ~"{\"hosts\":[\"alpha\",\"beta gamma\"],\"port\":80}\n"));
+    let (status, out, _) = run_cli(format!("{} | ./bin/toml --get server", hosts));
+    results.push(("cli --get table", status == 0 && out == ~"hosts = [\"alpha\", \"beta gamma\"]\nport = 80\n"));
+    let (status, out, err) = run_cli(format!("{} | ./bin/toml --get server.user", hosts));
+    results.push(("cli --get missing path",
+                   status == 3 && out.is_empty() && err == ~"server.user: key `server.user` not found\n"));
+    let (status, _, err) = run_cli("./bin/toml --bogus");
+    results.push(("cli rejects unknown options", status == 2 && !err.is_empty()));
+    let dir = os::tmpdir().join("rust-toml-cli-check-test");
+    let _ = fs::rmdir_recursive(&dir);
+    fs::mkdir(&dir, io::UserRWX).unwrap();
+    let (good, bad) = (dir.join("good.toml"), dir.join("bad.toml"));
+    File::create(&good).write_str("a = 1\n").unwrap();
+    File::create(&bad).write_str("a = 1\nb = [1, 2 3]\n").unwrap();
+    let (status, out, err) = run_cli(format!("./bin/toml --check {} {}", good.display(), example.display()));
+    results.push(("cli --check valid files", status == 0 && out.is_empty() && err.is_empty()));
+    let (status, out, err) = run_cli(format!("./bin/toml --check {} {} {}", bad.display(), good.display(), bad.display()));
+    results.push(("cli --check reports each invalid file",
+                   status == 1 && out.is_empty() &&
+                   err == format!("{0}:2:11: expected ',' or ']' after array element\nb = [1, 2 3]\n          ^\n\
+                                   {0}:2:11: expected ',' or ']' after array element\nb = [1, 2 3]\n          ^\n",
+                                  bad.display())));
+    let (status, _, err) = run_cli(format!("./bin/toml --check {} /nonexistent/file.toml {}", bad.display(), good.display()));
+    results.push(("cli --check exits with the worst status", status == 2 && err.lines().count() == 4));
+    let nested = dir.join("nested.toml");
+    File::create(&nested).write_str("title = \"x y\"\n[server]\nhost = \"a\"\nports = [80, 443]\n\
+                                      [[server.backend]]\nname = \"b1\"\n[[server.backend]]\nname = \"b2\"\n\
+                                      weight = 2\n[empty]\n").unwrap();
+    let (status, out, _) = run_cli(format!("./bin/toml --flat {}", nested.display()));
+    results.push(("cli --flat", status == 0 && out == ~"title = \"x y\"\nserver.host = \"a\"\n\
+                                                        server.ports.0 = 80\nserver.ports.1 = 443\n\
+                                                        server.backend.0.name = \"b1\"\n\
+                                                        server.backend.1.name = \"b2\"\n\
+                                                        server.backend.1.weight = 2\nempty = {}\n"));
+    let (status, out, _) = run_cli(format!("./bin/toml --flat --prefix server.backend {}", nested.display()));
+    results.push(("cli --flat --prefix", status == 0 && out == ~"server.backend.0.name = \"b1\"\n\
+                                                                 server.backend.1.name = \"b2\"\n\
+                                                                 server.backend.1.weight = 2\n"));
+    let (status, out, _) = run_cli(format!("./bin/toml --flat --prefix server.host {}", nested.display()));
+    results.push(("cli --flat --prefix of a leaf", status == 0 && out == ~"server.host = \"a\"\n"));
+    let (status, out, _) = run_cli(format!("./bin/toml --flat --prefix client {}", nested.display()));
+    results.push(("cli --flat --prefix missing", status == 3 && out.is_empty()));
+    let json = dir.join("config.json");
+    File::create(&json).write_str("{\"name\": \"web\", \"server\": {\"port\": 8080, \"proxy\": null}}").unwrap();
+    let (status, out, _) = run_cli(format!("./bin/toml --from-json --null=skip {}", json.display()));
+    results.push(("cli --from-json", status == 0 && out == ~"name = \"web\"\n\n[server]\nport = 8080\n"));
+    let (status, out, _) = run_cli(format!("./bin/toml --from-json --null=empty-string {}", json.display()));
+    results.push(("cli --from-json --null=empty-string",
+                   status == 0 && out == ~"name = \"web\"\n\n[server]\nport = 8080\nproxy = \"\"\n"));
+    let (status, out, err) = run_cli(format!("./bin/toml --from-json {}", json.display()));
+    results.push(("cli --from-json rejects nulls",
+                   status == 1 && out.is_empty() &&
+                   err == format!("{}: null has no TOML representation in line 1\n", json.display())));
+    let (status, _, err) = run_cli("echo '[1]' | ./bin/toml --from-json");
+    results.push(("cli --from-json rejects a non-object root",
+                   status == 1 && err == ~"<stdin>: JSON root must be an object\n"));
+    let (status, _, _) = run_cli(format!("./bin/toml --from-json --null=zero {}", json.display()));
+    results.push(("cli --from-json rejects unknown null policies", status == 2));
+    let (base, prod, local) = (dir.join("base.toml"), dir.join("prod.toml"), dir.join("local.toml"));
+    File::create(&base).write_str("name = \"app\"\nhosts = [\"a\"]\n[db]\nhost = \"localhost\"\nport = 5432\n\
+                                    [db.pool]\nsize = 5\n").unwrap();
+    File::create(&prod).write_str("hosts = [\"b\"]\n[db]\nhost = \"db.prod\"\n").unwrap();
+    File::create(&local).write_str("[db.pool]\nsize = 10\n").unwrap();
+    let layers = format!("{} {} {}", base.display(), prod.display(), local.display());
+    let merged = |args: &str, expected: &str| {
+        let (status, out, err) = run_cli(format!("./bin/toml --merge {} {}", args, layers));
+        let out = toml::parse_from_str(out.as_slice()).ok();
+        status == 0 && err.is_empty() && out.is_some() && out == toml::parse_from_str(expected).ok()
+    };
+    results.push(("cli --merge", merged("", "name = \"app\"\nhosts = [\"b\"]\n\
+                                             [db]\nhost = \"db.prod\"\nport = 5432\n[db.pool]\nsize = 10\n")));
+    results.push(("cli --merge --array=append",
+                   merged("--array=append", "name = \"app\"\nhosts = [\"a\", \"b\"]\n\
+                                             [db]\nhost = \"db.prod\"\nport = 5432\n[db.pool]\nsize = 10\n")));
+    let (status, out, _) = run_cli(format!("./bin/toml --merge --json {} {}", base.display(), local.display()));
+    results.push(("cli --merge --json", status == 0 && json::from_str(out.as_slice()).ok() ==
+                   json::from_str("{\"name\": \"app\", \"hosts\": [\"a\"], \
+                                   \"db\": {\"host\": \"localhost\", \"port\": 5432, \"pool\": {\"size\": 10}}}").ok()));
+    let (status, out, err) = run_cli(format!("./bin/toml --merge {} {} {}", base.display(), bad.display(), local.display()));
+    results.push(("cli --merge names the invalid file",
+                   status == 1 && out.is_empty() && err.starts_with(format!("{}:2:11: ", bad.display()))));
+    let (status, _, _) = run_cli(format!("./bin/toml --merge --array=zip {}", layers));
+    results.push(("cli --merge rejects unknown array strategies", status == 2));
+    let (status, _, _) = run_cli(format!("./bin/toml {} {}", good.display(), good.display()));
+    results.push(("cli takes several files only with --check", status == 2));
+    let _ = fs::rmdir_recursive(&dir);
+
+    // a few toml-test fixtures, run as the toml-test suite would
+    let valid = ["bool", "datetime", "float", "integer", "string-escapes", "arrays-nested",
+                 "table-array-nest", "key-special-chars"];
+    let mut valid_ok = true;
+    for name in valid.iter() {
+        let file = path.join("valid").join(format!("{}.toml", *name));
+        let (status, out, err) = run_cli(format!("./bin/toml --toml-test < {}", file.display()));
+        let expected = File::open(&file.with_extension("json")).read_to_str().ok()
+            .and_then(|s| json::from_str(s.as_slice()).ok());
+        valid_ok = valid_ok && status == 0 && err.is_empty() && expected.is_some() &&
+                   json::from_str(out.as_slice()).ok() == expected;
+    }
+    results.push(("cli --toml-test valid fixtures", valid_ok));
+    let invalid = ["duplicate-keys", "string-no-close", "text-after-table", "integer-overflow",
+                   "array-mixed-types-ints-and-floats", "inline-table-no-close"];
+    let mut invalid_ok = true;
+    for name in invalid.iter() {
+        let file = path.join("invalid").join(format!("{}.toml", *name));
+        let (status, out, _) = run_cli(format!("./bin/toml --toml-test < {}", file.display()));
+        invalid_ok = invalid_ok && status == 1 && out.is_empty();
+    }
+    results.push(("cli --toml-test invalid fixtures", invalid_ok));
+    let (status, out, _) = run_cli("printf 'a = \"\\377\\000\"\\n' | ./bin/toml --toml-test");
+    results.push(("cli --toml-test with invalid UTF-8", status == 1 && out.is_empty()));
+
+    let (status, out, err) = run_cli("./bin/toml /nonexistent/file.toml");
+    results.push(("cli exits with 2 for I/O errors",
+                   status == 2 && out.is_empty() && err.starts_with("/nonexistent/file.toml: ")));
+
+    return results;
+}
+
+// The options the fixtures are parsed with, as toml-test checks TOML 0.4
+fn fixture_options() -> toml::ParserOptions {
+    toml::ParserOptions { version: toml::V0_4, .. toml::ParserOptions::new() }
+}
+
 fn toml_test_runner() {
-    let toml = toml::parse_from_bytes(std::io::stdin().read_to_end().unwrap()).unwrap();
-    let json = to_json(&toml);
-    println!("{:s}", json.to_pretty_str());
+    let bytes = std::io::stdin().read_to_end().unwrap();
+    let toml = toml::parse_with_options(&mut BufReader::new(bytes), &fixture_options()).unwrap().value;
+    println!("{:s}", toml.to_tagged_json());
+}
+
+fn parse_fixture(path: &Path) -> Result<toml::Value, toml::Error> {
+    toml::parse_with_options(&mut BufferedReader::new(File::open(path)), &fixture_options()).map(|doc| doc.value)
 }
 
 fn independent_test_runner(path: ~str) {
@@ -83,7 +2328,7 @@ fn independent_test_runner(path: ~str) {
       println!("TEST/INVALID: {}", filename.filename_display());
       tests += 1;
 
-      match toml::parse_from_path(&filename) {
+      match parse_fixture(&filename) {
           Err(_) => {
               passed += 1;
               println!("   [PASS]");
@@ -112,7 +2357,7 @@ fn independent_test_runner(path: ~str) {
       tests += 1;
 
       let json = result.unwrap();
-      let toml = toml::parse_from_path(&filename);
+      let toml = parse_fixture(&filename);
       let toml_json = toml.map(|t| to_json(&t));
 
       if Ok(&json) == toml_json.as_ref() {
@@ -125,6 +2370,7 @@ fn independent_test_runner(path: ~str) {
           match toml_json {
               Ok(json) => println!("{:s}", json.to_pretty_str()),
               Err(toml::ParseError) => println!("(parse error)"),
+              Err(toml::Rejected { line: line, reason: reason }) => println!("(line {}: {})", line, reason),
               Err(toml::IOError(e)) => println!("({})", e)
           }
           println!("===============================================");
@@ -134,6 +2380,52 @@ fn independent_test_runner(path: ~str) {
     }
   }
 
+  // every valid document must survive being written out and read back
+  for filename in walk_dir(&path.join("valid")).unwrap() {
+    if filename.is_file() && filename.extension_str() == Some("toml") {
+      println!("TEST/ROUNDTRIP: {}", filename.filename_display());
+      tests += 1;
+
+      let bytes = File::open(&filename).read_to_end().unwrap();
+      let input = std::str::from_utf8(bytes).unwrap();
+
+      match toml::roundtrip_check(input) {
+          Ok(()) => {
+              passed += 1;
+              println!("   [PASS]");
+          }
+          Err(e) => {
+              println!("({})", e);
+              failed += 1;
+              println!("   [FAIL]");
+          }
+      }
+    }
+  }
+
+  let mut api_results = emitter_tests(&path);
+  api_results.push_all_move(parser_tests(&path));
+  api_results.push_all_move(value_tests(&path));
+  api_results.push_all_move(json_tests());
+  api_results.push_all_move(encoder_tests());
+  api_results.push_all_move(decoder_tests());
+  api_results.push_all_move(document_tests(&path));
+  api_results.push_all_move(cli_tests(&path));
+  api_results.push_all_move(fuzz_tests(&path));
+
+  for &(name, ok) in api_results.iter() {
+    println!("TEST/API: {}", name);
+    tests += 1;
+
+    if ok {
+        passed += 1;
+        println!("   [PASS]");
+    } else {
+        failed += 1;
+        println!("   [FAIL]");
+    }
+  }
+
   println!("");
   println!("Tests/PASS/FAIL: {:d}/{:d}/{:d}", tests, passed, failed);
   if failed > 0 { fail!(); }
@@ -0,0 +1,403 @@
+//! Values borrowing their keys and strings from the parsed text
+
+use std::fmt;
+use std::io::BufReader;
+use std::str::{MaybeOwned,Slice,Owned};
+
+use collections::hashmap::HashMap;
+
+use super::{DuplicatePolicy,Reject,KeepFirst,KeepLast};
+use super::{Error,ParserOptions,Span,TableMap,Value};
+use super::{Idx,KeyOrIdx,elm_key,parse_path};
+use super::{check_table_depth,equiv_types};
+use super::parser;
+
+/// A value parsed by `parse_borrowed`. Keys and strings written without
+/// escapes are slices of the parsed text, the others are owned. Tables
+/// keep their pairs in the order of the document.
+#[deriving(Clone,Eq,Show)]
+pub enum BorrowedValue<'a> {
+    Boolean(bool),
+    PosInt(u64),
+    NegInt(u64),
+    Float(f64),
+    String(MaybeOwned<'a>),
+    Datetime(u16,u8,u8,u8,u8,u8),
+    Array(~[BorrowedValue<'a>]),
+    TableArray(~[BorrowedValue<'a>]),
+    Table(bool, BorrowedTable<'a>) // bool=true iff section already defined
+}
+
+// Tables with up to this many pairs are searched linearly, see the
+// constant of the same name of `TableMap`
+static INDEX_THRESHOLD: uint = 8;
+
+/// The pairs of a table of `BorrowedValue`s, in the order of the
+/// document. Lookups in larger tables go through a hash index, like in
+/// `TableMap`.
+#[deriving(Clone)]
+pub struct BorrowedTable<'a> {
+    priv pairs: ~[(MaybeOwned<'a>, BorrowedValue<'a>)],
+    // the positions of the keys, once there are more than INDEX_THRESHOLD
+    priv index: Option<HashMap<MaybeOwned<'a>, uint>>
+}
+
+impl<'a> BorrowedTable<'a> {
+    pub fn new() -> BorrowedTable<'a> {
+        BorrowedTable { pairs: ~[], index: None }
+    }
+
+    /// The pairs in the order of the document
+    pub fn pairs<'b>(&'b self) -> &'b [(MaybeOwned<'a>, BorrowedValue<'a>)] {
+        self.pairs.as_slice()
+    }
+
+    pub fn find<'b>(&'b self, key: &str) -> Option<&'b BorrowedValue<'a>> {
+        match self.position(key) {
+            Some(i) => Some(self.pairs[i].ref1()),
+            None => None
+        }
+    }
+
+    fn find_mut<'b>(&'b mut self, key: &str) -> Option<&'b mut BorrowedValue<'a>> {
+        match self.position(key) {
+            Some(i) => Some(self.pairs[i].mut1()),
+            None => None
+        }
+    }
+
+    fn position(&self, key: &str) -> Option<uint> {
+        match self.index {
+            Some(ref index) => index.find_equiv(&key).map(|&i| i),
+            None => self.pairs.iter().position(|&(ref k, _)| k.as_slice() == key)
+        }
+    }
+
+    // Appends a key known to be missing, indexing the keys once the table
+    // outgrows a linear search
+    fn push(&mut self, key: MaybeOwned<'a>, value: BorrowedValue<'a>) {
+        let len = self.pairs.len();
+        if self.index.is_none() && len == INDEX_THRESHOLD {
+            let mut index = HashMap::with_capacity(len * 2);
+            for (i, &(ref k, _)) in self.pairs.iter().enumerate() {
+                index.insert(k.clone(), i);
+            }
+            self.index = Some(index);
+        }
+        match self.index {
+            Some(ref mut index) => { index.insert(key.clone(), len); }
+            None => {}
+        }
+        self.pairs.push((key, value));
+    }
+}
+
+impl<'a> Container for BorrowedTable<'a> {
+    fn len(&self) -> uint { self.pairs.len() }
+}
+
+/// Tables are equal if they have the same pairs in the same order
+impl<'a> Eq for BorrowedTable<'a> {
+    fn eq(&self, other: &BorrowedTable<'a>) -> bool {
+        self.pairs == other.pairs
+    }
+}
+
+impl<'a> fmt::Show for BorrowedTable<'a> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        try!(fmt.buf.write_str("{"));
+        for (i, &(ref k, ref v)) in self.pairs.iter().enumerate() {
+            if i > 0 { try!(fmt.buf.write_str(", ")) }
+            try!(write!(fmt.buf, "{}: {}", k.as_slice(), *v));
+        }
+        fmt.buf.write_str("}")
+    }
+}
+
+impl<'a> BorrowedValue<'a> {
+    /// Copies the value into a `Value`, which owns all its strings
+    pub fn to_owned_value(&self) -> Value {
+        match *self {
+            Boolean(b) => super::Boolean(b),
+            PosInt(n) => super::PosInt(n),
+            NegInt(n) => super::NegInt(n),
+            Float(f) => super::Float(f),
+            String(ref s) => super::String(s.as_slice().to_owned()),
+            Datetime(y, m, d, h, mi, s) => super::Datetime(y, m, d, h, mi, s),
+            Array(ref arr) => super::Array(arr.iter().map(|v| v.to_owned_value()).collect()),
+            TableArray(ref arr) => super::TableArray(arr.iter().map(|v| v.to_owned_value()).collect()),
+            Table(defined, ref table) => {
+                let mut map = ~TableMap::new();
+                for &(ref key, ref val) in table.pairs().iter() {
+                    map.insert(key.as_slice().to_owned(), val.to_owned_value());
+                }
+                super::Table(defined, map)
+            }
+        }
+    }
+
+    /// Returns a human readable name of the value's type, see
+    /// `Value::type_str`
+    pub fn type_str(&self) -> &'static str {
+        match *self {
+            Boolean(..) => "boolean",
+            PosInt(..) | NegInt(..) => "integer",
+            Float(..) => "float",
+            String(..) => "string",
+            Datetime(..) => "datetime",
+            Array(..) => "array",
+            TableArray(..) => "array of tables",
+            Table(..) => "table"
+        }
+    }
+
+    pub fn get_str<'b>(&'b self) -> Option<&'b str> {
+        match *self {
+            String(ref s) => Some(s.as_slice()),
+            _ => None
+        }
+    }
+
+    /// Looks up a value by a path like `servers[0].name`, see
+    /// `Value::lookup`
+    pub fn lookup<'b>(&'b self, path: &str) -> Option<&'b BorrowedValue<'a>> {
+        let elms = match parse_path(path) {
+            Some(elms) => elms,
+            None => { return None }
+        };
+        let mut cur = self;
+        for elm in elms.iter() {
+            let next = match *cur {
+                Array(ref arr) | TableArray(ref arr) => {
+                    match *elm {
+                        Idx(i) | KeyOrIdx(_, i) if i < arr.len() => Some(&arr[i]),
+                        _ => None
+                    }
+                }
+                Table(_, ref table) => {
+                    match *elm {
+                        Idx(..) => None,
+                        _ => table.find(elm_key(elm))
+                    }
+                }
+                _ => None
+            };
+            match next {
+                Some(val) => { cur = val }
+                None => { return None }
+            }
+        }
+        return Some(cur);
+    }
+
+    pub fn lookup_str<'b>(&'b self, path: &str) -> Option<&'b str> {
+        self.lookup(path).and_then(|v| v.get_str())
+    }
+}
+
+/// Parses a document held in a string into a `BorrowedValue`. Keys and
+/// strings without escapes are not copied but borrowed from `s`. The
+/// document is checked like by `parse_from_str`, as TOML 1.0.
+pub fn parse_borrowed<'a>(s: &'a str) -> Result<BorrowedValue<'a>, Error> {
+    parse_borrowed_with_options(s, &ParserOptions::new())
+}
+
+/// Like `parse_borrowed`, checking the document as `parse_with_options`
+/// does with `opts`. It fails with the same errors, except that a header
+/// conflicting with an earlier definition is only rejected as an invalid
+/// or duplicate section. Spans are not recorded, and duplicates let
+/// through by `opts.duplicates` give no warnings.
+pub fn parse_borrowed_with_options<'a>(s: &'a str, opts: &ParserOptions) -> Result<BorrowedValue<'a>, Error> {
+    let mut rd = BufReader::new(s.as_bytes());
+    let mut builder = BorrowedBuilder { source: s, root: BorrowedTable::new(), current_path: ~[],
+                                        duplicates: opts.duplicates.clone(),
+                                        max_table_depth: opts.max_table_depth };
+    {
+        let mut parser = parser::Parser::new(&mut rd, false);
+        parser.set_limits(opts);
+        parser.set_lossy_utf8(opts.lossy_utf8);
+        parser.set_strict(opts.strict);
+        parser.set_version(opts.version.clone());
+        parser.set_borrowing(true);
+        match parser.build(&mut builder) {
+            Ok(()) => {}
+            Err(e) => {
+                debug!("Error in line: {}", parser.get_line());
+                return Err(e);
+            }
+        }
+    }
+    return Ok(Table(false, builder.root));
+}
+
+// Builds the tables right away, following the rules of `ValueBuilder`
+struct BorrowedBuilder<'a> {
+    source: &'a str,
+    root: BorrowedTable<'a>,
+    // the keys of the current section
+    current_path: ~[MaybeOwned<'a>],
+    duplicates: DuplicatePolicy,
+    max_table_depth: uint
+}
+
+impl<'a> parser::Builder<MaybeOwned<'a>, BorrowedValue<'a>> for BorrowedBuilder<'a> {
+    // The slice of the source between the quotes of the key or string,
+    // unless it was rebuilt from escapes
+    fn text(&self, text: ~str, span: &Span, verbatim: bool) -> MaybeOwned<'a> {
+        if !verbatim { return Owned(text) }
+        let src = self.source.slice(span.byte_start, span.byte_end);
+        if !(src.starts_with("\"") || src.starts_with("'")) { return Slice(src) }
+        let quotes = if src.len() >= 6 && (src.starts_with("\"\"\"") || src.starts_with("'''")) { 3 } else { 1 };
+        return Slice(src.slice(quotes, src.len() - quotes));
+    }
+
+    fn scalar(&self, val: Value) -> BorrowedValue<'a> {
+        match val {
+            super::Boolean(b) => Boolean(b),
+            super::PosInt(n) => PosInt(n),
+            super::NegInt(n) => NegInt(n),
+            super::Float(f) => Float(f),
+            super::Datetime(y, m, d, h, mi, s) => Datetime(y, m, d, h, mi, s),
+            _ => { unreachable!() }
+        }
+    }
+
+    fn string(&self, s: MaybeOwned<'a>) -> BorrowedValue<'a> { String(s) }
+
+    fn array(&self, elms: ~[BorrowedValue<'a>], of_tables: bool) -> BorrowedValue<'a> {
+        if of_tables { TableArray(elms) } else { Array(elms) }
+    }
+
+    fn inline_table(&self) -> BorrowedValue<'a> { Table(true, BorrowedTable::new()) }
+
+    fn inline_pair(&self, table: &mut BorrowedValue<'a>, key: MaybeOwned<'a>,
+                   val: BorrowedValue<'a>) -> Result<(), MaybeOwned<'a>> {
+        match *table {
+            Table(_, ref mut table) => {
+                if table.position(key.as_slice()).is_some() { return Err(key) }
+                table.push(key, val);
+                Ok(())
+            }
+            _ => { unreachable!() }
+        }
+    }
+
+    fn is_table(&self, val: &BorrowedValue<'a>) -> bool {
+        match *val { Table(..) => true, _ => false }
+    }
+
+    fn same_type(&self, a: &BorrowedValue<'a>, b: &BorrowedValue<'a>) -> bool {
+        equiv_types(a.type_str(), b.type_str())
+    }
+
+    fn section(&mut self, path: ~[MaybeOwned<'a>], is_array: bool, _span: Span) -> Result<(), ~str> {
+        match check_table_depth(path.as_slice(), self.max_table_depth) {
+            Ok(()) => {}
+            Err(reason) => { return Err(reason) }
+        }
+        if !create_tree(path.as_slice(), &mut self.root, is_array) {
+            let names: ~[&str] = path.iter().map(|k| k.as_slice()).collect();
+            return Err(format!("invalid or duplicate section `{}`", names.connect(".")));
+        }
+        self.current_path = path;
+        return Ok(());
+    }
+
+    fn pair(&mut self, key: MaybeOwned<'a>, val: BorrowedValue<'a>, _span: Span) -> Result<(), ~str> {
+        let duplicates = self.duplicates.clone();
+        match section_table(self.current_path.as_slice(), &mut self.root) {
+            Some(table) => {
+                let exists = match table.find_mut(key.as_slice()) {
+                    None => false,
+                    // tables are never replaced, see
+                    // `ValueBuilder::resolve_duplicate`
+                    Some(&Table(..)) | Some(&TableArray(..)) => true,
+                    Some(old) => {
+                        match duplicates {
+                            Reject => true,
+                            KeepFirst => { return Ok(()) }
+                            KeepLast => {
+                                *old = val;
+                                return Ok(());
+                            }
+                        }
+                    }
+                };
+                if !exists {
+                    table.push(key, val);
+                    return Ok(());
+                }
+            }
+            None => {}
+        }
+        let mut path: ~[&str] = self.current_path.iter().map(|k| k.as_slice()).collect();
+        path.push(key.as_slice());
+        return Err(format!("duplicate key `{}`", path.connect(".")));
+    }
+}
+
+// Creates the tables of the section `path`, see
+// `ValueBuilder::recursive_create_tree`
+fn create_tree<'a>(path: &[MaybeOwned<'a>], table: &mut BorrowedTable<'a>, is_array: bool) -> bool {
+    let head = match path.head() {
+        Some(head) if !head.as_slice().is_empty() => head,
+        _ => { return false } // don't allow empty keys
+    };
+    let term_rec = path.len() == 1;
+
+    match table.find_mut(head.as_slice()) {
+        Some(&TableArray(ref mut tables)) => {
+            if term_rec {
+                if !is_array { return false }
+                tables.push(Table(true, BorrowedTable::new()));
+                return true;
+            }
+            return match tables.mut_last() {
+                Some(&Table(_, ref mut sub)) => create_tree(path.tail(), sub, is_array),
+                _ => false // TableArray's only contain Table's
+            };
+        }
+        Some(&Table(ref mut defined, ref mut sub)) => {
+            if term_rec {
+                // a table created for the header of a sub-table may be
+                // defined by a header of its own, once
+                if is_array || *defined { return false }
+                *defined = true;
+                return true;
+            }
+            return create_tree(path.tail(), sub, is_array);
+        }
+        Some(_) => {
+            debug!("Wrong type/duplicate key");
+            return false;
+        }
+        None => {}
+    }
+
+    let value = if term_rec {
+        if is_array { TableArray(~[Table(true, BorrowedTable::new())]) } else { Table(true, BorrowedTable::new()) }
+    } else {
+        let mut sub = BorrowedTable::new();
+        if !create_tree(path.tail(), &mut sub, is_array) { return false }
+        Table(false, sub)
+    };
+    table.push(head.clone(), value);
+    return true;
+}
+
+// The table receiving the pairs of the section `path`, see
+// `ValueBuilder::section_table`
+fn section_table<'a, 'b>(path: &[MaybeOwned<'a>], table: &'b mut BorrowedTable<'a>)
+                         -> Option<&'b mut BorrowedTable<'a>> {
+    if path.is_empty() { return Some(table) }
+    match table.find_mut(path[0].as_slice()) {
+        Some(&Table(_, ref mut sub)) => section_table(path.tail(), sub),
+        Some(&TableArray(ref mut tables)) => {
+            match tables.mut_last() {
+                Some(&Table(_, ref mut sub)) => section_table(path.tail(), sub),
+                _ => None
+            }
+        }
+        _ => None
+    }
+}
@@ -0,0 +1,344 @@
+//! A document model keeping the formatting of a file
+
+use std::fmt;
+use std::io::{BufReader,IoResult};
+
+use super::{Error,LookupError,KeyNotFound,WrongType,EmitOptions,Span,TableMap,Value,Table};
+use super::{NoValue,Array,TableArray};
+use super::{ToValue,Visitor,ValueBuilder,elm_key,parse_path};
+use super::emitter::{inline_str,key_str};
+use super::parser::Parser;
+
+/// A parsed document which remembers its source, see `parse_document`.
+/// Writing it reproduces the source byte for byte, except for the values
+/// changed with `set`: a changed value is rewritten in place, and added
+/// pairs get a line of their own, so comments, whitespace and the order
+/// of keys are kept.
+pub struct Document {
+    priv source: ~str,
+    priv value: Value,
+    priv items: ~[Item]
+}
+
+// A section header or a pair, with the comments around it
+struct Item {
+    // the canonical path of the section or of the pair's value
+    path: ~str,
+    kind: ItemKind,
+    // the lines before the item: comments, or None for blank lines
+    leading: ~[Option<~str>],
+    trailing: Option<~str>,
+    // the bytes of the source covered by the item, and by the value of a
+    // pair; None for added pairs
+    span: Option<(uint, uint)>,
+    value_span: Option<(uint, uint)>,
+    // whether the value of the pair was changed
+    edited: bool
+}
+
+impl Item {
+    fn is_header(&self) -> bool {
+        match self.kind { Header(..) => true, Pair(..) => false }
+    }
+}
+
+enum ItemKind {
+    // the keys of the name, and whether it is an array header
+    Header(~[~str], bool),
+    Pair(~str)
+}
+
+/// Parses a document, keeping its formatting
+pub fn parse_document(s: &str) -> Result<Document,Error> {
+    let mut ht = ~TableMap::new();
+    let mut items = {
+        let mut builder = DocumentBuilder::new(&mut ht);
+        let mut rd = BufReader::new(s.as_bytes());
+        let mut parser = Parser::new(&mut rd, true);
+        match parser.parse(&mut builder) {
+            Err(e) => {
+                debug!("Error in line: {}", parser.get_line());
+                return Err(e);
+            }
+            Ok(_) => ()
+        }
+        builder.items
+    };
+
+    // the text between two items holds the comment of the first one on
+    // its last line, and the comment lines before the second one
+    let mut prev_end = None;
+    for i in range(0, items.len()) {
+        let (start, end) = items[i].span.unwrap();
+        let gap = s.slice(prev_end.unwrap_or(0), start);
+        items[i].leading = gap_lines(gap, prev_end.is_none());
+        if i > 0 { items[i - 1].trailing = first_line_comment(gap) }
+        prev_end = Some(end);
+    }
+    match items.mut_last() {
+        Some(last) => { last.trailing = first_line_comment(s.slice_from(prev_end.unwrap())) }
+        None => {}
+    }
+
+    return Ok(Document { source: s.to_owned(), value: Table(false, ht), items: items });
+}
+
+//
+// The complete lines of a gap between two items, as comments or blank
+// lines (None). Unless the gap starts at the beginning of a line, the part
+// up to the first line break belongs to the previous item.
+//
+fn gap_lines(gap: &str, at_line_start: bool) -> ~[Option<~str>] {
+    let mut parts: ~[&str] = gap.split('\n').collect();
+    parts.pop(); // the indentation of the next item
+    if !at_line_start { parts.shift(); }
+    parts.move_iter().map(|line| comment_text(line)).collect()
+}
+
+fn first_line_comment(gap: &str) -> Option<~str> {
+    match gap.find('\n') {
+        Some(pos) => comment_text(gap.slice_to(pos)),
+        None => comment_text(gap)
+    }
+}
+
+// The text after the '#' of a line, if it has a comment
+fn comment_text(line: &str) -> Option<~str> {
+    match line.find('#') {
+        Some(pos) => Some(line.slice_from(pos + 1).trim_right_chars(&'\r').to_owned()),
+        None => None
+    }
+}
+
+impl Document {
+    /// The document as a plain value
+    pub fn value<'a>(&'a self) -> &'a Value {
+        &self.value
+    }
+
+    /// Looks up a value, see `Value::lookup`
+    pub fn lookup<'a>(&'a self, path: &str) -> Option<&'a Value> {
+        self.value.lookup(path)
+    }
+
+    /// The comment lines before the section or pair at `path`, without
+    /// their `#`. Sections are named by their table, so `[[servers]]`
+    /// headers are `servers[0]`, `servers[1]` and so on.
+    pub fn leading_comments<'a>(&'a self, path: &str) -> ~[&'a str] {
+        match self.find_item(path) {
+            Some(item) => item.leading.iter().filter_map(|line| line.as_ref().map(|s| s.as_slice())).collect(),
+            None => ~[]
+        }
+    }
+
+    /// The comment following the section or pair at `path` on the same
+    /// line, without its `#`
+    pub fn trailing_comment<'a>(&'a self, path: &str) -> Option<&'a str> {
+        self.find_item(path).and_then(|item| item.trailing.as_ref().map(|s| s.as_slice()))
+    }
+
+    fn find_item<'a>(&'a self, path: &str) -> Option<&'a Item> {
+        match self.value.lookup_at(path) {
+            Ok((_, at)) => self.items.iter().find(|item| item.path == at),
+            Err(_) => None
+        }
+    }
+
+    /// Sets the value at `path`, keeping the comments around it, and
+    /// returns the value it replaced. Values inside arrays and inline
+    /// tables can be set as well. New keys are added at the end of their
+    /// table, which must be the root table or have a header; other tables
+    /// are not created. Values holding `NoValue`, which cannot be written,
+    /// are rejected with `WrongType`.
+    pub fn set<T: ToValue>(&mut self, path: &str, val: T) -> Result<Option<Value>, LookupError> {
+        let val = val.to_value();
+        if holds_no_value(&val) {
+            return Err(WrongType { at: path.to_owned(), expected: "value", found: "none" });
+        }
+        let at = match self.value.lookup_at(path) {
+            Ok((_, at)) => at,
+            Err(KeyNotFound { at: at }) => {
+                return self.insert_pair(path, at, val);
+            }
+            Err(e) => { return Err(e) }
+        };
+
+        // the pair holding the value, or the value itself
+        let pos = self.items.iter().position(|item| {
+            !item.is_header() &&
+            (at == item.path ||
+             (at.starts_with(item.path.as_slice()) && (at.char_at(item.path.len()) == '.' ||
+                                                       at.char_at(item.path.len()) == '[')))
+        });
+        match pos {
+            Some(i) => {
+                let old = self.value.set(path, val);
+                if old.is_ok() { self.items[i].edited = true }
+                return old;
+            }
+            None => {
+                let found = self.value.lookup(path).map_or("value", |v| v.type_str());
+                return Err(WrongType { at: at, expected: "value of a pair", found: found });
+            }
+        }
+    }
+
+    // Adds the missing key `at` to the table of its section
+    fn insert_pair(&mut self, path: &str, at: ~str, val: Value) -> Result<Option<Value>, LookupError> {
+        // the innermost section containing the key: (index of its header,
+        // start of the key)
+        let mut section = None;
+        for (i, item) in self.items.iter().enumerate() {
+            match item.kind {
+                Header(..) if at.starts_with(item.path.as_slice()) && at.len() > item.path.len() &&
+                              at.char_at(item.path.len()) == '.' &&
+                              section.map_or(true, |(_, len)| item.path.len() + 1 > len) => {
+                    section = Some((i, item.path.len() + 1));
+                }
+                _ => {}
+            }
+        }
+        let (header, key_start) = match section {
+            Some((i, len)) => (Some(i), len),
+            None => (None, 0)
+        };
+
+        // the key must directly belong to the section
+        let key = match parse_path(at.slice_from(key_start)) {
+            Some(elms) if elms.len() == 1 => elm_key(&elms[0]).to_owned(),
+            _ => { return Err(KeyNotFound { at: at }) }
+        };
+
+        let end = match header {
+            Some(i) => {
+                self.items.iter().skip(i + 1).position(|item| item.is_header())
+                    .map_or(self.items.len(), |pos| i + 1 + pos)
+            }
+            None => {
+                self.items.iter().position(|item| item.is_header()).unwrap_or(self.items.len())
+            }
+        };
+
+        let old = match self.value.set(path, val) {
+            Ok(old) => old,
+            Err(e) => { return Err(e) }
+        };
+        let item = Item { path: at, kind: Pair(key), leading: ~[], trailing: None,
+                          span: None, value_span: None, edited: true };
+        self.items.insert(end, item);
+        return Ok(old);
+    }
+
+    /// Writes the document. The source is copied, with changed values
+    /// rewritten and added pairs inserted after the line of the item
+    /// preceding them.
+    pub fn write_toml<W: Writer>(&self, wr: &mut W) -> IoResult<()> {
+        let opts = EmitOptions::new();
+        let source = self.source.as_slice();
+        let mut pos = 0; // the part of the source written so far
+        let mut line_end = 0; // where pairs added next are inserted
+        let mut inserted = false; // whether a pair was added there
+
+        for item in self.items.iter() {
+            match (item.span, item.value_span) {
+                (Some((_, end)), value_span) => {
+                    if item.edited {
+                        let (start, end) = value_span.unwrap();
+                        // pairs always have a value, which the path leads to
+                        let value = self.value.lookup(item.path.as_slice()).unwrap();
+                        try!(wr.write_str(source.slice(pos, start)));
+                        try!(wr.write_str(inline_str(value, &opts)));
+                        pos = end;
+                    }
+                    line_end = match source.slice_from(end).find('\n') {
+                        Some(i) => end + i + 1,
+                        None => source.len()
+                    };
+                    inserted = false;
+                }
+                (None, _) => {
+                    // only pairs are added
+                    let key = match item.kind { Pair(ref key) => key.as_slice(), Header(..) => { fail!() } };
+                    let value = self.value.lookup(item.path.as_slice()).unwrap();
+                    try!(wr.write_str(source.slice(pos, line_end)));
+                    pos = line_end;
+                    // the last line of the source may lack its line break
+                    if !inserted && line_end > 0 && source.char_at_reverse(line_end) != '\n' {
+                        try!(wr.write_str("\n"));
+                    }
+                    try!(wr.write_str(format!("{} = {}\n", key_str(key, false, &opts), inline_str(value, &opts))));
+                    inserted = true;
+                }
+            }
+        }
+        wr.write_str(source.slice_from(pos))
+    }
+}
+
+// Whether `value` is or contains a `NoValue`
+fn holds_no_value(value: &Value) -> bool {
+    match *value {
+        NoValue => true,
+        Array(ref arr) | TableArray(ref arr) => arr.iter().any(|v| holds_no_value(v)),
+        Table(_, ref map) => map.values().any(|v| holds_no_value(v)),
+        _ => false
+    }
+}
+
+/// Writes the document, see `write_toml`
+impl fmt::Show for Document {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        self.write_toml(fmt.buf)
+    }
+}
+
+// Collects the items of a document and the bytes they cover
+struct DocumentBuilder<'a> {
+    values: ValueBuilder<'a>,
+    items: ~[Item]
+}
+
+impl<'a> DocumentBuilder<'a> {
+    fn new(root: &'a mut ~TableMap) -> DocumentBuilder<'a> {
+        let values = ValueBuilder::new(root);
+        DocumentBuilder { values: values, items: ~[] }
+    }
+
+    fn push(&mut self, kind: ItemKind, path: ~str, span: Span) {
+        self.items.push(Item { path: path, kind: kind, leading: ~[], trailing: None,
+                               span: Some((span.byte_start, span.byte_end)), value_span: None,
+                               edited: false });
+    }
+}
+
+impl<'a> Visitor for DocumentBuilder<'a> {
+    fn section(&mut self, keys: ~[~str], is_array: bool, span: Span) -> Result<(), ~str> {
+        try!(self.values.section(keys.clone(), is_array, span.clone()));
+        let path = self.values.section_path.clone();
+        self.push(Header(keys, is_array), path, span);
+        return Ok(());
+    }
+
+    fn pair(&mut self, key: ~str, val: Value, span: Span) -> Result<(), ~str> {
+        try!(self.values.pair(key.clone(), val, span.clone()));
+        let path = self.values.pair_path.clone();
+        self.push(Pair(key), path, span);
+        return Ok(());
+    }
+
+    fn value_spans(&mut self, spans: ~[(~str, Span)]) {
+        // the pair ends with its value
+        for &(ref rel, ref span) in spans.iter() {
+            if rel.is_empty() {
+                match self.items.mut_last() {
+                    Some(item) => {
+                        let (start, _) = item.span.unwrap();
+                        item.span = Some((start, span.byte_end));
+                        item.value_span = Some((span.byte_start, span.byte_end));
+                    }
+                    None => {}
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,517 @@
+//! Serialization of `Value`s into TOML text
+
+use std::f64;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::io::{File,IoError,IoResult,MemWriter};
+use std::io::fs;
+use std::path::Path;
+use std::str;
+
+use super::{TableMap,Value,NoValue,Boolean,PosInt,NegInt,Float,String,Datetime,Array,TableArray,Table};
+use super::{EmitOptions,EmitError,RootNotATable,Unrepresentable};
+use super::{WriteStrategy,AtomicRename,DirectWrite};
+use super::{join_path,path_segment};
+
+impl Value {
+    /// Serializes a table into a TOML document. Scalars and arrays of a
+    /// table are written as `key = value` pairs before its sub-tables,
+    /// which get `[a.b]` headers, and arrays of tables get one `[[a]]`
+    /// header per element. Keys are written in the order of the table, so
+    /// a parsed document keeps its order. Parsing the output yields a
+    /// value equal to `self`.
+    ///
+    /// Fails for non-table values, and for documents TOML cannot express,
+    /// like a plain `Array` holding only tables or an empty `TableArray`.
+    /// Arrays of mixed types are written as they are, so reading them
+    /// back requires TOML 1.0.
+    pub fn to_toml_str(&self) -> Result<~str, EmitError> {
+        self.to_toml_str_with(&EmitOptions::new())
+    }
+
+    /// Serializes a table into its canonical form, see
+    /// `EmitOptions::canonical`. Equal values produce identical text,
+    /// which makes it suitable for comparing and hashing documents.
+    pub fn to_canonical_toml_str(&self) -> Result<~str, EmitError> {
+        self.to_toml_str_with(&EmitOptions::canonical())
+    }
+
+    /// Like `to_toml_str`, with the layout controlled by `opts`
+    pub fn to_toml_str_with(&self, opts: &EmitOptions) -> Result<~str, EmitError> {
+        let map = match check_root(self) {
+            Ok(map) => map,
+            Err(e) => { return Err(e) }
+        };
+        let mut wr = MemWriter::new();
+        // writing into memory does not fail
+        Emitter::new(&mut wr, opts).emit_table(map, &mut ~[]).unwrap();
+        return Ok(str::from_utf8_owned(wr.unwrap()).unwrap());
+    }
+
+    /// Formats any value as it would appear on the right-hand side of a
+    /// `key = value` pair, with tables written as inline tables. `NoValue`,
+    /// which TOML cannot hold, is left out of arrays and tables, and gives
+    /// an empty string on its own.
+    pub fn to_inline_toml_str(&self) -> ~str {
+        inline_str(self, &EmitOptions::new())
+    }
+
+    /// Formats a scalar as text for logs and templates: like
+    /// `to_inline_toml_str`, but strings as they are, without quotes or
+    /// escapes. Arrays, tables and `NoValue` give `None`.
+    pub fn display_str(&self) -> Option<~str> {
+        match *self {
+            String(ref s) => Some(s.clone()),
+            Array(..) | TableArray(..) | Table(..) | NoValue => None,
+            _ => Some(self.to_inline_toml_str())
+        }
+    }
+
+    /// Like `to_toml_str`, but streams the document into `wr`. The value is
+    /// checked before anything is written, so a document TOML cannot
+    /// express fails with an `InvalidInput` error without producing any
+    /// output. Errors of `wr` are returned as they occur, after which
+    /// nothing more is written.
+    pub fn write_toml<W: Writer>(&self, wr: &mut W) -> IoResult<()> {
+        self.write_toml_with(wr, &EmitOptions::new())
+    }
+
+    /// Like `write_toml`, with the layout controlled by `opts`
+    pub fn write_toml_with<W: Writer>(&self, wr: &mut W, opts: &EmitOptions) -> IoResult<()> {
+        let map = match check_root(self) {
+            Ok(map) => map,
+            Err(e) => { return Err(to_io_error(e)) }
+        };
+        Emitter::new(wr, opts).emit_table(map, &mut ~[])
+    }
+
+    /// Writes the document to the file at `path`, replacing it. The
+    /// document goes to a temporary file in the same directory first,
+    /// which is then renamed over `path`, so that a crash leaves either
+    /// the old or the new file, but never a truncated one. If renaming
+    /// fails, `path` is overwritten directly; the returned strategy tells
+    /// which way was taken.
+    pub fn write_to_file(&self, path: &Path) -> IoResult<WriteStrategy> {
+        self.write_to_file_with(path, &EmitOptions::new())
+    }
+
+    /// Like `write_to_file`, with the layout controlled by `opts`
+    pub fn write_to_file_with(&self, path: &Path, opts: &EmitOptions) -> IoResult<WriteStrategy> {
+        // emit first, so that the file is not touched if that fails
+        let text = match self.to_toml_str_with(opts) {
+            Ok(text) => text,
+            Err(e) => { return Err(to_io_error(e)) }
+        };
+
+        let tmp = match path.filename_str() {
+            Some(name) => path.with_filename(format!(".{}.tmp", name)),
+            None => { return Err(io::standard_error(io::InvalidInput)) }
+        };
+
+        match write_synced(&tmp, text.as_slice()) {
+            Ok(()) => {}
+            Err(e) => {
+                let _ = fs::unlink(&tmp);
+                return Err(e);
+            }
+        }
+
+        match fs::rename(&tmp, path) {
+            Ok(()) => { return Ok(AtomicRename) }
+            Err(_) => {
+                let _ = fs::unlink(&tmp);
+                try!(write_synced(path, text.as_slice()));
+                return Ok(DirectWrite);
+            }
+        }
+    }
+}
+
+fn to_io_error(e: EmitError) -> IoError {
+    IoError {
+        kind: io::InvalidInput,
+        desc: "value cannot be written as TOML",
+        detail: Some(e.to_str())
+    }
+}
+
+fn write_synced(path: &Path, text: &str) -> IoResult<()> {
+    let mut file = try!(File::create(path));
+    try!(file.write_str(text));
+    file.fsync()
+}
+
+/// Formats a table as a TOML document, as `to_toml_str` does, when used
+/// with `{:s}`. Values `to_toml_str` rejects make formatting fail.
+impl fmt::String for Value {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self.to_toml_str() {
+            Ok(s) => fmt.buf.write_str(s),
+            Err(_) => Err(io::standard_error(io::InvalidInput))
+        }
+    }
+}
+
+//
+// Whether the parser reads `key` back unquoted on the left side of a pair,
+// as `Lexer::read_key` does: inside inline tables bare keys also end at
+// ',' and '}'.
+//
+fn is_bare_key(key: &str, inline: bool) -> bool {
+    !key.is_empty() &&
+    !key.starts_with("[") && !key.starts_with("#") &&
+    !key.starts_with("\"") && !key.starts_with("'") &&
+    !key.chars().any(|c| {
+        match c {
+            ' ' | '=' => true,
+            ',' | '{' | '}' => inline,
+            _ => is_control(c)
+        }
+    })
+}
+
+//
+// Whether the parser reads `key` back unquoted as a segment of a table
+// header, as `Lexer::parse_section_identifier` does. Spaces around it would
+// be skipped.
+//
+fn is_header_key(key: &str) -> bool {
+    !key.is_empty() && !key.starts_with(" ") && !key.ends_with(" ") &&
+    !key.chars().any(|c| {
+        match c {
+            '.' | '[' | ']' | '"' | '\'' => true,
+            _ => is_control(c)
+        }
+    })
+}
+
+fn check_root<'a>(value: &'a Value) -> Result<&'a TableMap, EmitError> {
+    match *value {
+        Table(_, ref map) => {
+            match check_table(&**map, "") {
+                Ok(()) => { return Ok(&**map) }
+                Err(e) => { return Err(e) }
+            }
+        }
+        _ => { return Err(RootNotATable(value.type_str())) }
+    }
+}
+
+fn check_table(map: &TableMap, at: &str) -> Result<(), EmitError> {
+    for (k, v) in map.iter() {
+        let child = join_path(at, path_segment(k.as_slice()).as_slice());
+        match check_value(v, child.as_slice()) {
+            Err(e) => { return Err(e) }
+            Ok(()) => {}
+        }
+    }
+    return Ok(());
+}
+
+fn check_value(value: &Value, at: &str) -> Result<(), EmitError> {
+    match *value {
+        NoValue => {
+            return Err(Unrepresentable { at: at.to_owned(), reason: "missing value" });
+        }
+        Array(ref arr) => {
+            // Arrays may mix types as in TOML 1.0, tables included, which
+            // are written inline. Tables alone would be read back as an
+            // array of tables, though.
+            if !arr.is_empty() && arr.iter().all(|v| v.get_table().is_some()) {
+                return Err(Unrepresentable { at: at.to_owned(), reason: "plain array holding only tables" });
+            }
+            for (i, v) in arr.iter().enumerate() {
+                let child = format!("{}[{}]", at, i);
+                match check_value(v, child.as_slice()) {
+                    Err(e) => { return Err(e) }
+                    Ok(()) => {}
+                }
+            }
+            return Ok(());
+        }
+        TableArray(ref arr) => {
+            if arr.is_empty() {
+                return Err(Unrepresentable { at: at.to_owned(), reason: "empty array of tables" });
+            }
+            for (i, v) in arr.iter().enumerate() {
+                let child = format!("{}[{}]", at, i);
+                match *v {
+                    Table(_, ref map) => {
+                        match check_table(&**map, child.as_slice()) {
+                            Err(e) => { return Err(e) }
+                            Ok(()) => {}
+                        }
+                    }
+                    _ => {
+                        return Err(Unrepresentable { at: child, reason: "array of tables contains a non-table" });
+                    }
+                }
+            }
+            return Ok(());
+        }
+        Table(_, ref map) => { return check_table(&**map, at) }
+        _ => { return Ok(()) }
+    }
+}
+
+struct Emitter<'a, W> {
+    wr: &'a mut W,
+    opts: &'a EmitOptions,
+    // whether nothing was written yet
+    empty: bool
+}
+
+impl<'a, W: Writer> Emitter<'a, W> {
+    fn new(wr: &'a mut W, opts: &'a EmitOptions) -> Emitter<'a, W> {
+        Emitter { wr: wr, opts: opts, empty: true }
+    }
+
+    fn write_str(&mut self, s: &str) -> IoResult<()> {
+        self.empty = false;
+        self.wr.write_str(s)
+    }
+
+    //
+    // Whether `value` gets a header, instead of being written inline.
+    //
+    fn has_header(&self, value: &Value) -> bool {
+        match *value {
+            Table(_, ref map) => {
+                self.opts.inline_tables == 0 || map.len() > self.opts.inline_tables
+            }
+            TableArray(..) => { !self.opts.inline_table_arrays }
+            _ => { false }
+        }
+    }
+
+    //
+    // Writes the pairs of `map`, followed by its sub-tables. `path` holds
+    // the keys leading to `map`. The header of `map` itself was already
+    // written by the caller (if needed).
+    //
+    fn emit_table(&mut self, map: &TableMap, path: &mut ~[~str]) -> IoResult<()> {
+        let entries = entries(map, self.opts);
+
+        for &(k, v) in entries.iter() {
+            if !self.has_header(v) {
+                try!(self.emit_pair(k.as_slice(), v));
+            }
+        }
+
+        for &(k, v) in entries.iter() {
+            if !self.has_header(v) { continue }
+
+            path.push(k.clone());
+            match *v {
+                Table(_, ref sub) => {
+                    // tables containing only tables are created implicitly
+                    if sub.is_empty() || sub.values().any(|v| !self.has_header(v)) {
+                        try!(self.emit_header(path.as_slice(), false));
+                    }
+                    try!(self.emit_table(&**sub, path));
+                }
+                TableArray(ref arr) => {
+                    for elm in arr.iter() {
+                        match *elm {
+                            Table(_, ref sub) => {
+                                try!(self.emit_header(path.as_slice(), true));
+                                try!(self.emit_table(&**sub, path));
+                            }
+                            _ => { unreachable!() }
+                        }
+                    }
+                }
+                _ => { unreachable!() }
+            }
+            path.pop();
+        }
+        return Ok(());
+    }
+
+    fn emit_header(&mut self, path: &[~str], is_array: bool) -> IoResult<()> {
+        let keys: ~[~str] = path.iter().map(|k| header_key_str(k.as_slice(), self.opts)).collect();
+        if !self.empty { try!(self.write_str("\n")) }
+        try!(self.write_str(if is_array { "[[" } else { "[" }));
+        try!(self.write_str(keys.connect(".")));
+        self.write_str(if is_array { "]]\n" } else { "]\n" })
+    }
+
+    fn emit_pair(&mut self, key: &str, value: &Value) -> IoResult<()> {
+        let line = format!("{} = {}", key_str(key, false, self.opts), inline_str(value, self.opts));
+        let too_long = self.opts.max_width > 0 && line.char_len() > self.opts.max_width;
+
+        match *value {
+            Array(ref arr) | TableArray(ref arr) if too_long && !arr.is_empty() => {
+                try!(self.write_str(format!("{} = [\n", key_str(key, false, self.opts))));
+                for elm in arr.iter() {
+                    let line = format!("{}{},\n", self.opts.indent, inline_str(elm, self.opts));
+                    try!(self.write_str(line));
+                }
+                self.write_str("]\n")
+            }
+            _ => { self.write_str(format!("{}\n", line)) }
+        }
+    }
+}
+
+fn entries<'a>(map: &'a TableMap, opts: &EmitOptions) -> ~[(&'a ~str, &'a Value)] {
+    if opts.sort_keys { map.sorted_entries() } else { map.iter().collect() }
+}
+
+// A key of a table header, quoted unless it reads back bare
+fn header_key_str(key: &str, opts: &EmitOptions) -> ~str {
+    if is_header_key(key) && (!opts.ascii_only || key.is_ascii()) { key.to_owned() }
+    else { quote_string(key, false, opts) }
+}
+
+// The key of a pair, quoted unless it reads back bare. `inline` is whether
+// the pair is written inside an inline table.
+pub fn key_str(key: &str, inline: bool, opts: &EmitOptions) -> ~str {
+    if is_bare_key(key, inline) && (!opts.ascii_only || key.is_ascii()) { key.to_owned() }
+    else { quote_string(key, false, opts) }
+}
+
+pub fn inline_str(value: &Value, opts: &EmitOptions) -> ~str {
+    match *value {
+        Boolean(b) => { (if b { "true" } else { "false" }).to_owned() }
+        PosInt(n) => { n.to_str() }
+        NegInt(0) => { ~"0" }
+        NegInt(n) => { "-" + n.to_str() }
+        Float(f) if f == 0.0 && opts.fold_negative_zero => { ~"0.0" }
+        Float(f) => { format_float(f) }
+        String(ref s) => { quote_string(s.as_slice(), true, opts) }
+        Datetime(y,m,d,h,mi,s) => {
+            format!("{:04u}-{:02u}-{:02u}T{:02u}:{:02u}:{:02u}Z", y,m,d,h,mi,s)
+        }
+        Array(ref arr) | TableArray(ref arr) => {
+            let elms: ~[~str] = arr.iter().filter(|v| !is_missing(*v)).map(|v| inline_str(v, opts)).collect();
+            format!("[{}]", elms.connect(", "))
+        }
+        Table(_, ref map) => {
+            let pairs: ~[~str] = entries(&**map, opts).iter().filter(|&&(_, v)| !is_missing(v)).map(|&(k, v)| {
+                format!("{} = {}", key_str(k.as_slice(), true, opts), inline_str(v, opts))
+            }).collect();
+            if pairs.is_empty() { return ~"{}" }
+            let mut table = ~"{ ";
+            table.push_str(pairs.connect(", "));
+            table.push_str(" }");
+            table
+        }
+        // missing values are left out, see `to_inline_toml_str`
+        NoValue => { ~"" }
+    }
+}
+
+fn is_missing(value: &Value) -> bool {
+    match *value { NoValue => true, _ => false }
+}
+
+//
+// Chooses the most readable form of string that can hold `s`: literal
+// strings for content with backslashes, multi-line strings (if allowed)
+// for content with newlines, basic strings otherwise.
+//
+fn quote_string(s: &str, multiline: bool, opts: &EmitOptions) -> ~str {
+    let has_controls = s.chars().any(|c| is_control(c) && c != '\n');
+    let has_newlines = s.contains_char('\n');
+
+    if opts.basic_strings {
+        let mut quoted = ~"\"";
+        escape_into(&mut quoted, s, false, opts.ascii_only);
+        quoted.push_char('"');
+        return quoted;
+    }
+
+    if multiline && has_newlines && !has_controls {
+        let mut quoted = ~"\"\"\"\n";
+        escape_into(&mut quoted, s, true, opts.ascii_only);
+        quoted.push_str("\"\"\"");
+        return quoted;
+    }
+
+    if s.contains_char('\\') && !s.contains_char('\'') && !has_controls && !has_newlines &&
+       (!opts.ascii_only || s.is_ascii()) {
+        return format!("'{}'", s);
+    }
+
+    let mut quoted = ~"\"";
+    escape_into(&mut quoted, s, false, opts.ascii_only);
+    quoted.push_char('"');
+    return quoted;
+}
+
+fn is_control(c: char) -> bool {
+    c < ' ' || c == '\u007F'
+}
+
+//
+// Appends `s` with the escapes of basic strings. Multi-line strings keep
+// their line breaks.
+//
+fn escape_into(out: &mut ~str, s: &str, multiline: bool, ascii_only: bool) {
+    for c in s.chars() {
+        match c {
+            '\n' if multiline => { out.push_char('\n') }
+            '\u0008' => { out.push_str("\\b") }
+            '\t' => { out.push_str("\\t") }
+            '\n' => { out.push_str("\\n") }
+            '\u000C' => { out.push_str("\\f") }
+            '\r' => { out.push_str("\\r") }
+            '"' => { out.push_str("\\\"") }
+            '\\' => { out.push_str("\\\\") }
+            c if is_control(c) => {
+                out.push_str(format!("\\u{:04X}", c as u32));
+            }
+            c if ascii_only && !c.is_ascii() => {
+                if (c as u32) > 0xFFFF { out.push_str(format!("\\U{:08X}", c as u32)) }
+                else { out.push_str(format!("\\u{:04X}", c as u32)) }
+            }
+            c => { out.push_char(c) }
+        }
+    }
+}
+
+//
+// Floats get a decimal point or an exponent, so that they read back as
+// floats, and as few digits as needed to read back the very same bits.
+// Candidates are checked with from_str, which the parser uses as well.
+// The parser applies the sign separately, so -0.0 stays negative zero
+// (unless fold_negative_zero is set). NaN is written as "nan", losing its
+// sign and payload; as NaN is unequal to itself, documents containing it
+// never compare equal after a round-trip.
+//
+pub fn format_float(f: f64) -> ~str {
+    if f.is_nan() { return ~"nan" }
+    if f.is_infinite() { return if f > 0.0 { ~"inf" } else { ~"-inf" } }
+
+    let bits: u64 = unsafe { mem::transmute(f) };
+    let sign = if bits >> 63 == 1 { "-" } else { "" };
+    let abs = f.abs();
+    let reads_back = |s: &str| {
+        match from_str::<f64>(s) {
+            Some(g) => {
+                let b: u64 = unsafe { mem::transmute(g) };
+                b == bits & !(1u64 << 63)
+            }
+            None => false
+        }
+    };
+
+    // plain notation for moderate magnitudes, exponents otherwise
+    if abs == 0.0 || (abs >= 1e-5 && abs < 1e16) {
+        for digits in range(0u, 18) {
+            let s = f64::to_str_digits(abs, digits);
+            if reads_back(s.as_slice()) {
+                let point = if s.contains_char('.') { "" } else { ".0" };
+                return format!("{}{}{}", sign, s, point);
+            }
+        }
+    }
+    for digits in range(0u, 18) {
+        let s = f64::to_str_exp_digits(abs, digits, false);
+        if reads_back(s.as_slice()) { return format!("{}{}", sign, s) }
+    }
+    // from_str is not exact for every float, the closest we can get
+    format!("{}{}", sign, f64::to_str_exp_digits(abs, 17, false))
+}
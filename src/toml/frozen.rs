@@ -0,0 +1,80 @@
+//! A read-only value shared between owners
+
+use std::fmt;
+
+use sync::Arc;
+
+use super::{FromValue,LookupError,Value};
+
+/// A value which can't be changed anymore, made by `Value::freeze`. Its
+/// clones share the value, so cloning is cheap and the clones can be sent
+/// to other tasks. The value is read with `value`, or the lookups below,
+/// which work as those of `Value`.
+#[deriving(Clone)]
+pub struct FrozenValue {
+    priv value: Arc<Value>
+}
+
+impl FrozenValue {
+    pub fn new(value: Value) -> FrozenValue {
+        FrozenValue { value: Arc::new(value) }
+    }
+
+    pub fn value<'a>(&'a self) -> &'a Value {
+        self.value.get()
+    }
+
+    /// A copy of the value, which can be changed
+    pub fn to_value(&self) -> Value {
+        self.value().clone()
+    }
+
+    /// Whether `other` shares the value of `self`
+    pub fn ptr_eq(&self, other: &FrozenValue) -> bool {
+        self.value() as *Value == other.value() as *Value
+    }
+
+    pub fn lookup<'a>(&'a self, path: &str) -> Option<&'a Value> {
+        self.value().lookup(path)
+    }
+
+    pub fn lookup_detailed<'a>(&'a self, path: &str) -> Result<&'a Value, LookupError> {
+        self.value().lookup_detailed(path)
+    }
+
+    pub fn lookup_str<'a>(&'a self, path: &str) -> Option<&'a str> {
+        self.value().lookup_str(path)
+    }
+
+    pub fn lookup_int(&self, path: &str) -> Option<i64> {
+        self.value().lookup_int(path)
+    }
+
+    pub fn lookup_uint(&self, path: &str) -> Option<u64> {
+        self.value().lookup_uint(path)
+    }
+
+    pub fn lookup_bool(&self, path: &str) -> Option<bool> {
+        self.value().lookup_bool(path)
+    }
+
+    pub fn lookup_float(&self, path: &str) -> Option<f64> {
+        self.value().lookup_float(path)
+    }
+
+    pub fn get<T: FromValue>(&self, path: &str) -> Option<T> {
+        self.value().get(path)
+    }
+}
+
+impl Eq for FrozenValue {
+    fn eq(&self, other: &FrozenValue) -> bool {
+        self.ptr_eq(other) || self.value() == other.value()
+    }
+}
+
+impl fmt::Show for FrozenValue {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt.buf, "{}", *self.value())
+    }
+}
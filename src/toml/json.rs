@@ -0,0 +1,494 @@
+//! Conversion between `Value`s and JSON text
+
+use std::char;
+use std::str;
+
+use super::{JsonOptions,SkipNull,KeepNull,RejectNull,NullAsEmptyString};
+use super::{TableMap,Value,NoValue,Boolean,PosInt,NegInt,Float,String,Datetime,Array,TableArray,Table};
+use super::emitter::format_float;
+
+impl Value {
+    /// Converts the value into compact JSON. Tables become objects (keeping
+    /// their key order), arrays and arrays of tables become arrays,
+    /// datetimes become RFC 3339 strings and `NoValue` becomes `null`.
+    ///
+    /// Integers are written with all their digits, even those beyond 2^53
+    /// which many JSON parsers (including `serialize::json`) cannot
+    /// represent exactly. JSON has no infinite or NaN numbers, so such
+    /// floats are written as `null`.
+    pub fn to_json_str(&self) -> ~str {
+        let mut out = ~"";
+        write_json(&mut out, self, false, 0);
+        return out;
+    }
+
+    /// Like `to_json_str`, but with one array element or object member
+    /// per line, indented by two spaces per level
+    pub fn to_pretty_json_str(&self) -> ~str {
+        let mut out = ~"";
+        write_json(&mut out, self, true, 0);
+        return out;
+    }
+
+    /// Converts the value into the tagged JSON of the toml-test suite:
+    /// every scalar becomes `{"type":"<type>","value":"<text>"}` with type
+    /// `string`, `integer`, `float`, `bool` or `datetime`, arrays become
+    /// `{"type":"array","value":[...]}`, tables become objects and arrays
+    /// of tables plain arrays of objects. Numbers are written as strings,
+    /// so no precision is lost. `NoValue` becomes `null`.
+    pub fn to_tagged_json(&self) -> ~str {
+        let mut out = ~"";
+        write_tagged_json(&mut out, self);
+        return out;
+    }
+}
+
+fn write_tagged(out: &mut ~str, typ: &str, value: &str) {
+    out.push_str("{\"type\":\"");
+    out.push_str(typ);
+    out.push_str("\",\"value\":");
+    write_json_string(out, value);
+    out.push_char('}');
+}
+
+fn write_tagged_json(out: &mut ~str, value: &Value) {
+    match *value {
+        NoValue => { out.push_str("null") }
+        Boolean(b) => { write_tagged(out, "bool", if b { "true" } else { "false" }) }
+        PosInt(n) => { write_tagged(out, "integer", n.to_str()) }
+        NegInt(0) => { write_tagged(out, "integer", "0") }
+        NegInt(n) => { write_tagged(out, "integer", format!("-{}", n)) }
+        Float(f) => { write_tagged(out, "float", format_float(f)) }
+        String(ref s) => { write_tagged(out, "string", s.as_slice()) }
+        Datetime(y, m, d, h, mi, sec) => {
+            let s = format!("{:04u}-{:02u}-{:02u}T{:02u}:{:02u}:{:02u}Z", y, m, d, h, mi, sec);
+            write_tagged(out, "datetime", s);
+        }
+        Array(ref arr) => {
+            out.push_str("{\"type\":\"array\",\"value\":[");
+            for (i, v) in arr.iter().enumerate() {
+                if i > 0 { out.push_char(',') }
+                write_tagged_json(out, v);
+            }
+            out.push_str("]}");
+        }
+        TableArray(ref arr) => {
+            out.push_char('[');
+            for (i, v) in arr.iter().enumerate() {
+                if i > 0 { out.push_char(',') }
+                write_tagged_json(out, v);
+            }
+            out.push_char(']');
+        }
+        Table(_, ref map) => {
+            out.push_char('{');
+            for (i, (k, v)) in map.iter().enumerate() {
+                if i > 0 { out.push_char(',') }
+                write_json_string(out, k.as_slice());
+                out.push_char(':');
+                write_tagged_json(out, v);
+            }
+            out.push_char('}');
+        }
+    }
+}
+
+fn newline(out: &mut ~str, pretty: bool, level: uint) {
+    if pretty {
+        out.push_char('\n');
+        for _ in range(0, level) { out.push_str("  ") }
+    }
+}
+
+fn write_json(out: &mut ~str, value: &Value, pretty: bool, level: uint) {
+    match *value {
+        NoValue => { out.push_str("null") }
+        Boolean(b) => { out.push_str(if b { "true" } else { "false" }) }
+        PosInt(n) => { out.push_str(n.to_str()) }
+        NegInt(0) => { out.push_str("0") }
+        NegInt(n) => { out.push_char('-'); out.push_str(n.to_str()) }
+        Float(f) if f.is_nan() || f.is_infinite() => { out.push_str("null") }
+        Float(f) => { out.push_str(format_float(f)) }
+        String(ref s) => { write_json_string(out, s.as_slice()) }
+        Datetime(y,m,d,h,mi,s) => {
+            out.push_str(format!("\"{:04u}-{:02u}-{:02u}T{:02u}:{:02u}:{:02u}Z\"", y,m,d,h,mi,s));
+        }
+        Array(ref arr) | TableArray(ref arr) => {
+            if arr.is_empty() {
+                out.push_str("[]");
+                return;
+            }
+            out.push_char('[');
+            for (i, v) in arr.iter().enumerate() {
+                if i > 0 { out.push_char(',') }
+                newline(out, pretty, level + 1);
+                write_json(out, v, pretty, level + 1);
+            }
+            newline(out, pretty, level);
+            out.push_char(']');
+        }
+        Table(_, ref map) => {
+            if map.is_empty() {
+                out.push_str("{}");
+                return;
+            }
+            out.push_char('{');
+            for (i, (k, v)) in map.iter().enumerate() {
+                if i > 0 { out.push_char(',') }
+                newline(out, pretty, level + 1);
+                write_json_string(out, k.as_slice());
+                out.push_str(if pretty { ": " } else { ":" });
+                write_json(out, v, pretty, level + 1);
+            }
+            newline(out, pretty, level);
+            out.push_char('}');
+        }
+    }
+}
+
+pub fn write_json_string(out: &mut ~str, s: &str) {
+    out.push_char('"');
+    for c in s.chars() {
+        match c {
+            '"' => { out.push_str("\\\"") }
+            '\\' => { out.push_str("\\\\") }
+            '\u0008' => { out.push_str("\\b") }
+            '\u000C' => { out.push_str("\\f") }
+            '\n' => { out.push_str("\\n") }
+            '\r' => { out.push_str("\\r") }
+            '\t' => { out.push_str("\\t") }
+            c if c < ' ' || c == '\u007F' => {
+                out.push_str(format!("\\u{:04X}", c as u32));
+            }
+            c => { out.push_char(c) }
+        }
+    }
+    out.push_char('"');
+}
+
+/// Builds a table from a JSON object, see `from_json_str_with`
+pub fn from_json_str(s: &str) -> Result<Value, ~str> {
+    from_json_str_with(s, &JsonOptions::new())
+}
+
+/// Builds a table from a JSON object. Objects become tables, arrays of
+/// objects become arrays of tables, other arrays become arrays. Numbers
+/// written with a fraction or exponent become floats, all others integers.
+/// Strings stay strings, unless `opts.datetimes` is set and they have the
+/// form of a TOML datetime. See `JsonOptions` for the handling of `null`.
+/// As TOML documents are tables, the JSON root must be an object.
+pub fn from_json_str_with(s: &str, opts: &JsonOptions) -> Result<Value, ~str> {
+    let mut parser = JsonParser { chars: s.chars(), ch: None, line: 1, opts: opts };
+    parser.bump();
+    parser.skip_whitespace();
+    if parser.ch != Some('{') { return Err(~"JSON root must be an object") }
+
+    let value = match parser.parse_value() {
+        Ok(Some(value)) => value,
+        Ok(None) => { fail!("object parsed as null") }
+        Err(e) => { return Err(e) }
+    };
+    parser.skip_whitespace();
+    if parser.ch.is_some() { return Err(parser.error("trailing characters")) }
+    return Ok(value);
+}
+
+/// Converts a JSON object into a TOML document, see `json_to_toml_str_with`
+pub fn json_to_toml_str(s: &str) -> Result<~str, ~str> {
+    let opts = JsonOptions { nulls: RejectNull, .. JsonOptions::new() };
+    json_to_toml_str_with(s, &opts)
+}
+
+/// Converts a JSON object into a TOML document, as `from_json_str_with`
+/// followed by `Value::to_toml_str`. Fails with a message for JSON that
+/// does not parse, or that TOML cannot express, like a root that is not
+/// an object.
+pub fn json_to_toml_str_with(s: &str, opts: &JsonOptions) -> Result<~str, ~str> {
+    match from_json_str_with(s, opts) {
+        Ok(value) => value.to_toml_str().map_err(|e| e.to_str()),
+        Err(e) => Err(e)
+    }
+}
+
+struct JsonParser<'a> {
+    chars: str::Chars<'a>,
+    ch: Option<char>,
+    line: uint,
+    opts: &'a JsonOptions
+}
+
+impl<'a> JsonParser<'a> {
+    fn bump(&mut self) {
+        if self.ch == Some('\n') { self.line += 1 }
+        self.ch = self.chars.next();
+    }
+
+    fn bump_if(&mut self, c: char) -> bool {
+        if self.ch == Some(c) {
+            self.bump();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn error(&self, msg: &str) -> ~str {
+        format!("{} in line {}", msg, self.line)
+    }
+
+    fn skip_whitespace(&mut self) {
+        loop {
+            match self.ch {
+                Some(' ') | Some('\t') | Some('\r') | Some('\n') => { self.bump() }
+                _ => { break }
+            }
+        }
+    }
+
+    //
+    // Returns None for a null that is to be left out.
+    //
+    fn parse_value(&mut self) -> Result<Option<Value>, ~str> {
+        self.skip_whitespace();
+        match self.ch {
+            Some('{') => { self.parse_object().map(|v| Some(v)) }
+            Some('[') => { self.parse_array().map(|v| Some(v)) }
+            Some('"') => {
+                self.parse_string().map(|s| {
+                    if self.opts.datetimes { Some(to_datetime(s)) } else { Some(String(s)) }
+                })
+            }
+            Some('t') => { self.parse_keyword("true", Boolean(true)).map(|v| Some(v)) }
+            Some('f') => { self.parse_keyword("false", Boolean(false)).map(|v| Some(v)) }
+            Some('n') => {
+                match self.parse_keyword("null", NoValue) {
+                    Ok(v) => {
+                        match self.opts.nulls {
+                            SkipNull => Ok(None),
+                            KeepNull => Ok(Some(v)),
+                            RejectNull => Err(self.error("null has no TOML representation")),
+                            NullAsEmptyString => Ok(Some(String(~"")))
+                        }
+                    }
+                    Err(e) => { Err(e) }
+                }
+            }
+            Some('-') | Some('0' .. '9') => { self.parse_number().map(|v| Some(v)) }
+            Some(_) => { Err(self.error("unexpected character")) }
+            None => { Err(self.error("unexpected end of input")) }
+        }
+    }
+
+    fn parse_keyword(&mut self, word: &str, value: Value) -> Result<Value, ~str> {
+        for c in word.chars() {
+            if !self.bump_if(c) { return Err(self.error("invalid literal")) }
+        }
+        return Ok(value);
+    }
+
+    fn read_digits(&mut self, text: &mut ~str) -> bool {
+        let mut any = false;
+        loop {
+            match self.ch {
+                Some(c) if c >= '0' && c <= '9' => {
+                    text.push_char(c);
+                    self.bump();
+                    any = true;
+                }
+                _ => { return any }
+            }
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<Value, ~str> {
+        let negative = self.bump_if('-');
+        let mut text = ~"";
+        if !self.read_digits(&mut text) { return Err(self.error("invalid number")) }
+        // JSON has no octal, nor any other leading zeros
+        if text.len() > 1 && text.starts_with("0") { return Err(self.error("leading zeros are not allowed")) }
+        let mut is_float = false;
+
+        if self.bump_if('.') {
+            text.push_char('.');
+            if !self.read_digits(&mut text) { return Err(self.error("invalid number")) }
+            is_float = true;
+        }
+        if self.bump_if('e') || self.bump_if('E') {
+            text.push_char('e');
+            if self.bump_if('-') { text.push_char('-') }
+            else { self.bump_if('+'); }
+            if !self.read_digits(&mut text) { return Err(self.error("invalid number")) }
+            is_float = true;
+        }
+
+        if is_float {
+            match from_str::<f64>(text.as_slice()) {
+                Some(f) => { return Ok(Float(if negative { -f } else { f })) }
+                None => { return Err(self.error("invalid number")) }
+            }
+        }
+        match from_str::<u64>(text.as_slice()) {
+            Some(n) if negative => { return Ok(NegInt(n)) }
+            Some(n) => { return Ok(PosInt(n)) }
+            None => { return Err(self.error("integer out of range")) }
+        }
+    }
+
+    fn parse_hex4(&mut self) -> Result<u32, ~str> {
+        let mut n = 0u32;
+        for _ in range(0, 4) {
+            let digit = match self.ch {
+                Some(c) => char::to_digit(c, 16),
+                None => None
+            };
+            match digit {
+                Some(d) => { n = (n << 4) | d as u32 }
+                None => { return Err(self.error("invalid unicode escape")) }
+            }
+            self.bump();
+        }
+        return Ok(n);
+    }
+
+    fn parse_string(&mut self) -> Result<~str, ~str> {
+        assert!(self.bump_if('"'));
+        let mut s = ~"";
+        loop {
+            match self.ch {
+                None => { return Err(self.error("unterminated string")) }
+                Some('"') => {
+                    self.bump();
+                    return Ok(s);
+                }
+                Some('\\') => {
+                    self.bump();
+                    let c = match self.ch {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('/') => '/',
+                        Some('b') => '\u0008',
+                        Some('f') => '\u000C',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('u') => {
+                            self.bump();
+                            let mut n = match self.parse_hex4() {
+                                Ok(n) => n,
+                                Err(e) => { return Err(e) }
+                            };
+                            // a surrogate pair encodes one character
+                            if n >= 0xD800 && n < 0xDC00 {
+                                if !self.bump_if('\\') || !self.bump_if('u') {
+                                    return Err(self.error("unpaired surrogate"));
+                                }
+                                let low = match self.parse_hex4() {
+                                    Ok(low) => low,
+                                    Err(e) => { return Err(e) }
+                                };
+                                if low < 0xDC00 || low >= 0xE000 {
+                                    return Err(self.error("unpaired surrogate"));
+                                }
+                                n = 0x10000 + ((n - 0xD800) << 10) + (low - 0xDC00);
+                            } else if n >= 0xDC00 && n < 0xE000 {
+                                return Err(self.error("unpaired surrogate"));
+                            }
+                            match char::from_u32(n) {
+                                Some(c) => { s.push_char(c) }
+                                None => { return Err(self.error("invalid unicode escape")) }
+                            }
+                            continue;
+                        }
+                        _ => { return Err(self.error("invalid escape")) }
+                    };
+                    s.push_char(c);
+                    self.bump();
+                }
+                Some(c) => {
+                    s.push_char(c);
+                    self.bump();
+                }
+            }
+        }
+    }
+
+    fn parse_array(&mut self) -> Result<Value, ~str> {
+        assert!(self.bump_if('['));
+        let mut arr = ~[];
+        self.skip_whitespace();
+        if !self.bump_if(']') {
+            loop {
+                match self.parse_value() {
+                    Ok(Some(v)) => { arr.push(v) }
+                    Ok(None) => { return Err(self.error("null in array")) }
+                    Err(e) => { return Err(e) }
+                }
+                self.skip_whitespace();
+                if self.bump_if(']') { break }
+                if !self.bump_if(',') { return Err(self.error("expected `,` or `]`")) }
+            }
+        }
+
+        if !arr.is_empty() && arr.iter().all(|v| match *v { Table(..) => true, _ => false }) {
+            return Ok(TableArray(arr));
+        }
+        return Ok(Array(arr));
+    }
+
+    fn parse_object(&mut self) -> Result<Value, ~str> {
+        assert!(self.bump_if('{'));
+        let mut map = ~TableMap::new();
+        self.skip_whitespace();
+        if self.bump_if('}') { return Ok(Table(true, map)) }
+
+        loop {
+            self.skip_whitespace();
+            if self.ch != Some('"') { return Err(self.error("expected key")) }
+            let key = match self.parse_string() {
+                Ok(key) => key,
+                Err(e) => { return Err(e) }
+            };
+            self.skip_whitespace();
+            if !self.bump_if(':') { return Err(self.error("expected `:`")) }
+
+            match self.parse_value() {
+                Ok(Some(v)) => {
+                    if !map.insert(key.clone(), v) {
+                        return Err(self.error(format!("duplicate key `{}`", key)));
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => { return Err(e) }
+            }
+
+            self.skip_whitespace();
+            if self.bump_if('}') { break }
+            if !self.bump_if(',') { return Err(self.error("expected `,` or `}`")) }
+        }
+        return Ok(Table(true, map));
+    }
+}
+
+//
+// Turns strings of the form 1979-05-27T07:32:00Z into datetimes.
+//
+fn to_datetime(s: ~str) -> Value {
+    let bytes = s.as_bytes();
+    let is_datetime = bytes.len() == 20 && bytes.iter().enumerate().all(|(i, &b)| {
+        match i {
+            4 | 7 => b == '-' as u8,
+            10 => b == 'T' as u8,
+            13 | 16 => b == ':' as u8,
+            19 => b == 'Z' as u8,
+            _ => b >= '0' as u8 && b <= '9' as u8
+        }
+    });
+    if !is_datetime { return String(s) }
+
+    let (y, m, d, h, mi, sec) = {
+        let num = |from: uint, to: uint| from_str::<u16>(s.slice(from, to)).unwrap();
+        (num(0, 4), num(5, 7), num(8, 10), num(11, 13), num(14, 16), num(17, 19))
+    };
+    if m < 1 || m > 12 || d < 1 || d > 31 || h > 24 || mi > 60 || sec > 60 { return String(s) }
+    return Datetime(y, m as u8, d as u8, h as u8, mi as u8, sec as u8);
+}
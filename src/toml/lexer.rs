@@ -0,0 +1,1035 @@
+//! Splitting TOML text into tokens
+
+use std::char;
+use std::cmp;
+use std::f64;
+use std::io;
+use std::io::{IoResult,IoError,EndOfFile,InvalidInput};
+use std::str;
+
+use super::{Error,ParseError,IOError,Span};
+
+// The most bytes taken from the reader at once. A buffer over a whole
+// document in memory hands it all out in one chunk, which would otherwise
+// be copied in full.
+static FILL_SIZE: uint = 64 * 1024;
+
+/// A token of a TOML document. Which characters form a token depends on
+/// where they appear: `true` is a key at the start of a line but a boolean
+/// after `=`, and `[` opens a header at the start of a line but an array
+/// after `=`.
+#[deriving(Clone,Eq,Show)]
+pub enum Token {
+    /// `[`, opening a header or an array
+    LBracket,
+    /// `[[`, opening the header of an array of tables
+    DoubleLBracket,
+    /// `]`, closing a header or an array
+    RBracket,
+    /// `]]`, closing the header of an array of tables
+    DoubleRBracket,
+    /// `{`, opening an inline table
+    LBrace,
+    /// `}`, closing an inline table
+    RBrace,
+    /// A key, bare or quoted (given without the quotes), on the left of a
+    /// pair or between the dots of a header's name
+    Key(~str),
+    /// `=`
+    Equals,
+    /// `,`
+    Comma,
+    /// `.`, separating the keys of a header's name
+    Dot,
+    /// A string value, with escapes resolved
+    StringLit(~str),
+    /// An integer value, as whether it is negative and its magnitude
+    IntLit(bool, u64),
+    FloatLit(f64),
+    BoolLit(bool),
+    /// A datetime value, as year, month, day, hour, minute and second
+    DatetimeLit(u16, u8, u8, u8, u8, u8),
+    /// A comment, without the leading `#` and the line break
+    Comment(~str),
+    Newline,
+    /// The end of the input, returned once
+    Eof
+}
+
+// What the lexer expects next
+#[deriving(Eq)]
+enum Mode {
+    // a key or header at the top level
+    TopKey,
+    // a key of a header's name, after the opening bracket(s) or a dot
+    HeaderName,
+    // a dot or the closing bracket(s) after a key of a header's name
+    HeaderEnd,
+    // the '=' after a key
+    AfterKey,
+    // a value
+    Value,
+    // ',' or the closing bracket after a value inside an array or inline table
+    AfterValue,
+    // a key inside an inline table
+    InlineKey
+}
+
+/// Splits a document read from a buffer into tokens, each with its span.
+/// Iterating yields the tokens up to and including `Eof`, or up to the
+/// first error.
+pub struct Lexer<'a, BUF> {
+    priv rd: &'a mut BUF,
+    // the input read from `rd` so far, and the position of the next
+    // character in it
+    priv buf: ~[u8],
+    priv pos: uint,
+    // the current character, and how many bytes of the input it took up
+    priv current_char: IoResult<char>,
+    priv width: uint,
+    // whether invalid UTF-8 is read as U+FFFD, and whether reading stopped
+    // at invalid UTF-8 otherwise
+    priv lossy_utf8: bool,
+    priv invalid_utf8: bool,
+    // the text of the token being read, reused between tokens
+    priv scratch: ~str,
+    // whether the text of verbatim tokens is left out, and whether the
+    // current one had to be rebuilt from escapes
+    priv borrowing: bool,
+    priv escaped: bool,
+    priv line: uint,
+    priv col: uint,
+    priv byte: uint,
+    priv mode: Mode,
+    // the brackets ('[' or '{') of the arrays and inline tables being read
+    priv nesting: ~[char],
+    priv double_header: bool,
+    priv done: bool,
+    // the longest string allowed, in bytes, and whether a string was longer
+    priv max_string_len: Option<uint>,
+    priv string_too_long: bool,
+    // what the current token is, like "string", if more than a character,
+    // and its line
+    priv reading: &'static str,
+    priv token_line: uint,
+    priv token_byte: uint,
+    // the most bytes to take from the reader, the bytes taken so far, and
+    // whether there were more
+    priv max_input_bytes: Option<uint>,
+    priv bytes_taken: uint,
+    priv input_too_long: bool,
+    // whether control characters are rejected in comments, and whether
+    // reading failed on one
+    priv strict: bool,
+    priv control: bool
+}
+
+impl<'a, BUF: Buffer> Lexer<'a, BUF> {
+    pub fn new(rd: &'a mut BUF) -> Lexer<'a, BUF> {
+        let mut lexer = Lexer { rd: rd, buf: ~[], pos: 0, current_char: Err(io::standard_error(EndOfFile)),
+                                width: 0, lossy_utf8: false, invalid_utf8: false,
+                                scratch: ~"", borrowing: false, escaped: false, line: 1, col: 1, byte: 0, mode: TopKey, nesting: ~[],
+                                double_header: false, done: false, max_string_len: None, string_too_long: false,
+                                reading: "", token_line: 1, token_byte: 0, max_input_bytes: None, bytes_taken: 0, input_too_long: false,
+                                strict: false, control: false };
+        lexer.current_char = lexer.read_char();
+        return lexer;
+    }
+
+    /// Leaves the text of keys, strings and comments out of their tokens
+    /// if it is written as it reads, without escapes. Such tokens are
+    /// empty; `verbatim` tells them apart, and their text is taken from
+    /// the source by their span.
+    pub fn set_borrowing(&mut self, borrowing: bool) {
+        self.borrowing = borrowing;
+    }
+
+    /// Whether the text of the last key, string or comment is its source
+    /// as written, without escapes, "\r\n" line breaks or a trimmed first
+    /// line break
+    pub fn verbatim(&self) -> bool {
+        !self.escaped
+    }
+
+    /// Fails strings (and quoted keys) longer than `max` bytes. The length
+    /// is checked as the string is read, so that no more than `max` bytes
+    /// and the rest of the buffer are taken up before failing.
+    pub fn set_max_string_len(&mut self, max: Option<uint>) {
+        self.max_string_len = max;
+    }
+
+    /// Whether reading failed on a string longer than allowed by
+    /// `set_max_string_len`
+    pub fn string_too_long(&self) -> bool {
+        self.string_too_long
+    }
+
+    /// Fails once more than `max` bytes are read. No more than that is
+    /// taken from the reader.
+    pub fn set_max_input_bytes(&mut self, max: Option<uint>) {
+        self.max_input_bytes = max;
+    }
+
+    /// Whether reading failed on input longer than allowed by
+    /// `set_max_input_bytes`
+    pub fn input_too_long(&self) -> bool {
+        self.input_too_long
+    }
+
+    /// How many bytes of the input were read, up to the current character.
+    /// At the end of the input, this is its length.
+    pub fn bytes_read(&self) -> uint {
+        self.byte
+    }
+
+    /// Reads bytes that are not valid UTF-8 as U+FFFD, one for each byte
+    /// that does not start a valid character and one for a character cut
+    /// off at the end of the input. Otherwise they are an error.
+    pub fn set_lossy_utf8(&mut self, lossy: bool) {
+        self.lossy_utf8 = lossy;
+    }
+
+    /// Whether reading failed on input that is not valid UTF-8, which
+    /// starts at `span`
+    pub fn invalid_utf8(&self) -> bool {
+        self.invalid_utf8
+    }
+
+    // Decodes the next character of the buffer, refilling it as needed.
+    // Errors are those of `Buffer::read_char`, and `InvalidInput` for
+    // invalid UTF-8.
+    fn read_char(&mut self) -> IoResult<char> {
+        if self.pos == self.buf.len() && !try!(self.fill()) {
+            self.width = 0;
+            return Err(io::standard_error(EndOfFile));
+        }
+        let first = self.buf[self.pos];
+        if first < 0x80 {
+            self.pos += 1;
+            self.width = 1;
+            return Ok(first as char);
+        }
+
+        let width = str::utf8_char_width(first);
+        if width == 0 { return self.invalid_char(1) }
+        // the rest of the character may be in the next chunk
+        while self.buf.len() - self.pos < width {
+            if !try!(self.fill()) {
+                let rest = self.buf.len() - self.pos;
+                return self.invalid_char(rest);
+            }
+        }
+        let c = match str::from_utf8(self.buf.slice(self.pos, self.pos + width)) {
+            Some(s) => s.char_at(0),
+            None => { return self.invalid_char(1) }
+        };
+        self.pos += width;
+        self.width = width;
+        return Ok(c);
+    }
+
+    // Reads the next `width` bytes, which are not valid UTF-8, as U+FFFD
+    // if `set_lossy_utf8` was given true, and fails otherwise
+    fn invalid_char(&mut self, width: uint) -> IoResult<char> {
+        if !self.lossy_utf8 {
+            self.invalid_utf8 = true;
+            return Err(io::standard_error(InvalidInput));
+        }
+        self.pos += width;
+        self.width = width;
+        return Ok('\uFFFD');
+    }
+
+    // Appends the next chunk of the reader to the unread part of the
+    // buffer. Returns false at the end of the input.
+    fn fill(&mut self) -> IoResult<bool> {
+        // the unread part is at most the start of a character
+        let unread = self.buf.len() - self.pos;
+        for i in range(0, unread) {
+            self.buf[i] = self.buf[self.pos + i];
+        }
+        self.buf.truncate(unread);
+        self.pos = 0;
+
+        let len = match self.rd.fill_buf() {
+            Ok(chunk) => {
+                let mut len = cmp::min(chunk.len(), FILL_SIZE);
+                match self.max_input_bytes {
+                    Some(max) if self.bytes_taken + len > max => {
+                        len = max - self.bytes_taken;
+                        if len == 0 {
+                            self.input_too_long = true;
+                            return Err(io::standard_error(InvalidInput));
+                        }
+                    }
+                    _ => {}
+                }
+                self.buf.push_all(chunk.slice_to(len));
+                len
+            }
+            Err(IoError { kind: EndOfFile, .. }) => { return Ok(false) }
+            Err(e) => { return Err(e) }
+        };
+        self.rd.consume(len);
+        self.bytes_taken += len;
+        return Ok(len > 0);
+    }
+
+    /// The line of the current character
+    pub fn get_line(&self) -> uint { self.line }
+
+    /// How many arrays and inline tables are open
+    pub fn nesting_depth(&self) -> uint { self.nesting.len() }
+
+    /// The position of the current character, as an empty span
+    pub fn span(&self) -> Span {
+        Span { line: self.line, col: self.col, byte_start: self.byte, byte_end: self.byte }
+    }
+
+    /// If reading a token failed because the input ended in it, what the
+    /// token was, like "string", and the line it started on
+    pub fn unfinished(&self) -> Option<(&'static str, uint)> {
+        match self.current_char {
+            Err(IoError { kind: EndOfFile, .. }) if !self.reading.is_empty() => Some((self.reading, self.token_line)),
+            _ => None
+        }
+    }
+
+    /// Rejects control characters other than tabs in comments, as TOML
+    /// 1.0 does. Otherwise only NUL is rejected there.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// If reading failed on a control character, which are not allowed
+    /// outside strings other than tabs and line breaks, nor NUL in
+    /// comments, that character
+    pub fn control_char(&self) -> Option<char> {
+        if self.control { self.ch() } else { None }
+    }
+
+    /// Describes what was expected where reading a token failed
+    pub fn expected(&self) -> &'static str {
+        match self.mode {
+            TopKey => "expected a key or a section header",
+            HeaderName => "expected a section name",
+            // a line break before any ']'
+            HeaderEnd if self.byte == self.token_byte && (self.ch() == Some('\n') || self.ch() == Some('\r')) => {
+                "unterminated table header"
+            }
+            HeaderEnd if self.double_header => "expected ']]' to close array-of-tables header",
+            HeaderEnd => "expected ']' after section name",
+            AfterKey => "expected '=' after key",
+            Value => "invalid value",
+            AfterValue if self.nesting.last() == Some(&'{') => "expected ',' or '}' after inline table value",
+            AfterValue => "expected ',' or ']' after array element",
+            InlineKey => "expected a key or '}' in inline table"
+        }
+    }
+
+    fn advance(&mut self) {
+        match self.current_char {
+            Ok('\n') => {
+                self.line += 1;
+                self.col = 1;
+                self.byte += 1;
+            }
+            Ok(_) => {
+                self.col += 1;
+                self.byte += self.width;
+            }
+            Err(_) => { self.col += 1 }
+        }
+        self.current_char = self.read_char();
+    }
+
+    fn ch(&self) -> Option<char> {
+        match self.current_char {
+            Ok(c) => Some(c),
+            Err(_) => None
+        }
+    }
+
+    // Whether the current character is the '\r' of a "\r\n" line break.
+    // The '\n' is looked for in the buffer, refilling it if the '\r' was
+    // its last byte.
+    fn at_crlf(&mut self) -> bool {
+        if self.ch() != Some('\r') { return false }
+        if self.pos == self.buf.len() {
+            match self.fill() {
+                Ok(true) => {}
+                _ => { return false }
+            }
+        }
+        return self.buf[self.pos] == '\n' as u8;
+    }
+
+    /// Returns any error encountered by the lexer. Returns `None` for EndOfFile.
+    fn to_err(&self) -> Option<IoError> {
+        match self.current_char {
+            Ok(_) | Err(IoError{kind: EndOfFile, ..}) => None,
+            Err(ref e) => Some(e.clone())
+        }
+    }
+
+    fn advance_if(&mut self, c: char) -> bool {
+        match self.ch() {
+            Some(ch) if ch == c => {
+               self.advance();
+               true
+            }
+            _ => {
+                false
+            }
+        }
+    }
+
+    /// Reads the next token
+    pub fn next_token(&mut self) -> Result<(Token, Span), Error> {
+        // inside a header, '#' and line breaks are no tokens of their own
+        let in_header = self.mode == HeaderName || self.mode == HeaderEnd;
+        self.skip_whitespaces();
+        let mut span = self.span();
+        self.scratch.truncate(0);
+        self.escaped = false;
+        self.reading = "";
+        self.token_line = span.line;
+        self.token_byte = span.byte_start;
+        self.control = false;
+
+        let ch = match self.ch() {
+            Some(ch) => ch,
+            None => {
+                return match self.to_err() {
+                    Some(e) => Err(IOError(e)),
+                    None => Ok((Eof, span))
+                };
+            }
+        };
+
+        let token = match ch {
+            _ if is_control(ch) => {
+                self.control = true;
+                return Err(ParseError);
+            }
+            _ if in_header => {
+                match self.read_significant(ch) {
+                    Some(token) => token,
+                    None => { return Err(ParseError) }
+                }
+            }
+            '\r' if self.at_crlf() => {
+                self.advance();
+                self.advance();
+                Newline
+            }
+            '\n' => {
+                self.advance();
+                Newline
+            }
+            // a '\r' on its own is no line break
+            '\r' => { return Err(ParseError) }
+            '#' => {
+                self.advance();
+                loop {
+                    self.scan_ascii(true, None, |b| (b >= 0x20 && b != 0x7f) || b == '\t' as u8);
+                    match self.ch() {
+                        Some('\r') if self.at_crlf() => { break }
+                        Some('\x00') => {
+                            self.control = true;
+                            return Err(ParseError);
+                        }
+                        Some(c) if self.strict && (is_control(c) || c == '\r') => {
+                            self.control = true;
+                            return Err(ParseError);
+                        }
+                        Some(c) if c != '\n' => {
+                            self.scratch.push_char(c);
+                            self.advance();
+                        }
+                        _ => { break }
+                    }
+                }
+                Comment(self.scratch_copy())
+            }
+            _ => {
+                match self.read_significant(ch) {
+                    Some(token) => token,
+                    None => { return Err(ParseError) }
+                }
+            }
+        };
+        span.byte_end = self.byte;
+        return Ok((token, span));
+    }
+
+    // Reads a token that is not a comment or line break, and determines
+    // what is expected after it
+    fn read_significant(&mut self, ch: char) -> Option<Token> {
+        match self.mode {
+            TopKey => {
+                if ch == '[' {
+                    self.advance();
+                    self.double_header = self.advance_if('[');
+                    self.mode = HeaderName;
+                    return Some(if self.double_header { DoubleLBracket } else { LBracket });
+                }
+                let key = self.read_key(false);
+                if key.is_some() { self.mode = AfterKey }
+                return key;
+            }
+            HeaderName => {
+                self.mode = HeaderEnd;
+                if ch == '"' || ch == '\'' {
+                    return self.parse_string(false).map(|key| Key(key));
+                }
+                return Some(Key(self.parse_section_identifier()));
+            }
+            HeaderEnd => {
+                if self.advance_if('.') {
+                    self.mode = HeaderName;
+                    return Some(Dot);
+                }
+                if !self.advance_if(']') { return None }
+                self.mode = TopKey;
+                if !self.double_header { return Some(RBracket) }
+                if !self.advance_if(']') { return None }
+                return Some(DoubleRBracket);
+            }
+            AfterKey => {
+                if !self.advance_if('=') { return None }
+                self.mode = Value;
+                return Some(Equals);
+            }
+            InlineKey => {
+                if ch == '}' { return self.close('{') }
+                let key = self.read_key(true);
+                if key.is_some() { self.mode = AfterKey }
+                return key;
+            }
+            Value => {
+                match ch {
+                    '[' => {
+                        self.advance();
+                        self.nesting.push('[');
+                        return Some(LBracket);
+                    }
+                    ']' => { return self.close('[') }
+                    '{' => {
+                        self.advance();
+                        self.nesting.push('{');
+                        self.mode = InlineKey;
+                        return Some(LBrace);
+                    }
+                    _ => {}
+                }
+                let token = self.read_scalar(ch);
+                if token.is_some() { self.end_value() }
+                return token;
+            }
+            AfterValue => {
+                match ch {
+                    ',' => {
+                        self.advance();
+                        self.mode = if self.nesting.last() == Some(&'{') { InlineKey } else { Value };
+                        return Some(Comma);
+                    }
+                    ']' => { return self.close('[') }
+                    '}' => { return self.close('{') }
+                    _ => { return None }
+                }
+            }
+        }
+    }
+
+    // Reads the bracket closing the innermost array ('[') or inline table
+    // ('{')
+    fn close(&mut self, open: char) -> Option<Token> {
+        if self.nesting.last() != Some(&open) { return None }
+        self.advance();
+        self.nesting.pop();
+        self.end_value();
+        return Some(if open == '[' { RBracket } else { RBrace });
+    }
+
+    fn end_value(&mut self) {
+        self.mode = if self.nesting.is_empty() { TopKey } else { AfterValue };
+    }
+
+    // Reads a bare or quoted key. Inside inline tables, bare keys also end
+    // at ',' and '}'.
+    fn read_key(&mut self, inline: bool) -> Option<Token> {
+        match self.ch() {
+            Some('"') | Some('\'') => {
+                return self.parse_string(false).map(|key| Key(key));
+            }
+            _ => {}
+        }
+        let key = self.read_token(|ch| {
+            match ch {
+                ' ' | '\t' | '\r' | '\n' | '=' => false,
+                ',' | '}' => !inline,
+                _ => !is_control(ch)
+            }
+        });
+        if inline && key.is_empty() { return None }
+        return Some(Key(key));
+    }
+
+    fn read_scalar(&mut self, ch: char) -> Option<Token> {
+        match ch {
+            '-' | '+' | '0' .. '9' | 'i' | 'n' => {
+                return self.parse_number();
+            }
+            't' => {
+                self.reading = "boolean";
+                self.advance();
+                if self.read_keyword_rest("rue") { return Some(BoolLit(true)) }
+                return None;
+            }
+            'f' => {
+                self.reading = "boolean";
+                self.advance();
+                if self.read_keyword_rest("alse") { return Some(BoolLit(false)) }
+                return None;
+            }
+            '"' | '\'' => {
+                return self.parse_string(true).map(|s| StringLit(s));
+            }
+            _ => { return None }
+        }
+    }
+
+    fn read_digit(&mut self, radix: uint) -> Option<u8> {
+        match self.ch() {
+            Some(ch) => {
+                match char::to_digit(ch, radix) {
+                    Some(n) => {
+                        self.advance();
+                        Some(n as u8)
+                    }
+                    None => { None }
+                }
+            }
+            None => { None }
+        }
+    }
+
+    fn read_two_digits(&mut self) -> Option<u8> {
+        let d1 = self.read_digit(10);
+        let d2 = self.read_digit(10);
+        match (d1, d2) {
+            (Some(d1), Some(d2)) => Some(d1*10+d2),
+            _ => None
+        }
+    }
+
+    // Appends decimal digits to the scratch buffer. Returns false if
+    // there are none.
+    fn scan_digits(&mut self) -> bool {
+        let len = self.scratch.len();
+        self.scan(|ch| ch >= '0' && ch <= '9');
+        return self.scratch.len() > len;
+    }
+
+    // reads the rest of a keyword like "true" after its first character
+    fn read_keyword_rest(&mut self, rest: &str) -> bool {
+        for c in rest.chars() {
+            if !self.advance_if(c) { return false }
+        }
+        return true;
+    }
+
+    //
+    // Integers, floats (with a fraction, an exponent or both), inf and nan,
+    // each with an optional sign, and datetimes.
+    //
+    fn parse_number(&mut self) -> Option<Token> {
+        self.reading = "number";
+        let negative = self.ch() == Some('-');
+        let signed = self.advance_if('-') || self.advance_if('+');
+
+        match self.ch() {
+            Some('i') => {
+                self.advance();
+                if !self.read_keyword_rest("nf") { return None }
+                return Some(FloatLit(if negative { f64::NEG_INFINITY } else { f64::INFINITY }));
+            }
+            Some('n') => {
+                self.advance();
+                if !self.read_keyword_rest("an") { return None }
+                return Some(FloatLit(f64::NAN));
+            }
+            _ => {}
+        }
+
+        // the number is collected in the scratch buffer, starting with
+        // its integer part
+        if !self.scan_digits() { return None }
+        let int_len = self.scratch.len();
+
+        if !signed && int_len == 4 && self.ch() == Some('-') {
+            self.advance();
+            let year = from_str::<u16>(self.scratch.as_slice()).unwrap();
+            return self.parse_datetime_rest(year);
+        }
+
+        let mut is_float = false;
+
+        if self.advance_if('.') {
+            self.scratch.push_char('.');
+            if !self.scan_digits() { return None }
+            is_float = true;
+        }
+
+        if self.advance_if('e') || self.advance_if('E') {
+            self.scratch.push_char('e');
+            if self.advance_if('-') { self.scratch.push_char('-') }
+            else { self.advance_if('+'); }
+            if !self.scan_digits() { return None }
+            is_float = true;
+        }
+
+        if is_float {
+            // the sign is applied afterwards, so that "-0.0" is negative
+            // zero. The emitter relies on floats being read this way.
+            match from_str::<f64>(self.scratch.as_slice()) {
+                Some(num) => { return Some(FloatLit(if negative { -num } else { num })) }
+                None => { return None }
+            }
+        }
+
+        match from_str::<u64>(self.scratch.slice_to(int_len)) {
+            Some(n) => { return Some(IntLit(negative, n)) }
+            None => {
+                debug!("Integer out of range");
+                return None;
+            }
+        }
+    }
+
+    // parses a datetime after its year and the following '-'
+    fn parse_datetime_rest(&mut self, year: u16) -> Option<Token> {
+        self.reading = "datetime";
+        let month = self.read_two_digits();
+        if month.is_none() || !self.advance_if('-') {
+            debug!("Invalid Datetime");
+            return None;
+        }
+
+        let day = self.read_two_digits();
+        if day.is_none() || !self.advance_if('T'){
+            debug!("Invalid Datetime");
+            return None;
+        }
+
+        let hour = self.read_two_digits();
+        if hour.is_none() || !self.advance_if(':') {
+            debug!("Invalid Datetime");
+            return None;
+        }
+
+        let min = self.read_two_digits();
+        if min.is_none() || !self.advance_if(':') {
+            debug!("Invalid Datetime");
+            return None;
+        }
+
+        let sec = self.read_two_digits();
+        if sec.is_none() || !self.advance_if('Z') {
+            debug!("Invalid Datetime");
+            return None;
+        }
+
+        match (month, day, hour, min, sec) {
+            (Some(m), Some(d), Some(h), Some(min), Some(s))
+            if m > 0 && m <= 12 && d > 0 && d <= 31 &&
+               h <= 24 && min <= 60 && s <= 60 => {
+                return Some(DatetimeLit(year,m,d,h,min,s))
+            }
+            _ => {
+                debug!("Invalid Datetime range");
+                return None;
+            }
+        }
+    }
+
+    //
+    // Parses a basic ("...") or literal ('...') string, or their multi-line
+    // forms if `multiline` is true. Keys cannot be multi-line strings.
+    //
+    fn parse_string(&mut self, multiline: bool) -> Option<~str> {
+        let quote = match self.ch() {
+            Some('"') => '"',
+            Some('\'') => '\'',
+            _ => { return None }
+        };
+        self.reading = if multiline { "string" } else { "quoted key" };
+        self.advance();
+
+        if self.advance_if(quote) {
+            if !self.advance_if(quote) { return Some(~"") } // empty string
+            if !multiline { return None }
+
+            // a newline directly after the opening quotes is trimmed
+            if self.at_crlf() { self.advance() }
+            if self.advance_if('\n') { self.escaped = true }
+
+            if quote == '"' { return self.parse_basic_string(true) }
+            else { return self.parse_literal_string(true) }
+        }
+
+        if quote == '"' { return self.parse_basic_string(false) }
+        else { return self.parse_literal_string(false) }
+    }
+
+    //
+    // Checks for the closing quotes after the first `quote` was read. If
+    // they don't follow, the quotes read are part of the string.
+    //
+    fn read_closing_quotes(&mut self, quote: char, multiline: bool) -> bool {
+        if !multiline { return true }
+        if !self.advance_if(quote) {
+            self.scratch.push_char(quote);
+            return false;
+        }
+        if !self.advance_if(quote) {
+            self.scratch.push_char(quote);
+            self.scratch.push_char(quote);
+            return false;
+        }
+        return true;
+    }
+
+    fn read_unicode_escape(&mut self, ndigits: uint) -> Option<char> {
+        let mut ch = 0u32;
+        for _ in range(0, ndigits) {
+            match self.read_digit(16) {
+                Some(d) => { ch = (ch << 4) | d as u32 }
+                None => { return None }
+            }
+        }
+        return char::from_u32(ch);
+    }
+
+    // The string is collected in the scratch buffer
+    fn parse_basic_string(&mut self, multiline: bool) -> Option<~str> {
+        loop {
+            let max = self.max_string_len;
+            self.scan_ascii(true, max, |b| b >= 0x20 && b != '"' as u8 && b != '\\' as u8);
+            if self.ch().is_none() || !self.check_string_len() { return None }
+            match self.ch().unwrap() {
+                // "\r\n" is read as "\n"
+                '\r' if multiline && self.at_crlf() => {
+                    self.advance();
+                    self.escaped = true;
+                }
+                '\n' if multiline => {
+                    self.scratch.push_char('\n');
+                    self.advance();
+                }
+                '\r' | '\n' | '\u000C' | '\u0008' => { return None }
+                '\\' => {
+                    self.advance();
+                    self.escaped = true;
+                    if self.ch().is_none() { return None }
+                    match self.ch().unwrap() {
+                        'b' => { self.scratch.push_char('\u0008'); self.advance() },
+                        't' => { self.scratch.push_char('\t'); self.advance() },
+                        'n' => { self.scratch.push_char('\n'); self.advance() },
+                        'f' => { self.scratch.push_char('\u000C'); self.advance() },
+                        'r' => { self.scratch.push_char('\r'); self.advance() },
+                        '"' => { self.scratch.push_char('"'); self.advance() },
+                        '/' => { self.scratch.push_char('/'); self.advance() },
+                        '\\' => { self.scratch.push_char('\\'); self.advance() },
+                        'u' | 'U' => {
+                            let ndigits = if self.ch() == Some('u') { 4 } else { 8 };
+                            self.advance();
+                            match self.read_unicode_escape(ndigits) {
+                                Some(ch) => { self.scratch.push_char(ch) }
+                                None => { return None }
+                            }
+                        }
+                        // a backslash at the end of a line removes the
+                        // line break and the whitespace that follows
+                        ' ' | '\t' | '\r' | '\n' if multiline => {
+                            self.skip_whitespaces();
+                            if self.at_crlf() { self.advance() }
+                            if !self.advance_if('\n') { return None }
+                            loop {
+                                match self.ch() {
+                                    Some(' ') | Some('\t') | Some('\n') => { self.advance() }
+                                    Some('\r') if self.at_crlf() => { self.advance() }
+                                    _ => { break }
+                                }
+                            }
+                        }
+                        _ => { return None }
+                    }
+                }
+                '"' => {
+                    self.advance();
+                    if self.read_closing_quotes('"', multiline) {
+                        return Some(self.scratch_copy());
+                    }
+                }
+                c => {
+                    self.scratch.push_char(c);
+                    self.advance();
+                }
+            }
+        }
+    }
+
+    // no escapes in literal strings
+    fn parse_literal_string(&mut self, multiline: bool) -> Option<~str> {
+        loop {
+            let max = self.max_string_len;
+            self.scan_ascii(true, max, |b| b >= 0x20 && b != '\'' as u8);
+            if self.ch().is_none() || !self.check_string_len() { return None }
+            match self.ch().unwrap() {
+                '\r' if multiline && self.at_crlf() => {
+                    self.advance();
+                    self.escaped = true;
+                }
+                '\n' if multiline => {
+                    self.scratch.push_char('\n');
+                    self.advance();
+                }
+                '\r' | '\n' | '\u000C' | '\u0008' => { return None }
+                '\'' => {
+                    self.advance();
+                    if self.read_closing_quotes('\'', multiline) {
+                        return Some(self.scratch_copy());
+                    }
+                }
+                c => {
+                    self.scratch.push_char(c);
+                    self.advance();
+                }
+            }
+        }
+    }
+
+    // Whether the string in the scratch buffer is within the limit. If not,
+    // that is recorded for `string_too_long`.
+    fn check_string_len(&mut self) -> bool {
+        match self.max_string_len {
+            Some(max) if self.scratch.len() > max => {
+                self.string_too_long = true;
+                false
+            }
+            _ => true
+        }
+    }
+
+    // Appends the characters accepted by `f` to the scratch buffer
+    fn scan(&mut self, f: |char| -> bool) {
+        loop {
+            match self.ch() {
+                Some(ch) => {
+                    if f(ch) { self.scratch.push_char(ch) }
+                    else { break }
+                }
+                None => { break }
+            }
+            self.advance();
+        }
+    }
+
+    // The text collected in the scratch buffer, copied into a string of
+    // its exact size
+    fn scratch_copy(&self) -> ~str {
+        if self.borrowing && !self.escaped { return ~"" }
+        self.scratch.as_slice().to_owned()
+    }
+
+    fn read_token(&mut self, f: |char| -> bool) -> ~str {
+        self.scan(f);
+        return self.scratch_copy();
+    }
+
+    // Reads a bare key of a header's name, which may hold spaces but not
+    // end with them
+    fn parse_section_identifier(&mut self) -> ~str {
+        self.scan(|ch| {
+            match ch {
+                '\t' | '\n' | '\r' | '[' | ']' | '.' | '"' | '\'' => false,
+                _ => !is_control(ch)
+            }
+        });
+        let len = self.scratch.trim_right().len();
+        if len < self.scratch.len() {
+            // the key is no longer its source as written
+            self.scratch.truncate(len);
+            self.escaped = true;
+        }
+        return self.scratch_copy();
+    }
+
+    // skips whitespace other than line breaks
+    fn skip_whitespaces(&mut self) {
+        self.scan_ascii(false, None, |b| b == ' ' as u8 || b == '\t' as u8);
+    }
+
+    //
+    // Reads the run of ASCII characters accepted by `f` from the current
+    // one on, appending them to the scratch buffer if `keep` is true. The
+    // run is found in the bytes of the buffer, without decoding and
+    // advancing character by character, so `f` must not accept '\n'.
+    // With a `max` length, the run stops once the scratch buffer is longer,
+    // rather than holding a whole chunk first.
+    //
+    fn scan_ascii(&mut self, keep: bool, max: Option<uint>, f: |u8| -> bool) {
+        loop {
+            let first = match self.current_char {
+                Ok(c) if (c as u32) < 0x80 => c,
+                _ => { return }
+            };
+            if !f(first as u8) { return }
+            let start = self.pos;
+            let limit = match max {
+                // one byte over `max`, with `first`, is enough to fail
+                Some(max) if keep && max > self.scratch.len() => {
+                    cmp::min(self.buf.len(), start + max - self.scratch.len())
+                }
+                Some(_) if keep => start,
+                _ => self.buf.len()
+            };
+            let mut end = start;
+            while end < limit && self.buf[end] < 0x80 && f(self.buf[end]) {
+                end += 1;
+            }
+            if keep {
+                self.scratch.push_char(first);
+                self.scratch.push_str(str::from_utf8(self.buf.slice(start, end)).unwrap());
+            }
+            self.col += end - start + 1;
+            self.byte += end - start + 1;
+            self.pos = end;
+            // the run may go on in the next chunk
+            self.current_char = self.read_char();
+            match max {
+                Some(max) if keep && self.scratch.len() > max => { return }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl<'a, BUF: Buffer> Iterator<Result<(Token, Span), Error>> for Lexer<'a, BUF> {
+    fn next(&mut self) -> Option<Result<(Token, Span), Error>> {
+        if self.done { return None }
+        let result = self.next_token();
+        match result {
+            Ok((Eof, _)) | Err(_) => { self.done = true }
+            _ => {}
+        }
+        return Some(result);
+    }
+}
+
+// Whether `c` is a control character other than a tab or line break
+fn is_control(c: char) -> bool {
+    (c < ' ' && c != '\t' && c != '\n' && c != '\r') || c == '\x7f'
+}
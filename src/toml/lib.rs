@@ -2,7 +2,7 @@
 #[desc = "A TOML configuration file parser for Rust"];
 #[license = "MIT"];
 #[crate_type = "lib"];
-#[feature(phase)];
+#[feature(phase, macro_rules, struct_variant)];
 
 /// A TOML [1] configuration file parser
 ///
@@ -12,21 +12,47 @@
 
 extern crate serialize;
 extern crate collections;
+extern crate sync;
 #[phase(syntax, link)] extern crate log;
 
-use std::char;
+use std::cmp;
+use std::ascii::StrAsciiExt;
+use std::{int,i8,i16,i32,i64,uint,u8,u16,u32,u64};
 use std::mem;
+use std::hash;
+use std::hash::Hash;
 
-use collections::hashmap::{HashMap,MoveEntries};
+use collections::hashmap::HashMap;
+use sync::Arc;
+use std::vec;
 use std::vec::MoveItems;
 
-use std::io::{File,IoError,IoResult,EndOfFile};
+use std::io::{File,IoError};
 use std::io::{Buffer,BufReader,BufferedReader};
 use std::path::Path;
 
-use serialize::Decodable;
+use serialize::{Decodable,Encodable};
 
 use std::fmt;
+use std::iter::{FromIterator,Extendable};
+
+pub use table::TableMap;
+pub use borrowed::{BorrowedValue,BorrowedTable,parse_borrowed,parse_borrowed_with_options};
+pub use lexer::{Lexer,Token};
+pub use json::{from_json_str,from_json_str_with,json_to_toml_str,json_to_toml_str_with};
+pub use document::{Document,parse_document};
+pub use frozen::FrozenValue;
+pub use stats::{Stats,StatsVisitor};
+
+pub mod borrowed;
+mod document;
+mod emitter;
+mod frozen;
+mod json;
+pub mod lexer;
+mod parser;
+mod stats;
+pub mod table;
 
 #[deriving(Clone)]
 pub enum Value {
@@ -39,7 +65,7 @@ pub enum Value {
     Datetime(u16,u8,u8,u8,u8,u8),
     Array(~[Value]),
     TableArray(~[Value]),
-    Table(bool, ~HashMap<~str, Value>) // bool=true iff section already defiend
+    Table(bool, ~TableMap) // bool=true iff section already defiend
 }
 
 impl fmt::Show for Value {
@@ -61,50 +87,719 @@ impl fmt::Show for Value {
     }
 }
 
+/// Structural equality. Tables compare as maps (key order and whether a
+/// table was explicitly defined do not matter), arrays element-wise.
+/// `PosInt` and `NegInt` compare by numeric value, so `-0` equals `0`.
+/// Floats use `f64` equality, so `Float(NaN)` is never equal to itself.
+impl Eq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (&NoValue, &NoValue) => true,
+            (&Boolean(a), &Boolean(b)) => a == b,
+            (&PosInt(a), &PosInt(b)) => a == b,
+            (&NegInt(a), &NegInt(b)) => a == b,
+            (&PosInt(a), &NegInt(b)) | (&NegInt(b), &PosInt(a)) => a == 0 && b == 0,
+            (&Float(a), &Float(b)) => a == b,
+            (&String(ref a), &String(ref b)) => a == b,
+            (&Datetime(a1,a2,a3,a4,a5,a6), &Datetime(b1,b2,b3,b4,b5,b6)) => {
+                a1 == b1 && a2 == b2 && a3 == b3 && a4 == b4 && a5 == b5 && a6 == b6
+            }
+            (&Array(ref a), &Array(ref b)) => a == b,
+            (&TableArray(ref a), &TableArray(ref b)) => a == b,
+            (&Table(_, ref a), &Table(_, ref b)) => **a == **b,
+            _ => false
+        }
+    }
+}
+
+/// Hashing consistent with the equality above: tables hash independently
+/// of key order, `-0` hashes like `0`, and floats hash by bit pattern
+/// (with `-0.0` folded into `0.0`). `NoValue` and values containing NaN
+/// can be hashed, but as NaN is unequal to itself such values never
+/// match an existing key.
+impl<S: Writer> Hash<S> for Value {
+    fn hash(&self, state: &mut S) {
+        match *self {
+            NoValue => { 0u8.hash(state) }
+            Boolean(b) => { 1u8.hash(state); b.hash(state) }
+            PosInt(n) | NegInt(n) if n == 0 => { 2u8.hash(state); n.hash(state) }
+            PosInt(n) => { 2u8.hash(state); n.hash(state) }
+            NegInt(n) => { 3u8.hash(state); n.hash(state) }
+            Float(f) => {
+                let f = if f == 0.0 { 0.0 } else { f };
+                let bits: u64 = unsafe { mem::transmute(f) };
+                4u8.hash(state);
+                bits.hash(state);
+            }
+            String(ref s) => { 5u8.hash(state); s.hash(state) }
+            Datetime(a,b,c,d,e,f) => {
+                6u8.hash(state);
+                a.hash(state); b.hash(state); c.hash(state);
+                d.hash(state); e.hash(state); f.hash(state);
+            }
+            Array(ref arr) => { 7u8.hash(state); arr.hash(state) }
+            TableArray(ref arr) => { 8u8.hash(state); arr.hash(state) }
+            Table(_, ref map) => {
+                // XOR the entry hashes so that iteration order doesn't matter
+                let mut acc = 0u64;
+                for entry in map.iter() {
+                    acc ^= hash::hash(&entry);
+                }
+                9u8.hash(state);
+                map.len().hash(state);
+                acc.hash(state);
+            }
+        }
+    }
+}
+
+/// Returned by the `try_*` accessors when a value is not of the
+/// requested type
+#[deriving(Clone,Eq)]
+pub struct TypeMismatch {
+    expected: &'static str,
+    found: &'static str
+}
+
+impl fmt::Show for TypeMismatch {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt.buf, "expected {:s}, found {:s}", self.expected, self.found)
+    }
+}
+
+/// Reasons for a failed `lookup_detailed`
+#[deriving(Clone,Eq)]
+pub enum LookupError {
+    /// The path itself is malformed
+    InvalidPath,
+    /// The key at the end of `at` does not exist
+    KeyNotFound { at: ~str },
+    /// A key was applied to the non-table value at `at`
+    NotATable { at: ~str, found: &'static str },
+    /// An index was applied to the non-array value at `at`
+    NotAnArray { at: ~str, found: &'static str },
+    /// The array at `at` has only `len` elements
+    IndexOutOfBounds { at: ~str, len: uint },
+    /// The value at `at` is not of the expected type
+    WrongType { at: ~str, expected: &'static str, found: &'static str }
+}
+
+impl fmt::Show for LookupError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InvalidPath => write!(fmt.buf, "invalid path"),
+            KeyNotFound { at: ref at } => write!(fmt.buf, "key `{:s}` not found", *at),
+            NotATable { at: ref at, found: found } => {
+                write!(fmt.buf, "`{:s}` is {:s}, not a table", *at, found)
+            }
+            NotAnArray { at: ref at, found: found } => {
+                write!(fmt.buf, "`{:s}` is {:s}, not an array", *at, found)
+            }
+            IndexOutOfBounds { at: ref at, len: len } => {
+                write!(fmt.buf, "index out of bounds: `{:s}` has {} elements", *at, len)
+            }
+            WrongType { at: ref at, expected: expected, found: found } => {
+                write!(fmt.buf, "`{:s}`: expected {:s}, found {:s}", *at, expected, found)
+            }
+        }
+    }
+}
+
+/// How `Value::merge_with` combines two arrays (or arrays of tables)
+#[deriving(Clone,Eq,Show)]
+pub enum ArrayMerge {
+    /// The overlay's array replaces the original one
+    Replace,
+    /// The overlay's elements are appended to the original ones
+    Append,
+    /// Elements at the same index are merged, extra ones appended
+    MergeByIndex
+}
+
+/// Options for `Value::merge_with`
+#[deriving(Clone,Eq,Show)]
+pub struct MergeOptions {
+    arrays: ArrayMerge,
+    /// Whether a `NoValue` in the overlay removes the key. Otherwise
+    /// such entries are ignored.
+    delete_on_novalue: bool,
+    /// Whether a value in the overlay replaces one of a different type,
+    /// e.g. a string replacing a table. Otherwise that's an error.
+    overlay_wins: bool
+}
+
+impl MergeOptions {
+    /// The options used by `Value::merge`
+    pub fn new() -> MergeOptions {
+        MergeOptions { arrays: Replace, delete_on_novalue: false, overlay_wins: true }
+    }
+}
+
+/// A single difference found by `Value::diff`
+#[deriving(Clone,Eq,Show)]
+pub struct DiffEntry {
+    path: ~str,
+    kind: DiffKind
+}
+
+#[deriving(Clone,Eq,Show)]
+pub enum DiffKind {
+    Added(Value),
+    Removed(Value),
+    Changed { old: Value, new: Value }
+}
+
+/// Layout options for `Value::to_toml_str_with` and `write_toml_with`
+#[deriving(Clone,Eq,Show)]
+pub struct EmitOptions {
+    /// Tables with at most this many entries are written inline, as
+    /// `key = { a = 1, b = 2 }`. 0 writes all tables with `[a.b]` headers.
+    inline_tables: uint,
+    /// Whether arrays of tables are written as inline arrays of inline
+    /// tables instead of one `[[a]]` section per element
+    inline_table_arrays: bool,
+    /// Arrays whose `key = [...]` line would be longer than this are
+    /// written with one element per line. 0 means no limit.
+    max_width: uint,
+    /// Indentation of the elements of multi-line arrays
+    indent: ~str,
+    /// Whether all non-ASCII characters of strings and keys are written
+    /// as `\uXXXX` (or `\UXXXXXXXX`) escapes
+    ascii_only: bool,
+    /// Whether keys are written in sorted order instead of the order of
+    /// the table
+    sort_keys: bool,
+    /// Whether all strings are written as single-line basic strings,
+    /// instead of choosing literal or multi-line strings where they read
+    /// better
+    basic_strings: bool,
+    /// Whether `-0.0` is written as `0.0`
+    fold_negative_zero: bool
+}
+
+impl EmitOptions {
+    /// The options used by `Value::to_toml_str`
+    pub fn new() -> EmitOptions {
+        EmitOptions {
+            inline_tables: 0,
+            inline_table_arrays: false,
+            max_width: 0,
+            indent: ~"    ",
+            ascii_only: false,
+            sort_keys: false,
+            basic_strings: false,
+            fold_negative_zero: false
+        }
+    }
+
+    /// Options producing a canonical form: keys sorted at every level,
+    /// tables always with headers, strings always as basic strings, and
+    /// `-0.0` written as `0.0`. Values equal under `Eq` produce identical
+    /// output. As datetimes are always UTC, they need no normalization.
+    pub fn canonical() -> EmitOptions {
+        EmitOptions {
+            sort_keys: true,
+            basic_strings: true,
+            fold_negative_zero: true,
+            .. EmitOptions::new()
+        }
+    }
+}
+
+/// What to do with a key defined twice in the same table. Only keys
+/// holding plain values are affected: redefining a table, whether by a
+/// header, a dotted header or an inline table, is always an error.
+#[deriving(Clone,Eq,Show)]
+pub enum DuplicatePolicy {
+    /// Fail with `Rejected`
+    Reject,
+    /// Ignore later definitions, with a warning
+    KeepFirst,
+    /// Let later definitions replace earlier ones, with a warning
+    KeepLast
+}
+
+/// A version of the TOML specification, see `ParserOptions::version`
+#[deriving(Clone,Eq,Show)]
+pub enum TomlVersion {
+    /// TOML 0.4.0: the elements of an array are all of one type, and
+    /// floats are finite
+    V0_4,
+    /// TOML 1.0.0
+    V1_0
+}
+
+/// Options for `parse_with_options`. `parse_borrowed_with_options` only
+/// uses `max_table_depth` and `version`.
+#[deriving(Clone,Eq,Show)]
+pub struct ParserOptions {
+    /// Whether to record where each value was found, see
+    /// `ParsedDocument::span`
+    record_spans: bool,
+    /// How keys defined twice are treated
+    duplicates: DuplicatePolicy,
+    /// How many keys a section header may have, e.g. 3 for `[a.b.c]`.
+    /// Deeper sections are rejected.
+    max_table_depth: uint,
+    /// The longest string or quoted key allowed, in bytes
+    max_string_len: Option<uint>,
+    /// The most elements an array may have
+    max_array_len: Option<uint>,
+    /// The most keys the document may define, counting those of pairs and
+    /// inline tables
+    max_total_keys: Option<uint>,
+    /// The most bytes to read from the input. Reading stops there, and
+    /// longer documents are rejected.
+    max_input_bytes: Option<uint>,
+    /// Whether bytes that are not valid UTF-8 are read as U+FFFD, for
+    /// files in legacy encodings, instead of being an error
+    lossy_utf8: bool,
+    /// Whether to reject what TOML 1.0 forbids but this parser lets
+    /// through otherwise: control characters other than tabs in comments,
+    /// and values starting on a line after their `=`
+    strict: bool,
+    /// The version of TOML the document has to conform to. What later
+    /// versions added is rejected, such as arrays of mixed types, inf and
+    /// nan in TOML 0.4.
+    version: TomlVersion
+}
+
+impl ParserOptions {
+    /// The options used by `parse_from_buffer`. There are no size limits.
+    pub fn new() -> ParserOptions {
+        ParserOptions {
+            record_spans: false,
+            duplicates: Reject,
+            max_table_depth: MAX_TABLE_DEPTH,
+            max_string_len: None,
+            max_array_len: None,
+            max_total_keys: None,
+            max_input_bytes: None,
+            lossy_utf8: false,
+            strict: false,
+            version: V1_0
+        }
+    }
+}
+
+// The default of `ParserOptions::max_table_depth`
+static MAX_TABLE_DEPTH: uint = 64;
+
+// How deeply arrays and inline tables may nest. Values are parsed
+// recursively, so without a limit a long run of '[' would overflow the
+// stack.
+static MAX_VALUE_DEPTH: uint = 128;
+
+/// What to do with JSON `null`, which has no TOML representation
+#[deriving(Clone,Eq,Show)]
+pub enum NullPolicy {
+    /// Leave out object members that are `null`. A `null` inside an array
+    /// is an error.
+    SkipNull,
+    /// Make `null` a `NoValue`, which cannot be written as TOML
+    KeepNull,
+    /// Fail at any `null`
+    RejectNull,
+    /// Make `null` an empty string
+    NullAsEmptyString
+}
+
+/// Options for `from_json_str_with`
+#[deriving(Clone,Eq,Show)]
+pub struct JsonOptions {
+    /// Whether strings of the form `1979-05-27T07:32:00Z` become
+    /// datetimes
+    datetimes: bool,
+    /// How `null` is treated
+    nulls: NullPolicy
+}
+
+impl JsonOptions {
+    /// The options used by `from_json_str`
+    pub fn new() -> JsonOptions {
+        JsonOptions { datetimes: false, nulls: SkipNull }
+    }
+}
+
+/// How `Value::write_to_file` replaced the file
+#[deriving(Clone,Eq,Show)]
+pub enum WriteStrategy {
+    /// The document was written to a temporary file, which was then
+    /// renamed over the destination
+    AtomicRename,
+    /// Renaming failed, so the destination was overwritten directly
+    DirectWrite
+}
+
+/// Returned by `Value::to_toml_str` for values that cannot be written as
+/// a TOML document
+#[deriving(Clone,Eq)]
+pub enum EmitError {
+    /// The root of a document must be a table, not the given type
+    RootNotATable(&'static str),
+    /// The value at `at` has no TOML representation
+    Unrepresentable { at: ~str, reason: &'static str }
+}
 
+impl fmt::Show for EmitError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RootNotATable(found) => {
+                write!(fmt.buf, "document root must be a table, found {:s}", found)
+            }
+            Unrepresentable { at: ref at, reason: reason } => {
+                write!(fmt.buf, "`{:s}`: {:s}", *at, reason)
+            }
+        }
+    }
+}
 
 /// Possible errors returned from the parse functions
 #[deriving(Show,Clone,Eq)]
 pub enum Error {
     /// An parser error occurred during parsing
     ParseError,
+    /// The visitor rejected the section or pair at `line`, see `Visitor`,
+    /// the document exceeded a limit of `ParserOptions` there, or the
+    /// input is not valid UTF-8 there
+    Rejected { line: uint, reason: ~str },
     /// An I/O error occurred during parsing
     IOError(IoError)
 }
 
+/// An error of `parse_located`, with the position where it was found and
+/// a message for users, like "expected ']' after section name". It is
+/// formatted as `LINE:COLUMN: MESSAGE`.
+#[deriving(Clone,Eq)]
+pub struct Diagnostic {
+    error: Error,
+    span: Span,
+    message: ~str
+}
+
+impl Diagnostic {
+    /// Formats the diagnostic followed by the line of `source` it refers
+    /// to and a caret under its column. Tabs before the column are kept
+    /// in the caret line so that the caret lines up however tabs are
+    /// displayed, and every other character counts as one column. If
+    /// `source` has no such line, only the diagnostic is formatted.
+    pub fn render(&self, source: &str) -> ~str {
+        let header = self.to_str();
+        if self.span.line == 0 { return header }
+        let line = match source.split('\n').nth(self.span.line - 1) {
+            Some(line) => line.trim_right_chars(&'\r'),
+            None => { return header }
+        };
+        let mut caret = ~"";
+        for c in line.chars().take(self.span.col - 1) {
+            caret.push_char(if c == '\t' { '\t' } else { ' ' });
+        }
+        caret.push_char('^');
+        return format!("{}\n{}\n{}", header, line, caret);
+    }
+}
+
+impl fmt::Show for Diagnostic {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt.buf, "{}:{}: {:s}", self.span.line, self.span.col, self.message)
+    }
+}
+
+/// Returned by `roundtrip_check`
+#[deriving(Clone,Eq)]
+pub enum RoundtripError {
+    /// The input itself does not parse
+    InputError(Error),
+    /// The parsed input cannot be emitted
+    EmitFailed(EmitError),
+    /// The emitted text does not parse
+    ReparseError { error: Error, emitted: ~str },
+    /// The emitted text parses to a different value, first at `path`
+    Mismatch { path: ~str, emitted: ~str }
+}
+
+impl fmt::Show for RoundtripError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InputError(ref e) => write!(fmt.buf, "input does not parse: {}", *e),
+            EmitFailed(ref e) => write!(fmt.buf, "cannot emit: {}", *e),
+            ReparseError { error: ref e, .. } => {
+                write!(fmt.buf, "emitted document does not parse: {}", *e)
+            }
+            Mismatch { path: ref path, .. } => {
+                write!(fmt.buf, "emitted document differs at `{:s}`", *path)
+            }
+        }
+    }
+}
+
+/// Options for `decode_with`
+#[deriving(Clone,Eq,Show)]
+pub struct DecodeOptions {
+    /// Whether keys of a table that no struct field was decoded from are
+    /// an error. Maps take all keys, so this only affects structs.
+    deny_unknown_keys: bool,
+    /// Whether a `-` in keys matches a `_` in field names, so that
+    /// `max-connections` fills `max_connections`
+    kebab_case: bool,
+    /// Whether keys match field names regardless of ASCII case
+    ignore_case: bool,
+    /// Keys to decode into differently named fields, as pairs of key and
+    /// field name. A renamed key matches only its given field.
+    renames: ~[(~str, ~str)]
+}
+
+impl DecodeOptions {
+    /// The options used by `decode`
+    pub fn new() -> DecodeOptions {
+        DecodeOptions { deny_unknown_keys: false, kebab_case: false, ignore_case: false, renames: ~[] }
+    }
+
+    fn key_matches(&self, key: &str, field: &str) -> bool {
+        match self.renames.iter().find(|&&(ref k, _)| k.as_slice() == key) {
+            Some(&(_, ref renamed)) => { return renamed.as_slice() == field }
+            None => {}
+        }
+        let key = if self.kebab_case { key.replace("-", "_") } else { key.to_owned() };
+        if self.ignore_case {
+            key.to_ascii_lower() == field.to_ascii_lower()
+        } else {
+            key.as_slice() == field
+        }
+    }
+}
+
+/// Errors of `decode`
+#[deriving(Clone,Eq)]
+pub enum DecodeError {
+    /// The table at `table` has no key for the non-optional `field`
+    MissingField { table: ~str, field: ~str },
+    /// The value at `at` is not of the expected type
+    InvalidType { at: ~str, expected: &'static str, found: &'static str },
+    /// The integer at `at` does not fit into `target`
+    OutOfRange { at: ~str, target: &'static str },
+    /// The type decoded at `at` cannot be represented in TOML
+    Unsupported { at: ~str, what: &'static str },
+    /// The table at `table` has keys no struct field was decoded from,
+    /// each with the closest field name if one is similar
+    UnknownKeys { table: ~str, keys: ~[(~str, Option<~str>)] },
+    /// The defaults given to `decode_with_defaults` cannot be encoded
+    InvalidDefaults(~str),
+    /// Several keys of the table at `table` match `field` under the key
+    /// matching of `DecodeOptions`
+    AmbiguousKeys { table: ~str, field: ~str, keys: ~[~str] }
+}
+
+impl fmt::Show for DecodeError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MissingField { table: ref table, field: ref field } => {
+                if table.is_empty() {
+                    write!(fmt.buf, "missing field `{:s}` at the top level", *field)
+                } else {
+                    write!(fmt.buf, "missing field `{:s}` in table `{:s}`", *field, *table)
+                }
+            }
+            InvalidType { at: ref at, expected: expected, found: found } => {
+                write!(fmt.buf, "`{:s}`: expected {:s}, found {:s}", *at, expected, found)
+            }
+            OutOfRange { at: ref at, target: target } => {
+                write!(fmt.buf, "`{:s}`: integer out of range for {:s}", *at, target)
+            }
+            Unsupported { at: ref at, what: what } => {
+                write!(fmt.buf, "`{:s}`: {:s} cannot be decoded", *at, what)
+            }
+            UnknownKeys { table: ref table, keys: ref keys } => {
+                if table.is_empty() {
+                    try!(write!(fmt.buf, "unknown keys at the top level: "));
+                } else {
+                    try!(write!(fmt.buf, "unknown keys in table `{:s}`: ", *table));
+                }
+                for (i, &(ref key, ref suggestion)) in keys.iter().enumerate() {
+                    if i > 0 { try!(fmt.buf.write_str(", ")) }
+                    try!(write!(fmt.buf, "`{:s}`", *key));
+                    match *suggestion {
+                        Some(ref name) => { try!(write!(fmt.buf, " (did you mean `{:s}`?)", *name)) }
+                        None => {}
+                    }
+                }
+                Ok(())
+            }
+            InvalidDefaults(ref e) => write!(fmt.buf, "invalid defaults: {:s}", *e),
+            AmbiguousKeys { table: ref table, field: ref field, keys: ref keys } => {
+                let keys: ~[~str] = keys.iter().map(|k| format!("`{}`", *k)).collect();
+                if table.is_empty() {
+                    write!(fmt.buf, "keys {:s} at the top level all match field `{:s}`",
+                           keys.connect(", "), *field)
+                } else {
+                    write!(fmt.buf, "keys {:s} in table `{:s}` all match field `{:s}`",
+                           keys.connect(", "), *table, *field)
+                }
+            }
+        }
+    }
+}
+
 //
+// `n` as a float, if one holds it exactly
+fn exact_float(n: u64) -> Option<f64> {
+    let f = n as f64;
+    // the integers closest to 2^64 round up to it, which is out of range
+    if f < 18446744073709551616.0 && f as u64 == n { Some(f) } else { None }
+}
+
 // This function determines if v1 and v2 have compatible ("equivalent") types
-// as TOML allows only arrays where all elements are of the same type.
+// as TOML 0.4 allows only arrays where all elements are of the same type.
 //
 fn have_equiv_types(v1: &Value, v2: &Value) -> bool {
-    match (v1, v2) {
-        (&Boolean(_), &Boolean(_)) => true,
-        (&PosInt(_), &PosInt(_)) => true,
-        (&PosInt(_), &NegInt(_)) => true,
-        (&NegInt(_), &PosInt(_)) => true,
-        (&NegInt(_), &NegInt(_)) => true,
-        (&Float(_), &Float(_)) => true,
-        (&String(_), &String(_)) => true,
-        (&Datetime(..), &Datetime(..)) => true,
-        (&Array(_), &Array(_)) => true, // Arrays can be heterogenous in TOML
-        _ => false
+    equiv_types(v1.type_str(), v2.type_str())
+}
+
+// The same for the names of the types, as given by `type_str`, so that
+// `BorrowedValue`s are compared alike. Integers share a name whatever
+// their sign, and arrays, which can be heterogenous in TOML, are all
+// "array".
+fn equiv_types(t1: &str, t2: &str) -> bool {
+    t1 == t2 && t1 != "array of tables" && t1 != "none"
+}
+
+enum PathElement {
+    Key(~str),
+    Idx(uint),
+    // A bare numeric segment: an index for arrays, a key for tables
+    KeyOrIdx(~str, uint),
+    // A bare "*", which lookup_all treats as a wildcard and all other
+    // lookups as the key "*"
+    Wildcard
+}
+
+// The key named by a path element which isn't an explicit index
+fn elm_key<'a>(elm: &'a PathElement) -> &'a str {
+    match *elm {
+        Key(ref key) | KeyOrIdx(ref key, _) => key.as_slice(),
+        Wildcard => "*",
+        Idx(..) => { unreachable!() }
+    }
+}
+
+//
+// Renders a key as a path segment that parse_path reads back as the same
+// key, quoting it if necessary. Numeric keys are quoted as well, so they
+// cannot be mistaken for indices.
+//
+fn path_segment(key: &str) -> ~str {
+    let needs_quotes = key.is_empty() || from_str::<uint>(key).is_some() ||
+        key.chars().any(|c| {
+            match c {
+                '.' | '[' | ']' | '"' | '\\' | ' ' | '\t' => true,
+                _ => false
+            }
+        });
+
+    if !needs_quotes { return key.to_owned() }
+
+    let mut seg = ~"\"";
+    for c in key.chars() {
+        if c == '"' || c == '\\' { seg.push_char('\\') }
+        seg.push_char(c);
     }
+    seg.push_char('"');
+    return seg;
 }
 
-enum PathElement<'a> {
-    Key(&'a str),
-    Idx(uint)
+//
+// Splits a lookup path into its elements. Segments are separated by '.';
+// a segment may be quoted ("127.0.0.1") to include dots, in which case a
+// backslash escapes the character following it. Each segment can be
+// followed by any number of "[n]" indices. Returns None if the path is
+// malformed.
+//
+fn parse_path(path: &str) -> Option<~[PathElement]> {
+    let mut elms = ~[];
+    let mut chars = path.chars().peekable();
+    loop {
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            let mut key = ~"";
+            loop {
+                match chars.next() {
+                    Some('"') => { break }
+                    Some('\\') => {
+                        match chars.next() {
+                            Some(c) => { key.push_char(c) }
+                            None => { return None }
+                        }
+                    }
+                    Some(c) => { key.push_char(c) }
+                    None => { return None }
+                }
+            }
+            elms.push(Key(key));
+        }
+        else {
+            let mut seg = ~"";
+            loop {
+                let c = match chars.peek() {
+                    Some(&'.') | Some(&'[') | None => { break }
+                    Some(&c) => c
+                };
+                seg.push_char(c);
+                chars.next();
+            }
+            // "[0]" directly at the start of a segment indexes the parent
+            if !(seg.is_empty() && chars.peek() == Some(&'[')) {
+                let elm = match from_str::<uint>(seg) {
+                    Some(idx) => KeyOrIdx(seg, idx),
+                    None if seg.as_slice() == "*" => Wildcard,
+                    None => Key(seg)
+                };
+                elms.push(elm);
+            }
+        }
+
+        while chars.peek() == Some(&'[') {
+            chars.next();
+            let mut digits = ~"";
+            loop {
+                match chars.next() {
+                    Some(']') => { break }
+                    Some(c) => { digits.push_char(c) }
+                    None => { return None } // unterminated index
+                }
+            }
+            match from_str::<uint>(digits) {
+                Some(idx) => { elms.push(Idx(idx)) }
+                None => { return None }
+            }
+        }
+
+        match chars.next() {
+            Some('.') => { }
+            None => { return Some(elms) }
+            Some(_) => { return None } // garbage after a quoted segment or index
+        }
+    }
 }
 
 trait LookupValue<'a> {
     fn lookup_in(&self, value: &'a Value) -> Option<&'a Value>;
+    fn lookup_in_mut(&self, value: &'a mut Value) -> Option<&'a mut Value>;
 }
 
 impl<'a> LookupValue<'a> for uint {
     fn lookup_in(&self, value: &'a Value) -> Option<&'a Value> {
         match value {
-           &TableArray(ref tableary) => {
-               tableary.get(*self)
+           &TableArray(ref ary) | &Array(ref ary) => {
+               ary.get(*self)
+           }
+           _ => { None }
+        }
+    }
+
+    fn lookup_in_mut(&self, value: &'a mut Value) -> Option<&'a mut Value> {
+        match value {
+           &TableArray(ref mut ary) | &Array(ref mut ary) => {
+               if *self < ary.len() { Some(&mut ary[*self]) } else { None }
            }
            _ => { None }
         }
@@ -120,27 +815,89 @@ impl<'a, 'b> LookupValue<'a> for &'b str {
             _ => { None }
         }
     }
+
+    fn lookup_in_mut(&self, value: &'a mut Value) -> Option<&'a mut Value> {
+        match value {
+            &Table(_, ref mut map) => {
+                map.find_mut(&self.to_owned()) // XXX: find_mut_equiv
+            }
+            _ => { None }
+        }
+    }
 }
 
-impl<'a, 'b> LookupValue<'a> for PathElement<'b> {
+impl<'a> LookupValue<'a> for PathElement {
     fn lookup_in(&self, value: &'a Value) -> Option<&'a Value> {
         match *self {
-            Key(key) => key.lookup_in(value),
-            Idx(idx) => idx.lookup_in(value)
+            Key(ref key) => key.as_slice().lookup_in(value),
+            Idx(idx) => idx.lookup_in(value),
+            KeyOrIdx(ref key, idx) => {
+                if value.get_table().is_some() { key.as_slice().lookup_in(value) }
+                else { idx.lookup_in(value) }
+            }
+            Wildcard => "*".lookup_in(value)
+        }
+    }
+
+    fn lookup_in_mut(&self, value: &'a mut Value) -> Option<&'a mut Value> {
+        match *self {
+            Key(ref key) => key.as_slice().lookup_in_mut(value),
+            Idx(idx) => idx.lookup_in_mut(value),
+            KeyOrIdx(ref key, idx) => {
+                if value.get_table().is_some() { key.as_slice().lookup_in_mut(value) }
+                else { idx.lookup_in_mut(value) }
+            }
+            Wildcard => "*".lookup_in_mut(value)
         }
     }
 }
 
-impl<'a, 'b, 'c> LookupValue<'a> for &'b[PathElement<'c>] {
+impl<'a, 'b> LookupValue<'a> for &'b[PathElement] {
     fn lookup_in(&self, value: &'a Value) -> Option<&'a Value> {
         match self.head() {
           None => Some(value),
           Some(head) => value.lookup_elm(head).and_then(|a| a.lookup_elm(&self.tail()))
         }
     }
+
+    fn lookup_in_mut(&self, value: &'a mut Value) -> Option<&'a mut Value> {
+        match self.head() {
+          None => Some(value),
+          Some(head) => value.lookup_elm_mut(head).and_then(|a| a.lookup_elm_mut(&self.tail()))
+        }
+    }
 }
 
 impl Value {
+    /// Returns an empty table
+    pub fn table() -> Value {
+        Table(false, ~TableMap::new())
+    }
+
+    /// Returns an empty array
+    pub fn array() -> Value {
+        Array(~[])
+    }
+
+    /// Returns a human readable name of the value's type
+    pub fn type_str(&self) -> &'static str {
+        match *self {
+            NoValue => "none",
+            Boolean(..) => "boolean",
+            PosInt(..) | NegInt(..) => "integer",
+            Float(..) => "float",
+            String(..) => "string",
+            Datetime(..) => "datetime",
+            Array(..) => "array",
+            TableArray(..) => "array of tables",
+            Table(..) => "table"
+        }
+    }
+
+    fn mismatch(&self, expected: &'static str) -> TypeMismatch {
+        TypeMismatch { expected: expected, found: self.type_str() }
+    }
+
     pub fn get_bool(&self) -> Option<bool> {
         match self {
             &Boolean(b) => { Some(b) }
@@ -148,10 +905,23 @@ impl Value {
         }
     }
 
-    pub fn get_int(&self) -> Option<i64> { // XXX
+    /// Returns `None` for integers outside the range of `i64`. Use
+    /// `get_uint` for values above `i64::MAX`.
+    pub fn get_int(&self) -> Option<i64> {
         match self {
-            &PosInt(u) => { Some(u.to_i64().unwrap()) } // XXX
-            &NegInt(u) => { Some(-(u.to_i64().unwrap())) } // XXX
+            &PosInt(u) => { u.to_i64() }
+            // the magnitude of i64::MIN is one above i64::MAX
+            &NegInt(u) if u == 1u64 << 63 => { Some(i64::MIN) }
+            &NegInt(u) => { u.to_i64().map(|i| -i) }
+            _ => { None }
+        }
+    }
+
+    /// Returns the full `u64` range of positive integers. Negative
+    /// integers return `None`.
+    pub fn get_uint(&self) -> Option<u64> {
+        match self {
+            &PosInt(u) => { Some(u) }
             _ => { None }
         }
     }
@@ -163,6 +933,28 @@ impl Value {
         }
     }
 
+    /// Returns floats, and integers which an `f64` holds exactly: all up
+    /// to 2^53 in magnitude, and larger ones if their low bits are zero.
+    /// Other integers give `None`, see `get_as_float_lossy`.
+    pub fn get_as_float(&self) -> Option<f64> {
+        match self {
+            &Float(num) => { Some(num) }
+            &PosInt(u) => { exact_float(u) }
+            &NegInt(u) => { exact_float(u).map(|f| -f) }
+            _ => { None }
+        }
+    }
+
+    /// Like `get_as_float`, but rounds integers which an `f64` does not
+    /// hold exactly to the nearest float
+    pub fn get_as_float_lossy(&self) -> Option<f64> {
+        match self {
+            &PosInt(u) => { Some(u as f64) }
+            &NegInt(u) => { Some(-(u as f64)) }
+            _ => { self.get_as_float() }
+        }
+    }
+
     pub fn get_str<'a>(&'a self) -> Option<&'a ~str> {
         match self {
             &String(ref str) => { Some(str) }
@@ -177,7 +969,7 @@ impl Value {
         }
     }
 
-    pub fn get_table<'a>(&'a self) -> Option<&'a ~HashMap<~str, Value>> {
+    pub fn get_table<'a>(&'a self) -> Option<&'a ~TableMap> {
         match self {
             &Table(_, ref table) => { Some(table) }
             _ => { None }
@@ -191,675 +983,1978 @@ impl Value {
         }
     }
 
-    pub fn lookup_elm<'a>(&'a self, elm: &LookupValue<'a>) -> Option<&'a Value> {
-        elm.lookup_in(self)
-    }
- 
-    pub fn lookup_vec<'a>(&'a self, idx: uint) -> Option<&'a Value> {
+    /// Returns the number of entries of a table or elements of an array
+    /// or array of tables, and `None` for other values
+    pub fn len(&self) -> Option<uint> {
         match self {
-            &Array(ref ary) => {
-                ary.get(idx)
-            }
+            &Table(_, ref table) => { Some(table.len()) }
+            &Array(ref vec) | &TableArray(ref vec) => { Some(vec.len()) }
             _ => { None }
         }
     }
 
-    pub fn lookup<'a>(&'a self, path: &'a str) -> Option<&'a Value> {
-        let mut curr: Option<&'a Value> = Some(self);
+    /// Returns true for tables, arrays and arrays of tables without
+    /// entries. Other values are never empty, as they have no `len`.
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
 
-        for p in path.split_str(".") {
-          match curr {
-            None => break,
-            Some(s) => { 
-              let elm = match from_str::<uint>(p) {
-                Some(idx) => Idx(idx),
-                None => Key(p),
-              };
-              curr = s.lookup_elm(&elm);
-            }
-          }
+    /// Returns true if the value is a table with an entry `key`
+    pub fn contains_key(&self, key: &str) -> bool {
+        match self {
+            &Table(_, ref table) => { table.find_equiv(&key).is_some() }
+            _ => { false }
         }
+    }
 
-        return curr 
+    pub fn try_bool(&self) -> Result<bool, TypeMismatch> {
+        self.get_bool().ok_or(self.mismatch("boolean"))
     }
-}
 
-trait Visitor {
-    fn section(&mut self, name: ~str, is_array: bool) -> bool;
-    fn pair(&mut self, key: ~str, val: Value) -> bool;
-}
+    pub fn try_int(&self) -> Result<i64, TypeMismatch> {
+        self.get_int().ok_or(self.mismatch("integer"))
+    }
 
-struct ValueBuilder<'a> {
-    root: &'a mut ~HashMap<~str, Value>,
-    current_path: ~[~str]
-}
+    pub fn try_uint(&self) -> Result<u64, TypeMismatch> {
+        self.get_uint().ok_or(self.mismatch("integer"))
+    }
 
-impl<'a> ValueBuilder<'a> {
-    fn new(root: &'a mut ~HashMap<~str, Value>) -> ValueBuilder<'a> {
-        ValueBuilder { root: root, current_path: ~[] }
+    pub fn try_float(&self) -> Result<f64, TypeMismatch> {
+        self.get_float().ok_or(self.mismatch("float"))
     }
 
-    fn recursive_create_tree(path: &[~str], ht: &mut ~HashMap<~str, Value>, is_array: bool) -> bool {
-        assert!(path.len() > 0);
+    pub fn try_str<'a>(&'a self) -> Result<&'a ~str, TypeMismatch> {
+        self.get_str().ok_or(self.mismatch("string"))
+    }
 
-        if path.head().unwrap().is_empty() { return false } // don't allow empty keys
+    pub fn try_vec<'a>(&'a self) -> Result<&'a ~[Value], TypeMismatch> {
+        self.get_vec().ok_or(self.mismatch("array"))
+    }
 
-        let term_rec: bool = path.len() == 1;
+    pub fn try_table<'a>(&'a self) -> Result<&'a ~TableMap, TypeMismatch> {
+        self.get_table().ok_or(self.mismatch("table"))
+    }
 
-        let head = path.head().unwrap(); // TODO: optimize
+    pub fn try_table_array<'a>(&'a self) -> Result<&'a ~[Value], TypeMismatch> {
+        self.get_table_array().ok_or(self.mismatch("array of tables"))
+    }
 
-        match ht.find_mut(head) {
-            Some(&TableArray(ref mut table_array)) => {
-                assert!(table_array.len() > 0);
+    pub fn get_vec_mut<'a>(&'a mut self) -> Option<&'a mut ~[Value]> {
+        match self {
+            &Array(ref mut vec) => { Some(vec) }
+            _ => { None }
+        }
+    }
 
-                if term_rec { // terminal recursion
-                    if is_array {
-                        table_array.push(Table(true, ~HashMap::new()));
-                        return true;
-                    }
-                    else {
-                        debug!("Duplicate key");
-                        return false;
-                    }
-                }
-                else {
-                    //let last_table = &mut ;
-                    match table_array[table_array.len()-1] {
-                        Table(_, ref mut hmap) => {
-                            return ValueBuilder::recursive_create_tree(path.tail(), hmap, is_array);
-                        }
-                        _ => {
-                            // TableArray's only contain Table's
-                            unreachable!();
-                        }
-                    }
-                }
-            }
-            Some(&Table(already_created, ref mut table)) => {
-                if term_rec { // terminal recursion
-                    if is_array {
-                        debug!("Duplicate key");
-                        return false;
-                    }
-                    else {
-                        if already_created {
-                            debug!("Duplicate section");
-                            return false;
-                        }
-                        return true;
-                    }
-                }
-                else {
-                    return ValueBuilder::recursive_create_tree(path.tail(), table, is_array);
-                }
-            }
-            Some(_) => {
-                debug!("Wrong type/duplicate key");
-                return false;
-            }
-            None => {
-                // fall-through, as we cannot modify 'ht' here
+    pub fn get_table_mut<'a>(&'a mut self) -> Option<&'a mut ~TableMap> {
+        match self {
+            &Table(_, ref mut table) => { Some(table) }
+            _ => { None }
+        }
+    }
+
+    pub fn get_table_array_mut<'a>(&'a mut self) -> Option<&'a mut ~[Value]> {
+        match self {
+            &TableArray(ref mut vec) => { Some(vec) }
+            _ => { None }
+        }
+    }
+
+    /// Moves the string out of the value. On a type mismatch the value
+    /// is handed back unchanged.
+    pub fn into_str(self) -> Result<~str, Value> {
+        match self {
+            String(str) => { Ok(str) }
+            other => { Err(other) }
+        }
+    }
+
+    pub fn into_vec(self) -> Result<~[Value], Value> {
+        match self {
+            Array(vec) => { Ok(vec) }
+            other => { Err(other) }
+        }
+    }
+
+    pub fn into_table(self) -> Result<~TableMap, Value> {
+        match self {
+            Table(_, table) => { Ok(table) }
+            other => { Err(other) }
+        }
+    }
+
+    pub fn into_table_array(self) -> Result<~[Value], Value> {
+        match self {
+            TableArray(vec) => { Ok(vec) }
+            other => { Err(other) }
+        }
+    }
+
+    /// Makes the value read-only, to be shared cheaply, see `FrozenValue`
+    pub fn freeze(self) -> FrozenValue {
+        FrozenValue::new(self)
+    }
+
+    pub fn lookup_elm<'a>(&'a self, elm: &LookupValue<'a>) -> Option<&'a Value> {
+        elm.lookup_in(self)
+    }
+
+    pub fn lookup_elm_mut<'a>(&'a mut self, elm: &LookupValue<'a>) -> Option<&'a mut Value> {
+        elm.lookup_in_mut(self)
+    }
+ 
+    pub fn lookup_vec<'a>(&'a self, idx: uint) -> Option<&'a Value> {
+        match self {
+            &Array(ref ary) => {
+                ary.get(idx)
             }
+            _ => { None }
         }
+    }
 
-        let value =
-        if term_rec { // terminal recursion
-            if is_array { TableArray(~[Table(false, ~HashMap::new())]) }
-            else { Table(true, ~HashMap::new()) }
+    /// Looks up a value by a dotted path like `db.products[0].id` or
+    /// `matrix[1][2]`. The older form `db.products.0.id` is still
+    /// accepted: a numeric segment indexes into arrays and arrays of
+    /// tables, but is used as a key when applied to a table, so
+    /// `errors.404` finds the key `404`. Bracketed indices are never
+    /// treated as keys. Keys containing dots or brackets can be quoted
+    /// as in TOML: `hosts."127.0.0.1".port`.
+    /// Inside quotes a backslash escapes the next character, so `"a\\b"`
+    /// is the key `a\b`. See `lookup_path` for a variant without any
+    /// escaping.
+    pub fn lookup<'a>(&'a self, path: &str) -> Option<&'a Value> {
+        match parse_path(path) {
+            Some(elms) => self.lookup_elm(&elms.as_slice()),
+            None => None
         }
-        else {
-            let mut table = ~HashMap::new();
-            let ok = ValueBuilder::recursive_create_tree(path.tail(), &mut table, is_array);
-            if !ok { return false }
-            Table(false, table)
+    }
+
+    /// Like `lookup`, but reports why the lookup failed. The `at` field
+    /// of the error holds the part of the path that was traversed.
+    pub fn lookup_detailed<'a>(&'a self, path: &str) -> Result<&'a Value, LookupError> {
+        let elms = match parse_path(path) {
+            Some(elms) => elms,
+            None => { return Err(InvalidPath) }
         };
-        let ok = ht.insert(head.to_owned(), value);
-        assert!(ok);
-        return ok;
+        match self.lookup_elm(&elms.as_slice()) {
+            Some(value) => Ok(value),
+            // walk the path again, this time noting where it failed
+            None => self.lookup_elms_at(elms.as_slice()).map(|(value, _)| value)
+        }
     }
 
-    fn insert_value(path: &[~str], key: &str, ht: &mut ~HashMap<~str, Value>, val: Value) -> bool {
-        if path.is_empty() {
-            return ht.insert(key.to_owned(), val);
+    // Like lookup_detailed, also returning the path in canonical form:
+    // keys as written by path_segment, indices in brackets
+    fn lookup_at<'a>(&'a self, path: &str) -> Result<(&'a Value, ~str), LookupError> {
+        match parse_path(path) {
+            Some(elms) => self.lookup_elms_at(elms.as_slice()),
+            None => Err(InvalidPath)
         }
-        else {
-            let head = path.head().unwrap(); // TODO: optimize
-            match ht.find_mut(head) {
-                Some(&Table(_, ref mut table)) => {
-                    return ValueBuilder::insert_value(path.tail(), key, table, val);
+    }
+
+    fn lookup_elms_at<'a>(&'a self, elms: &[PathElement]) -> Result<(&'a Value, ~str), LookupError> {
+        let mut curr: &'a Value = self;
+        let mut at = ~"";
+
+        for elm in elms.iter() {
+            let index = match *elm {
+                Key(..) | Wildcard => None,
+                Idx(idx) => Some(idx),
+                KeyOrIdx(_, idx) => {
+                    if curr.get_table().is_some() { None } else { Some(idx) }
                 }
-                Some(&TableArray(ref mut table_array)) => {
-                    assert!(table_array.len() > 0);
-                    match table_array[table_array.len()-1] {
-                        Table(_, ref mut hmap) => {
-                            return ValueBuilder::insert_value(path.tail(), key, hmap, val);
-                        }
-                        _ => {
-                            // TableArray's only contain Table's
-                            unreachable!();
-                        }
+            };
+
+            match index {
+                Some(idx) => {
+                    let ary = match *curr {
+                        Array(ref ary) | TableArray(ref ary) => ary,
+                        _ => { return Err(NotAnArray { at: at, found: curr.type_str() }) }
+                    };
+                    if idx >= ary.len() {
+                        return Err(IndexOutOfBounds { at: at, len: ary.len() });
                     }
+                    curr = &ary[idx];
+                    at.push_str(format!("[{}]", idx));
                 }
-                _ => {
-                    debug!("Wrong type/duplicate key");
-                    return false;
+                None => {
+                    let key = elm_key(elm);
+                    let table = match curr.get_table() {
+                        Some(table) => table,
+                        None => { return Err(NotATable { at: at, found: curr.type_str() }) }
+                    };
+                    if !at.is_empty() { at.push_char('.') }
+                    at.push_str(path_segment(key));
+                    match table.find_equiv(&key) {
+                        Some(v) => { curr = v }
+                        None => { return Err(KeyNotFound { at: at }) }
+                    }
                 }
             }
         }
+
+        return Ok((curr, at))
     }
-}
 
-impl<'a> Visitor for ValueBuilder<'a> {
-    fn section(&mut self, name: ~str, is_array: bool) -> bool {
-        self.current_path = name.split_str(".").map(|i| i.to_owned()).collect();
+    /// Sets the value at `path` (in `lookup` syntax) and returns the value
+    /// it replaced, if any. Missing tables along the path are created, and
+    /// an index equal to the length of an array appends to it. Existing
+    /// values on the way which are neither tables nor arrays are not
+    /// replaced; the path is reported as not being a table instead. As
+    /// with `push`, arrays of tables only take tables.
+    pub fn set(&mut self, path: &str, val: Value) -> Result<Option<Value>, LookupError> {
+        self.set_with(path, val, ParserOptions::new().version)
+    }
 
-        let ok = ValueBuilder::recursive_create_tree(self.current_path.as_slice(), self.root, is_array);
-        if !ok {
-            debug!("Duplicate section: {}", name);
+    /// Like `set`, for a document of the given TOML version: in TOML 0.4,
+    /// arrays only take values of the type of their other elements.
+    pub fn set_with(&mut self, path: &str, val: Value, version: TomlVersion)
+                    -> Result<Option<Value>, LookupError> {
+        match parse_path(path) {
+            Some(elms) => set_in(self, elms.as_slice(), ~"", val, false, version),
+            None => Err(InvalidPath)
         }
-        return ok;
     }
 
-    fn pair(&mut self, key: ~str, val: Value) -> bool {
-        let ok = ValueBuilder::insert_value(self.current_path.as_slice(), key, self.root, val);
-        if !ok {
-            debug!("Duplicate key: {} in path {:?}", key, self.current_path);
+    /// Like `set`, but replaces values on the way that are neither tables
+    /// nor arrays with empty tables.
+    pub fn set_force(&mut self, path: &str, val: Value) -> Result<Option<Value>, LookupError> {
+        match parse_path(path) {
+            Some(elms) => set_in(self, elms.as_slice(), ~"", val, true, ParserOptions::new().version),
+            None => Err(InvalidPath)
         }
-        return ok;
     }
-}
 
-struct Parser<'a, BUF> {
-    rd: &'a mut BUF,
-    current_char: IoResult<char>,
-    line: uint
-}
+    /// Appends to an array or an array of tables. Arrays of tables only
+    /// accept tables, while arrays accept any value, as the parser does by
+    /// default; see `push_with`.
+    pub fn push(&mut self, val: Value) -> Result<(), TypeMismatch> {
+        self.push_with(val, ParserOptions::new().version)
+    }
 
-impl<'a, BUF: Buffer> Parser<'a, BUF> {
-    fn new(rd: &'a mut BUF) -> Parser<'a, BUF> {
-        let ch = rd.read_char();
-        let mut line = 1;
-        if ch == Ok('\n') { line += 1 }
-        Parser { rd: rd, current_char: ch, line: line }
+    /// Like `push`, for a document of the given TOML version: in TOML 0.4,
+    /// arrays only accept values of the same type as their elements.
+    pub fn push_with(&mut self, val: Value, version: TomlVersion) -> Result<(), TypeMismatch> {
+        match *self {
+            Array(ref mut ary) => {
+                match ary.head() {
+                    Some(first) if version == V0_4 && !have_equiv_types(first, &val) => {
+                        return Err(TypeMismatch { expected: first.type_str(), found: val.type_str() });
+                    }
+                    _ => { }
+                }
+                ary.push(val);
+                Ok(())
+            }
+            TableArray(ref mut ary) => {
+                if val.get_table().is_none() {
+                    return Err(val.mismatch("table"));
+                }
+                ary.push(val);
+                Ok(())
+            }
+            _ => { Err(self.mismatch("array")) }
+        }
     }
 
-    fn advance(&mut self) {
-        self.current_char = self.rd.read_char();
+    /// Appends to the array at `path`. If nothing exists at `path`, a new
+    /// array (or array of tables, if `val` is a table) is created there.
+    pub fn push_at(&mut self, path: &str, val: Value) -> Result<(), LookupError> {
+        self.push_at_with(path, val, ParserOptions::new().version)
     }
 
-    fn get_line(&self) -> uint { self.line }
+    /// Like `push_at`, for a document of the given TOML version, see
+    /// `push_with`
+    pub fn push_at_with(&mut self, path: &str, val: Value, version: TomlVersion) -> Result<(), LookupError> {
+        match self.lookup_mut(path) {
+            Some(target) => {
+                return target.push_with(val, version).map_err(|e| {
+                    WrongType { at: path.to_owned(), expected: e.expected, found: e.found }
+                });
+            }
+            None => { }
+        }
+        let ary = if val.get_table().is_some() { TableArray(~[val]) } else { Array(~[val]) };
+        self.set_with(path, ary, version).map(|_| ())
+    }
 
-    fn ch(&self) -> Option<char> {
-        match self.current_char {
-            Ok(c) => Some(c),
-            Err(_) => None
+    /// Merges the table `other` into this table. Tables present in both
+    /// are merged recursively; any other value in `other`, including
+    /// arrays, replaces the one in `self`. Fails without changing anything
+    /// unless both values are tables.
+    pub fn merge(&mut self, other: &Value) -> Result<(), TypeMismatch> {
+        if other.get_table().is_none() {
+            return Err(other.mismatch("table"));
         }
+        if self.get_table().is_none() {
+            return Err(self.mismatch("table"));
+        }
+        // the overlay wins every conflict, so this cannot fail
+        self.merge_with(other, MergeOptions::new()).ok();
+        Ok(())
     }
 
-    /// Returns `true` if the input is exhausted (due to EOF or an error)
-    fn eos(&self) -> bool {
-        return self.current_char.is_err();
+    /// Like `merge`, with control over how arrays are combined and how
+    /// values of different types are treated, see `MergeOptions`. On an
+    /// error, the parts merged so far are kept.
+    pub fn merge_with(&mut self, other: &Value, opts: MergeOptions) -> Result<(), LookupError> {
+        if other.get_table().is_none() {
+            return Err(WrongType { at: ~"", expected: "table", found: other.type_str() });
+        }
+        if self.get_table().is_none() {
+            return Err(WrongType { at: ~"", expected: "table", found: self.type_str() });
+        }
+        merge_values(self, other, &opts, "")
     }
 
-    /// Returns any error encountered by the parser. Returns `None` for EndOfFile.
-    fn to_err(&self) -> Option<IoError> {
-        match self.current_char {
-            Ok(_) | Err(IoError{kind: EndOfFile, ..}) => None,
-            Err(ref e) => Some(e.clone())
+    /// Removes the value at `path` and returns it. Elements following a
+    /// removed array element shift down. Tables left empty by the removal
+    /// are kept; see `remove_pruned`. Arrays of tables left empty are not,
+    /// as they could not be written.
+    pub fn remove(&mut self, path: &str) -> Option<Value> {
+        match parse_path(path) {
+            Some(elms) => remove_in(self, elms.as_slice(), false),
+            None => None
         }
     }
 
-    fn advance_if(&mut self, c: char) -> bool {
-        match self.ch() {
-            Some(ch) if ch == c => {
-               self.advance();
-               true
+    /// Like `remove`, but also removes the tables on the path which are
+    /// left empty.
+    pub fn remove_pruned(&mut self, path: &str) -> Option<Value> {
+        match parse_path(path) {
+            Some(elms) => remove_in(self, elms.as_slice(), true),
+            None => None
+        }
+    }
+
+    /// Collects all values matching `pattern`, a path in which a bare `*`
+    /// segment matches every entry of a table or every element of an
+    /// array, e.g. `servers.*.ip`. Values lacking the rest of the path are
+    /// skipped. Array matches come in array order.
+    pub fn lookup_all<'a>(&'a self, pattern: &str) -> ~[&'a Value] {
+        let elms = match parse_path(pattern) {
+            Some(elms) => elms,
+            None => { return ~[] }
+        };
+        let mut curr: ~[&'a Value] = ~[self];
+
+        for elm in elms.iter() {
+            let mut next = ~[];
+            for v in curr.iter() {
+                let v: &'a Value = *v;
+                match (elm, v) {
+                    (&Wildcard, &Table(_, ref map)) => {
+                        for (_, child) in map.iter() { next.push(child) }
+                    }
+                    (&Wildcard, &Array(ref ary)) | (&Wildcard, &TableArray(ref ary)) => {
+                        for child in ary.iter() { next.push(child) }
+                    }
+                    (&Wildcard, _) => { }
+                    _ => {
+                        match v.lookup_elm(elm) {
+                            Some(child) => { next.push(child) }
+                            None => { }
+                        }
+                    }
+                }
             }
-            _ => {
-                false
+            curr = next;
+        }
+
+        return curr
+    }
+
+    /// Like `lookup`, but returns a mutable reference into the document.
+    pub fn lookup_mut<'a>(&'a mut self, path: &str) -> Option<&'a mut Value> {
+        let elms = match parse_path(path) {
+            Some(elms) => elms,
+            None => { return None }
+        };
+        let mut curr: &'a mut Value = self;
+
+        for elm in elms.iter() {
+            let tmp = curr;
+            match tmp.lookup_elm_mut(elm) {
+                Some(s) => { curr = s }
+                None => { return None }
             }
         }
+
+        return Some(curr)
     }
 
-    fn read_digit(&mut self, radix: uint) -> Option<u8> {
-        if self.eos() { return None }
-        match char::to_digit(self.ch().unwrap(), radix) {
-            Some(n) => {
-                self.advance();
-                Some(n as u8)
+    /// Looks up a value by a sequence of table keys. No splitting or
+    /// unescaping is performed, so any key can be reached.
+    pub fn lookup_path<'a>(&'a self, path: &[&str]) -> Option<&'a Value> {
+        let mut curr: &'a Value = self;
+
+        for key in path.iter() {
+            match curr.lookup_elm(key) {
+                Some(s) => { curr = s }
+                None => { return None }
             }
-            None => { None }
         }
+
+        return Some(curr)
     }
 
-    fn read_two_digits(&mut self) -> Option<u8> {
-        let d1 = self.read_digit(10);
-        let d2 = self.read_digit(10);
-        match (d1, d2) {
-            (Some(d1), Some(d2)) => Some(d1*10+d2),
-            _ => None
+    // Typed lookups. These return None both if the path doesn't resolve
+    // and if the value has a different type; use lookup_detailed to tell
+    // the two apart.
+
+    pub fn lookup_str<'a>(&'a self, path: &str) -> Option<&'a str> {
+        self.lookup(path).and_then(|v| v.get_str()).map(|s| s.as_slice())
+    }
+
+    pub fn lookup_int(&self, path: &str) -> Option<i64> {
+        self.lookup(path).and_then(|v| v.get_int())
+    }
+
+    pub fn lookup_uint(&self, path: &str) -> Option<u64> {
+        self.lookup(path).and_then(|v| v.get_uint())
+    }
+
+    pub fn lookup_bool(&self, path: &str) -> Option<bool> {
+        self.lookup(path).and_then(|v| v.get_bool())
+    }
+
+    pub fn lookup_float(&self, path: &str) -> Option<f64> {
+        self.lookup(path).and_then(|v| v.get_float())
+    }
+
+    /// Like `lookup_float`, but also accepts integers, see `get_as_float`
+    pub fn lookup_float_lenient(&self, path: &str) -> Option<f64> {
+        self.lookup(path).and_then(|v| v.get_as_float())
+    }
+
+    /// Returns `None` unless the value is an array of strings
+    pub fn lookup_strs<'a>(&'a self, path: &str) -> Option<~[&'a str]> {
+        let vec = match self.lookup(path).and_then(|v| v.get_vec()) {
+            Some(vec) => vec,
+            None => { return None }
+        };
+        let mut strs = ~[];
+        for v in vec.iter() {
+            match v.get_str() {
+                Some(s) => { strs.push(s.as_slice()) }
+                None => { return None }
+            }
         }
+        Some(strs)
     }
 
-    fn read_digits(&mut self) -> (Option<u64>, uint) {
-        let mut num: u64;
-        match self.read_digit(10) {
-            Some(n) => { num = n as u64; }
-            None => { return (None, 0) }
+    // Typed lookups reporting why they failed. A value of the wrong type
+    // gives a `WrongType` error naming its path, so that the message reads
+    // like "`server.port`: expected integer, found string".
+
+    pub fn try_lookup_str<'a>(&'a self, path: &str) -> Result<&'a str, LookupError> {
+        self.try_lookup(path, |v| v.try_str().map(|s| s.as_slice()))
+    }
+
+    pub fn try_lookup_int(&self, path: &str) -> Result<i64, LookupError> {
+        self.try_lookup(path, |v| v.try_int())
+    }
+
+    pub fn try_lookup_uint(&self, path: &str) -> Result<u64, LookupError> {
+        self.try_lookup(path, |v| v.try_uint())
+    }
+
+    pub fn try_lookup_bool(&self, path: &str) -> Result<bool, LookupError> {
+        self.try_lookup(path, |v| v.try_bool())
+    }
+
+    pub fn try_lookup_float(&self, path: &str) -> Result<f64, LookupError> {
+        self.try_lookup(path, |v| v.try_float())
+    }
+
+    fn try_lookup<'a, T>(&'a self, path: &str, get: |&'a Value| -> Result<T, TypeMismatch>)
+                         -> Result<T, LookupError> {
+        match self.lookup_detailed(path) {
+            Ok(value) => get(value).map_err(|e| {
+                let at = self.lookup_at(path).map(|(_, at)| at).ok().unwrap_or(path.to_owned());
+                WrongType { at: at, expected: e.expected, found: e.found }
+            }),
+            Err(e) => Err(e)
         }
-        let mut ndigits = 1;
-        loop {
-            match self.read_digit(10) {
-                Some(n) => {
-                    // XXX: check range
-                    num = num * 10 + (n as u64);
-                    ndigits += 1;
-                }
-                None => {
-                    return (Some(num), ndigits)
-                }
+    }
+
+    // The *_or lookups fall back to the default both when the path is
+    // missing and when the value has the wrong type. Use checked_lookup_or
+    // to only default on a missing path.
+
+    pub fn lookup_str_or<'a>(&'a self, path: &str, default: &'a str) -> &'a str {
+        self.lookup_str(path).unwrap_or(default)
+    }
+
+    pub fn lookup_int_or(&self, path: &str, default: i64) -> i64 {
+        self.lookup_int(path).unwrap_or(default)
+    }
+
+    pub fn lookup_bool_or(&self, path: &str, default: bool) -> bool {
+        self.lookup_bool(path).unwrap_or(default)
+    }
+
+    pub fn lookup_float_or(&self, path: &str, default: f64) -> f64 {
+        self.lookup_float(path).unwrap_or(default)
+    }
+
+    pub fn lookup_or<T: FromValue>(&self, path: &str, default: T) -> T {
+        self.get(path).unwrap_or(default)
+    }
+
+    /// Returns `default` if `path` doesn't resolve, and `None` if it
+    /// resolves to a value that can't be converted to `T`.
+    pub fn checked_lookup_or<T: FromValue>(&self, path: &str, default: T) -> Option<T> {
+        match self.lookup(path) {
+            Some(v) => FromValue::from_value(v),
+            None => Some(default)
+        }
+    }
+
+    /// Looks up `path` and converts the value found there, e.g.
+    /// `let port: Option<u16> = doc.get("server.port");`
+    pub fn get<T: FromValue>(&self, path: &str) -> Option<T> {
+        self.lookup(path).and_then(|v| FromValue::from_value(v))
+    }
+
+    /// Iterates over the entries of a table. Other values yield nothing.
+    pub fn iter<'a>(&'a self) -> Entries<'a> {
+        match *self {
+            Table(_, ref map) => Entries { iter: Some(map.iter()) },
+            _ => Entries { iter: None }
+        }
+    }
+
+    /// Iterates over the elements and their indices of an array or an
+    /// array of tables. Other values yield nothing.
+    pub fn iter_vec<'a>(&'a self) -> Elements<'a> {
+        match *self {
+            Array(ref ary) | TableArray(ref ary) => Elements { iter: Some(ary.iter()), idx: 0 },
+            _ => Elements { iter: None, idx: 0 }
+        }
+    }
+
+    /// Returns the keys of a table, or an empty vector for other values
+    pub fn keys<'a>(&'a self) -> ~[&'a str] {
+        self.iter().map(|(k, _)| k).collect()
+    }
+
+    /// Calls `f` for every leaf of the document, depth-first, with the
+    /// keys (and, for arrays, the indices) leading to it. Empty tables and
+    /// arrays count as leaves.
+    pub fn walk(&self, mut f: |&[~str], &Value|) {
+        let mut path = ~[];
+        walk_value(self, &mut path, false, &mut f);
+    }
+
+    /// The entries of a table sorted by key, as the canonical emitter
+    /// writes them, or an empty vector for other values
+    pub fn sorted_entries<'a>(&'a self) -> ~[(&'a str, &'a Value)] {
+        match *self {
+            Table(_, ref map) => map.sorted_entries().move_iter().map(|(k, v)| (k.as_slice(), v)).collect(),
+            _ => ~[]
+        }
+    }
+
+    /// Like `walk`, but visits the entries of every table sorted by key,
+    /// so that the order does not depend on the document
+    pub fn sorted_walk(&self, mut f: |&[~str], &Value|) {
+        let mut path = ~[];
+        walk_value(self, &mut path, true, &mut f);
+    }
+
+    /// Lists the differences between this document and `other`. Tables are
+    /// compared key by key, recursively, while arrays and arrays of tables
+    /// are compared as a whole. Entries are sorted by key at each level,
+    /// and paths use the same syntax as `lookup` and `flatten`.
+    pub fn diff(&self, other: &Value) -> ~[DiffEntry] {
+        let mut entries = ~[];
+        diff_values(self, other, "", &mut entries);
+        return entries;
+    }
+
+    /// Maps the path of every leaf to a copy of its value. Paths use the
+    /// `lookup` syntax: array indices become numeric segments and keys are
+    /// quoted where necessary, so every path can be passed to `lookup`.
+    /// Empty tables and arrays are kept as leaves.
+    pub fn flatten(&self) -> HashMap<~str, Value> {
+        self.leaves().move_iter().map(|(path, v)| (path, v.clone())).collect()
+    }
+
+    /// The paths and values of `flatten`, in document order
+    pub fn leaves<'a>(&'a self) -> ~[(~str, &'a Value)] {
+        let mut leaves = ~[];
+        flatten_into(self, ~"", &mut leaves);
+        return leaves;
+    }
+
+    /// The inverse of `flatten`: rebuilds the nested document from a map
+    /// of paths to leaf values. Intermediate tables are created as needed
+    /// and numeric or bracketed segments become array indices, which must
+    /// be contiguous from 0. Arrays whose elements are all tables become
+    /// arrays of tables. Fails if a path is both a leaf and the prefix of
+    /// another path, or is malformed.
+    pub fn unflatten(map: &HashMap<~str, Value>) -> Result<Value, ~str> {
+        // The empty path is the root itself, as produced by flattening an
+        // empty document
+        match map.find_equiv(&("")) {
+            Some(root) if map.len() == 1 => { return Ok(root.clone()) }
+            Some(_) => { return Err(~"the empty path conflicts with other paths") }
+            None => { }
+        }
+
+        let mut root = Keys(HashMap::new());
+        for (path, v) in map.iter() {
+            let elms = match parse_path(path.as_slice()) {
+                Some(elms) => elms,
+                None => { return Err(format!("invalid path `{}`", path.as_slice())) }
+            };
+            if !insert_node(&mut root, elms.as_slice(), v.clone()) {
+                return Err(format!("conflicting definitions at `{}`", path.as_slice()));
             }
         }
+        node_to_value(root, "")
     }
+}
 
-    // allows a single "."
-    fn read_float_mantissa(&mut self) -> f64 {
-        let mut num: f64 = 0.0;
-        let mut div: f64 = 10.0;
+// Intermediate tree used by unflatten while the paths are collected
+enum Node {
+    Leaf(Value),
+    Keys(HashMap<~str, Node>),
+    Indices(HashMap<uint, Node>)
+}
 
-        loop {
-            match self.read_digit(10) {
-                Some(n) => {
-                    num = num + (n as f64)/div;
-                    div = div * 10.0;
-                }
-                None => {
-                    return num;
-                }
+fn new_node(elm: &PathElement) -> Node {
+    match *elm {
+        Idx(..) | KeyOrIdx(..) => Indices(HashMap::new()),
+        Key(..) | Wildcard => Keys(HashMap::new())
+    }
+}
+
+fn insert_node(node: &mut Node, path: &[PathElement], val: Value) -> bool {
+    let head = &path[0];
+    let rest = path.slice_from(1);
+
+    match *node {
+        Keys(ref mut map) => {
+            let key = match *head {
+                Key(ref key) => key.clone(),
+                Wildcard => ~"*",
+                Idx(..) | KeyOrIdx(..) => { return false }
+            };
+            if rest.is_empty() {
+                return map.insert(key, Leaf(val));
             }
+            let child = map.find_or_insert_with(key, |_| new_node(&rest[0]));
+            return insert_node(child, rest, val);
         }
+        Indices(ref mut map) => {
+            let idx = match *head {
+                Idx(idx) | KeyOrIdx(_, idx) => idx,
+                Key(..) | Wildcard => { return false }
+            };
+            if rest.is_empty() {
+                return map.insert(idx, Leaf(val));
+            }
+            let child = map.find_or_insert_with(idx, |_| new_node(&rest[0]));
+            return insert_node(child, rest, val);
+        }
+        Leaf(..) => { return false }
     }
+}
 
-    fn parse_float_rest(&mut self, n: u64, mul: f64) -> Value {
-        if self.ch().is_none() { return NoValue }
-        match self.ch().unwrap() {
-            '0' .. '9' => {
-                let num = self.read_float_mantissa();
-                let num = (n as f64) + num;
-                Float(num * mul)
+fn node_to_value(node: Node, path: &str) -> Result<Value, ~str> {
+    match node {
+        Leaf(v) => { Ok(v) }
+        Keys(map) => {
+            let mut table = ~TableMap::new();
+            for (k, child) in map.move_iter() {
+                let child_path = join_path(path, path_segment(k.as_slice()));
+                match node_to_value(child, child_path) {
+                    Ok(v) => { table.insert(k, v); }
+                    Err(e) => { return Err(e) }
+                }
+            }
+            Ok(Table(false, table))
+        }
+        Indices(mut map) => {
+            let mut ary = ~[];
+            for i in range(0, map.len()) {
+                let child = match map.pop(&i) {
+                    Some(child) => child,
+                    None => { return Err(format!("missing index {} in `{}`", i, path)) }
+                };
+                match node_to_value(child, format!("{}[{}]", path, i)) {
+                    Ok(v) => { ary.push(v) }
+                    Err(e) => { return Err(e) }
+                }
+            }
+            if ary.iter().all(|v| v.get_table().is_some()) {
+                Ok(TableArray(ary))
+            } else {
+                Ok(Array(ary))
             }
-            _ => NoValue
         }
     }
+}
 
-    fn parse_value(&mut self) -> Value {
-        self.skip_whitespaces_and_comments();
+fn is_container(value: &Value) -> bool {
+    match *value {
+        Table(..) | Array(..) | TableArray(..) => true,
+        _ => false
+    }
+}
 
-        if self.eos() { return NoValue }
-        match self.ch().unwrap() {
-            '-' => {
-                self.advance();
-                match self.read_digits() {
-                    (Some(n), _) => {
-                        if self.ch() == Some('.') {
-                            // floating point
-                            self.advance();
-                            return self.parse_float_rest(n, -1.0);
-                        }
-                        else {
-                            return NegInt(n);
+fn set_in(node: &mut Value, elms: &[PathElement], at: ~str, val: Value, force: bool,
+          version: TomlVersion) -> Result<Option<Value>, LookupError> {
+    let elm = &elms[0];
+    let rest = elms.slice_from(1);
+    let found = node.type_str();
+    let index = match *elm {
+        Key(..) | Wildcard => None,
+        Idx(idx) => Some(idx),
+        KeyOrIdx(_, idx) => if node.get_table().is_some() { None } else { Some(idx) }
+    };
+
+    let (child, child_at) = match index {
+        None => {
+            let key = elm_key(elm);
+            let child_at = join_path(at, path_segment(key));
+            let table = match node.get_table_mut() {
+                Some(table) => table,
+                None => { return Err(NotATable { at: at, found: found }) }
+            };
+            if rest.is_empty() {
+                return Ok(table.swap(key.to_owned(), val));
+            }
+            let child = table.find_or_insert_with(key.to_owned(), |_| Table(false, ~TableMap::new()));
+            (child, child_at)
+        }
+        Some(idx) => {
+            let is_table_array = match *node { TableArray(..) => true, _ => false };
+            let child_at = at + format!("[{}]", idx);
+            let ary = match *node {
+                Array(ref mut ary) | TableArray(ref mut ary) => ary,
+                _ => { return Err(NotAnArray { at: at, found: found }) }
+            };
+            // Only arrays of tables can grow a new element to descend into
+            if idx > ary.len() || (idx == ary.len() && !rest.is_empty() && !is_table_array) {
+                return Err(IndexOutOfBounds { at: at, len: ary.len() });
+            }
+            if rest.is_empty() {
+                // the same rules as for `push_with`
+                if !is_table_array && version == V0_4 {
+                    match ary.iter().enumerate().find(|&(i, _)| i != idx) {
+                        Some((_, other)) if !have_equiv_types(other, &val) => {
+                            return Err(WrongType { at: child_at, expected: other.type_str(),
+                                                   found: val.type_str() });
                         }
-                    }
-                    (None, _) => {
-                        return NoValue
+                        _ => { }
                     }
                 }
+                if is_table_array && val.get_table().is_none() {
+                    return Err(WrongType { at: child_at, expected: "table", found: val.type_str() });
+                }
+                if idx == ary.len() {
+                    ary.push(val);
+                    return Ok(None);
+                }
+                return Ok(Some(mem::replace(&mut ary[idx], val)));
             }
-            '0' .. '9' => {
-                match self.read_digits() {
-                    (Some(n), ndigits) => {
-                        match self.ch() {
-                            Some('.') => {
-                                // floating point
-                                self.advance();
-                                return self.parse_float_rest(n, 1.0);
-                            }
-                            Some('-') => {
-                                if ndigits != 4 {
-                                    debug!("Invalid Datetime");
-                                    return NoValue;
-                                }
-                                self.advance();
-
-                                let year = n;
-
-                                let month = self.read_two_digits();
-                                if month.is_none() || !self.advance_if('-') {
-                                    debug!("Invalid Datetime");
-                                    return NoValue;
-                                }
-
-                                let day = self.read_two_digits();
-                                if day.is_none() || !self.advance_if('T'){
-                                    debug!("Invalid Datetime");
-                                    return NoValue;
-                                }
-
-                                let hour = self.read_two_digits();
-                                if hour.is_none() || !self.advance_if(':') {
-                                    debug!("Invalid Datetime");
-                                    return NoValue;
-                                }
-
-                                let min = self.read_two_digits();
-                                if min.is_none() || !self.advance_if(':') {
-                                    debug!("Invalid Datetime");
-                                    return NoValue;
-                                }
-
-                                let sec = self.read_two_digits();
-                                if sec.is_none() || !self.advance_if('Z') {
-                                    debug!("Invalid Datetime");
-                                    return NoValue;
-                                }
-
-                                match (year, month, day, hour, min, sec) {
-                                    (y, Some(m), Some(d),
-                                     Some(h), Some(min), Some(s))
-                                    if m > 0 && m <= 12 && d > 0 && d <= 31 &&
-                                       h <= 24 && min <= 60 && s <= 60 => {
-                                        return Datetime(y as u16,m,d,h,min,s)
-                                    }
-                                    _ => {
-                                        debug!("Invalid Datetime range");
-                                        return NoValue;
-                                    }
-                                }
-                            }
-                            _ => {
-                                return PosInt(n)
-                            }
-                        }
-                    }
-                    (None, _) => {
-                        assert!(false);
-                        return NoValue
+            if idx == ary.len() {
+                ary.push(Table(false, ~TableMap::new()));
+            }
+            (&mut ary[idx], child_at)
+        }
+    };
+
+    if !is_container(child) {
+        if !force {
+            return Err(NotATable { at: child_at, found: child.type_str() });
+        }
+        *child = Table(false, ~TableMap::new());
+    }
+    set_in(child, rest, child_at, val, force, version)
+}
+
+fn merge_values(ours: &mut Value, theirs: &Value, opts: &MergeOptions, at: &str)
+                -> Result<(), LookupError> {
+    if ours.type_str() != theirs.type_str() {
+        if !opts.overlay_wins {
+            return Err(WrongType { at: at.to_owned(), expected: ours.type_str(),
+                                   found: theirs.type_str() });
+        }
+        *ours = theirs.clone();
+        return Ok(());
+    }
+
+    match *ours {
+        Table(_, ref mut a) => {
+            let b = theirs.get_table().unwrap();
+            return merge_entries(&mut **a, &**b, opts, at);
+        }
+        Array(ref mut a) | TableArray(ref mut a) => {
+            let b = match *theirs {
+                Array(ref b) | TableArray(ref b) => b,
+                _ => { unreachable!() }
+            };
+            return merge_arrays(a, b, opts, at);
+        }
+        _ => { }
+    }
+    *ours = theirs.clone();
+    Ok(())
+}
+
+fn merge_entries(ours: &mut TableMap, theirs: &TableMap,
+                 opts: &MergeOptions, at: &str) -> Result<(), LookupError> {
+    for (k, v) in theirs.iter() {
+        match *v {
+            NoValue => {
+                if opts.delete_on_novalue { ours.pop(k); }
+                continue;
+            }
+            _ => { }
+        }
+
+        let child_at = join_path(at, path_segment(k.as_slice()));
+        let res = match ours.find_mut(k) {
+            Some(mine) => Some(merge_values(mine, v, opts, child_at.as_slice())),
+            None => None
+        };
+        match res {
+            Some(Err(e)) => { return Err(e) }
+            Some(Ok(())) => { }
+            None => { ours.insert(k.clone(), v.clone()); }
+        }
+    }
+    Ok(())
+}
+
+fn merge_arrays(ours: &mut ~[Value], theirs: &~[Value], opts: &MergeOptions, at: &str)
+                -> Result<(), LookupError> {
+    match opts.arrays {
+        Replace => {
+            *ours = theirs.clone();
+        }
+        Append => {
+            let compatible = match (ours.head(), theirs.head()) {
+                (Some(a), Some(b)) => a.type_str() == b.type_str(),
+                _ => true
+            };
+            if compatible {
+                ours.push_all(theirs.as_slice());
+            } else if opts.overlay_wins {
+                *ours = theirs.clone();
+            } else {
+                return Err(WrongType { at: at.to_owned(), expected: ours[0].type_str(),
+                                       found: theirs[0].type_str() });
+            }
+        }
+        MergeByIndex => {
+            for (i, v) in theirs.iter().enumerate() {
+                if i < ours.len() {
+                    match merge_values(&mut ours[i], v, opts, format!("{}[{}]", at, i)) {
+                        Ok(()) => { }
+                        Err(e) => { return Err(e) }
                     }
+                } else {
+                    ours.push(v.clone());
                 }
             }
-            't' => {
-                self.advance();
-                if self.advance_if('r') &&
-                   self.advance_if('u') &&
-                   self.advance_if('e') {
-                    return Boolean(true)
-                } else {
-                    return NoValue
+        }
+    }
+    Ok(())
+}
+
+fn remove_in(node: &mut Value, elms: &[PathElement], prune: bool) -> Option<Value> {
+    let elm = &elms[0];
+    let rest = elms.slice_from(1);
+    let index = match *elm {
+        Key(..) | Wildcard => None,
+        Idx(idx) => Some(idx),
+        KeyOrIdx(_, idx) => if node.get_table().is_some() { None } else { Some(idx) }
+    };
+
+    match index {
+        None => {
+            let key = elm_key(elm).to_owned();
+            let table = match node.get_table_mut() {
+                Some(table) => table,
+                None => { return None }
+            };
+            if rest.is_empty() {
+                return table.pop(&key);
+            }
+            let (removed, now_empty) = match table.find_mut(&key) {
+                Some(child) => {
+                    let removed = remove_in(child, rest, prune);
+                    // an array of tables cannot be written without tables,
+                    // so it goes with its last table even when not pruning
+                    let now_empty = match *child {
+                        Table(_, ref map) => prune && map.is_empty(),
+                        TableArray(ref ary) => ary.is_empty(),
+                        _ => false
+                    };
+                    (removed, now_empty)
                 }
+                None => { return None }
+            };
+            if removed.is_some() && now_empty {
+                table.pop(&key);
+            }
+            return removed;
+        }
+        Some(idx) => {
+            let ary = match *node {
+                Array(ref mut ary) | TableArray(ref mut ary) if idx < ary.len() => ary,
+                _ => { return None }
+            };
+            if rest.is_empty() {
+                return ary.remove(idx);
+            }
+            let removed = remove_in(&mut ary[idx], rest, prune);
+            let now_empty = match ary[idx] {
+                TableArray(ref inner) => inner.is_empty(),
+                _ => false
+            };
+            if now_empty {
+                ary[idx] = Array(~[]);
+            }
+            return removed;
+        }
+    }
+}
 
+/// Iterator over the entries of a table, see `Value::iter`
+pub struct Entries<'a> {
+    priv iter: Option<table::Entries<'a>>
+}
+
+impl<'a> Iterator<(&'a str, &'a Value)> for Entries<'a> {
+    fn next(&mut self) -> Option<(&'a str, &'a Value)> {
+        match self.iter {
+            Some(ref mut iter) => iter.next().map(|(k, v)| (k.as_slice(), v)),
+            None => None
         }
-            'f' => {
-                self.advance();
-                if self.advance_if('a') &&
-                   self.advance_if('l') &&
-                   self.advance_if('s') &&
-                   self.advance_if('e') {
-                    return Boolean(false)
-                } else {
-                    return NoValue
-                }
+    }
+}
+
+/// Iterator over the elements of an array, see `Value::iter_vec`
+pub struct Elements<'a> {
+    priv iter: Option<vec::Items<'a, Value>>,
+    priv idx: uint
+}
+
+impl<'a> Iterator<(uint, &'a Value)> for Elements<'a> {
+    fn next(&mut self) -> Option<(uint, &'a Value)> {
+        let elt = match self.iter {
+            Some(ref mut iter) => iter.next(),
+            None => None
+        };
+        elt.map(|v| { self.idx += 1; (self.idx - 1, v) })
+    }
+}
+
+fn walk_value(value: &Value, path: &mut ~[~str], sorted: bool, f: &mut |&[~str], &Value|) {
+    match *value {
+        Table(_, ref map) if !map.is_empty() => {
+            let entries = if sorted { map.sorted_entries() } else { map.iter().collect() };
+            for &(k, v) in entries.iter() {
+                path.push(k.clone());
+                walk_value(v, path, sorted, f);
+                path.pop();
             }
-            '[' => {
-                self.advance();
-                let mut arr = ~[];
-                loop {
-                    match self.parse_value() {
-                        NoValue => {
-                            break;
-                        }
-                        val => {
-                            if !arr.is_empty() {
-                                if !have_equiv_types(arr.head().unwrap(), &val) {
-                                    debug!("Incompatible element types in array");
-                                    return NoValue;
-                                }
-                            }
-                            arr.push(val);
-                        }
-                    }
+        }
+        Array(ref ary) | TableArray(ref ary) if !ary.is_empty() => {
+            for (i, v) in ary.iter().enumerate() {
+                path.push(i.to_str());
+                walk_value(v, path, sorted, f);
+                path.pop();
+            }
+        }
+        _ => { (*f)(path.as_slice(), value) }
+    }
+}
 
-                    self.skip_whitespaces_and_comments();
-                    if !self.advance_if(',') { break }
+fn diff_values(a: &Value, b: &Value, path: &str, out: &mut ~[DiffEntry]) {
+    match (a, b) {
+        (&Table(_, ref x), &Table(_, ref y)) => {
+            let mut keys: ~[&~str] = x.keys().collect();
+            for k in y.keys() {
+                if !x.contains_key(k) { keys.push(k) }
+            }
+            keys.sort();
+
+            for k in keys.iter() {
+                let child = join_path(path, path_segment(k.as_slice()));
+                match (x.find(*k), y.find(*k)) {
+                    (Some(v1), Some(v2)) => { diff_values(v1, v2, child, out) }
+                    (Some(v1), None) => { out.push(DiffEntry { path: child, kind: Removed(v1.clone()) }) }
+                    (None, Some(v2)) => { out.push(DiffEntry { path: child, kind: Added(v2.clone()) }) }
+                    (None, None) => { unreachable!() }
                 }
-                self.skip_whitespaces_and_comments();
-                if self.advance_if(']') {
-                    return Array(arr);
-                } else {
-                    return NoValue;
+            }
+        }
+        _ => {
+            if a != b {
+                let kind = Changed { old: a.clone(), new: b.clone() };
+                out.push(DiffEntry { path: path.to_owned(), kind: kind });
+            }
+        }
+    }
+}
+
+// Fails if the section header with the keys `path` has more than `max`
+fn check_table_depth<S: Str>(path: &[S], max: uint) -> Result<(), ~str> {
+    if path.len() > max {
+        return Err(format!("section `{}` is nested deeper than {} tables", path.connect("."), max));
+    }
+    return Ok(());
+}
+
+fn join_path(prefix: &str, seg: &str) -> ~str {
+    if prefix.is_empty() { seg.to_owned() }
+    else { prefix + "." + seg }
+}
+
+fn flatten_into<'a>(value: &'a Value, prefix: ~str, out: &mut ~[(~str, &'a Value)]) {
+    match *value {
+        Table(_, ref map) if !map.is_empty() => {
+            for (k, v) in map.iter() {
+                flatten_into(v, join_path(prefix, path_segment(k.as_slice())), out);
+            }
+        }
+        Array(ref ary) | TableArray(ref ary) if !ary.is_empty() => {
+            for (i, v) in ary.iter().enumerate() {
+                flatten_into(v, join_path(prefix, i.to_str()), out);
+            }
+        }
+        _ => {
+            out.push((prefix, value));
+        }
+    }
+}
+
+/// Conversion of Rust values into a `Value`. Signed integers become
+/// `PosInt` or `NegInt` depending on their sign, vectors of tables become
+/// arrays of tables.
+pub trait ToValue {
+    fn to_value(&self) -> Value;
+}
+
+impl ToValue for Value {
+    fn to_value(&self) -> Value { self.clone() }
+}
+
+impl ToValue for bool {
+    fn to_value(&self) -> Value { Boolean(*self) }
+}
+
+impl ToValue for f64 {
+    fn to_value(&self) -> Value { Float(*self) }
+}
+
+impl ToValue for f32 {
+    fn to_value(&self) -> Value { Float(*self as f64) }
+}
+
+impl<'a> ToValue for &'a str {
+    fn to_value(&self) -> Value { String(self.to_owned()) }
+}
+
+impl ToValue for ~str {
+    fn to_value(&self) -> Value { String(self.clone()) }
+}
+
+macro_rules! to_value_signed(
+    ($t:ty) => (
+        impl ToValue for $t {
+            fn to_value(&self) -> Value {
+                let n = *self as i64;
+                if n < 0 { NegInt((-n) as u64) } else { PosInt(n as u64) }
+            }
+        }
+    )
+)
+
+macro_rules! to_value_unsigned(
+    ($t:ty) => (
+        impl ToValue for $t {
+            fn to_value(&self) -> Value { PosInt(*self as u64) }
+        }
+    )
+)
+
+to_value_signed!(int)
+to_value_signed!(i64)
+to_value_signed!(i32)
+to_value_signed!(i16)
+to_value_signed!(i8)
+to_value_unsigned!(uint)
+to_value_unsigned!(u64)
+to_value_unsigned!(u32)
+to_value_unsigned!(u16)
+to_value_unsigned!(u8)
+
+impl<T: ToValue> ToValue for ~[T] {
+    fn to_value(&self) -> Value {
+        let ary: ~[Value] = self.iter().map(|v| v.to_value()).collect();
+        if !ary.is_empty() && ary.iter().all(|v| v.get_table().is_some()) {
+            TableArray(ary)
+        } else {
+            Array(ary)
+        }
+    }
+}
+
+impl<T: ToValue> ToValue for HashMap<~str, T> {
+    fn to_value(&self) -> Value {
+        let mut table = ~TableMap::new();
+        for (k, v) in self.iter() {
+            table.insert(k.clone(), v.to_value());
+        }
+        Table(false, table)
+    }
+}
+
+/// Converts anything implementing `ToValue`, mainly for use by `value!`
+pub fn to_value<T: ToValue>(v: &T) -> Value {
+    v.to_value()
+}
+
+/// Builds a `Value` from a JSON-like literal:
+///
+/// ```rust
+/// let doc = value!({
+///     server: { port: 8080, hosts: ["a", "b"] },
+///     offset: (-5),
+///     debug: true
+/// });
+/// ```
+///
+/// Keys are identifiers, or string literals for keys which are not, like
+/// `"max-connections"` or `"127.0.0.1"`. Values are tables, arrays or
+/// single tokens; anything else, including negative numbers, must be
+/// parenthesised. Arrays of tables become arrays of tables, as when
+/// parsed. A key given twice in a table fails the task, naming the key.
+#[macro_export]
+macro_rules! value(
+    (@key $k:ident) => (
+        stringify!($k)
+    );
+    (@key $k:expr) => (
+        $k
+    );
+    ({ $($k:tt : $v:tt),* }) => (
+        match ::toml::TableBuilder::new()
+            $( .insert(value!(@key $k), value!($v)) )*
+            .build() {
+            Ok(value) => value,
+            Err(e) => fail!("value!: {}", e)
+        }
+    );
+    ([]) => (
+        ::toml::Value::array()
+    );
+    ([ $($v:tt),* ]) => (
+        ::toml::to_value(&~[ $( value!($v) ),* ])
+    );
+    ($e:expr) => (
+        ::toml::to_value(&$e)
+    );
+)
+
+/// Conversion from a borrowed `Value` into a Rust type. Integer
+/// conversions fail rather than truncate if the value is out of range.
+pub trait FromValue {
+    fn from_value(v: &Value) -> Option<Self>;
+}
+
+impl FromValue for Value {
+    fn from_value(v: &Value) -> Option<Value> { Some(v.clone()) }
+}
+
+impl FromValue for bool {
+    fn from_value(v: &Value) -> Option<bool> { v.get_bool() }
+}
+
+impl FromValue for i64 {
+    fn from_value(v: &Value) -> Option<i64> { v.get_int() }
+}
+
+impl FromValue for u64 {
+    fn from_value(v: &Value) -> Option<u64> { v.get_uint() }
+}
+
+impl FromValue for f64 {
+    fn from_value(v: &Value) -> Option<f64> { v.get_float() }
+}
+
+impl FromValue for ~str {
+    fn from_value(v: &Value) -> Option<~str> { v.get_str().map(|s| s.clone()) }
+}
+
+macro_rules! from_value_narrow(
+    ($t:ty, $get:ident, $conv:ident) => (
+        impl FromValue for $t {
+            fn from_value(v: &Value) -> Option<$t> { v.$get().and_then(|n| n.$conv()) }
+        }
+    )
+)
+
+from_value_narrow!(int, get_int, to_int)
+from_value_narrow!(i32, get_int, to_i32)
+from_value_narrow!(i16, get_int, to_i16)
+from_value_narrow!(i8, get_int, to_i8)
+from_value_narrow!(uint, get_uint, to_uint)
+from_value_narrow!(u32, get_uint, to_u32)
+from_value_narrow!(u16, get_uint, to_u16)
+from_value_narrow!(u8, get_uint, to_u8)
+
+/// Accepts both arrays and arrays of tables. Fails if any element fails.
+impl<T: FromValue> FromValue for ~[T] {
+    fn from_value(v: &Value) -> Option<~[T]> {
+        let arr = match *v {
+            Array(ref arr) | TableArray(ref arr) => arr,
+            _ => { return None }
+        };
+        let mut res = ~[];
+        for elt in arr.iter() {
+            match FromValue::from_value(elt) {
+                Some(x) => { res.push(x) }
+                None => { return None }
+            }
+        }
+        Some(res)
+    }
+}
+
+impl<T: FromValue> FromValue for HashMap<~str, T> {
+    fn from_value(v: &Value) -> Option<HashMap<~str, T>> {
+        let table = match v.get_table() {
+            Some(table) => table,
+            None => { return None }
+        };
+        let mut res = HashMap::new();
+        for (k, elt) in table.iter() {
+            match FromValue::from_value(elt) {
+                Some(x) => { res.insert(k.clone(), x); }
+                None => { return None }
+            }
+        }
+        Some(res)
+    }
+}
+
+/// A region of a document. `line` and `col` give its start, counting
+/// from 1, columns in characters. `byte_start` and `byte_end` are the
+/// offsets of its first byte and of the byte after it.
+#[deriving(Clone,Eq,Show)]
+pub struct Span {
+    line: uint,
+    col: uint,
+    byte_start: uint,
+    byte_end: uint
+}
+
+/// Receives the contents of a document as the parser reads it, see
+/// `parse_with_visitor`. Nothing is collected unless the visitor does so,
+/// which allows processing documents too large to hold as a `Value`.
+pub trait Visitor {
+    /// Called for each section header, `[name]` or, with `is_array` set,
+    /// `[[name]]`. The name is given as its keys, split at the dots
+    /// between them and without quotes, so that `[a."b.c"]` gives `a` and
+    /// `b.c`; `span` is the position of the opening bracket. Returning
+    /// an error stops parsing, which then fails with `Rejected`, giving
+    /// the line of the header and the reason.
+    fn section(&mut self, path: ~[~str], is_array: bool, span: Span) -> Result<(), ~str>;
+
+    /// Called for each `key = value` pair, belonging to the section last
+    /// passed to `section`, or to the root table before the first one;
+    /// `span` is the position of the key. Errors stop parsing like those
+    /// of `section`.
+    fn pair(&mut self, key: ~str, val: Value, span: Span) -> Result<(), ~str>;
+
+    /// Called after `pair` with the spans of the pair's value and of the
+    /// values nested in it, if spans are recorded. Each comes with its
+    /// path relative to the pair's value: empty for the value itself,
+    /// `[0]` for the first element of an array and `.key` for a key of an
+    /// inline table.
+    fn value_spans(&mut self, _spans: ~[(~str, Span)]) {}
+}
+
+struct ValueBuilder<'a> {
+    root: &'a mut ~TableMap,
+    // the keys of the current section
+    current_path: ~[~str],
+    // the spans recorded so far, by canonical path
+    spans: Option<HashMap<~str, Span>>,
+    // the canonical paths of the current section and of the last pair
+    section_path: ~str,
+    pair_path: ~str,
+    // the lines of the headers and pairs which created the tables, table
+    // arrays and other values so far, or defined them, by canonical path
+    lines: HashMap<~str, uint>,
+    duplicates: DuplicatePolicy,
+    // the lines and messages of duplicates that were let through
+    warnings: ~[(uint, ~str)],
+    max_table_depth: uint,
+    // the keys of pairs, shared by all tables which have them
+    keys: HashMap<~str, Arc<~str>>
+}
+
+impl<'a> ValueBuilder<'a> {
+    fn new(root: &'a mut ~TableMap) -> ValueBuilder<'a> {
+        ValueBuilder { root: root, current_path: ~[], spans: None,
+                       section_path: ~"", pair_path: ~"", lines: HashMap::new(),
+                       duplicates: Reject, warnings: ~[], max_table_depth: MAX_TABLE_DEPTH,
+                       keys: HashMap::new() }
+    }
+
+    // The shared copy of `key`, see `TableMap::insert_shared`
+    fn share_key(keys: &mut HashMap<~str, Arc<~str>>, key: ~str) -> Arc<~str> {
+        match keys.find(&key) {
+            Some(shared) => { return shared.clone() }
+            None => {}
+        }
+        let shared = Arc::new(key.clone());
+        keys.insert(key, shared.clone());
+        return shared;
+    }
+
+    // The table receiving the pairs of the section `path`
+    fn section_table<'b>(path: &[~str], ht: &'b mut ~TableMap) -> Option<&'b mut ~TableMap> {
+        if path.is_empty() { return Some(ht) }
+        match ht.find_mut(&path[0]) {
+            Some(&Table(_, ref mut table)) => ValueBuilder::section_table(path.tail(), table),
+            Some(&TableArray(ref mut table_array)) => {
+                match table_array.mut_last() {
+                    Some(&Table(_, ref mut table)) => ValueBuilder::section_table(path.tail(), table),
+                    _ => None
                 }
             }
-            '"' => {
-                match self.parse_string() {
-                    Some(str) => { return String(str) }
-                    None => { return NoValue }
+            _ => None
+        }
+    }
+
+    // Applies the duplicate policy if `key` already holds a plain value in
+    // the current section. Returns the value unless the policy took care
+    // of it.
+    fn resolve_duplicate(&mut self, key: &str, val: Value, line: uint) -> Option<Value> {
+        if self.duplicates == Reject { return Some(val) }
+        let keep_last = self.duplicates == KeepLast;
+        let mut val = Some(val);
+        let duplicate = match ValueBuilder::section_table(self.current_path.as_slice(), self.root) {
+            Some(table) => {
+                match table.find_mut(&key.to_owned()) {
+                    Some(&Table(..)) | Some(&TableArray(..)) | None => false,
+                    Some(old) => {
+                        if keep_last { *old = val.take_unwrap() }
+                        true
+                    }
                 }
             }
-            _ => { return NoValue }
-        }
-    }
-
-    fn parse_string(&mut self) -> Option<~str> {
-        if !self.advance_if('"') { return None }
-
-        let mut str = ~"";
-        loop {
-            if self.ch().is_none() { return None }
-            match self.ch().unwrap() {
-                '\r' | '\n' | '\u000C' | '\u0008' => { return None }
-                '\\' => {
-                    self.advance();
-                    if self.ch().is_none() { return None }
-                    match self.ch().unwrap() {
-                        'b' => { str.push_char('\u0008'); self.advance() },
-                        't' => { str.push_char('\t'); self.advance() },
-                        'n' => { str.push_char('\n'); self.advance() },
-                        'f' => { str.push_char('\u000C'); self.advance() },
-                        'r' => { str.push_char('\r'); self.advance() },
-                        '"' => { str.push_char('"'); self.advance() },
-                        '/' => { str.push_char('/'); self.advance() },
-                        '\\' => { str.push_char('\\'); self.advance() },
-                        'u' => {
-                            self.advance();
-                            let d1 = self.read_digit(16);
-                            let d2 = self.read_digit(16);
-                            let d3 = self.read_digit(16);
-                            let d4 = self.read_digit(16);
-                            match (d1, d2, d3, d4) {
-                                (Some(d1), Some(d2), Some(d3), Some(d4)) => {
-                                    // XXX: how to construct an UTF character
-                                    let ch = (((((d1 as u32 << 4) | d2 as u32) << 4) | d3 as u32) << 4) | d4 as u32;
-                                    match char::from_u32(ch) {
-                                        Some(ch) => {
-                                            str.push_char(ch);
-                                        }
-                                        None => {
-                                            return None;
-                                        }
-                                    }
-                                }
-                                _ => return None
-                            }
-                        }
-                        _ => { return None }
+            None => false
+        };
+        if !duplicate { return val }
+
+        let path = self.current_path + [key.to_owned()];
+        let action = if keep_last { "replaces the earlier definition" } else { "is ignored" };
+        self.warnings.push((line, format!("duplicate key `{}` {}", path.connect("."), action)));
+        return None;
+    }
+
+    // The canonical path of the keys `path`, indexing the last table of
+    // each table array on the way, and their value if there is one
+    fn find_path<'b>(&'b self, path: &[~str]) -> (~str, Option<&'b Value>) {
+        let mut at = ~"";
+        let mut table: &TableMap = &**self.root;
+        let mut value = None;
+        for (i, seg) in path.iter().enumerate() {
+            if i > 0 {
+                let next = match value {
+                    Some(&TableArray(ref ary)) => {
+                        at.push_str(format!("[{}]", ary.len() - 1));
+                        ary.last()
                     }
+                    other => other
+                };
+                table = match next {
+                    Some(&Table(_, ref map)) => &**map,
+                    _ => { return (at, None) }
+                };
+            }
+            at = join_path(at.as_slice(), path_segment(seg.as_slice()));
+            value = table.find(seg);
+        }
+        return (at, value);
+    }
+
+    // The canonical path of the current section, in the last table of a
+    // table array
+    fn canonical_section_path(&self) -> ~str {
+        let (mut at, value) = self.find_path(self.current_path.as_slice());
+        match value {
+            Some(&TableArray(ref ary)) => { at.push_str(format!("[{}]", ary.len() - 1)) }
+            _ => {}
+        }
+        return at;
+    }
+
+    // Records `line` as that of the header of the current section, for
+    // the tables it created and the one it defined
+    fn record_tables(&mut self, is_array: bool, line: uint) {
+        let len = self.current_path.len();
+        for i in range(1, len + 1) {
+            let (at, _) = self.find_path(self.current_path.slice_to(i));
+            // the first header of a table array is kept
+            if i == len && !is_array { self.lines.insert(at, line); }
+            else { self.lines.find_or_insert(at, line); }
+        }
+    }
+
+    // Describes how the header of the current section, on `line`,
+    // conflicts with the value at its first `n` keys
+    fn conflict(&self, n: uint, is_array: bool, line: uint) -> ~str {
+        let keys = self.current_path.slice_to(n);
+        let name = keys.connect(".");
+        let header = self.current_path.connect(".");
+        let header = if is_array { format!("[[{}]]", header) } else { format!("[{}]", header) };
+        let (at, value) = self.find_path(keys);
+        match (value, self.lines.find(&at)) {
+            _ if keys[n - 1].is_empty() => format!("empty key in section `{}`", self.current_path.connect(".")),
+            (Some(&Table(..)), Some(first)) if !is_array => {
+                format!("table `{}` defined twice, on lines {} and {}", name, *first, line)
+            }
+            (Some(&Table(..)), Some(first)) => {
+                format!("`{}` on line {} conflicts with table `{}` from line {}", header, line, name, *first)
+            }
+            (Some(&TableArray(..)), Some(first)) => {
+                format!("`{}` on line {} conflicts with array of tables `{}` from line {}", header, line, name, *first)
+            }
+            (Some(other), Some(first)) => {
+                // only the last key of the header is no plain table
+                let kind = if is_array && n == self.current_path.len() { "array of tables" } else { "table" };
+                format!("key `{}` defined twice: {} on line {}, {} on line {}", name, other.type_str(), *first, kind, line)
+            }
+            _ => format!("invalid or duplicate section `{}`", self.current_path.connect("."))
+        }
+    }
+
+    fn record_span(&mut self, path: ~str, span: Span) {
+        match self.spans {
+            Some(ref mut spans) => { spans.insert(path, span); }
+            None => {}
+        }
+    }
+
+    // Creates the tables of a header with the keys `path`, and the table
+    // or the table in a table array which it defines at the end. Fails
+    // with the number of keys up to the one whose value conflicts.
+    fn recursive_create_tree(path: &[~str], ht: &mut ~TableMap, is_array: bool) -> Result<(), uint> {
+        let head = match path.head() {
+            Some(head) if !head.is_empty() => head,
+            _ => { return Err(1) } // don't allow empty keys
+        };
+
+        let term_rec: bool = path.len() == 1;
+
+        match ht.find_mut(head) {
+            Some(&TableArray(ref mut table_array)) => {
+                if term_rec { // terminal recursion
+                    if !is_array { return Err(1) }
+                    table_array.push(Table(true, ~TableMap::new()));
+                    return Ok(());
                 }
-                '"' => {
-                    self.advance();
-                    return Some(str);
+                // TableArray's only contain Table's
+                match table_array.mut_last() {
+                    Some(&Table(_, ref mut hmap)) => {
+                        return ValueBuilder::recursive_create_tree(path.tail(), hmap, is_array).map_err(|n| n + 1);
+                    }
+                    _ => { return Err(1) }
                 }
-                c => {
-                    str.push_char(c);
-                    self.advance();
+            }
+            Some(&Table(ref mut defined, ref mut table)) => {
+                if term_rec { // terminal recursion
+                    // a table created for the header of a sub-table may
+                    // be defined by a header of its own, once
+                    if is_array || *defined { return Err(1) }
+                    *defined = true;
+                    return Ok(());
                 }
+                return ValueBuilder::recursive_create_tree(path.tail(), table, is_array).map_err(|n| n + 1);
+            }
+            Some(_) => {
+                debug!("Wrong type/duplicate key");
+                return Err(1);
+            }
+            None => {
+                // fall-through, as we cannot modify 'ht' here
+            }
+        }
+
+        let value =
+        if term_rec { // terminal recursion
+            if is_array { TableArray(~[Table(true, ~TableMap::new())]) }
+            else { Table(true, ~TableMap::new()) }
+        }
+        else {
+            let mut table = ~TableMap::new();
+            match ValueBuilder::recursive_create_tree(path.tail(), &mut table, is_array) {
+                Ok(()) => {}
+                Err(n) => { return Err(n + 1) }
+            }
+            Table(false, table)
+        };
+        ht.insert(head.to_owned(), value);
+        return Ok(());
+    }
+
+    fn insert_value(path: &[~str], key: &str, ht: &mut ~TableMap, val: Value) -> bool {
+        match ValueBuilder::section_table(path, ht) {
+            Some(table) => table.insert(key.to_owned(), val),
+            None => {
+                debug!("Wrong type/duplicate key");
+                return false;
             }
         }
     }
+}
 
-    fn read_token(&mut self, f: |char| -> bool) -> ~str {
-        let mut token = ~"";
-        loop {
-            match self.ch() {
-                Some(ch) => {
-                    if f(ch) { token.push_char(ch) }
-                    else { break }
+impl<'a> Visitor for ValueBuilder<'a> {
+    fn section(&mut self, path: ~[~str], is_array: bool, span: Span) -> Result<(), ~str> {
+        match check_table_depth(path.as_slice(), self.max_table_depth) {
+            Ok(()) => {}
+            Err(e) => { return Err(e) }
+        }
+        self.current_path = path;
+
+        match ValueBuilder::recursive_create_tree(self.current_path.as_slice(), self.root, is_array) {
+            Ok(()) => {}
+            Err(n) => {
+                debug!("Duplicate section: {}", self.current_path.connect("."));
+                return Err(self.conflict(n, is_array, span.line));
+            }
+        }
+        self.record_tables(is_array, span.line);
+        self.section_path = self.canonical_section_path();
+        if self.spans.is_some() {
+            let path = self.section_path.clone();
+            self.record_span(path, span);
+        }
+        return Ok(());
+    }
+
+    fn pair(&mut self, key: ~str, val: Value, span: Span) -> Result<(), ~str> {
+        self.pair_path = join_path(self.section_path.as_slice(), path_segment(key));
+        let val = match self.resolve_duplicate(key, val, span.line) {
+            Some(val) => val,
+            None => { return Ok(()) }
+        };
+        let kind = val.type_str();
+        let existing = match ValueBuilder::section_table(self.current_path.as_slice(), self.root) {
+            Some(table) => {
+                let existing = table.find(&key).map(|old| old.type_str());
+                if existing.is_none() {
+                    table.insert_shared(ValueBuilder::share_key(&mut self.keys, key), val);
+                    self.lines.insert(self.pair_path.clone(), span.line);
+                    return Ok(());
                 }
-                None => { break }
+                existing
+            }
+            None => None
+        };
+        debug!("Duplicate key: {} in path {:?}", key, self.current_path);
+        let path = self.current_path + [key];
+        match (existing, self.lines.find(&self.pair_path)) {
+            // a table defined by a header before
+            (Some(old), Some(first)) if old == "table" || old == "array of tables" => {
+                Err(format!("key `{}` defined twice: {} on line {}, {} on line {}",
+                            path.connect("."), old, *first, kind, span.line))
             }
-            self.advance();
+            _ => Err(format!("duplicate key `{}`", path.connect(".")))
+        }
+    }
+
+    fn value_spans(&mut self, spans: ~[(~str, Span)]) {
+        for (rel, span) in spans.move_iter() {
+            let path = format!("{}{}", self.pair_path, rel);
+            self.record_span(path, span);
+        }
+    }
+}
+
+/// Passes the sections accepted by a predicate, and their pairs, on to
+/// another visitor; see `parse_filtered`. The predicate gets the name of
+/// each section, its keys joined by dots, and the empty name for the
+/// pairs before the first section. Skipped sections are still parsed, so
+/// syntax errors in them are reported, but their values are dropped
+/// right away.
+pub struct SectionFilter<'a, V> {
+    priv visitor: &'a mut V,
+    priv accept: 'a |&str| -> bool,
+    priv skipping: bool
+}
+
+impl<'a, V: Visitor> SectionFilter<'a, V> {
+    pub fn new(visitor: &'a mut V, accept: 'a |&str| -> bool) -> SectionFilter<'a, V> {
+        let skipping = !accept("");
+        SectionFilter { visitor: visitor, accept: accept, skipping: skipping }
+    }
+}
+
+impl<'a, V: Visitor> Visitor for SectionFilter<'a, V> {
+    fn section(&mut self, path: ~[~str], is_array: bool, span: Span) -> Result<(), ~str> {
+        self.skipping = !(self.accept)(path.connect(".").as_slice());
+        if self.skipping { return Ok(()) }
+        self.visitor.section(path, is_array, span)
+    }
+
+    fn pair(&mut self, key: ~str, val: Value, span: Span) -> Result<(), ~str> {
+        if self.skipping { return Ok(()) }
+        self.visitor.pair(key, val, span)
+    }
+
+    fn value_spans(&mut self, spans: ~[(~str, Span)]) {
+        if !self.skipping { self.visitor.value_spans(spans) }
+    }
+}
+
+// Collects the section `name` and its sub-sections, see `parse_section`
+struct SectionExtractor<'a> {
+    builder: ValueBuilder<'a>,
+    // the keys of the section's name
+    name: &'a [~str],
+    // whether the pairs read belong to the section
+    inside: bool
+}
+
+impl<'a> Visitor for SectionExtractor<'a> {
+    fn section(&mut self, path: ~[~str], is_array: bool, span: Span) -> Result<(), ~str> {
+        // sub-tables may follow other sections, so the section is only
+        // left until the next header below it
+        self.inside = path.starts_with(self.name);
+        if !self.inside { return Ok(()) }
+        self.builder.section(path, is_array, span)
+    }
+
+    fn pair(&mut self, key: ~str, val: Value, span: Span) -> Result<(), ~str> {
+        if !self.inside { return Ok(()) }
+        self.builder.pair(key, val, span)
+    }
+}
+
+/// Builds a document programmatically:
+///
+/// ```rust
+/// let doc = TableBuilder::new()
+///     .insert("name", "app")
+///     .table("server", |t| t.insert("port", 8080u))
+///     .table_array("backends", |t| t.insert("host", "a"))
+///     .table_array("backends", |t| t.insert("host", "b"))
+///     .build();
+/// ```
+///
+/// Each call to `table_array` appends one table to the array. Defining a
+/// key twice makes `build` fail, while `build_lenient` keeps the later
+/// definition. A builder can also be collected from, or extended with, an
+/// iterator of `(key, value)` pairs.
+pub struct TableBuilder {
+    priv table: ~TableMap,
+    priv duplicates: ~[~str]
+}
+
+impl TableBuilder {
+    pub fn new() -> TableBuilder {
+        TableBuilder { table: ~TableMap::new(), duplicates: ~[] }
+    }
+
+    pub fn insert<T: ToValue>(mut self, key: &str, val: T) -> TableBuilder {
+        if !ValueBuilder::insert_value([], key, &mut self.table, val.to_value()) {
+            self.duplicates.push(key.to_owned());
         }
+        self
+    }
 
-        return token;
+    pub fn table(self, key: &str, f: |TableBuilder| -> TableBuilder) -> TableBuilder {
+        let child = f(TableBuilder::new());
+        self.insert_child(key, child, false)
     }
 
-    fn parse_section_identifier(&mut self) -> ~str {
-        self.read_token(|ch| {
-            match ch {
-                '\t' | '\n' | '\r' | '[' | ']' => false,
-                _ => true
-            }
-        })
+    pub fn table_array(self, key: &str, f: |TableBuilder| -> TableBuilder) -> TableBuilder {
+        let child = f(TableBuilder::new());
+        self.insert_child(key, child, true)
     }
 
-    fn skip_whitespaces(&mut self) {
-        loop {
-            match self.ch() {
-                Some(' ') | Some('\t') | Some('\r') => {
-                    self.advance();
-                }
-                Some('\n') => {
-                    self.advance();
-                    self.line += 1;
-                }
-                _ => { break }
-            }
+    fn insert_child(mut self, key: &str, child: TableBuilder, is_array: bool) -> TableBuilder {
+        let TableBuilder { table: child_table, duplicates: child_dups } = child;
+        for dup in child_dups.move_iter() {
+            self.duplicates.push(key + "." + dup);
+        }
+
+        let path = [key.to_owned()];
+        if ValueBuilder::recursive_create_tree(path, &mut self.table, is_array).is_err() {
+            self.duplicates.push(key.to_owned());
+            let child = Table(true, child_table);
+            self.table.insert(path[0].clone(), if is_array { TableArray(~[child]) } else { child });
+            return self;
         }
+        // recursive_create_tree added an empty table; replace it
+        let slot = match self.table.find_mut(&path[0]) {
+            Some(&TableArray(ref mut ary)) => { let last = ary.len() - 1; &mut ary[last] }
+            Some(other) => other,
+            None => { unreachable!() }
+        };
+        *slot = Table(true, child_table);
+        self
     }
 
-    fn skip_whitespaces_and_comments(&mut self) {
-        loop {
-            match self.ch() {
-                Some(' ') | Some('\t') | Some('\r') => {
-                    self.advance();
-                }
-                Some('\n') => {
-                    self.advance();
-                    self.line += 1;
-                }
-                Some('#') => {
-                    self.skip_comment();
-                }
-                _ => { break }
-            }
+    /// Returns the document, or the first key that was defined twice
+    pub fn build(self) -> Result<Value, ~str> {
+        if !self.duplicates.is_empty() {
+            return Err(format!("duplicate key `{}`", self.duplicates[0]));
         }
+        Ok(Table(false, self.table))
+    }
+
+    /// Returns the document; later definitions of a key replace earlier ones
+    pub fn build_lenient(self) -> Value {
+        Table(false, self.table)
+    }
+}
+
+impl FromIterator<(~str, Value)> for TableBuilder {
+    fn from_iterator<T: Iterator<(~str, Value)>>(iterator: &mut T) -> TableBuilder {
+        let mut builder = TableBuilder::new();
+        builder.extend(iterator);
+        builder
     }
+}
 
-    fn skip_comment(&mut self) {
-        assert!(self.ch() == Some('#'));
-        // skip to end of line
-        loop {
-            self.advance();
-            match self.ch() {
-                Some('\n') => { break }
-                None => { return }
-                _ => { /* skip */ }
+impl Extendable<(~str, Value)> for TableBuilder {
+    fn extend<T: Iterator<(~str, Value)>>(&mut self, iterator: &mut T) {
+        for (k, v) in iterator.by_ref() {
+            if !ValueBuilder::insert_value([], k.as_slice(), &mut self.table, v) {
+                self.duplicates.push(k);
             }
         }
-        self.line += 1;
-        self.advance();
     }
+}
+
+/// Collects values into an array. Unlike `push`, this does not check
+/// that the elements are of the same type.
+impl FromIterator<Value> for Value {
+    fn from_iterator<T: Iterator<Value>>(iterator: &mut T) -> Value {
+        Array(iterator.collect())
+    }
+}
+
+/// Parses the file at `path`. A file that cannot be opened or read gives
+/// an `IOError`.
+pub fn parse_from_path(path: &Path) -> Result<Value,Error> {
+    let mut file = File::open(path);
+    return parse_from_reader(&mut file);
+}
 
-    fn parse<V: Visitor>(&mut self, visitor: &mut V) -> Result<(),Error> {
-        loop {
-            self.skip_whitespaces_and_comments();
+pub fn parse_from_file(name: &str) -> Result<Value,Error> {
+    parse_from_path(&Path::new(name))
+}
 
-            if self.eos() {
-                return self.to_err().map_or(Ok(()), |e| Err(IOError(e)));
+/// Parses many files with up to `concurrency` tasks, each taking every
+/// `concurrency`-th file. The results are in the order of `paths`, and a
+/// file failing to parse doesn't stop the others.
+pub fn parse_files(paths: &[Path], concurrency: uint) -> ~[(Path, Result<Value,Error>)] {
+    let tasks = cmp::max(1, cmp::min(concurrency, paths.len()));
+    let (tx, rx) = channel();
+    for task in range(0, tasks) {
+        let batch: ~[(uint, Path)] = paths.iter().enumerate()
+            .filter(|&(i, _)| i % tasks == task)
+            .map(|(i, path)| (i, path.clone())).collect();
+        let tx = tx.clone();
+        spawn(proc() {
+            for (i, path) in batch.move_iter() {
+                tx.send((i, parse_from_path(&path)));
             }
+        });
+    }
 
-            match self.ch().unwrap() {
-                // section
-                '[' => {
-                    self.advance();
-                    let mut double_section = false;
-                    match self.ch() {
-                        Some('[') => {
-                            double_section = true;
-                            self.advance();
-                        }
-                        _ => {}
-                    }
+    let mut results = vec::from_fn(paths.len(), |_| None);
+    for _ in range(0, paths.len()) {
+        let (i, result) = rx.recv();
+        results[i] = Some(result);
+    }
+    paths.iter().zip(results.move_iter()).map(|(path, result)| (path.clone(), result.unwrap())).collect()
+}
 
-                    let section_name = self.parse_section_identifier();
-                    // don"t allow empty section names
-                    if section_name.is_empty() { return Err(ParseError) }
+/// Parses a document from any reader, like a socket or pipe, buffering
+/// the input internally. Short reads are fine, even ones splitting a
+/// UTF-8 sequence.
+pub fn parse_from_reader<R: Reader>(rd: &mut R) -> Result<Value,Error> {
+    let mut buffered = BufferedReader::new(rd.by_ref());
+    return parse_from_buffer(&mut buffered);
+}
 
-                    if !self.advance_if(']') { return Err(ParseError) }
-                    if double_section {
-                        if !self.advance_if(']') { return Err(ParseError) }
-                    }
+/// Parses a document from a buffered reader. All other parse functions
+/// end up here.
+pub fn parse_from_buffer<BUF: Buffer>(rd: &mut BUF) -> Result<Value,Error> {
+    let mut ht = ~TableMap::new();
+    {
+        let mut builder = ValueBuilder::new(&mut ht);
+        match parse_with_visitor(rd, &mut builder) {
+            Err(e) => { return Err(e) }
+            Ok(_) => ()
+        }
+    }
+    return Ok(Table(false, ht));
+}
 
-                    if !visitor.section(section_name, double_section) {
-                        return Err(ParseError)
-                    }
-                }
+/// A document parsed by `parse_with_options`
+pub struct ParsedDocument {
+    value: Value,
+    /// The lines and descriptions of keys defined twice, if the
+    /// `duplicates` option let them through
+    warnings: ~[(uint, ~str)],
+    priv spans: HashMap<~str, Span>,
+    priv bytes_read: uint
+}
 
-                // identifier: anything else starts an idenfifier!
-                // NOTE that we do not allow '.' in identifiers!
-                _ => {
-                    let ident = self.read_token(|ch| {
-                        match ch {
-                            ' ' | '\t' | '\r' | '\n' | '=' => false,
-                            _ => true
-                        }
-                    });
+impl ParsedDocument {
+    /// How many bytes of input the document took up
+    pub fn bytes_read(&self) -> uint {
+        self.bytes_read
+    }
 
-                    self.skip_whitespaces();
+    /// Returns where the value at `path`, in `lookup` syntax, was found.
+    /// Tables defined by a header span the header. Spans are only
+    /// available if `record_spans` was set, and not for tables that are
+    /// only implied by a header or key.
+    pub fn span(&self, path: &str) -> Option<Span> {
+        match self.value.lookup_at(path) {
+            Ok((_, at)) => self.spans.find(&at).map(|span| span.clone()),
+            Err(_) => None
+        }
+    }
+}
 
-                    if !self.advance_if('=') { return Err(ParseError) } // assign wanted
+/// Parses a document from a buffered reader like `parse_from_buffer`,
+/// with the given options
+pub fn parse_with_options<BUF: Buffer>(rd: &mut BUF, opts: &ParserOptions) -> Result<ParsedDocument,Error> {
+    let mut ht = ~TableMap::new();
+    let (spans, warnings, bytes_read) = {
+        let mut builder = ValueBuilder::new(&mut ht);
+        if opts.record_spans { builder.spans = Some(HashMap::new()) }
+        builder.duplicates = opts.duplicates.clone();
+        builder.max_table_depth = opts.max_table_depth;
+        let mut parser = parser::Parser::new(rd, opts.record_spans);
+        parser.set_limits(opts);
+        parser.set_lossy_utf8(opts.lossy_utf8);
+        parser.set_strict(opts.strict);
+        parser.set_version(opts.version.clone());
+        match parser.parse(&mut builder) {
+            Err(e) => {
+                debug!("Error in line: {}", parser.get_line());
+                return Err(e);
+            }
+            Ok(_) => ()
+        }
+        (builder.spans.take().unwrap_or(HashMap::new()), mem::replace(&mut builder.warnings, ~[]), parser.bytes_read())
+    };
+    return Ok(ParsedDocument { value: Table(false, ht), warnings: warnings, spans: spans, bytes_read: bytes_read });
+}
 
-                    match self.parse_value() {
-                        NoValue => { return Err(ParseError); }
-                        val => {
-                            if !visitor.pair(ident, val) { return Err(ParseError); }
-                        }
-                    }
-                }
-            } /* end match */
+/// Parses a document from a buffered reader, keeping only the sections
+/// for which `accept` returns true, see `SectionFilter`. Tables which
+/// contain accepted sections are kept as well. For example,
+/// `|name| name == "metadata"` keeps just the `[metadata]` table.
+pub fn parse_filtered<BUF: Buffer>(rd: &mut BUF, accept: |&str| -> bool) -> Result<Value,Error> {
+    let mut ht = ~TableMap::new();
+    {
+        let mut builder = ValueBuilder::new(&mut ht);
+        let mut filter = SectionFilter::new(&mut builder, accept);
+        match parse_with_visitor(rd, &mut filter) {
+            Err(e) => { return Err(e) }
+            Ok(_) => ()
         }
     }
+    return Ok(Table(false, ht));
 }
 
+/// Parses the section `name`, given in `lookup` syntax so that keys with
+/// dots are quoted, and returns its table, or the array of tables for
+/// `[[name]]` headers, including any sub-tables wherever they are in the
+/// document. Other sections are parsed, so that a header inside a string
+/// is not mistaken for one, but not kept. Returns `None` if there is no
+/// such section.
+pub fn parse_section<BUF: Buffer>(rd: &mut BUF, name: &str) -> Result<Option<Value>,Error> {
+    let keys: ~[~str] = match parse_path(name) {
+        Some(ref elms) if elms.iter().all(|elm| match *elm { Key(..) | KeyOrIdx(..) => true, _ => false }) => {
+            elms.iter().map(|elm| elm_key(elm).to_owned()).collect()
+        }
+        _ => { return Ok(None) }
+    };
 
-pub fn parse_from_path(path: &Path) -> Result<Value,Error> {
-    let file = File::open(path);
-    let mut rd = BufferedReader::new(file);
-    return parse_from_buffer(&mut rd);
+    let mut ht = ~TableMap::new();
+    {
+        let mut extractor = SectionExtractor {
+            builder: ValueBuilder::new(&mut ht), name: keys.as_slice(), inside: false
+        };
+        match parse_with_visitor(rd, &mut extractor) {
+            Err(e) => { return Err(e) }
+            Ok(_) => ()
+        }
+    }
+
+    let mut value = Table(false, ht);
+    for seg in keys.iter() {
+        let next = match value {
+            Table(_, mut map) => map.pop(seg),
+            // sub-tables of an array of tables are in its last table
+            TableArray(mut ary) => {
+                match ary.pop() {
+                    Some(Table(_, mut map)) => map.pop(seg),
+                    _ => None
+                }
+            }
+            _ => None
+        };
+        value = match next {
+            Some(v) => v,
+            None => { return Ok(None) }
+        };
+    }
+    return Ok(Some(value));
 }
 
-pub fn parse_from_file(name: &str) -> Result<Value,Error> {
-    parse_from_path(&Path::new(name))
+/// Parses a document from a buffered reader, passing its sections and
+/// pairs to `visitor` instead of building a `Value`
+pub fn parse_with_visitor<V: Visitor, BUF: Buffer>(rd: &mut BUF, visitor: &mut V) -> Result<(),Error> {
+    let mut parser = parser::Parser::new(rd, false);
+    match parser.parse(visitor) {
+        Err(e) => {
+            debug!("Error in line: {}", parser.get_line());
+            return Err(e);
+        }
+        Ok(_) => { return Ok(()) }
+    }
 }
 
-pub fn parse_from_buffer<BUF: Buffer>(rd: &mut BUF) -> Result<Value,Error> {
-    let mut ht = ~HashMap::<~str, Value>::new();
+/// Like `parse_from_buffer`, but an error comes with the position in the
+/// input where it was found and what was wrong there, to point users to
+/// it. Parsing stops at the first error.
+pub fn parse_located<BUF: Buffer>(rd: &mut BUF) -> Result<Value,Diagnostic> {
+    let mut ht = ~TableMap::new();
     {
         let mut builder = ValueBuilder::new(&mut ht);
-        let mut parser = Parser::new(rd);
-
+        let mut parser = parser::Parser::new(rd, false);
         match parser.parse(&mut builder) {
             Err(e) => {
-                debug!("Error in line: {}", parser.get_line());
-                return Err(e);
+                let message = match e {
+                    ParseError => parser.message().to_owned(),
+                    Rejected { reason: ref reason, .. } => reason.clone(),
+                    IOError(ref e) => e.to_str()
+                };
+                return Err(Diagnostic { error: e, span: parser.error_span(), message: message });
             }
             Ok(_) => ()
         }
@@ -867,172 +2962,389 @@ pub fn parse_from_buffer<BUF: Buffer>(rd: &mut BUF) -> Result<Value,Error> {
     return Ok(Table(false, ht));
 }
 
+/// Parses a document held in memory, like a memory-mapped file. The bytes
+/// are borrowed and read in place: the parser only copies a small window
+/// of them at a time, and checks that they are UTF-8 as it goes, so an
+/// error near the start of a large region is found without reading the
+/// rest.
 pub fn parse_from_bytes(bytes: &[u8]) -> Result<Value,Error> {
     let mut rd = BufReader::new(bytes);
     return parse_from_buffer(&mut rd);
 }
 
+/// Parses a document held in a string, reading it in place
+pub fn parse_from_str(s: &str) -> Result<Value,Error> {
+    parse_from_bytes(s.as_bytes())
+}
+
+/// Runs `data` through the parsers, and writes out what they accept, for
+/// fuzzers: whatever the input, this must return instead of failing the
+/// task or overflowing the stack. The results are thrown away.
+pub fn fuzz_one(data: &[u8]) {
+    match parse_from_bytes(data) {
+        Ok(value) => { let _ = value.to_toml_str(); }
+        Err(_) => {}
+    }
+    let _ = parse_located(&mut BufReader::new(data));
+    match std::str::from_utf8(data) {
+        Some(s) => { let _ = parse_borrowed(s); }
+        None => {}
+    }
+}
+
+/// Parses a document, see `parse_from_str`. Errors give `None`.
+impl FromStr for Value {
+    fn from_str(s: &str) -> Option<Value> {
+        parse_from_str(s).ok()
+    }
+}
+
+/// Parses `input`, emits the result with `to_toml_str`, parses that again
+/// and checks that both values are equal. Useful to test that a document
+/// survives being rewritten.
+pub fn roundtrip_check(input: &str) -> Result<(), RoundtripError> {
+    let value = match parse_from_bytes(input.as_bytes()) {
+        Ok(value) => value,
+        Err(e) => { return Err(InputError(e)) }
+    };
+    let emitted = match value.to_toml_str() {
+        Ok(emitted) => emitted,
+        Err(e) => { return Err(EmitFailed(e)) }
+    };
+    let reparsed = match parse_from_bytes(emitted.as_bytes()) {
+        Ok(reparsed) => reparsed,
+        Err(e) => { return Err(ReparseError { error: e, emitted: emitted }) }
+    };
+
+    match value.diff(&reparsed).move_iter().next() {
+        None => { return Ok(()) }
+        Some(entry) => { return Err(Mismatch { path: entry.path, emitted: emitted }) }
+    }
+}
+
 enum State {
     No,
     Arr(MoveItems<Value>),
-    Tab(~HashMap<~str, Value>),
-    Map(MoveEntries<~str, Value>)
+    Tab(~TableMap),
+    Map(table::MoveEntries)
 }
 
+/// Decodes `Decodable` types from a `Value`, see `decode`. After the
+/// first error the decoder keeps going with placeholder values, so that
+/// the `Decodable` implementation can finish; only that first error is
+/// reported.
 pub struct Decoder {
     priv value: Value,
-    priv state: State
+    priv state: State,
+    priv path: ~str,
+    priv opts: DecodeOptions,
+    priv fields: ~[~str],
+    priv missing: Option<(~str, ~str)>,
+    priv entry: Option<(~str, Value)>,
+    priv error: Option<DecodeError>
 }
 
 impl Decoder {
     pub fn new(value: Value) -> Decoder {
-        Decoder {value: value, state: No}
+        Decoder::with_options(value, DecodeOptions::new())
     }
     pub fn new_state(state: State) -> Decoder {
-        Decoder {value: NoValue, state: state}
+        Decoder::build(NoValue, state, ~"", DecodeOptions::new())
+    }
+    pub fn with_options(value: Value, opts: DecodeOptions) -> Decoder {
+        Decoder::build(value, No, ~"", opts)
     }
-}
 
-impl serialize::Decoder for Decoder {
-    fn read_nil(&mut self) -> () { fail!() }
+    /// The first error, if decoding failed
+    pub fn error<'a>(&'a self) -> Option<&'a DecodeError> {
+        self.error.as_ref()
+    }
 
-    fn read_u64(&mut self) -> u64 {
-        match self.value {
-            PosInt(v) => v,
-            _ => fail!()
+    fn build(value: Value, state: State, path: ~str, opts: DecodeOptions) -> Decoder {
+        Decoder { value: value, state: state, path: path, opts: opts, fields: ~[],
+                  missing: None, entry: None, error: None }
+    }
+
+    fn child(&self, value: Value, path: ~str) -> Decoder {
+        Decoder::build(value, No, path, self.opts.clone())
+    }
+
+    fn child_state(&self, state: State, path: ~str) -> Decoder {
+        Decoder::build(NoValue, state, path, self.opts.clone())
+    }
+
+    fn fail(&mut self, error: DecodeError) {
+        if self.error.is_none() { self.error = Some(error) }
+    }
+
+    // Records that the current value is not of the `expected` type, or that
+    // it is missing
+    fn mismatch(&mut self, expected: &'static str) {
+        let error = match self.missing {
+            Some((ref table, ref field)) => MissingField { table: table.clone(), field: field.clone() },
+            None => InvalidType { at: self.path.clone(), expected: expected, found: self.value.type_str() }
+        };
+        self.fail(error);
+    }
+
+    fn out_of_range(&mut self, target: &'static str) {
+        let error = OutOfRange { at: self.path.clone(), target: target };
+        self.fail(error);
+    }
+
+    // Removes the entry of the struct field `name` from the table being
+    // decoded, matching keys as configured in the options. More than one
+    // matching key is an error.
+    fn take_field(&mut self, name: &str) -> Option<(~str, Value)> {
+        let opts = &self.opts;
+        let tab = match self.state {
+            Tab(ref mut tab) => tab,
+            _ => { return None }
+        };
+        if !opts.kebab_case && !opts.ignore_case && opts.renames.is_empty() {
+            let key = name.to_owned();
+            return tab.pop(&key).map(|v| (key, v));
+        }
+
+        let keys: ~[~str] = tab.keys().filter(|k| opts.key_matches(k.as_slice(), name))
+                                      .map(|k| k.clone()).collect();
+        match keys.len() {
+            0 => None,
+            1 => {
+                let key = keys[0].clone();
+                tab.pop(&key).map(|v| (key, v))
+            }
+            _ => {
+                let error = AmbiguousKeys { table: self.path.clone(), field: name.to_owned(), keys: keys };
+                if self.error.is_none() { self.error = Some(error) }
+                None
+            }
         }
     }
 
-    fn read_uint(&mut self) -> uint { self.read_u64().to_uint().unwrap() }
-    fn read_u32(&mut self) -> u32 { self.read_u64().to_u32().unwrap() }
-    fn read_u16(&mut self) -> u16 { self.read_u64().to_u16().unwrap() }
-    fn read_u8(&mut self) -> u8 { self.read_u64().to_u8().unwrap() }
+    // Runs `f` on `child` and takes over its error
+    fn decode_child<T>(&mut self, child: Decoder, f: |&mut Decoder| -> T) -> T {
+        let mut child = child;
+        let result = f(&mut child);
+        if self.error.is_none() { self.error = child.error }
+        result
+    }
 
-    fn read_i64(&mut self) -> i64 {
+    fn read_unsigned(&mut self, max: u64, target: &'static str) -> u64 {
         match self.value {
-            PosInt(v) => v.to_i64().unwrap(),
-            NegInt(v) => -(v.to_i64().unwrap()),
-            _ => fail!()
+            PosInt(v) if v <= max => v,
+            NegInt(0) => 0,
+            PosInt(..) | NegInt(..) => { self.out_of_range(target); 0 }
+            _ => { self.mismatch("integer"); 0 }
+        }
+    }
+
+    fn read_signed(&mut self, min: i64, max: i64, target: &'static str) -> i64 {
+        let v = match self.value {
+            PosInt(v) if v <= i64::MAX as u64 => v as i64,
+            NegInt(v) if v <= i64::MAX as u64 => -(v as i64),
+            NegInt(v) if v == i64::MAX as u64 + 1 => i64::MIN,
+            PosInt(..) | NegInt(..) => { self.out_of_range(target); return 0 }
+            _ => { self.mismatch("integer"); return 0 }
+        };
+        if v < min || v > max {
+            self.out_of_range(target);
+            return 0;
         }
+        return v;
+    }
+}
+
+impl serialize::Decoder for Decoder {
+    fn read_nil(&mut self) -> () {
+        let error = Unsupported { at: self.path.clone(), what: "()" };
+        self.fail(error);
     }
 
-    fn read_int(&mut self) -> int { self.read_i64().to_int().unwrap() }
-    fn read_i32(&mut self) -> i32 { self.read_i64().to_i32().unwrap() }
-    fn read_i16(&mut self) -> i16 { self.read_i64().to_i16().unwrap() }
-    fn read_i8(&mut self) -> i8 { self.read_i64().to_i8().unwrap() }
+    fn read_u64(&mut self) -> u64 { self.read_unsigned(u64::MAX, "u64") }
+    fn read_uint(&mut self) -> uint { self.read_unsigned(uint::MAX as u64, "uint") as uint }
+    fn read_u32(&mut self) -> u32 { self.read_unsigned(u32::MAX as u64, "u32") as u32 }
+    fn read_u16(&mut self) -> u16 { self.read_unsigned(u16::MAX as u64, "u16") as u16 }
+    fn read_u8(&mut self) -> u8 { self.read_unsigned(u8::MAX as u64, "u8") as u8 }
+
+    fn read_i64(&mut self) -> i64 { self.read_signed(i64::MIN, i64::MAX, "i64") }
+    fn read_int(&mut self) -> int { self.read_signed(int::MIN as i64, int::MAX as i64, "int") as int }
+    fn read_i32(&mut self) -> i32 { self.read_signed(i32::MIN as i64, i32::MAX as i64, "i32") as i32 }
+    fn read_i16(&mut self) -> i16 { self.read_signed(i16::MIN as i64, i16::MAX as i64, "i16") as i16 }
+    fn read_i8(&mut self) -> i8 { self.read_signed(i8::MIN as i64, i8::MAX as i64, "i8") as i8 }
 
     fn read_bool(&mut self) -> bool {
         match self.value {
             Boolean(b) => b,
-            _ => fail!()
+            _ => { self.mismatch("boolean"); false }
         }
     }
 
     fn read_f64(&mut self) -> f64 {
-         match self.value {
+        match self.value {
             Float(f) => f,
-            _ => fail!()
+            _ => { self.mismatch("float"); 0.0 }
         }
     }
 
     fn read_f32(&mut self) -> f32 {
-        self.read_f64().to_f32().unwrap()
+        self.read_f64() as f32
     }
 
     fn read_char(&mut self) -> char {
-        let s = self.read_str();
-        if s.len() != 1 { fail!("no character") }
-        s[0] as char
+        match self.value {
+            String(ref s) if s.char_len() == 1 => { return s.char_at(0) }
+            _ => {}
+        }
+        self.mismatch("character");
+        '\0'
     }
 
     fn read_str(&mut self) -> ~str {
         match mem::replace(&mut self.value, NoValue) {
             String(s) => s,
-            _ => fail!()
+            other => {
+                self.value = other;
+                self.mismatch("string");
+                ~""
+            }
         }
     }
 
-    fn read_enum<T>(&mut self, _name: &str, _f: |&mut Decoder| -> T) -> T { fail!() }
-    fn read_enum_variant<T>(&mut self, _names: &[&str], _f: |&mut Decoder, uint| -> T) -> T { fail!() }
-    fn read_enum_variant_arg<T>(&mut self, _idx: uint, _f: |&mut Decoder| -> T) -> T { fail!() }
+    fn read_enum<T>(&mut self, _name: &str, f: |&mut Decoder| -> T) -> T { f(self) }
 
-    fn read_seq<T>(&mut self, f: |&mut Decoder, uint| -> T) -> T {
-        match mem::replace(&mut self.value, NoValue) {
-            Array(a) | TableArray(a) => {
-                let l = a.len();
-                f(&mut Decoder::new_state(Arr(a.move_iter())), l)
+    // Only variants without fields are supported, written as their name
+    fn read_enum_variant<T>(&mut self, names: &[&str], f: |&mut Decoder, uint| -> T) -> T {
+        let idx = match self.value {
+            String(ref s) => names.iter().position(|name| *name == s.as_slice()),
+            _ => None
+        };
+        match idx {
+            Some(idx) => f(self, idx),
+            None => {
+                self.mismatch("name of an enum variant");
+                f(self, 0)
             }
-            _ => fail!()
         }
     }
 
-    fn read_seq_elt<T>(&mut self, _idx: uint, f: |&mut Decoder| -> T) -> T {
-        // XXX: assert(idx)
-        // XXX: assert!(self.value == NoValue);
-        // XXX: self.value = ...
-        match self.state {
-            Arr(ref mut a) => f(&mut Decoder::new(a.next().unwrap())),
-            _ => fail!()
-        }
+    fn read_enum_variant_arg<T>(&mut self, _idx: uint, f: |&mut Decoder| -> T) -> T {
+        let error = Unsupported { at: self.path.clone(), what: "enum variants with fields" };
+        self.fail(error);
+        let child = self.child(NoValue, self.path.clone());
+        self.decode_child(child, f)
+    }
+
+    fn read_seq<T>(&mut self, f: |&mut Decoder, uint| -> T) -> T {
+        let arr = match mem::replace(&mut self.value, NoValue) {
+            Array(a) | TableArray(a) => a,
+            other => {
+                self.value = other;
+                self.mismatch("array");
+                ~[]
+            }
+        };
+        let len = arr.len();
+        let mut seq = self.child_state(Arr(arr.move_iter()), self.path.clone());
+        let result = f(&mut seq, len);
+        if self.error.is_none() { self.error = seq.error }
+        result
+    }
+
+    fn read_seq_elt<T>(&mut self, idx: uint, f: |&mut Decoder| -> T) -> T {
+        let value = match self.state {
+            Arr(ref mut a) => a.next().unwrap_or(NoValue),
+            _ => NoValue
+        };
+        let child = self.child(value, format!("{}[{}]", self.path, idx));
+        self.decode_child(child, f)
     }
 
     fn read_struct<T>(&mut self, _name: &str, _len: uint, f: |&mut Decoder| -> T) -> T {
-        match mem::replace(&mut self.value, NoValue) {
-            Table(_, hm) => {
-                f(&mut Decoder::new_state(Tab(hm)))
+        let map = match mem::replace(&mut self.value, NoValue) {
+            Table(_, map) => map,
+            other => {
+                self.value = other;
+                self.mismatch("table");
+                ~TableMap::new()
+            }
+        };
+        let mut child = self.child_state(Tab(map), self.path.clone());
+        let result = f(&mut child);
+        if self.error.is_none() { self.error = child.error.take() }
+
+        if self.opts.deny_unknown_keys && self.error.is_none() {
+            match child.state {
+                Tab(ref map) if !map.is_empty() => {
+                    let keys = map.keys().map(|k| {
+                        (k.clone(), closest_name(k.as_slice(), child.fields.as_slice()))
+                    }).collect();
+                    let error = UnknownKeys { table: self.path.clone(), keys: keys };
+                    self.fail(error);
+                }
+                _ => {}
             }
-            _ => fail!()
         }
+        result
     }
 
     fn read_struct_field<T>(&mut self, name: &str, _idx: uint, f: |&mut Decoder| -> T) -> T {
-        // XXX: assert!(self.value == NoValue);
-        match self.state {
-            Tab(ref mut tab) => {
-                match tab.pop(&name.to_owned()) { // XXX: pop_equiv(...) or find_equiv_mut...
-                    None => f(&mut Decoder::new(NoValue)), // XXX: NoValue means "nil" here
-                    Some(val) => f(&mut Decoder::new(val))
-                }
+        self.fields.push(name.to_owned());
+        let child = match self.take_field(name) {
+            Some((key, val)) => {
+                let path = join_path(self.path.as_slice(), path_segment(key).as_slice());
+                self.child(val, path)
             }
-            _ => fail!()
-        }
+            None => {
+                // NoValue means "nil" here
+                let path = join_path(self.path.as_slice(), path_segment(name).as_slice());
+                let mut child = self.child(NoValue, path);
+                child.missing = Some((self.path.clone(), name.to_owned()));
+                child
+            }
+        };
+        self.decode_child(child, f)
     }
 
     fn read_option<T>(&mut self, f: |&mut Decoder, bool| -> T) -> T {
         match self.value {
-            NoValue => f(self, false), // XXX
+            NoValue => f(self, false),
             _ => f(self, true)
         }
     }
 
     fn read_map<T>(&mut self, f: |&mut Decoder, uint| -> T) -> T {
-        match mem::replace(&mut self.value, NoValue) {
-            Table(_, hm) => {
-                let len = hm.len();
-                f(&mut Decoder::new_state(Map(hm.move_iter())), len)
+        let map = match mem::replace(&mut self.value, NoValue) {
+            Table(_, map) => map,
+            other => {
+                self.value = other;
+                self.mismatch("table");
+                ~TableMap::new()
             }
-            _ => fail!()
-        }
+        };
+        let len = map.len();
+        let mut entries = self.child_state(Map(map.move_iter()), self.path.clone());
+        let result = f(&mut entries, len);
+        if self.error.is_none() { self.error = entries.error }
+        result
     }
 
     fn read_map_elt_key<T>(&mut self, _idx: uint, f: |&mut Decoder| -> T) -> T {
         let (k, v) = match self.state {
-            Map(ref mut map) => {
-                match map.next() {
-                    None => fail!(),
-                    Some((k, v)) => (k, v)
-                }
-            }
-            _ => fail!()
+            Map(ref mut map) => map.next().unwrap_or((~"", NoValue)),
+            _ => (~"", NoValue)
         };
-        self.value = String(k);
-        let res = f(self);
-        self.value = v;
+        let path = join_path(self.path.as_slice(), path_segment(k).as_slice());
+        let child = self.child(String(k), path.clone());
+        let res = self.decode_child(child, f);
+        self.entry = Some((path, v));
         res
     }
 
     fn read_map_elt_val<T>(&mut self, _idx: uint, f: |&mut Decoder| -> T) -> T {
-        f(self)
+        let (path, value) = self.entry.take().unwrap_or((self.path.clone(), NoValue));
+        let child = self.child(value, path);
+        self.decode_child(child, f)
     }
 
     fn read_enum_struct_variant<T>(&mut self,
@@ -1074,7 +3386,279 @@ impl serialize::Decoder for Decoder {
     }
 }
 
+/// Decodes a `T` from `value`. Struct fields are looked up by name;
+/// missing fields of type `Option` become `None`, other missing fields are
+/// an error. Vectors can be decoded from arrays and arrays of tables, and
+/// integers are checked to fit the target type. Keys without a matching
+/// field are ignored, see `DecodeOptions` to reject them.
+pub fn decode<T: Decodable<Decoder>>(value: Value) -> Result<T, DecodeError> {
+    decode_with(value, &DecodeOptions::new())
+}
+
+/// Like `decode`, with options
+pub fn decode_with<T: Decodable<Decoder>>(value: Value, opts: &DecodeOptions) -> Result<T, DecodeError> {
+    let mut decoder = Decoder::with_options(value, opts.clone());
+    let result = Decodable::decode(&mut decoder);
+    match decoder.error {
+        Some(e) => Err(e),
+        None => Ok(result)
+    }
+}
+
+//
+// Returns the name closest to `key` by edit distance, if it is close
+// enough to be a likely typo.
+//
+fn closest_name(key: &str, names: &[~str]) -> Option<~str> {
+    let mut best: Option<(uint, &~str)> = None;
+    for name in names.iter() {
+        let d = edit_distance(key, name.as_slice());
+        if d <= 2 && d < key.char_len() && best.map_or(true, |(bd, _)| d < bd) {
+            best = Some((d, name));
+        }
+    }
+    best.map(|(_, name)| name.clone())
+}
+
+fn edit_distance(a: &str, b: &str) -> uint {
+    let b: ~[char] = b.chars().collect();
+    let mut prev: ~[uint] = range(0, b.len() + 1).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = ~[i + 1];
+        for (j, &cb) in b.iter().enumerate() {
+            let cost = if ca == cb { 0 } else { 1 };
+            let d = cmp::min(cmp::min(prev[j + 1] + 1, cur[j] + 1), prev[j] + cost);
+            cur.push(d);
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+/// Decodes a `T` from `value`, taking keys missing in `value` from
+/// `defaults`: the encoded defaults are merged with `value` as by
+/// `Value::merge`, so nested tables fall back key by key, while arrays in
+/// `value` replace those of the defaults as a whole.
+pub fn decode_with_defaults<T: Decodable<Decoder> + Encodable<Encoder>>(value: Value, defaults: &T)
+                                                                         -> Result<T, DecodeError> {
+    let mut merged = match encode(defaults) {
+        Ok(v) => v,
+        Err(e) => { return Err(InvalidDefaults(e)) }
+    };
+    match merged.merge(&value) {
+        Ok(()) => {}
+        Err(_) => { return Err(InvalidType { at: ~"", expected: "table", found: value.type_str() }) }
+    }
+    decode(merged)
+}
+
+/// Like `decode`, but fails the task on errors
 pub fn from_toml<T: Decodable<Decoder>>(value: Value) -> T {
-    let mut decoder = Decoder::new(value);
-    Decodable::decode(&mut decoder)
+    match decode(value) {
+        Ok(v) => v,
+        Err(e) => fail!("{}", e)
+    }
+}
+
+/// Builds a `Value` from an `Encodable` type. Structs and string-keyed
+/// maps become tables, vectors become arrays (arrays of tables if all
+/// elements are tables), tuples become arrays and fieldless enum variants
+/// become strings of their name. Fields whose value is `None` are left
+/// out. Values TOML cannot represent are errors, see `encode`.
+pub struct Encoder {
+    priv value: Value,
+    priv path: ~str,
+    priv table: TableMap,
+    priv array: ~[Value],
+    priv key: Option<~str>,
+    priv error: Option<~str>
+}
+
+impl Encoder {
+    pub fn new() -> Encoder {
+        Encoder::at(~"")
+    }
+
+    fn at(path: ~str) -> Encoder {
+        Encoder { value: NoValue, path: path, table: TableMap::new(), array: ~[],
+                  key: None, error: None }
+    }
+
+    /// The first error, if any value could not be encoded
+    pub fn error<'a>(&'a self) -> Option<&'a ~str> {
+        self.error.as_ref()
+    }
+
+    /// The encoded value
+    pub fn unwrap(self) -> Value {
+        self.value
+    }
+
+    fn fail(&mut self, msg: &str) {
+        if self.error.is_none() {
+            let at = if self.path.is_empty() { ~"root" } else { self.path.clone() };
+            self.error = Some(format!("{}: {}", at, msg));
+        }
+    }
+
+    // Encodes into a fresh encoder for the child at `path` and takes over
+    // its error. Returns NoValue on errors.
+    fn encode_child(&mut self, path: ~str, f: |&mut Encoder|) -> Value {
+        let mut child = Encoder::at(path);
+        f(&mut child);
+        match child.error {
+            Some(e) => {
+                if self.error.is_none() { self.error = Some(e) }
+                NoValue
+            }
+            None => child.value
+        }
+    }
+}
+
+impl serialize::Encoder for Encoder {
+    fn emit_nil(&mut self) { self.fail("`()` cannot be represented") }
+
+    fn emit_uint(&mut self, v: uint) { self.emit_u64(v as u64) }
+    fn emit_u64(&mut self, v: u64) { self.value = PosInt(v) }
+    fn emit_u32(&mut self, v: u32) { self.emit_u64(v as u64) }
+    fn emit_u16(&mut self, v: u16) { self.emit_u64(v as u64) }
+    fn emit_u8(&mut self, v: u8) { self.emit_u64(v as u64) }
+
+    fn emit_int(&mut self, v: int) { self.emit_i64(v as i64) }
+    fn emit_i64(&mut self, v: i64) {
+        self.value = if v < 0 { NegInt((-(v + 1)) as u64 + 1) } else { PosInt(v as u64) };
+    }
+    fn emit_i32(&mut self, v: i32) { self.emit_i64(v as i64) }
+    fn emit_i16(&mut self, v: i16) { self.emit_i64(v as i64) }
+    fn emit_i8(&mut self, v: i8) { self.emit_i64(v as i64) }
+
+    fn emit_bool(&mut self, v: bool) { self.value = Boolean(v) }
+    fn emit_f64(&mut self, v: f64) { self.value = Float(v) }
+    fn emit_f32(&mut self, v: f32) { self.emit_f64(v as f64) }
+    fn emit_char(&mut self, v: char) { self.value = String(std::str::from_char(v)) }
+    fn emit_str(&mut self, v: &str) { self.value = String(v.to_owned()) }
+
+    fn emit_enum(&mut self, _name: &str, f: |&mut Encoder|) { f(self) }
+
+    fn emit_enum_variant(&mut self, name: &str, _id: uint, len: uint, _f: |&mut Encoder|) {
+        if len == 0 {
+            self.value = String(name.to_owned());
+        } else {
+            self.fail(format!("enum variant `{}` has fields", name));
+        }
+    }
+
+    fn emit_enum_variant_arg(&mut self, _idx: uint, _f: |&mut Encoder|) {}
+
+    fn emit_enum_struct_variant(&mut self, name: &str, id: uint, len: uint, f: |&mut Encoder|) {
+        self.emit_enum_variant(name, id, len, f)
+    }
+
+    fn emit_enum_struct_variant_field(&mut self, _name: &str, idx: uint, f: |&mut Encoder|) {
+        self.emit_enum_variant_arg(idx, f)
+    }
+
+    fn emit_struct(&mut self, _name: &str, _len: uint, f: |&mut Encoder|) {
+        self.emit_map(0, f)
+    }
+
+    fn emit_struct_field(&mut self, name: &str, _idx: uint, f: |&mut Encoder|) {
+        let path = join_path(self.path.as_slice(), path_segment(name).as_slice());
+        match self.encode_child(path, f) {
+            NoValue => {}
+            v => { self.table.insert(name.to_owned(), v); }
+        }
+    }
+
+    fn emit_tuple(&mut self, len: uint, f: |&mut Encoder|) { self.emit_seq(len, f) }
+    fn emit_tuple_arg(&mut self, idx: uint, f: |&mut Encoder|) { self.emit_seq_elt(idx, f) }
+
+    fn emit_tuple_struct(&mut self, _name: &str, len: uint, f: |&mut Encoder|) {
+        self.emit_seq(len, f)
+    }
+
+    fn emit_tuple_struct_arg(&mut self, idx: uint, f: |&mut Encoder|) {
+        self.emit_seq_elt(idx, f)
+    }
+
+    fn emit_option(&mut self, f: |&mut Encoder|) { f(self) }
+    fn emit_option_none(&mut self) { self.value = NoValue }
+    fn emit_option_some(&mut self, f: |&mut Encoder|) { f(self) }
+
+    fn emit_seq(&mut self, _len: uint, f: |&mut Encoder|) {
+        let mut seq = Encoder::at(self.path.clone());
+        f(&mut seq);
+        if seq.error.is_some() {
+            self.error = seq.error;
+            return;
+        }
+        let arr = seq.array;
+        let all_tables = !arr.is_empty() && arr.iter().all(|v| match *v { Table(..) => true, _ => false });
+        self.value = if all_tables { TableArray(arr) } else { Array(arr) };
+    }
+
+    fn emit_seq_elt(&mut self, idx: uint, f: |&mut Encoder|) {
+        let path = format!("{}[{}]", self.path, idx);
+        match self.encode_child(path, f) {
+            NoValue => { self.fail("`None` inside a sequence cannot be represented") }
+            v => { self.array.push(v) }
+        }
+    }
+
+    fn emit_map(&mut self, _len: uint, f: |&mut Encoder|) {
+        let mut map = Encoder::at(self.path.clone());
+        f(&mut map);
+        if map.error.is_some() {
+            self.error = map.error;
+            return;
+        }
+        self.value = Table(false, ~map.table);
+    }
+
+    fn emit_map_elt_key(&mut self, _idx: uint, f: |&mut Encoder|) {
+        let path = self.path.clone();
+        match self.encode_child(path, f) {
+            String(key) => { self.key = Some(key) }
+            NoValue => {}
+            _ => { self.fail("map keys must be strings") }
+        }
+    }
+
+    fn emit_map_elt_val(&mut self, _idx: uint, f: |&mut Encoder|) {
+        let key = match self.key.take() {
+            Some(key) => key,
+            None => { return }
+        };
+        let path = join_path(self.path.as_slice(), path_segment(key.as_slice()).as_slice());
+        match self.encode_child(path, f) {
+            NoValue => {}
+            v => { self.table.insert(key, v); }
+        }
+    }
+}
+
+/// Encodes `value` into a table. Fails with a message naming the path of
+/// the offending value if the value is not a struct or map, or contains
+/// `()`, enum variants with fields, maps with non-string keys or `None`
+/// inside a sequence.
+pub fn encode<T: Encodable<Encoder>>(value: &T) -> Result<Value, ~str> {
+    let mut encoder = Encoder::new();
+    value.encode(&mut encoder);
+    match encoder.error {
+        Some(e) => { return Err(e) }
+        None => {}
+    }
+    match encoder.value {
+        v @ Table(..) => Ok(v),
+        _ => Err(~"root: only structs and maps can be encoded as a TOML document")
+    }
+}
+
+/// Encodes `value` as TOML text, see `encode`
+pub fn encode_str<T: Encodable<Encoder>>(value: &T) -> Result<~str, ~str> {
+    match encode(value) {
+        Ok(v) => v.to_toml_str().map_err(|e| format!("{}", e)),
+        Err(e) => Err(e)
+    }
 }
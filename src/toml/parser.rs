@@ -0,0 +1,576 @@
+//! Building documents from the tokens of the lexer
+
+use std::mem;
+
+use super::{Error,ParseError,ParserOptions,Rejected,Span,Visitor,MAX_VALUE_DEPTH};
+use super::{TomlVersion,V0_4,V1_0};
+use super::{TableMap,Value,Boolean,PosInt,NegInt,Float,String,Datetime,Array,TableArray,Table};
+use super::{have_equiv_types,path_segment};
+use super::lexer;
+use super::lexer::{Lexer,Token};
+
+// Receives what the parser reads, like a `Visitor`, and builds the values
+// of pairs, of type `V` with keys and strings of type `K`. This lets
+// `parse_borrowed` share the grammar with the other parsers.
+pub trait Builder<K, V> {
+    // The key or string of the token just read at `span`: `text`, unless
+    // the lexer left it empty to be borrowed, see `Lexer::verbatim`
+    fn text(&self, text: ~str, span: &Span, verbatim: bool) -> K;
+
+    // A boolean, number or datetime
+    fn scalar(&self, val: Value) -> V;
+    fn string(&self, s: K) -> V;
+    // An array, holding only tables if `of_tables` is set
+    fn array(&self, elms: ~[V], of_tables: bool) -> V;
+    fn inline_table(&self) -> V;
+    // Adds a pair to a table made by `inline_table`, giving the key back
+    // if it is there already
+    fn inline_pair(&self, table: &mut V, key: K, val: V) -> Result<(), K>;
+
+    fn is_table(&self, val: &V) -> bool;
+    // Whether TOML 0.4 allows `a` and `b` in the same array
+    fn same_type(&self, a: &V, b: &V) -> bool;
+
+    // See the methods of the same names of `Visitor`
+    fn section(&mut self, path: ~[K], is_array: bool, span: Span) -> Result<(), ~str>;
+    fn pair(&mut self, key: K, val: V, span: Span) -> Result<(), ~str>;
+    fn value_spans(&mut self, _spans: ~[(~str, Span)]) {}
+}
+
+// Builds `Value`s for a `Visitor`
+struct VisitorBuilder<'v, T> {
+    visitor: &'v mut T
+}
+
+impl<'v, T: Visitor> Builder<~str, Value> for VisitorBuilder<'v, T> {
+    fn text(&self, text: ~str, _span: &Span, _verbatim: bool) -> ~str { text }
+
+    fn scalar(&self, val: Value) -> Value { val }
+
+    fn string(&self, s: ~str) -> Value { String(s) }
+
+    fn array(&self, elms: ~[Value], of_tables: bool) -> Value {
+        if of_tables { TableArray(elms) } else { Array(elms) }
+    }
+
+    fn inline_table(&self) -> Value { Table(true, ~TableMap::new()) }
+
+    fn inline_pair(&self, table: &mut Value, key: ~str, val: Value) -> Result<(), ~str> {
+        match *table {
+            Table(_, ref mut map) => {
+                if map.contains_key(&key) { return Err(key) }
+                map.insert(key, val);
+                Ok(())
+            }
+            _ => { unreachable!() }
+        }
+    }
+
+    fn is_table(&self, val: &Value) -> bool {
+        match *val { Table(..) => true, _ => false }
+    }
+
+    fn same_type(&self, a: &Value, b: &Value) -> bool { have_equiv_types(a, b) }
+
+    fn section(&mut self, path: ~[~str], is_array: bool, span: Span) -> Result<(), ~str> {
+        self.visitor.section(path, is_array, span)
+    }
+
+    fn pair(&mut self, key: ~str, val: Value, span: Span) -> Result<(), ~str> {
+        self.visitor.pair(key, val, span)
+    }
+
+    fn value_spans(&mut self, spans: ~[(~str, Span)]) {
+        self.visitor.value_spans(spans)
+    }
+}
+
+pub struct Parser<'a, BUF> {
+    priv lexer: Lexer<'a, BUF>,
+    // the span of the last token read
+    priv last: Span,
+    // the spans of the values of the current pair, if recorded
+    priv spans: Option<~[(~str, Span)]>,
+    // what was wrong when parsing failed with a `ParseError`, and where
+    priv message: ~str,
+    priv error_span: Option<Span>,
+    // the limits of `ParserOptions`, and the number of keys read
+    priv max_string_len: Option<uint>,
+    priv max_array_len: Option<uint>,
+    priv max_total_keys: Option<uint>,
+    priv max_input_bytes: Option<uint>,
+    priv total_keys: uint,
+    // whether values have to start on the line of their '='
+    priv strict: bool,
+    // the version of TOML to accept
+    priv version: TomlVersion,
+    // the sections, pairs, arrays and inline tables being read, and their
+    // lines, innermost last
+    priv within: ~[(~str, uint)]
+}
+
+impl<'a, BUF: Buffer> Parser<'a, BUF> {
+    pub fn new(rd: &'a mut BUF, record_spans: bool) -> Parser<'a, BUF> {
+        Parser {
+            lexer: Lexer::new(rd),
+            last: Span { line: 1, col: 1, byte_start: 0, byte_end: 0 },
+            spans: if record_spans { Some(~[]) } else { None },
+            message: ~"",
+            error_span: None,
+            max_string_len: None,
+            max_array_len: None,
+            max_total_keys: None,
+            max_input_bytes: None,
+            total_keys: 0,
+            strict: false,
+            version: V1_0,
+            within: ~[]
+        }
+    }
+
+    // Applies the size limits of `opts`
+    pub fn set_limits(&mut self, opts: &ParserOptions) {
+        self.max_string_len = opts.max_string_len;
+        self.max_array_len = opts.max_array_len;
+        self.max_total_keys = opts.max_total_keys;
+        self.lexer.set_max_string_len(opts.max_string_len);
+        self.lexer.set_max_input_bytes(opts.max_input_bytes);
+        self.max_input_bytes = opts.max_input_bytes;
+    }
+
+    // How many bytes of the input were read so far
+    pub fn bytes_read(&self) -> uint { self.lexer.bytes_read() }
+
+    // Reads invalid UTF-8 as U+FFFD instead of failing
+    pub fn set_lossy_utf8(&mut self, lossy: bool) {
+        self.lexer.set_lossy_utf8(lossy);
+    }
+
+    // Rejects what TOML 1.0 forbids but is let through otherwise, see
+    // `ParserOptions::strict`
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+        self.lexer.set_strict(strict);
+    }
+
+    // Rejects what later versions than `version` added to TOML
+    pub fn set_version(&mut self, version: TomlVersion) {
+        self.version = version;
+    }
+
+    // Leaves keys and strings without escapes to be borrowed from the
+    // input by the builder, see `Lexer::set_borrowing`
+    pub fn set_borrowing(&mut self, borrowing: bool) {
+        self.lexer.set_borrowing(borrowing);
+    }
+
+    pub fn get_line(&self) -> uint { self.lexer.get_line() }
+
+    // Where parsing failed: the token that was not expected, the section
+    // or pair the visitor rejected, or else the position reached in the
+    // input
+    pub fn error_span(&self) -> Span {
+        match self.error_span {
+            Some(span) => span,
+            None => self.lexer.span()
+        }
+    }
+
+    // Describes the `ParseError` parsing failed with, like "expected '='
+    // after key"
+    pub fn message<'b>(&'b self) -> &'b str {
+        if self.message.is_empty() { "invalid TOML" } else { self.message.as_slice() }
+    }
+
+    // Fails at the last token read
+    fn fail<T>(&mut self, message: &str) -> Result<T, Error> {
+        let span = self.last;
+        self.fail_at(span, message)
+    }
+
+    fn fail_at<T>(&mut self, span: Span, message: &str) -> Result<T, Error> {
+        self.message = message.to_owned();
+        self.error_span = Some(span);
+        Err(ParseError)
+    }
+
+    fn reject<T>(&mut self, span: Span, reason: ~str) -> Result<T, Error> {
+        self.error_span = Some(span);
+        Err(Rejected { line: span.line, reason: reason })
+    }
+
+    // Counts the key of the last token read against `max_total_keys`
+    fn count_key(&mut self) -> Result<(), Error> {
+        self.total_keys += 1;
+        match self.max_total_keys {
+            Some(max) if self.total_keys > max => {
+                let span = self.last;
+                self.reject(span, format!("more than max_total_keys ({}) keys", max))
+            }
+            _ => Ok(())
+        }
+    }
+
+    // Notes that `what`, like "array", starts at `span`, until `leave` is
+    // called, so that the end of the input inside it can be reported
+    fn enter(&mut self, what: ~str, span: Span) {
+        self.within.push((what, span.line));
+    }
+
+    fn leave(&mut self) {
+        self.within.pop();
+    }
+
+    // Returns the next token, skipping comments and line breaks
+    fn next(&mut self) -> Result<(Token, Span), Error> {
+        loop {
+            match self.next_in_line() {
+                Ok((lexer::Comment(..), _)) | Ok((lexer::Newline, _)) => {}
+                other => { return other }
+            }
+        }
+    }
+
+    // Returns the next token, including comments and line breaks
+    fn next_in_line(&mut self) -> Result<(Token, Span), Error> {
+        match self.lexer.next_token() {
+            Ok((lexer::Eof, span)) if !self.within.is_empty() => {
+                self.last = span;
+                let message = match self.within.last() {
+                    Some(&(ref what, line)) => unexpected_end(what.as_slice(), line),
+                    None => ~""
+                };
+                self.fail_at(span, message)
+            }
+            Ok((token, span)) => {
+                self.last = span;
+                Ok((token, span))
+            }
+            Err(_) if self.lexer.input_too_long() => {
+                let span = self.lexer.span();
+                let max = self.max_input_bytes.unwrap_or(0);
+                self.reject(span, format!("input longer than max_input_bytes ({} bytes)", max))
+            }
+            Err(_) if self.lexer.invalid_utf8() => {
+                let span = self.lexer.span();
+                self.reject(span, format!("invalid UTF-8 at byte {}", span.byte_start))
+            }
+            Err(ParseError) => {
+                let span = self.lexer.span();
+                if self.lexer.string_too_long() {
+                    let max = self.max_string_len.unwrap();
+                    return self.reject(span, format!("string longer than max_string_len ({} bytes)", max));
+                }
+                match self.lexer.unfinished() {
+                    Some((what, line)) => { return self.fail_at(span, unexpected_end(what, line)) }
+                    None => {}
+                }
+                match self.lexer.control_char() {
+                    Some(c) => { return self.fail_at(span, format!("unexpected control character 0x{:02X}", c as u32)) }
+                    None => {}
+                }
+                let message = self.lexer.expected();
+                self.fail_at(span, message)
+            }
+            Err(e) => Err(e)
+        }
+    }
+
+    // Reads the rest of the line after a pair or section header, which
+    // may only hold a comment, failing with `message` on anything else
+    fn end_line(&mut self, message: &str) -> Result<(), Error> {
+        loop {
+            match self.next_in_line() {
+                Ok((lexer::Comment(..), _)) => {}
+                Ok((lexer::Newline, _)) | Ok((lexer::Eof, _)) => { return Ok(()) }
+                Ok(_) => { return self.fail(message) }
+                Err(e) => { return Err(e) }
+            }
+        }
+    }
+
+    fn next_token(&mut self) -> Result<Token, Error> {
+        self.next().map(|(token, _)| token)
+    }
+
+    // Records the span of a value from its first token up to the last
+    // token read
+    fn record_span(&mut self, path: &str, span: Span) {
+        let end = self.last.byte_end;
+        match self.spans {
+            Some(ref mut spans) => { spans.push((path.to_owned(), Span { byte_end: end, .. span })) }
+            None => {}
+        }
+    }
+
+    fn expect(&mut self, expected: Token, message: &str) -> Result<(), Error> {
+        match self.next_token() {
+            Ok(ref token) if *token == expected => Ok(()),
+            Ok(_) => self.fail(message),
+            Err(e) => Err(e)
+        }
+    }
+
+    pub fn parse<T: Visitor>(&mut self, visitor: &mut T) -> Result<(),Error> {
+        self.build(&mut VisitorBuilder { visitor: visitor })
+    }
+
+    // Like `parse`, building the values with `builder`
+    pub fn build<K: Str, V, B: Builder<K, V>>(&mut self, builder: &mut B) -> Result<(),Error> {
+        loop {
+            let (token, span) = match self.next() {
+                Ok(next) => next,
+                Err(e) => { return Err(e) }
+            };
+
+            match token {
+                lexer::Eof => { return Ok(()) }
+
+                // section
+                lexer::LBracket | lexer::DoubleLBracket => {
+                    let double_section = token == lexer::DoubleLBracket;
+                    let (close, message) = if double_section {
+                        (lexer::DoubleRBracket, "expected ']]' to close array-of-tables header")
+                    } else {
+                        (lexer::RBracket, "expected ']' after section name")
+                    };
+                    // the keys of the name, separated by dots, and the
+                    // first one left empty without quotes
+                    let mut path = ~[];
+                    let mut empty_key = None;
+                    loop {
+                        match self.next() {
+                            Ok((lexer::Key(key), key_span)) => {
+                                if key_span.byte_start == key_span.byte_end && empty_key.is_none() {
+                                    empty_key = Some(key_span);
+                                }
+                                path.push(builder.text(key, &key_span, self.lexer.verbatim()));
+                            }
+                            Ok((lexer::Eof, _)) => { return self.fail("unterminated table header") }
+                            Ok(_) => { return self.fail("expected a section name") }
+                            Err(e) => { return Err(e) }
+                        }
+                        match self.next_token() {
+                            Ok(lexer::Dot) => {}
+                            Ok(ref token) if *token == close => { break }
+                            Ok(lexer::Eof) => { return self.fail("unterminated table header") }
+                            Ok(_) => { return self.fail(message) }
+                            Err(e) => { return Err(e) }
+                        }
+                    }
+                    match empty_key {
+                        Some(_) if path.len() == 1 => { return self.fail_at(span, "empty table name in header") }
+                        Some(key_span) => { return self.fail_at(key_span, "empty key in table header") }
+                        None => {}
+                    }
+
+                    let span = Span { byte_end: self.last.byte_end, .. span };
+                    match builder.section(path, double_section, span) {
+                        Ok(()) => {}
+                        Err(reason) => { return self.reject(span, reason) }
+                    }
+                    match self.end_line("unexpected character after table header") {
+                        Ok(()) => {}
+                        Err(e) => { return Err(e) }
+                    }
+                }
+
+                lexer::Key(ident) => {
+                    let key = builder.text(ident, &span, self.lexer.verbatim());
+                    match self.count_key() {
+                        Ok(()) => {}
+                        Err(e) => { return Err(e) }
+                    }
+                    self.enter(format!("pair `{}`", path_segment(key.as_slice())), span);
+                    match self.parse_pair_rest(key, span, builder) {
+                        Err(e) => { return Err(e) }
+                        Ok(()) => {}
+                    }
+                    self.leave();
+                    match self.end_line("expected a line break after value") {
+                        Ok(()) => {}
+                        Err(e) => { return Err(e) }
+                    }
+                }
+
+                _ => { return self.fail("expected a key or a section header") }
+            }
+        }
+    }
+
+    // parses the "= value" part of a key/value pair
+    fn parse_pair_rest<K: Str, V, B: Builder<K, V>>(&mut self, key: K, span: Span, builder: &mut B) -> Result<(),Error> {
+        match self.expect(lexer::Equals, "expected '=' after key") {
+            Ok(()) => {}
+            Err(e) => { return Err(e) }
+        }
+
+        match self.parse_assigned_value("", builder) {
+            Ok(val) => {
+                match builder.pair(key, val, span) {
+                    Ok(()) => {}
+                    Err(reason) => { return self.reject(span, reason) }
+                }
+                match self.spans {
+                    Some(ref mut spans) if !spans.is_empty() => {
+                        builder.value_spans(mem::replace(spans, ~[]));
+                    }
+                    _ => {}
+                }
+                return Ok(());
+            }
+            Err(e) => { return Err(e) }
+        }
+    }
+
+    // parses the value after a '=', which has to start on the same line
+    // in strict mode
+    fn parse_assigned_value<K: Str, V, B: Builder<K, V>>(&mut self, path: &str, builder: &mut B) -> Result<V, Error> {
+        if !self.strict { return self.parse_value(path, builder) }
+        let line = self.last.line;
+        match self.next_in_line() {
+            Ok((lexer::Newline, _)) | Ok((lexer::Comment(..), _)) | Ok((lexer::Eof, _)) => {
+                self.fail(format!("expected value after '=' on line {}", line))
+            }
+            Ok((token, span)) => self.parse_value_from(token, span, path, builder),
+            Err(e) => Err(e)
+        }
+    }
+
+    // parses a value at `path`, relative to the value of the current pair
+    fn parse_value<K: Str, V, B: Builder<K, V>>(&mut self, path: &str, builder: &mut B) -> Result<V, Error> {
+        match self.next() {
+            Ok((token, span)) => self.parse_value_from(token, span, path, builder),
+            Err(e) => Err(e)
+        }
+    }
+
+    fn parse_value_from<K: Str, V, B: Builder<K, V>>(&mut self, token: Token, span: Span, path: &str,
+                                                     builder: &mut B) -> Result<V, Error> {
+        let value = match token {
+            lexer::StringLit(s) => {
+                let s = builder.text(s, &span, self.lexer.verbatim());
+                builder.string(s)
+            }
+            lexer::IntLit(true, n) => builder.scalar(NegInt(n)),
+            lexer::IntLit(false, n) => builder.scalar(PosInt(n)),
+            lexer::FloatLit(f) if self.version == V0_4 && (f.is_nan() || f.is_infinite()) => {
+                return self.reject(span, ~"inf and nan are not allowed in TOML 0.4");
+            }
+            lexer::FloatLit(f) => builder.scalar(Float(f)),
+            lexer::BoolLit(b) => builder.scalar(Boolean(b)),
+            lexer::DatetimeLit(y, m, d, h, mi, s) => builder.scalar(Datetime(y, m, d, h, mi, s)),
+            lexer::LBracket | lexer::LBrace if self.lexer.nesting_depth() > MAX_VALUE_DEPTH => {
+                return self.fail_at(span, format!("arrays and inline tables nested deeper than {}", MAX_VALUE_DEPTH));
+            }
+            lexer::LBracket => {
+                self.enter(~"array", span);
+                let value = match self.parse_array(path, builder) {
+                    Ok(value) => value,
+                    Err(e) => { return Err(e) }
+                };
+                self.leave();
+                value
+            }
+            lexer::LBrace => {
+                self.enter(~"inline table", span);
+                let value = match self.parse_inline_table(path, builder) {
+                    Ok(value) => value,
+                    Err(e) => { return Err(e) }
+                };
+                self.leave();
+                value
+            }
+            _ => { return self.fail("expected a value") }
+        };
+        self.record_span(path, span);
+        return Ok(value);
+    }
+
+    // parses the elements of an array after the '['
+    fn parse_array<K: Str, V, B: Builder<K, V>>(&mut self, path: &str, builder: &mut B) -> Result<V, Error> {
+        let mut arr = ~[];
+        loop {
+            let val = match self.next() {
+                Ok((lexer::RBracket, _)) => { break }
+                Ok((_, span)) if Some(arr.len()) == self.max_array_len => {
+                    let max = arr.len();
+                    return self.reject(span, format!("array longer than max_array_len ({} elements)", max));
+                }
+                Ok((token, span)) => {
+                    let elm_path = format!("{}[{}]", path, arr.len());
+                    match self.parse_value_from(token, span, elm_path, builder) {
+                        Ok(val) => val,
+                        Err(e) => { return Err(e) }
+                    }
+                }
+                Err(e) => { return Err(e) }
+            };
+            if !arr.is_empty() && self.version == V0_4 && !builder.same_type(arr.head().unwrap(), &val) {
+                debug!("Incompatible element types in array");
+                let span = self.last;
+                return self.reject(span, ~"arrays of mixed types are not allowed in TOML 0.4");
+            }
+            arr.push(val);
+
+            match self.next_token() {
+                Ok(lexer::Comma) => {}
+                Ok(lexer::RBracket) => { break }
+                Ok(_) => { return self.fail("expected ',' or ']' after array element") }
+                Err(e) => { return Err(e) }
+            }
+        }
+
+        // an array of inline tables is an array of tables
+        let of_tables = !arr.is_empty() && arr.iter().all(|v| builder.is_table(v));
+        Ok(builder.array(arr, of_tables))
+    }
+
+    // parses the pairs of an inline table after the '{'
+    fn parse_inline_table<K: Str, V, B: Builder<K, V>>(&mut self, path: &str, builder: &mut B) -> Result<V, Error> {
+        let mut table = builder.inline_table();
+        loop {
+            let key = match self.next() {
+                Ok((lexer::RBrace, _)) => { break }
+                Ok((lexer::Key(key), span)) => builder.text(key, &span, self.lexer.verbatim()),
+                Ok(_) => { return self.fail("expected a key or '}' in inline table") }
+                Err(e) => { return Err(e) }
+            };
+            match self.count_key() {
+                Ok(()) => {}
+                Err(e) => { return Err(e) }
+            }
+            match self.expect(lexer::Equals, "expected '=' after key") {
+                Ok(()) => {}
+                Err(e) => { return Err(e) }
+            }
+            let key_path = format!("{}.{}", path, path_segment(key.as_slice()));
+            match self.parse_assigned_value(key_path, builder) {
+                Ok(val) => {
+                    match builder.inline_pair(&mut table, key, val) {
+                        Ok(()) => {}
+                        Err(key) => {
+                            debug!("Duplicate key in inline table");
+                            return self.fail(format!("duplicate key `{}` in inline table", key.as_slice()));
+                        }
+                    }
+                }
+                Err(e) => { return Err(e) }
+            }
+
+            match self.next_token() {
+                Ok(lexer::Comma) => {}
+                Ok(lexer::RBrace) => { break }
+                Ok(_) => { return self.fail("expected ',' or '}' after inline table value") }
+                Err(e) => { return Err(e) }
+            }
+        }
+        return Ok(table);
+    }
+}
+
+// The message for the end of the input inside `what`, which started on
+// `line`
+fn unexpected_end(what: &str, line: uint) -> ~str {
+    format!("unexpected end of input while parsing {} started at line {}", what, line)
+}
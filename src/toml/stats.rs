@@ -0,0 +1,163 @@
+//! Structural statistics of documents, for linters and the like
+
+use std::cmp;
+use collections::hashmap::{HashMap,HashSet};
+
+use super::{Value,NoValue,Boolean,PosInt,NegInt,Float,String,Datetime,Array,TableArray,Table};
+use super::{Span,TableMap,Visitor,join_path,path_segment};
+
+/// The numbers of `Value::stats`. `tables` counts the tables below the
+/// root, every table of an array of tables included, and `keys` the
+/// entries of all tables. A value's depth is the number of keys and array
+/// indices leading to it, so the keys of the root table are at depth 1.
+/// `max_array_len` covers arrays of tables too, and `total_string_bytes`
+/// is the length in UTF-8 of all strings.
+#[deriving(Clone,Eq,Show)]
+pub struct Stats {
+    keys: uint,
+    tables: uint,
+    table_arrays: uint,
+    arrays: uint,
+    strings: uint,
+    integers: uint,
+    floats: uint,
+    booleans: uint,
+    datetimes: uint,
+    max_depth: uint,
+    max_array_len: uint,
+    total_string_bytes: uint
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats { keys: 0, tables: 0, table_arrays: 0, arrays: 0, strings: 0, integers: 0, floats: 0,
+                booleans: 0, datetimes: 0, max_depth: 0, max_array_len: 0, total_string_bytes: 0 }
+    }
+
+    // Counts `value`, found at `depth`, and the values nested in it
+    fn add(&mut self, value: &Value, depth: uint) {
+        self.max_depth = cmp::max(self.max_depth, depth);
+        match *value {
+            NoValue => {}
+            Boolean(_) => { self.booleans += 1 }
+            PosInt(_) | NegInt(_) => { self.integers += 1 }
+            Float(_) => { self.floats += 1 }
+            String(ref s) => {
+                self.strings += 1;
+                self.total_string_bytes += s.len();
+            }
+            Datetime(..) => { self.datetimes += 1 }
+            Array(ref ary) => {
+                self.arrays += 1;
+                self.add_elements(ary.as_slice(), depth);
+            }
+            TableArray(ref ary) => {
+                self.table_arrays += 1;
+                self.add_elements(ary.as_slice(), depth);
+            }
+            Table(_, ref map) => {
+                self.tables += 1;
+                self.add_entries(&**map, depth);
+            }
+        }
+    }
+
+    fn add_elements(&mut self, ary: &[Value], depth: uint) {
+        self.max_array_len = cmp::max(self.max_array_len, ary.len());
+        for v in ary.iter() {
+            self.add(v, depth + 1);
+        }
+    }
+
+    fn add_entries(&mut self, map: &TableMap, depth: uint) {
+        self.keys += map.len();
+        for (_, v) in map.iter() {
+            self.add(v, depth + 1);
+        }
+    }
+}
+
+impl Value {
+    /// Counts the values of the document by type and measures its
+    /// nesting, see `Stats`. The root table itself is not counted.
+    pub fn stats(&self) -> Stats {
+        let mut stats = Stats::new();
+        match *self {
+            Table(_, ref map) => { stats.add_entries(&**map, 0) }
+            _ => { stats.add(self, 0) }
+        }
+        return stats;
+    }
+}
+
+/// Computes the `Stats` of a document while it is parsed, without
+/// building it, see `parse_with_visitor`. The result is that of
+/// `Value::stats` for a valid document; as the visitor does not check
+/// for duplicate keys and tables, it is undefined for others.
+pub struct StatsVisitor {
+    priv stats: Stats,
+    // the paths of the tables seen so far, in `lookup` syntax
+    priv tables: HashSet<~str>,
+    // the number of tables of each array of tables seen so far
+    priv arrays: HashMap<~str, uint>,
+    // the depth of the current section
+    priv depth: uint
+}
+
+impl StatsVisitor {
+    pub fn new() -> StatsVisitor {
+        StatsVisitor { stats: Stats::new(), tables: HashSet::new(), arrays: HashMap::new(), depth: 0 }
+    }
+
+    /// The numbers for the part of the document read so far
+    pub fn stats(&self) -> Stats {
+        self.stats.clone()
+    }
+}
+
+impl Visitor for StatsVisitor {
+    fn section(&mut self, path: ~[~str], is_array: bool, _span: Span) -> Result<(), ~str> {
+        let mut current = ~"";
+        let mut depth = 0;
+        for (i, key) in path.iter().enumerate() {
+            current = join_path(current.as_slice(), path_segment(key.as_slice()));
+            depth += 1;
+            let len = if is_array && i == path.len() - 1 {
+                if !self.arrays.contains_key(&current) {
+                    self.stats.keys += 1;
+                    self.stats.table_arrays += 1;
+                }
+                let len = self.arrays.find_or_insert(current.clone(), 0);
+                *len += 1;
+                self.stats.tables += 1;
+                self.stats.max_array_len = cmp::max(self.stats.max_array_len, *len);
+                Some(*len)
+            } else {
+                self.arrays.find(&current).map(|&len| len)
+            };
+
+            match len {
+                // the keys below an array of tables belong to its last table
+                Some(len) => {
+                    current = join_path(current.as_slice(), (len - 1).to_str());
+                    depth += 1;
+                }
+                None => {
+                    if self.tables.insert(current.clone()) {
+                        self.stats.keys += 1;
+                        self.stats.tables += 1;
+                    }
+                }
+            }
+        }
+        self.depth = depth;
+        self.stats.max_depth = cmp::max(self.stats.max_depth, depth);
+        Ok(())
+    }
+
+    fn pair(&mut self, _key: ~str, val: Value, _span: Span) -> Result<(), ~str> {
+        self.stats.keys += 1;
+        self.stats.add(&val, self.depth + 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,291 @@
+//! The map type of tables, remembering the order in which keys were
+//! inserted
+
+use std::fmt;
+use std::hash::Hash;
+use std::iter;
+use std::iter::{FromIterator,Extendable};
+use std::mem;
+use std::vec;
+
+use collections::hashmap::HashMap;
+use sync::Arc;
+
+use super::Value;
+
+// Tables with up to this many entries are searched linearly. Most
+// tables are small, like the elements of a large table array, and so
+// keep a single copy of their keys and no hash map.
+static INDEX_THRESHOLD: uint = 8;
+
+/// A map of keys to values, iterated in the order the keys were first
+/// inserted. Replacing the value of a key keeps its position, removing a
+/// key shifts the later ones. Lookups in larger tables go through a hash
+/// index. Keys can share their storage with other tables, see
+/// `insert_shared`.
+#[deriving(Clone)]
+pub struct TableMap {
+    priv entries: ~[(Arc<~str>, Value)],
+    // the positions of the keys, once there are more than INDEX_THRESHOLD
+    priv index: Option<HashMap<~str, uint>>
+}
+
+impl TableMap {
+    pub fn new() -> TableMap {
+        TableMap { entries: ~[], index: None }
+    }
+
+    pub fn with_capacity(capacity: uint) -> TableMap {
+        let index = if capacity > INDEX_THRESHOLD { Some(HashMap::with_capacity(capacity)) } else { None };
+        TableMap { entries: vec::with_capacity(capacity), index: index }
+    }
+
+    /// Like `find`, but the key can be of any type equivalent to `~str`,
+    /// like `&str`
+    pub fn find_equiv<'a, Q: Hash + Equiv<~str>>(&'a self, key: &Q) -> Option<&'a Value> {
+        match self.position(key) {
+            Some(i) => Some(self.entries[i].ref1()),
+            None => None
+        }
+    }
+
+    fn position<Q: Hash + Equiv<~str>>(&self, key: &Q) -> Option<uint> {
+        match self.index {
+            Some(ref index) => index.find_equiv(key).map(|&i| i),
+            None => self.entries.iter().position(|&(ref k, _)| key.equiv(k.get()))
+        }
+    }
+
+    // Appends a key known to be missing, indexing the keys once the table
+    // outgrows a linear search
+    fn push(&mut self, key: Arc<~str>, value: Value) {
+        let len = self.entries.len();
+        if self.index.is_none() && len == INDEX_THRESHOLD {
+            let mut index = HashMap::with_capacity(len * 2);
+            for (i, &(ref k, _)) in self.entries.iter().enumerate() {
+                index.insert(k.get().clone(), i);
+            }
+            self.index = Some(index);
+        }
+        match self.index {
+            Some(ref mut index) => { index.insert(key.get().clone(), len); }
+            None => {}
+        }
+        self.entries.push((key, value));
+    }
+
+    /// Like `insert`, with a key whose storage may be shared with other
+    /// tables. The parser shares the keys repeated across a document, like
+    /// those of the tables of a large table array, so that each is stored
+    /// once.
+    pub fn insert_shared(&mut self, key: Arc<~str>, value: Value) -> bool {
+        match self.position(key.get()) {
+            Some(i) => {
+                *self.entries[i].mut1() = value;
+                false
+            }
+            None => {
+                self.push(key, value);
+                true
+            }
+        }
+    }
+
+    /// Returns the value of `key`, which must exist
+    pub fn get<'a>(&'a self, key: &~str) -> &'a Value {
+        match self.find(key) {
+            Some(v) => v,
+            None => fail!("TableMap::get: key not found: {}", *key)
+        }
+    }
+
+    /// Returns the value of `key`, inserting the result of `f` at the end
+    /// if the key does not exist
+    pub fn find_or_insert_with<'a>(&'a mut self, key: ~str, f: |&~str| -> Value) -> &'a mut Value {
+        let i = match self.position(&key) {
+            Some(i) => i,
+            None => {
+                let v = f(&key);
+                self.push(Arc::new(key), v);
+                self.entries.len() - 1
+            }
+        };
+        self.entries[i].mut1()
+    }
+
+    /// Iterates over the entries in insertion order
+    pub fn iter<'a>(&'a self) -> Entries<'a> {
+        Entries { iter: self.entries.iter() }
+    }
+
+    /// The entries sorted by key, comparing the keys byte by byte, so
+    /// that upper case comes before lower case and a key before the
+    /// longer keys it is a prefix of
+    pub fn sorted_entries<'a>(&'a self) -> ~[(&'a ~str, &'a Value)] {
+        let mut entries: ~[(&'a ~str, &'a Value)] = self.iter().collect();
+        entries.sort_by(|&(k1, _), &(k2, _)| k1.cmp(k2));
+        return entries;
+    }
+
+    pub fn mut_iter<'a>(&'a mut self) -> MutEntries<'a> {
+        MutEntries { iter: self.entries.mut_iter() }
+    }
+
+    pub fn move_iter(self) -> MoveEntries {
+        MoveEntries { iter: self.entries.move_iter() }
+    }
+
+    pub fn keys<'a>(&'a self) -> Keys<'a> {
+        self.iter().map(|(k, _)| k)
+    }
+
+    pub fn values<'a>(&'a self) -> Values<'a> {
+        self.iter().map(|(_, v)| v)
+    }
+}
+
+impl Container for TableMap {
+    fn len(&self) -> uint { self.entries.len() }
+}
+
+impl Mutable for TableMap {
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.index = None;
+    }
+}
+
+impl Map<~str, Value> for TableMap {
+    fn find<'a>(&'a self, key: &~str) -> Option<&'a Value> {
+        self.find_equiv(key)
+    }
+}
+
+impl MutableMap<~str, Value> for TableMap {
+    fn swap(&mut self, key: ~str, value: Value) -> Option<Value> {
+        match self.position(&key) {
+            Some(i) => {
+                return Some(mem::replace(self.entries[i].mut1(), value));
+            }
+            None => {}
+        }
+        self.push(Arc::new(key), value);
+        return None;
+    }
+
+    fn pop(&mut self, key: &~str) -> Option<Value> {
+        let i = match self.position(key) {
+            Some(i) => i,
+            None => { return None }
+        };
+        let (_, value) = self.entries.remove(i).unwrap();
+        match self.index {
+            Some(ref mut index) => {
+                index.pop(key);
+                for &(ref k, _) in self.entries.slice_from(i).iter() {
+                    *index.get_mut(k.get()) -= 1;
+                }
+            }
+            None => {}
+        }
+        return Some(value);
+    }
+
+    fn find_mut<'a>(&'a mut self, key: &~str) -> Option<&'a mut Value> {
+        match self.position(key) {
+            Some(i) => Some(self.entries[i].mut1()),
+            None => None
+        }
+    }
+}
+
+/// Tables are equal if they have the same entries, in any order
+impl Eq for TableMap {
+    fn eq(&self, other: &TableMap) -> bool {
+        self.len() == other.len() &&
+        self.iter().all(|(k, v)| other.find(k).map_or(false, |v2| v == v2))
+    }
+}
+
+impl fmt::Show for TableMap {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        try!(fmt.buf.write_str("{"));
+        for (i, (k, v)) in self.iter().enumerate() {
+            if i > 0 { try!(fmt.buf.write_str(", ")) }
+            try!(write!(fmt.buf, "{}: {}", *k, *v));
+        }
+        fmt.buf.write_str("}")
+    }
+}
+
+impl FromIterator<(~str, Value)> for TableMap {
+    fn from_iterator<T: Iterator<(~str, Value)>>(iterator: &mut T) -> TableMap {
+        let mut map = TableMap::new();
+        map.extend(iterator);
+        map
+    }
+}
+
+impl Extendable<(~str, Value)> for TableMap {
+    fn extend<T: Iterator<(~str, Value)>>(&mut self, iterator: &mut T) {
+        for (k, v) in iterator.by_ref() {
+            self.insert(k, v);
+        }
+    }
+}
+
+/// Iterator over the entries of a `TableMap`
+pub struct Entries<'a> {
+    priv iter: vec::Items<'a, (Arc<~str>, Value)>
+}
+
+impl<'a> Iterator<(&'a ~str, &'a Value)> for Entries<'a> {
+    fn next(&mut self) -> Option<(&'a ~str, &'a Value)> {
+        self.iter.next().map(|&(ref k, ref v)| (k.get(), v))
+    }
+
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        self.iter.size_hint()
+    }
+}
+
+/// Iterator over the entries of a `TableMap`, with mutable values
+pub struct MutEntries<'a> {
+    priv iter: vec::MutItems<'a, (Arc<~str>, Value)>
+}
+
+impl<'a> Iterator<(&'a ~str, &'a mut Value)> for MutEntries<'a> {
+    fn next(&mut self) -> Option<(&'a ~str, &'a mut Value)> {
+        match self.iter.next() {
+            Some(entry) => {
+                let (ref k, ref mut v) = *entry;
+                Some((k.get(), v))
+            }
+            None => None
+        }
+    }
+
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        self.iter.size_hint()
+    }
+}
+
+/// Iterator over the entries of a `TableMap`, by value
+pub struct MoveEntries {
+    priv iter: vec::MoveItems<(Arc<~str>, Value)>
+}
+
+impl Iterator<(~str, Value)> for MoveEntries {
+    // a shared key is copied
+    fn next(&mut self) -> Option<(~str, Value)> {
+        self.iter.next().map(|(k, v)| (k.get().clone(), v))
+    }
+
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        self.iter.size_hint()
+    }
+}
+
+pub type Keys<'a> = iter::Map<'a, (&'a ~str, &'a Value), &'a ~str, Entries<'a>>;
+
+pub type Values<'a> = iter::Map<'a, (&'a ~str, &'a Value), &'a Value, Entries<'a>>;